@@ -0,0 +1,93 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use log::{info, warn};
+
+/// Where downloaded releases are kept, to resume an interrupted download and
+/// to seed delta downloads of later releases. On persistent storage of the
+/// live medium, they outlive the live session too.
+pub const CACHE_DIR: &str = "/var/cache/deploykit";
+
+fn is_cacheable(path: &Path) -> bool {
+    let name = path.to_string_lossy();
+    name.ends_with(".tar.xz") || name.ends_with(".squashfs")
+}
+
+/// Where to keep the download of `url` for later, None if it is not a
+/// release or the cache can not be created
+pub fn cache_path(url: &str) -> Option<PathBuf> {
+    let dir = Path::new(CACHE_DIR);
+    let name = url.rsplit('/').next().filter(|x| !x.is_empty())?;
+    let path = dir.join(name);
+    if !is_cacheable(&path) {
+        return None;
+    }
+    if let Err(e) = std::fs::create_dir_all(dir) {
+        warn!("Not caching {url}, could not create {CACHE_DIR}: {e}");
+        return None;
+    }
+
+    Some(path)
+}
+
+/// Releases in the cache, to seed a delta download
+pub fn cached_tarballs() -> Vec<PathBuf> {
+    let Ok(dir) = std::fs::read_dir(CACHE_DIR) else {
+        return vec![];
+    };
+
+    dir.flatten()
+        .map(|x| x.path())
+        .filter(|x| is_cacheable(x))
+        .collect()
+}
+
+fn progress_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".partial");
+
+    PathBuf::from(name)
+}
+
+/// How much of the release with `sha256` was downloaded to `path` by an
+/// earlier, interrupted run, 0 if there is nothing to resume
+pub fn resume_offset(path: &Path, sha256: &str) -> u64 {
+    let Ok(state) = std::fs::read_to_string(progress_path(path)) else {
+        return 0;
+    };
+    let offset = match state.split_once(' ') {
+        Some((hash, offset)) if hash == sha256 => offset.trim().parse().unwrap_or(0),
+        _ => 0,
+    };
+    let written = path.metadata().map_or(0, |x| x.len());
+
+    if offset > written {
+        0
+    } else {
+        offset
+    }
+}
+
+/// Record that the first `offset` bytes of the release with `sha256` are in
+/// `path`, so that a later run carries on from there
+pub fn save_progress(path: &Path, sha256: &str, offset: u64) -> Result<()> {
+    std::fs::write(progress_path(path), format!("{sha256} {offset}\n"))?;
+
+    Ok(())
+}
+
+/// Forget about a partial download in `path`, once it is complete or no
+/// longer of use
+pub fn clear_progress(path: &Path) {
+    std::fs::remove_file(progress_path(path)).ok();
+}
+
+/// Remove cached releases other than `keep`, which is the better seed for the
+/// next release
+pub fn prune_cache(keep: &Path) {
+    for path in cached_tarballs().iter().filter(|x| *x != keep) {
+        info!("Removing {} from the cache", path.display());
+        std::fs::remove_file(path).ok();
+        clear_progress(path);
+    }
+}
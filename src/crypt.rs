@@ -0,0 +1,252 @@
+use std::{
+    ffi::OsStr,
+    fmt,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    disks::{self, Partition},
+    install::{run_command, run_command_with_input},
+};
+
+/// Name of the opened LUKS container, under /dev/mapper
+const LUKS_NAME: &str = "aosc-crypt";
+const VG_NAME: &str = "aosc";
+const ROOT_LV_NAME: &str = "root";
+const SWAP_LV_NAME: &str = "swap";
+
+/// Encrypt the system partition with LUKS and put LVM on top of it
+#[derive(Clone, Serialize, Deserialize)]
+pub struct EncryptionConfig {
+    pub passphrase: String,
+    /// Also unlocks the container, in a keyslot of its own
+    #[serde(default)]
+    pub recovery_key: Option<String>,
+}
+
+// the configuration is logged, the secrets must not be
+impl fmt::Debug for EncryptionConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptionConfig")
+            .field("passphrase", &"<redacted>")
+            .field(
+                "recovery_key",
+                &self.recovery_key.as_ref().map(|_| "<redacted>"),
+            )
+            .finish()
+    }
+}
+
+/// Characters of recovery keys, the same as systemd-cryptenroll uses, which
+/// type the same on most keyboard layouts
+const RECOVERY_KEY_CHARS: &[u8] = b"cbdefghijklnrtuv";
+
+/// A random recovery key of 256 bits, as 8 dash-separated groups of 8
+/// characters
+pub fn generate_recovery_key() -> String {
+    let mut rng = rand::thread_rng();
+    let groups = (0..8)
+        .map(|_| {
+            (0..8)
+                .map(|_| RECOVERY_KEY_CHARS[rng.gen_range(0..RECOVERY_KEY_CHARS.len())] as char)
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>();
+
+    groups.join("-")
+}
+
+fn luks_device() -> PathBuf {
+    Path::new("/dev/mapper").join(LUKS_NAME)
+}
+
+/// The logical volume the system is installed to
+pub fn root_volume_path() -> PathBuf {
+    Path::new("/dev").join(VG_NAME).join(ROOT_LV_NAME)
+}
+
+/// The logical volume to swap to, inside the encrypted container
+pub fn swap_volume_path() -> PathBuf {
+    Path::new("/dev").join(VG_NAME).join(SWAP_LV_NAME)
+}
+
+fn partition_path(partition: &Partition) -> Result<&Path> {
+    partition
+        .path
+        .as_deref()
+        .ok_or_else(|| anyhow!("Installer could not find the specified partition."))
+}
+
+/// Arguments of cryptsetup to create a LUKS container on `path`, with the
+/// passphrase read from stdin
+pub fn luks_format_args(path: &Path) -> Vec<&OsStr> {
+    // GRUB can only unlock LUKS2 containers whose key is derived with PBKDF2
+    vec![
+        OsStr::new("luksFormat"),
+        OsStr::new("--batch-mode"),
+        OsStr::new("--type=luks2"),
+        OsStr::new("--pbkdf=pbkdf2"),
+        OsStr::new("--key-file=-"),
+        path.as_os_str(),
+    ]
+}
+
+/// Create a LUKS container on `partition` with a volume group inside,
+/// returns the logical volume to install the system to. With `swap_size`,
+/// a logical volume of that many bytes is created for swap as well, see
+/// [`swap_volume_path`].
+pub fn create_lvm_on_luks(
+    partition: &Partition,
+    encryption: &EncryptionConfig,
+    swap_size: Option<u64>,
+) -> Result<Partition> {
+    let path = partition_path(partition)?;
+    let passphrase = encryption.passphrase.as_str();
+
+    info!("Creating LUKS container on {}", path.display());
+    run_command_with_input("cryptsetup", luks_format_args(path), passphrase.as_bytes())?;
+    if let Some(recovery_key) = &encryption.recovery_key {
+        add_key(path, passphrase, recovery_key)?;
+    }
+    open_luks(path, passphrase)?;
+
+    info!("Creating LVM volume group {VG_NAME}");
+    let luks_device = luks_device();
+    run_command("pvcreate", [&luks_device])?;
+    run_command("vgcreate", [OsStr::new(VG_NAME), luks_device.as_os_str()])?;
+    if let Some(size) = swap_size {
+        let size = format!("{size}b");
+        run_command(
+            "lvcreate",
+            ["--yes", "-L", size.as_str(), "-n", SWAP_LV_NAME, VG_NAME],
+        )?;
+    }
+    run_command(
+        "lvcreate",
+        ["--yes", "-l", "100%FREE", "-n", ROOT_LV_NAME, VG_NAME],
+    )?;
+
+    Ok(Partition {
+        path: Some(root_volume_path()),
+        parent_path: partition.parent_path.clone(),
+        fs_type: partition.fs_type.clone(),
+        size: partition.size,
+    })
+}
+
+/// Add `new_key` to a keyslot of the LUKS container at `path`
+fn add_key(path: &Path, passphrase: &str, new_key: &str) -> Result<()> {
+    info!("Adding recovery key to {}", path.display());
+    // the new key is read from a file, stdin already carries the passphrase
+    let mut key_file = tempfile::NamedTempFile::new()?;
+    key_file.write_all(new_key.as_bytes())?;
+    key_file.flush()?;
+    run_command_with_input(
+        "cryptsetup",
+        [
+            OsStr::new("luksAddKey"),
+            OsStr::new("--batch-mode"),
+            OsStr::new("--pbkdf=pbkdf2"),
+            OsStr::new("--key-file=-"),
+            path.as_os_str(),
+            key_file.path().as_os_str(),
+        ],
+        passphrase.as_bytes(),
+    )
+}
+
+fn open_luks(path: &Path, passphrase: &str) -> Result<()> {
+    run_command_with_input(
+        "cryptsetup",
+        [
+            OsStr::new("open"),
+            OsStr::new("--key-file=-"),
+            path.as_os_str(),
+            OsStr::new(LUKS_NAME),
+        ],
+        passphrase.as_bytes(),
+    )
+}
+
+/// Deactivate the volume group and close the LUKS container, if open
+pub fn close() {
+    if !luks_device().exists() {
+        return;
+    }
+
+    info!("Closing {LUKS_NAME}");
+    run_command("vgchange", ["-an", VG_NAME]).ok();
+    run_command("cryptsetup", ["close", LUKS_NAME]).ok();
+}
+
+fn luks_uuid(path: &Path) -> Result<String> {
+    disks::partition_uuid(path).ok_or_else(|| {
+        anyhow!(
+            "Installer could not obtain the UUID of the LUKS container on {}.",
+            path.display()
+        )
+    })
+}
+
+/// Add the LUKS container on `partition` to /etc/crypttab of the system at `root_path`
+pub fn write_crypttab(partition: &Partition, root_path: &Path) -> Result<()> {
+    write_crypttab_entry(LUKS_NAME, partition_path(partition)?, root_path)
+}
+
+/// Add the LUKS container on `device`, opened as `name`, to /etc/crypttab of
+/// the system at `root_path`
+pub fn write_crypttab_entry(name: &str, device: &Path, root_path: &Path) -> Result<()> {
+    let uuid = luks_uuid(device)?;
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(root_path.join("etc/crypttab"))?;
+    writeln!(f, "{name} UUID={uuid} none luks")?;
+
+    Ok(())
+}
+
+/// Kernel parameters for the initramfs to unlock the container and activate
+/// the system volume, and the swap volume to resume from if `with_swap`
+pub fn kernel_cmdline(partition: &Partition, with_swap: bool) -> Result<String> {
+    let uuid = luks_uuid(partition_path(partition)?)?;
+    let mut cmdline = format!("rd.luks.uuid={uuid} rd.lvm.lv={VG_NAME}/{ROOT_LV_NAME}");
+    if with_swap {
+        cmdline.push_str(&format!(" rd.lvm.lv={VG_NAME}/{SWAP_LV_NAME}"));
+    }
+
+    Ok(cmdline)
+}
+
+/// Kernel parameters for the initramfs to unlock the container on `device`
+pub fn opened_kernel_cmdline(device: &Path) -> Result<String> {
+    Ok(format!("rd.luks.uuid={}", luks_uuid(device)?))
+}
+
+/// Make sure the initramfs can unlock the system volume
+/// Must be used in a chroot context
+pub fn write_dracut_conf() -> Result<()> {
+    std::fs::create_dir_all("/etc/dracut.conf.d")?;
+    std::fs::write(
+        "/etc/dracut.conf.d/80-deploykit-crypt.conf",
+        "add_dracutmodules+=\" crypt lvm \"\n",
+    )?;
+
+    Ok(())
+}
+
+#[test]
+fn test_generate_recovery_key() {
+    let key = generate_recovery_key();
+    assert_eq!(key.len(), 8 * 8 + 7);
+    assert!(key
+        .split('-')
+        .all(|x| x.len() == 8 && x.bytes().all(|c| RECOVERY_KEY_CHARS.contains(&c))));
+    assert_ne!(key, generate_recovery_key());
+}
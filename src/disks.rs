@@ -18,17 +18,36 @@ use libparted_sys::PedPartitionFlag;
 use libparted_sys::PedPartitionType;
 use log::error;
 use log::info;
+use log::warn;
+use rand::Rng;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::ffi::CStr;
+use std::ffi::OsStr;
 use std::ffi::OsString;
+use std::fs;
 use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 use std::path::PathBuf;
 use std::process::Command;
+use std::process::Stdio;
+
+use crate::parser::parse_proc_partitions;
 
 const EFI_DETECT_PATH: &str = "/sys/firmware/efi";
-pub const ALLOWED_FS_TYPE: &[&str] = &["ext4", "xfs"];
-const DEFAULT_FS_TYPE: &str = "ext4";
+const EFI_PLATFORM_SIZE_PATH: &str = "/sys/firmware/efi/fw_platform_size";
+const SYS_BLOCK_PATH: &str = "/sys/block";
+const SYS_CLASS_BLOCK_PATH: &str = "/sys/class/block";
+/// Boundary partitions should start on, in bytes
+const ALIGNMENT: u64 = 1024 * 1024;
+const PROC_PARTITIONS_PATH: &str = "/proc/partitions";
+const SYS_PCI_DEVICES_PATH: &str = "/sys/bus/pci/devices";
+pub const ALLOWED_FS_TYPE: &[&str] = &["ext4", "xfs", "btrfs", "zfs"];
+/// Subvolumes created on a btrfs system partition, and where they are mounted
+pub const BTRFS_SUBVOLUMES: &[(&str, &str)] = &[("@", "/"), ("@home", "/home"), ("@swap", "/swap")];
+pub const DEFAULT_FS_TYPE: &str = "ext4";
 
 const SUPPORT_PARTITION_TYPE: &[&str] = &["primary", "logical"];
 
@@ -52,6 +71,12 @@ pub fn is_efi_booted() -> bool {
     Path::new(EFI_DETECT_PATH).is_dir()
 }
 
+/// Whether the UEFI firmware is 32-bit, as on some Bay Trail tablets and
+/// laptops, which can only load ia32 bootloaders despite a 64-bit CPU
+pub fn is_efi_ia32() -> bool {
+    std::fs::read_to_string(EFI_PLATFORM_SIZE_PATH).is_ok_and(|x| x.trim() == "32")
+}
+
 pub fn get_recommended_fs_type(type_: &str) -> &str {
     for i in ALLOWED_FS_TYPE {
         if *i == type_ {
@@ -63,145 +88,2154 @@ pub fn get_recommended_fs_type(type_: &str) -> &str {
 }
 
 pub fn format_partition(partition: &Partition) -> Result<()> {
-    let default_fs = DEFAULT_FS_TYPE.to_owned();
-    let fs_type = partition.fs_type.as_ref().unwrap_or(&default_fs);
-    let mut command = Command::new(format!("mkfs.{fs_type}"));
-    let cmd;
+    format_partition_with_args(partition, &XfsOptions::default(), &MkfsArgs::new(), None)
+}
+
+/// Extra mkfs arguments for each filesystem type, quoted as in a shell
+/// (e.g., "-O casefold" for ext4)
+pub type MkfsArgs = BTreeMap<String, String>;
+
+/// The arguments in `extra_args` for `fs_type`
+pub fn split_mkfs_args(extra_args: &MkfsArgs, fs_type: &str) -> Result<Vec<String>> {
+    let args = extra_args.get(fs_type).map_or("", |x| x.as_str());
+
+    shell_words::split(args)
+        .map_err(|e| anyhow!("Installer could not parse the mkfs arguments for {fs_type}: {e}"))
+}
+
+/// Format `partition`, passing the `extra_args` for its filesystem to mkfs
+pub fn format_partition_with_args(
+    partition: &Partition,
+    xfs: &XfsOptions,
+    extra_args: &MkfsArgs,
+    label: Option<&str>,
+) -> Result<()> {
+    run_mkfs(mkfs_command(partition, xfs, extra_args, label)?)
+}
+
+/// Create a btrfs filesystem mirrored (RAID1) across `partition` and `mirror`
+pub fn format_btrfs_raid1(
+    partition: &Partition,
+    mirror: &Partition,
+    extra_args: &MkfsArgs,
+    label: Option<&str>,
+) -> Result<()> {
+    run_mkfs(btrfs_raid1_command(partition, mirror, extra_args, label)?)
+}
+
+fn run_mkfs(mut cmd: Command) -> Result<()> {
+    info!("{cmd:?}");
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Installer failed to format the specified partition: \n{}\n{}",
+            String::from_utf8_lossy(&output.stderr),
+            String::from_utf8_lossy(&output.stdout)
+        ));
+    }
+
+    Ok(())
+}
 
+/// Filesystem `partition` is formatted with
+pub fn mkfs_fs_type(partition: &Partition) -> &str {
+    partition.fs_type.as_deref().unwrap_or(DEFAULT_FS_TYPE)
+}
+
+/// The mkfs option that sets the filesystem label
+fn mkfs_label_arg(fs_type: &str) -> &'static str {
+    match fs_type {
+        "vfat" => "-n",
+        "f2fs" => "-l",
+        _ => "-L",
+    }
+}
+
+/// The mkfs command that formats `partition`
+pub fn mkfs_command(
+    partition: &Partition,
+    xfs: &XfsOptions,
+    extra_args: &MkfsArgs,
+    label: Option<&str>,
+) -> Result<Command> {
+    let path = partition.path.as_ref().ok_or_else(|| {
+        anyhow!(
+            "Installer could not find the specified partition.\nDid you partition your target disk?"
+        )
+    })?;
+    let fs_type = mkfs_fs_type(partition);
+    let mut cmd = Command::new(format!("mkfs.{fs_type}"));
     if fs_type == "ext4" {
-        cmd = command.arg("-Fq");
+        cmd.arg("-Fq");
     } else if fs_type == "vfat" {
-        cmd = command.arg("-F32");
+        cmd.arg("-F32");
+    } else if fs_type == "xfs" {
+        cmd.args(["-f", "-m", &xfs.metadata_options()]);
     } else {
-        cmd = command.arg("-f");
+        cmd.arg("-f");
     }
+    if let Some(label) = label {
+        cmd.args([mkfs_label_arg(fs_type), label]);
+    }
+    cmd.args(split_mkfs_args(extra_args, fs_type)?).arg(path);
 
-    info!("{cmd:?}");
-    let output = cmd
-        .arg(
-            partition
-                .path
-                .as_ref()
-                .ok_or_else(|| anyhow!("Installer could not find the specified partition.\nDid you partition your target disk?"))?,
+    Ok(cmd)
+}
+
+/// The mkfs command that creates a btrfs filesystem with both data and
+/// metadata mirrored across `partition` and `mirror`
+pub fn btrfs_raid1_command(
+    partition: &Partition,
+    mirror: &Partition,
+    extra_args: &MkfsArgs,
+    label: Option<&str>,
+) -> Result<Command> {
+    let (Some(path), Some(mirror_path)) = (&partition.path, &mirror.path) else {
+        return Err(anyhow!(
+            "Installer could not find the specified partition.\nDid you partition your target disk?"
+        ));
+    };
+    let mut cmd = Command::new("mkfs.btrfs");
+    cmd.args(["-f", "-d", "raid1", "-m", "raid1"]);
+    if let Some(label) = label {
+        cmd.args(["-L", label]);
+    }
+    cmd.args(split_mkfs_args(extra_args, "btrfs")?)
+        .arg(path)
+        .arg(mirror_path);
+
+    Ok(cmd)
+}
+
+/// Features of newly created XFS filesystems
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct XfsOptions {
+    /// Allow sharing data between files, e.g., for `cp --reflink`
+    pub reflink: bool,
+    /// Timestamps beyond the year 2038
+    pub bigtime: bool,
+}
+
+impl Default for XfsOptions {
+    fn default() -> Self {
+        XfsOptions {
+            reflink: true,
+            bigtime: true,
+        }
+    }
+}
+
+impl XfsOptions {
+    fn metadata_options(&self) -> String {
+        format!(
+            "reflink={},bigtime={}",
+            self.reflink as u8, self.bigtime as u8
         )
-        .output()?;
+    }
+}
+
+/// Share of a new filesystem that is not available to files, in percent:
+/// reserved blocks and the journal on ext4, metadata on the others
+pub fn fs_overhead_percent(fs_type: Option<&str>) -> u64 {
+    match fs_type.unwrap_or(DEFAULT_FS_TYPE) {
+        "ext4" => 7,
+        "btrfs" => 3,
+        "xfs" => 2,
+        _ => 5,
+    }
+}
+
+pub fn fill_fs_type(part: &Partition, use_ext4: bool) -> Partition {
+    let mut new_part = part.clone();
+    let new_fs_type: String;
+    if let Some(fs_type) = new_part.fs_type.clone() {
+        if !use_ext4 {
+            new_fs_type = get_recommended_fs_type(&fs_type).to_string();
+        } else {
+            new_fs_type = DEFAULT_FS_TYPE.to_string();
+        }
+    } else {
+        new_fs_type = DEFAULT_FS_TYPE.to_string();
+    }
+    new_part.fs_type = Some(new_fs_type);
+
+    new_part
+}
+
+pub fn find_esp_partition(device_path: &Path) -> Result<Partition> {
+    find_esp_partitions(device_path)?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow!("Installer could not detect the EFI system partition."))
+}
+
+/// List every EFI system partition on `device_path`
+pub fn find_esp_partitions(device_path: &Path) -> Result<Vec<Partition>> {
+    let mut device = libparted::Device::get(device_path)?;
+    let sector_size = device.sector_size();
+    let mut res = vec![];
+    if let Ok(disk) = libparted::Disk::new(&mut device) {
+        for mut part in disk.parts() {
+            if part.num() < 0 {
+                continue;
+            }
+            if part.get_flag(libparted::PartitionFlag::PED_PARTITION_ESP) {
+                let fs_type = if let Ok(type_) = part.get_geom().probe_fs() {
+                    Some(type_.name().to_owned())
+                } else {
+                    None
+                };
+                let path = part.get_path().ok_or_else(|| {
+                    anyhow!("Installer could not detect the EFI system partition.")
+                })?;
+                res.push(Partition {
+                    path: Some(path.to_owned()),
+                    parent_path: Some(device_path.to_owned()),
+                    size: sector_size * part.geom_length().max(0) as u64,
+                    fs_type,
+                });
+            }
+        }
+    }
+
+    Ok(res)
+}
+
+/// Refuse to install the system to `partition` if it is an EFI system
+/// partition, or a FAT filesystem smaller than `required_size`, which is most
+/// likely one without the flag
+pub fn check_not_esp(partition: &Partition, required_size: u64) -> Result<()> {
+    let Some(path) = partition.path.as_deref() else {
+        return Ok(());
+    };
+    let is_esp = partition
+        .parent_path
+        .as_deref()
+        .and_then(|x| find_esp_partitions(x).ok())
+        .is_some_and(|x| x.iter().any(|x| x.path.as_deref() == Some(path)));
+    let is_small_fat = partition
+        .fs_type
+        .as_deref()
+        .is_some_and(|x| x.starts_with("fat") || x == "vfat")
+        && partition.size < required_size;
+    if is_esp || is_small_fat {
+        bail!(
+            "{} appears to be an EFI System Partition, which your computer needs to boot. Installing AOSC OS to it would leave other systems unbootable, please select another partition.",
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// List the EFI system partitions on all disks, for systems with more than one
+pub fn list_esp_partitions() -> Vec<Partition> {
+    list_devices()
+        .iter()
+        .filter_map(|x| find_esp_partitions(&x.path).ok())
+        .flatten()
+        .collect()
+}
+
+/// Size of the EFI system partition created by the installer
+pub const ESP_SIZE: u64 = 512 * 1024 * 1024;
+
+/// Find free space on `dev` large enough for an ESP, returns the start and
+/// end sectors, aligned to 1MiB
+pub fn esp_free_space(dev: &Path) -> Result<Option<(u64, u64)>> {
+    find_free_space(dev, ESP_SIZE)
+}
+
+/// Find free space of at least `size` bytes on `dev`, returns the start and
+/// end sectors, aligned to 1MiB
+pub fn find_free_space(dev: &Path, size: u64) -> Result<Option<(u64, u64)>> {
+    Ok(list_free_space(dev)?
+        .into_iter()
+        .find(|x| x.size >= size)
+        .map(|x| {
+            let sectors = size.div_ceil(x.sector_size);
+            (x.start_sector, x.start_sector + sectors)
+        }))
+}
+
+/// Unallocated region on a disk, aligned to 1MiB
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeSpace {
+    pub start_sector: u64,
+    pub end_sector: u64,
+    pub sector_size: u64,
+    pub size: u64,
+}
+
+/// All unallocated regions on `dev` of at least 1MiB, in disk order
+pub fn list_free_space(dev: &Path) -> Result<Vec<FreeSpace>> {
+    let mut device = libparted::Device::new(dev)?;
+    let sector_size = device.sector_size();
+    let align = ALIGNMENT / sector_size;
+    let disk = libparted::Disk::new(&mut device)?;
+
+    let mut res = vec![];
+    for part in disk.parts() {
+        if part.type_get_name() != "free" {
+            continue;
+        }
+        let start = (part.geom_start().max(0) as u64).div_ceil(align) * align;
+        let end = (part.geom_end().max(0) as u64 + 1) / align * align;
+        if start < end {
+            res.push(FreeSpace {
+                start_sector: start,
+                end_sector: end,
+                sector_size,
+                size: (end - start) * sector_size,
+            });
+        }
+    }
+
+    Ok(res)
+}
+
+/// The largest unallocated region on `dev`, if any
+pub fn largest_free_space(dev: &Path) -> Result<Option<FreeSpace>> {
+    Ok(list_free_space(dev)?.into_iter().max_by_key(|x| x.size))
+}
+
+/// Create a partition filling `free` on `dev` for the system to be installed
+/// to, it is formatted later along with the rest of the installation
+pub fn create_partition_in_free_space(dev: &Path, free: &FreeSpace) -> Result<Partition> {
+    info!(
+        "Creating partition on {}: {} - {}",
+        dev.display(),
+        free.start_sector,
+        free.end_sector
+    );
+
+    let kind = free_space_partition_type(dev, free)?;
+    let mut device = libparted::Device::new(dev)?;
+    let part = &PartitionCreate {
+        path: dev.to_path_buf(),
+        start_sector: free.start_sector,
+        end_sector: free.end_sector,
+        format: false,
+        file_system: None,
+        kind,
+        flags: vec![],
+        label: None,
+    };
+    create_partition(&mut device, part).map_err(|e| {
+        anyhow!(
+            "Installer could not create a partition in the free space on {}: {e}",
+            dev.display()
+        )
+    })?;
+
+    let disk = libparted::Disk::new(&mut device)?;
+    let mut part = disk
+        .get_partition_by_sector(free.start_sector as i64)
+        .ok_or_else(|| anyhow!("Could not find partition by sector: {}", free.start_sector))?;
+
+    Ok(Partition {
+        path: part.get_path().map(|x| x.to_path_buf()),
+        parent_path: Some(dev.to_path_buf()),
+        fs_type: None,
+        size: free.size,
+    })
+}
+
+/// Free space inside the extended partition of an MBR disk can only hold
+/// logical partitions, and there is no room for a fifth primary one
+fn free_space_partition_type(dev: &Path, free: &FreeSpace) -> Result<PartitionType> {
+    if get_partition_table_type(Some(dev)).map_or(true, |x| x != "msdos") {
+        return Ok(PartitionType::Primary);
+    }
+
+    let mut device = libparted::Device::new(dev)?;
+    let disk = libparted::Disk::new(&mut device)?;
+    let mut primaries = 0;
+    for part in disk.parts() {
+        match part.type_get_name() {
+            "extended" => {
+                if part.geom_start() as u64 <= free.start_sector
+                    && part.geom_end() as u64 >= free.end_sector - 1
+                {
+                    return Ok(PartitionType::Logical);
+                }
+                primaries += 1;
+            }
+            "primary" if part.num() > 0 => primaries += 1,
+            _ => (),
+        }
+    }
+
+    if primaries >= 4 {
+        bail!(
+            "Installer could not create a partition in the free space on {}: the MBR partition table already has 4 primary partitions and the free space is not inside an extended partition.",
+            dev.display()
+        );
+    }
+
+    Ok(PartitionType::Primary)
+}
+
+/// Create an ESP on `dev`, in free space if there is enough, otherwise at the
+/// end of `system`, which is about to be formatted anyway
+pub fn create_esp_partition(
+    dev: &Path,
+    system: &Partition,
+    label: Option<&str>,
+) -> Result<Partition> {
+    let (start_sector, end_sector) = match esp_free_space(dev)? {
+        Some(free) => free,
+        None => shrink_for_esp(dev, system)?,
+    };
+    info!(
+        "Creating EFI system partition on {}: {start_sector} - {end_sector}",
+        dev.display()
+    );
+
+    let mut device = libparted::Device::new(dev)?;
+    let sector_size = device.sector_size();
+    let esp = &PartitionCreate {
+        path: dev.to_path_buf(),
+        start_sector,
+        end_sector,
+        format: true,
+        file_system: Some(FileSystem::Fat32),
+        kind: PartitionType::Primary,
+        flags: vec![
+            PedPartitionFlag::PED_PARTITION_BOOT,
+            PedPartitionFlag::PED_PARTITION_ESP,
+        ],
+        label: None,
+    };
+    create_partition(&mut device, esp)?;
+
+    let disk = libparted::Disk::new(&mut device)?;
+    let mut part = disk
+        .get_partition_by_sector(start_sector as i64)
+        .ok_or_else(|| anyhow!("Could not find partition by sector: {start_sector}"))?;
+    let p = Partition {
+        path: part.get_path().map(|x| x.to_path_buf()),
+        parent_path: Some(dev.to_path_buf()),
+        fs_type: Some("vfat".to_string()),
+        size: (end_sector - start_sector) * sector_size,
+    };
+    format_partition_with_args(&p, &XfsOptions::default(), &MkfsArgs::new(), label)?;
+
+    Ok(p)
+}
+
+/// Size of the BIOS boot partition created by the installer, GRUB embeds
+/// its core image there on GPT disks
+pub const BIOS_GRUB_SIZE: u64 = 1024 * 1024;
+
+/// Whether GRUB needs a BIOS boot partition on `dev` that it does not have,
+/// i.e., a GPT disk on a BIOS system
+pub fn needs_bios_grub_partition(dev: &Path) -> bool {
+    if is_efi_booted() || !cfg!(any(target_arch = "x86_64", target_arch = "x86")) {
+        return false;
+    }
+    if get_partition_table_type(Some(dev)).ok().as_deref() != Some("gpt") {
+        return false;
+    }
+    let Ok(mut device) = libparted::Device::new(dev) else {
+        return false;
+    };
+    let Ok(disk) = libparted::Disk::new(&mut device) else {
+        return false;
+    };
+    let has_bios_grub = disk
+        .parts()
+        .any(|x| x.num() >= 0 && x.get_flag(libparted::PartitionFlag::PED_PARTITION_BIOS_GRUB));
+
+    !has_bios_grub
+}
+
+/// Create a BIOS boot partition in free space on `dev`
+pub fn create_bios_grub_partition(dev: &Path) -> Result<()> {
+    let (start_sector, end_sector) = find_free_space(dev, BIOS_GRUB_SIZE)?.ok_or_else(|| {
+        anyhow!(
+            "Installer could not find 1MiB of free space on {} to create a BIOS boot partition.",
+            dev.display()
+        )
+    })?;
+    info!(
+        "Creating BIOS boot partition on {}: {start_sector} - {end_sector}",
+        dev.display()
+    );
+
+    let mut device = libparted::Device::new(dev)?;
+    let bios_grub = &PartitionCreate {
+        path: dev.to_path_buf(),
+        start_sector,
+        end_sector,
+        format: false,
+        file_system: None,
+        kind: PartitionType::Primary,
+        flags: vec![PedPartitionFlag::PED_PARTITION_BIOS_GRUB],
+        label: None,
+    };
+    create_partition(&mut device, bios_grub)?;
+
+    Ok(())
+}
+
+/// How to wipe a disk before it is partitioned
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WipeMethod {
+    #[default]
+    None,
+    /// Discard every block, fast but only supported by SSDs and the like
+    Discard,
+    /// Overwrite the whole disk with zeros, slow
+    Zero,
+}
+
+impl WipeMethod {
+    pub const ALL: [WipeMethod; 3] = [WipeMethod::None, WipeMethod::Discard, WipeMethod::Zero];
+
+    pub fn description(self) -> &'static str {
+        match self {
+            WipeMethod::None => "Do not wipe",
+            WipeMethod::Discard => "Discard all blocks (fast, SSD only)",
+            WipeMethod::Zero => "Fill with zeros (slow)",
+        }
+    }
+}
+
+/// Erase the contents of the whole disk at `dev`
+pub fn wipe_disk(dev: &Path, method: WipeMethod) -> Result<()> {
+    match method {
+        WipeMethod::None => return Ok(()),
+        WipeMethod::Discard => {
+            crate::preflight::check_tools(&["blkdiscard"])?;
+            info!("Discarding all blocks on {}", dev.display());
+            command_output("blkdiscard", &[OsStr::new("-f"), dev.as_os_str()])?;
+        }
+        WipeMethod::Zero => {
+            info!("Filling {} with zeros", dev.display());
+            let device = libparted::Device::new(dev)?;
+            let size = device.length() * device.sector_size();
+            let mut f = fs::OpenOptions::new().write(true).open(dev)?;
+            let buf = vec![0u8; 4 * 1024 * 1024];
+            let mut written = 0;
+            while written < size {
+                let len = (buf.len() as u64).min(size - written) as usize;
+                f.write_all(&buf[..len])?;
+                written += len as u64;
+            }
+            f.sync_all()?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Amount of data written or read to measure the speed of the target
+const BENCHMARK_SIZE: usize = 64 * 1024 * 1024;
+
+/// Below this many bytes per second read, e.g. SD cards and USB 2.0 drives,
+/// installing takes much longer than usual
+pub const SLOW_READ_SPEED: f64 = 20.0 * 1024.0 * 1024.0;
+
+/// Measure the sequential read speed of the partition at `path`, in bytes per
+/// second. Unlike writing, this is harmless before the user has confirmed.
+pub fn benchmark_read(path: &Path) -> Result<f64> {
+    let mut f = fs::File::open(path)?;
+    // what is cached says nothing about the disk
+    rustix::fs::fadvise(&f, 0, 0, rustix::fs::Advice::DontNeed)?;
+    let mut buf = vec![0u8; 4 * 1024 * 1024];
+
+    info!("Measuring read speed of {}", path.display());
+    let started = std::time::Instant::now();
+    let mut read = 0;
+    while read < BENCHMARK_SIZE {
+        match f.read(&mut buf)? {
+            0 => break,
+            n => read += n,
+        }
+    }
+    let elapsed = started.elapsed().as_secs_f64();
+    if read == 0 {
+        bail!("Installer could not read from {}.", path.display());
+    }
+
+    Ok(read as f64 / elapsed)
+}
+
+/// Measure the sequential write speed of the filesystem mounted at `dir`,
+/// in bytes per second
+pub fn benchmark_write(dir: &Path) -> Result<f64> {
+    let path = dir.join(".deploykit-benchmark");
+    // random data, so that compressing filesystems do not skew the result
+    let mut buf = vec![0u8; 4 * 1024 * 1024];
+    rand::thread_rng().fill(&mut buf[..]);
+
+    info!("Measuring write speed of {}", dir.display());
+    let started = std::time::Instant::now();
+    let res = (|| -> Result<()> {
+        let mut f = fs::File::create(&path)?;
+        for _ in 0..BENCHMARK_SIZE / buf.len() {
+            f.write_all(&buf)?;
+        }
+        f.sync_all()?;
+        Ok(())
+    })();
+    let elapsed = started.elapsed().as_secs_f64();
+    fs::remove_file(&path).ok();
+    res?;
+
+    Ok(BENCHMARK_SIZE as f64 / elapsed)
+}
+
+/// Filesystems that can be shrunk to make room for AOSC OS
+pub const SHRINKABLE_FS_TYPE: &[&str] = &["ntfs", "ext4"];
+
+/// Space left on a shrunk filesystem on top of its minimum size, so that it
+/// stays usable
+pub const SHRINK_MARGIN: u64 = 1024 * 1024 * 1024;
+
+fn command_output(command: &str, args: &[&OsStr]) -> Result<String> {
+    let output = Command::new(command).args(args).output()?;
     if !output.status.success() {
-        return Err(anyhow!(
-            "Installer failed to format the specified partition: \n{}\n{}",
+        bail!(
+            "Installer failed to run {command}: \n{}\n{}",
             String::from_utf8_lossy(&output.stderr),
             String::from_utf8_lossy(&output.stdout)
-        ));
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+fn shrinkable_path(partition: &Partition) -> Result<&Path> {
+    let fs_type = partition.fs_type.as_deref().unwrap_or_default();
+    if !SHRINKABLE_FS_TYPE.contains(&fs_type) {
+        bail!("Installer can not shrink {fs_type} filesystems, only NTFS and ext4 are supported.");
+    }
+    if fs_type == "ntfs" {
+        crate::preflight::check_tools(&["ntfsresize"])?;
+    } else {
+        crate::preflight::check_tools(&["e2fsck", "resize2fs", "dumpe2fs"])?;
+    }
+
+    let path = partition
+        .path
+        .as_deref()
+        .ok_or_else(|| anyhow!("Installer could not find the specified partition."))?;
+    let usage = crate::install::partition_usage(partition);
+    if !usage.is_empty() {
+        bail!(
+            "Installer can not shrink {} while it is in use ({}), please unmount it first.",
+            path.display(),
+            usage.join(", ")
+        );
+    }
+
+    Ok(path)
+}
+
+/// Run ntfsresize, which refuses volumes that Windows left hibernated or
+/// marked for a disk check, with the reason why
+fn ntfsresize(args: &[&OsStr]) -> Result<String> {
+    let mut child = Command::new("ntfsresize")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    // answer the confirmation prompt ourselves, --force would also skip the
+    // checks
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(b"y\n").ok();
+    }
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "ntfsresize refused to resize this NTFS volume. If Windows is hibernated or uses Fast Startup, shut it down fully (or run chkdsk) and try again.\n{}\n{}",
+            String::from_utf8_lossy(&output.stderr),
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout).to_string())
+}
+
+/// Smallest size `partition` can be shrunk to, in bytes
+pub fn min_shrink_size(partition: &Partition) -> Result<u64> {
+    let path = shrinkable_path(partition)?;
+    let size = if partition.fs_type.as_deref() == Some("ntfs") {
+        let output = ntfsresize(&[
+            OsStr::new("--info"),
+            OsStr::new("--no-progress-bar"),
+            path.as_os_str(),
+        ])?;
+        parse_ntfsresize_min_size(&output)
+    } else {
+        let blocks = command_output("resize2fs", &[OsStr::new("-P"), path.as_os_str()])?;
+        let header = command_output("dumpe2fs", &[OsStr::new("-h"), path.as_os_str()])?;
+        parse_resize2fs_min_blocks(&blocks)
+            .zip(parse_field(&header, "Block size:"))
+            .map(|(blocks, block_size)| blocks * block_size)
+    };
+
+    size.ok_or_else(|| {
+        anyhow!(
+            "Installer could not determine how far {} can be shrunk.",
+            path.display()
+        )
+    })
+}
+
+fn parse_ntfsresize_min_size(output: &str) -> Option<u64> {
+    output
+        .lines()
+        .find_map(|x| x.trim().strip_prefix("You might resize at "))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()
+}
+
+fn parse_resize2fs_min_blocks(output: &str) -> Option<u64> {
+    parse_field(output, "Estimated minimum size of the filesystem:")
+}
+
+fn parse_field(output: &str, field: &str) -> Option<u64> {
+    output
+        .lines()
+        .find_map(|x| x.trim().strip_prefix(field))?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Shrink the filesystem on `partition` and then the partition itself to
+/// `new_size` bytes, leaving free space behind it
+pub fn shrink_partition(partition: &Partition, new_size: u64) -> Result<()> {
+    let path = shrinkable_path(partition)?;
+    let min_size = min_shrink_size(partition)?;
+    if new_size < min_size + SHRINK_MARGIN || new_size >= partition.size {
+        bail!(
+            "Installer can only shrink {} to between {} and {} bytes.",
+            path.display(),
+            min_size + SHRINK_MARGIN,
+            partition.size
+        );
+    }
+    // the partition is rounded up to 1MiB, the filesystem has to fit
+    let new_size = new_size / (1024 * 1024) * (1024 * 1024);
+
+    info!("Shrinking {} to {new_size} bytes", path.display());
+    if partition.fs_type.as_deref() == Some("ntfs") {
+        let size = new_size.to_string();
+        ntfsresize(&[
+            OsStr::new("--no-progress-bar"),
+            OsStr::new("--size"),
+            OsStr::new(&size),
+            path.as_os_str(),
+        ])?;
+    } else {
+        // resize2fs refuses filesystems that have not been checked recently
+        command_output("e2fsck", &[OsStr::new("-fy"), path.as_os_str()])?;
+        let size = format!("{}K", new_size / 1024);
+        command_output("resize2fs", &[path.as_os_str(), OsStr::new(&size)])?;
+    }
+
+    shrink_partition_entry(partition, new_size)
+}
+
+fn shrink_partition_entry(partition: &Partition, new_size: u64) -> Result<()> {
+    set_partition_bounds(partition, |start, _, sector_size| {
+        (start, start + (new_size / sector_size) as i64 - 1)
+    })
+}
+
+/// Move the bounds of `partition` in its partition table, `bounds` maps the
+/// current start and end sectors and the sector size to the new ones
+fn set_partition_bounds<F>(partition: &Partition, bounds: F) -> Result<()>
+where
+    F: FnOnce(i64, i64, u64) -> (i64, i64),
+{
+    let path = partition
+        .path
+        .as_deref()
+        .ok_or_else(|| anyhow!("Installer could not find the specified partition."))?;
+    let dev = partition
+        .parent_path
+        .as_deref()
+        .ok_or_else(|| anyhow!("Installer could not find the disk of {}.", path.display()))?;
+    let num = partition_number(path)
+        .ok_or_else(|| anyhow!("Installer could not find the number of {}.", path.display()))?;
+
+    let mut device = libparted::Device::new(dev)?;
+    let device = &mut device as *mut Device;
+    let device = unsafe { &mut (*device) };
+    let sector_size = device.sector_size();
+    let (start, end) = {
+        let disk = libparted::Disk::new(&mut *device)?;
+        let part = disk.get_partition(num).ok_or_else(|| {
+            anyhow!(
+                "Installer could not find partition {num} on {}.",
+                dev.display()
+            )
+        })?;
+        bounds(part.geom_start(), part.geom_end(), sector_size)
+    };
+
+    let geometry = Geometry::new(device, start, end - start + 1)?;
+    let constraint = geometry.exact().expect("exact constraint not found");
+    let mut disk = open_disk(device)?;
+    {
+        let disk_ptr = &mut disk as *mut Disk;
+        let mut part = disk.get_partition(num).ok_or_else(|| {
+            anyhow!(
+                "Installer could not find partition {num} on {}.",
+                dev.display()
+            )
+        })?;
+        unsafe { (*disk_ptr).set_partition_geom(&mut part, &constraint, start, end)? };
+    }
+    commit(&mut disk)?;
+
+    Ok(())
+}
+
+/// Offset of `partition` from the start of its disk in bytes, None if it is
+/// not a partition of a disk (e.g., a logical volume)
+pub fn partition_offset(partition: &Partition) -> Option<u64> {
+    let name = fs::canonicalize(partition.path.as_deref()?).ok()?;
+    // always in 512-byte sectors, whatever the sector size of the disk
+    let start = fs::read_to_string(
+        Path::new(SYS_CLASS_BLOCK_PATH)
+            .join(name.file_name()?)
+            .join("start"),
+    )
+    .ok()?;
+
+    start.trim().parse::<u64>().ok().map(|x| x * 512)
+}
+
+/// Whether `partition` starts on a 1MiB boundary, misaligned partitions
+/// are much slower on SSDs and disks with 4K sectors
+pub fn is_aligned(partition: &Partition) -> bool {
+    partition_offset(partition).map_or(true, |x| x % ALIGNMENT == 0)
+}
+
+/// Move the start of `partition` forward to the next 1MiB boundary, its
+/// content is lost
+pub fn realign_partition(partition: &Partition) -> Result<Partition> {
+    let path = partition
+        .path
+        .as_deref()
+        .ok_or_else(|| anyhow!("Installer could not find the specified partition."))?;
+    info!("Realigning {}", path.display());
+    let mut size = partition.size;
+    set_partition_bounds(partition, |start, end, sector_size| {
+        let align = (ALIGNMENT / sector_size) as i64;
+        let start = (start + align - 1) / align * align;
+        size = (end - start + 1) as u64 * sector_size;
+        (start, end)
+    })?;
+
+    Ok(Partition {
+        size,
+        ..partition.clone()
+    })
+}
+
+/// Mount points that are managed by the installer or the system itself
+const RESERVED_MOUNT_POINTS: &[&str] = &[
+    "/",
+    "/boot/efi",
+    "/dev",
+    "/efi",
+    "/home",
+    "/proc",
+    "/run",
+    "/sys",
+    "/usr",
+];
+
+/// Another partition mounted into the installed system, e.g. /var or /srv
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExtraMount {
+    pub partition: Partition,
+    pub mount_point: PathBuf,
+    /// Format the partition before installing, otherwise its content is kept
+    pub format: bool,
+}
+
+impl ExtraMount {
+    pub fn new(partition: &Partition, mount_point: &str, format: bool) -> Result<Self> {
+        let mount_point = mount_point.trim().trim_end_matches('/');
+        let path = Path::new(mount_point);
+        let valid = path.is_absolute()
+            && path
+                .components()
+                .skip(1)
+                .all(|x| matches!(x, std::path::Component::Normal(_)));
+        if !valid || RESERVED_MOUNT_POINTS.contains(&mount_point) {
+            bail!("{mount_point} can not be used as a mount point.");
+        }
+        let partition = if format {
+            fill_fs_type(partition, false)
+        } else if partition.fs_type.is_some() {
+            partition.clone()
+        } else {
+            bail!(
+                "{} is not formatted, it has to be formatted to be mounted.",
+                partition
+                    .path
+                    .as_deref()
+                    .unwrap_or(Path::new("?"))
+                    .display()
+            );
+        };
+
+        Ok(ExtraMount {
+            partition,
+            mount_point: path.to_path_buf(),
+            format,
+        })
+    }
+
+    /// Where it is mounted below the root of the installed system
+    pub fn relative(&self) -> &Path {
+        self.mount_point
+            .strip_prefix("/")
+            .unwrap_or(&self.mount_point)
+    }
+}
+
+impl std::fmt::Display for ExtraMount {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} on {}{}",
+            self.partition
+                .path
+                .as_deref()
+                .unwrap_or(Path::new("?"))
+                .display(),
+            self.mount_point.display(),
+            if self.format { ", formatted" } else { "" }
+        )
+    }
+}
+
+/// Where to swap to instead of a swapfile
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SwapPartition {
+    /// Format an existing partition as swap
+    Existing(Partition),
+    /// Create a partition of `size` bytes in free space on `disk`
+    New { disk: PathBuf, size: u64 },
+}
+
+impl SwapPartition {
+    /// Get the swap partition, creating it first if needed
+    pub fn resolve(&self) -> Result<Partition> {
+        match self {
+            SwapPartition::Existing(partition) => Ok(Partition {
+                fs_type: Some("swap".to_string()),
+                ..partition.clone()
+            }),
+            SwapPartition::New { disk, size } => create_swap_partition(disk, *size),
+        }
+    }
+}
+
+impl std::fmt::Display for SwapPartition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SwapPartition::Existing(partition) => write!(
+                f,
+                "{}",
+                partition
+                    .path
+                    .as_deref()
+                    .unwrap_or(Path::new("?"))
+                    .display()
+            ),
+            SwapPartition::New { disk, size } => write!(
+                f,
+                "a new {:.1}GiB partition on {}",
+                *size as f64 / 1024.0 / 1024.0 / 1024.0,
+                disk.display()
+            ),
+        }
+    }
+}
+
+fn create_swap_partition(dev: &Path, size: u64) -> Result<Partition> {
+    if size == 0 {
+        bail!("Installer can not create an empty swap partition.");
+    }
+    let (start_sector, end_sector) = find_free_space(dev, size)?.ok_or_else(|| {
+        anyhow!(
+            "Installer could not find enough free space on {} to create a swap partition.",
+            dev.display()
+        )
+    })?;
+    info!(
+        "Creating swap partition on {}: {start_sector} - {end_sector}",
+        dev.display()
+    );
+
+    let mut device = libparted::Device::new(dev)?;
+    let sector_size = device.sector_size();
+    let swap = &PartitionCreate {
+        path: dev.to_path_buf(),
+        start_sector,
+        end_sector,
+        format: true,
+        file_system: Some(FileSystem::Swap),
+        kind: PartitionType::Primary,
+        flags: vec![],
+        label: None,
+    };
+    create_partition(&mut device, swap)?;
+
+    let disk = libparted::Disk::new(&mut device)?;
+    let mut part = disk
+        .get_partition_by_sector(start_sector as i64)
+        .ok_or_else(|| anyhow!("Could not find partition by sector: {start_sector}"))?;
+
+    Ok(Partition {
+        path: part.get_path().map(|x| x.to_path_buf()),
+        parent_path: Some(dev.to_path_buf()),
+        fs_type: Some("swap".to_string()),
+        size: (end_sector - start_sector) * sector_size,
+    })
+}
+
+/// Delete `partition` from the partition table of its disk
+pub fn remove_partition(partition: &Partition) -> Result<()> {
+    let (Some(path), Some(dev)) = (&partition.path, &partition.parent_path) else {
+        bail!("Installer could not find the disk of the partition to remove.");
+    };
+    let num = partition_number(path)
+        .ok_or_else(|| anyhow!("Installer could not find the number of {}.", path.display()))?;
+    info!("Removing partition {}", path.display());
+
+    let mut device = libparted::Device::new(dev)?;
+    let mut disk = libparted::Disk::new(&mut device)?;
+    disk.remove_partition_by_number(num)?;
+    commit(&mut disk)?;
+
+    Ok(())
+}
+
+/// Shrink `system` in place by 512MiB to make room for an ESP after it,
+/// returns the start and end sectors of the freed space
+fn shrink_for_esp(dev: &Path, system: &Partition) -> Result<(u64, u64)> {
+    if system.size < ESP_SIZE + ALIGNMENT {
+        bail!("Installer could not make room for an EFI system partition: the specified partition is too small.");
+    }
+
+    info!(
+        "Shrinking {:?} on {} to make room for an ESP",
+        system.path,
+        dev.display()
+    );
+    let mut freed = (0, 0);
+    set_partition_bounds(system, |start, end, sector_size| {
+        let align = (ALIGNMENT / sector_size) as i64;
+        let esp_sectors = (ESP_SIZE / sector_size) as i64;
+        let system_end = (end + 1 - esp_sectors) / align * align;
+        freed = (system_end as u64, (system_end + esp_sectors) as u64);
+        (start, system_end - 1)
+    })?;
+
+    Ok(freed)
+}
+
+/// List the disks AOSC OS may be installed to
+///
+/// Disks are enumerated from `/proc/partitions` and `/sys/block` so that no
+/// device has to be opened; libparted is only used if that fails.
+pub fn list_devices() -> Vec<DkDerive> {
+    match list_devices_sysfs() {
+        Ok(devices) => devices,
+        Err(e) => {
+            warn!("Could not enumerate disks from sysfs, falling back to libparted: {e}");
+            list_devices_libparted()
+        }
+    }
+}
+
+fn list_devices_sysfs() -> Result<Vec<DkDerive>> {
+    let buf = fs::read(PROC_PARTITIONS_PATH)?;
+    let parts = parse_proc_partitions(&buf)
+        .map_err(|e| anyhow!("Failed to parse {PROC_PARTITIONS_PATH}, {e}"))?
+        .1;
+
+    let live_medium = live_medium_disks();
+    let mut res = vec![];
+    for part in parts {
+        // Only whole disks have an entry in /sys/block
+        let sys_path = Path::new(SYS_BLOCK_PATH).join(part.name);
+        if !sys_path.is_dir() || part.blocks == 0 {
+            continue;
+        }
+
+        let path = Path::new("/dev").join(part.name);
+        if !device_is_supported(&path) || live_medium.contains(&path) {
+            continue;
+        }
+
+        let model = fs::read_to_string(sys_path.join("device/model"))
+            .map(|x| x.trim().to_string())
+            .ok()
+            .filter(|x| !x.is_empty())
+            .unwrap_or_else(|| "Unknown".to_string());
+
+        res.push(DkDerive {
+            path,
+            model,
+            size: part.blocks * 1024,
+        });
+    }
+
+    Ok(res)
+}
+
+/// Identifying details of a disk, to tell disks of the same size apart
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskInfo {
+    pub model: String,
+    pub bus: &'static str,
+    pub serial: Option<String>,
+    pub sector_sizes: SectorSizes,
+}
+
+impl std::fmt::Display for DiskInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}, {}", self.model, self.bus)?;
+        if self.sector_sizes.is_4kn() {
+            write!(f, ", 4Kn")?;
+        } else if self.sector_sizes.physical > self.sector_sizes.logical {
+            write!(f, ", 512e")?;
+        }
+        if let Some(serial) = &self.serial {
+            write!(f, ", S/N {serial}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Look up the model, bus and serial number of the disk at `dev` from sysfs
+pub fn disk_info(dev: &Path) -> DiskInfo {
+    let sys_path = dev
+        .file_name()
+        .map(|x| Path::new(SYS_BLOCK_PATH).join(x))
+        .unwrap_or_default();
+    let read = |name: &str| {
+        fs::read_to_string(sys_path.join("device").join(name))
+            .map(|x| x.trim().to_string())
+            .ok()
+            .filter(|x| !x.is_empty())
+    };
+    let bus = fs::canonicalize(&sys_path)
+        .map(|x| bus_from_sys_path(&x.to_string_lossy()))
+        .unwrap_or("Unknown");
+
+    DiskInfo {
+        model: read("model")
+            .or_else(|| read("name"))
+            .unwrap_or_else(|| "Unknown".to_string()),
+        bus,
+        // NVMe and SD cards have their serial in sysfs, SCSI disks in VPD page 0x80
+        serial: read("serial").or_else(|| {
+            let vpd = fs::read(sys_path.join("device/vpd_pg80")).ok()?;
+            let serial = String::from_utf8_lossy(vpd.get(4..)?).trim().to_string();
+
+            Some(serial).filter(|x| !x.is_empty())
+        }),
+        sector_sizes: sector_sizes(dev),
+    }
+}
+
+/// Logical and physical sector sizes of a disk, in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SectorSizes {
+    /// What partition tables and filesystems address the disk in
+    pub logical: u64,
+    /// What the disk writes in, partitions should be aligned to it
+    pub physical: u64,
+}
+
+impl SectorSizes {
+    /// Native 4K sectors, without 512-byte emulation
+    pub fn is_4kn(&self) -> bool {
+        self.logical >= 4096
+    }
+}
+
+/// Look up the sector sizes of the disk at `dev` from sysfs, assuming 512
+/// bytes where unknown
+pub fn sector_sizes(dev: &Path) -> SectorSizes {
+    let queue = dev
+        .file_name()
+        .map(|x| Path::new(SYS_BLOCK_PATH).join(x).join("queue"))
+        .unwrap_or_default();
+    let read = |name: &str| {
+        fs::read_to_string(queue.join(name))
+            .ok()
+            .and_then(|x| x.trim().parse::<u64>().ok())
+            .filter(|x| *x > 0)
+            .unwrap_or(512)
+    };
+    let logical = read("logical_block_size");
+
+    SectorSizes {
+        logical,
+        physical: read("physical_block_size").max(logical),
+    }
+}
+
+/// Smallest FAT32 filesystem on a 4Kn disk, 65525 clusters of 4KiB
+const FAT32_4KN_MIN_SIZE: u64 = 65525 * 4096;
+
+/// Make sure the disk of `partition` can be booted from and the filesystem
+/// on it supports its sector size
+pub fn check_sector_size(partition: &Partition) -> Result<()> {
+    let Some(disk) = &partition.parent_path else {
+        return Ok(());
+    };
+    let sizes = sector_sizes(disk);
+    if !sizes.is_4kn() {
+        return Ok(());
+    }
+    if !is_efi_booted() {
+        bail!(
+            "{} uses 4096-byte sectors (4Kn), which GRUB can not boot from on BIOS systems. Please boot the installer in UEFI mode.",
+            disk.display()
+        );
+    }
+    if partition.fs_type.as_deref() == Some("vfat") && partition.size < FAT32_4KN_MIN_SIZE {
+        bail!(
+            "{} is too small for a FAT32 filesystem on a disk with 4096-byte sectors (4Kn), at least 256MiB is required.",
+            partition.path.as_deref().unwrap_or(disk).display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Largest disk an MBR partition table can address, with 32-bit sector
+/// numbers
+fn mbr_size_limit(sector_size: u64) -> u64 {
+    sector_size * u32::MAX as u64
+}
+
+/// Guess the bus a disk is attached to from its canonical sysfs path
+fn bus_from_sys_path(sys_path: &str) -> &'static str {
+    if sys_path.contains("/usb") {
+        "USB"
+    } else if sys_path.contains("/nvme") {
+        "NVMe"
+    } else if sys_path.contains("/mmc_host/") {
+        "MMC/SD"
+    } else if sys_path.contains("/virtio") {
+        "VirtIO"
+    } else if sys_path.contains("/ata") {
+        "SATA"
+    } else if sys_path.contains("/host") {
+        "SCSI"
+    } else {
+        "Unknown"
+    }
+}
+
+/// Intel RST (firmware RAID) and VMD controllers that may hide disks from
+/// the installer, by PCI address. VMD controllers handled by the vmd driver
+/// expose their disks and are left out.
+pub fn intel_raid_controllers() -> Vec<String> {
+    let Ok(devices) = fs::read_dir(SYS_PCI_DEVICES_PATH) else {
+        return vec![];
+    };
+
+    devices
+        .filter_map(|x| x.ok())
+        .filter(|x| {
+            let read = |name: &str| fs::read_to_string(x.path().join(name)).unwrap_or_default();
+            let driver = fs::read_link(x.path().join("driver")).ok();
+            is_intel_raid(&read("vendor"), &read("class"))
+                && driver.as_deref().and_then(|path| path.file_name()) != Some(OsStr::new("vmd"))
+        })
+        .map(|x| x.file_name().to_string_lossy().to_string())
+        .collect()
+}
+
+/// Whether a PCI device is an Intel RAID controller, from its vendor and
+/// class in sysfs
+fn is_intel_raid(vendor: &str, class: &str) -> bool {
+    // class 0x0104xx: mass storage controller, RAID
+    vendor.trim() == "0x8086" && class.trim().starts_with("0x0104")
+}
+
+fn list_devices_libparted() -> Vec<DkDerive> {
+    let live_medium = live_medium_disks();
+    libparted::Device::devices(true)
+        .filter(|dev| {
+            device_is_supported(dev.path()) && !live_medium.iter().any(|x| x == dev.path())
+        })
+        .map(|dev| DkDerive {
+            path: dev.path().to_path_buf(),
+            model: dev.model().to_string(),
+            size: dev.sector_size() * dev.length(),
+        })
+        .collect()
+}
+
+fn device_is_supported(path: &Path) -> bool {
+    let is_sata = device_is_sata(path);
+    info!("{} is sata: {is_sata}", path.display());
+
+    let is_sdcard = device_is_sdcard(path);
+    info!("{} is sdcard: {is_sdcard}", path.display());
+
+    let is_nvme = device_is_nvme(path);
+    info!("{} is nvme: {is_nvme}", path.display());
+
+    is_sata || is_sdcard || is_nvme
+}
+
+fn device_is_sata(path: &Path) -> bool {
+    device_is_match(path, r"^([^0-9]+)$")
+}
+
+fn device_is_sdcard(path: &Path) -> bool {
+    device_is_match(path, r"^(mmcblk[0-9]+)$")
+}
+
+/// Boot and RPMB hardware partitions of eMMC devices, which are not for
+/// installing to
+fn device_is_emmc_hw_partition(path: &Path) -> bool {
+    device_is_match(path, r"^(mmcblk[0-9]+(boot[0-9]+|rpmb))$")
+}
+
+/// Whether `dev` is soldered-on eMMC storage, rather than an SD card
+pub fn is_emmc(dev: &Path) -> bool {
+    dev.file_name()
+        .and_then(|x| {
+            fs::read_to_string(Path::new(SYS_BLOCK_PATH).join(x).join("device/type")).ok()
+        })
+        .is_some_and(|x| x.trim() == "MMC")
+}
+
+fn device_is_nvme(path: &Path) -> bool {
+    device_is_match(path, r"^(nvme[0-9]+n[0-9]+)$")
+}
+
+fn device_is_match(path: &Path, pattern: &str) -> bool {
+    Regex::new(pattern)
+        .ok()
+        .and_then(|x| {
+            path.display()
+                .to_string()
+                .split('/')
+                .last()
+                .and_then(|dev| x.is_match(dev).ok())
+        })
+        .unwrap_or(false)
+}
+
+/// Whether `partition` is formatted as swap, as named by libparted or lsblk
+pub fn is_swap(partition: &Partition) -> bool {
+    partition
+        .fs_type
+        .as_deref()
+        .is_some_and(|x| x == "swap" || x.starts_with("linux-swap"))
+}
+
+/// Filesystems that may hold another operating system
+const PROBED_FS_TYPE: &[&str] = &["ext4", "xfs", "btrfs", "f2fs", "ntfs"];
+
+/// Name of the operating system installed on `partition`, if any, found by
+/// mounting it read-only (e.g., "Windows", "AOSC OS (12.0.0)")
+pub fn detect_os(partition: &Partition) -> Option<String> {
+    with_probe_mount(partition, |root| {
+        if root.join("Windows/System32/ntoskrnl.exe").exists() {
+            return Some("Windows".to_string());
+        }
+        // btrfs systems are usually installed to a subvolume
+        ["", "@", "@rootfs"].iter().find_map(|subvol| {
+            ["etc/os-release", "usr/lib/os-release"]
+                .iter()
+                .find_map(|x| fs::read_to_string(root.join(subvol).join(x)).ok())
+                .and_then(|x| parse_os_release(&x))
+        })
+    })
+}
+
+/// Directories of a previous system on `partition` that installing onto it
+/// without formatting would overwrite, see [`existing_system_dirs`]
+pub fn partition_system_dirs(partition: &Partition) -> Vec<&'static str> {
+    let is_btrfs = partition.fs_type.as_deref() == Some("btrfs");
+    with_probe_mount(partition, |root| {
+        // the system is installed to the @ subvolume of btrfs filesystems
+        let root = if is_btrfs {
+            root.join("@")
+        } else {
+            root.to_path_buf()
+        };
+        Some(existing_system_dirs(&root))
+    })
+    .unwrap_or_default()
+}
+
+/// Whether the btrfs filesystem on `partition` has all of `subvols` in its
+/// top level, so that the subvolume layout fits a filesystem that is kept
+pub fn has_btrfs_subvolumes(partition: &Partition, subvols: &[&str]) -> bool {
+    with_probe_mount(partition, |root| {
+        Some(subvols.iter().all(|x| root.join(x).is_dir()))
+    })
+    .unwrap_or(false)
+}
+
+/// Mount `partition` read-only at a temporary directory for `f` to look into
+fn with_probe_mount<T>(partition: &Partition, f: impl FnOnce(&Path) -> Option<T>) -> Option<T> {
+    let fs_type = partition.fs_type.as_deref()?;
+    if !PROBED_FS_TYPE.contains(&fs_type) {
+        return None;
+    }
+    let path = partition.path.as_deref()?;
+    let tempdir = tempfile::Builder::new().prefix(".dkprobe").tempdir().ok()?;
+    let mounted = Command::new("mount")
+        .arg("-o")
+        .arg(probe_mount_options(fs_type))
+        .arg(path)
+        .arg(tempdir.path())
+        .output()
+        .is_ok_and(|x| x.status.success());
+    if !mounted {
+        return None;
+    }
+
+    let res = f(tempdir.path());
+    Command::new("umount").arg(tempdir.path()).output().ok();
+
+    res
+}
+
+/// Read-only mount options that also keep the journal of `fs_type` from
+/// being replayed, so that a hibernated or uncleanly shut down system is
+/// left exactly as it was
+fn probe_mount_options(fs_type: &str) -> &'static str {
+    match fs_type {
+        "ext4" => "ro,noload",
+        "xfs" | "f2fs" => "ro,norecovery",
+        "btrfs" => "ro,nologreplay",
+        _ => "ro",
+    }
+}
+
+fn parse_os_release(content: &str) -> Option<String> {
+    let field = |name: &str| {
+        content.lines().find_map(|x| {
+            let value = x.strip_prefix(name)?.strip_prefix('=')?;
+            Some(value.trim().trim_matches('"').to_string())
+        })
+    };
+
+    field("PRETTY_NAME").or_else(|| field("NAME"))
+}
+
+/// Filesystems the system can be installed onto without formatting them
+pub const KEEP_DATA_FS_TYPE: &[&str] = &["ext4", "btrfs"];
+/// Top-level directories that an existing system on the partition leaves
+/// behind, and are overwritten by the new one
+const SYSTEM_DIRS: &[&str] = &["etc", "usr", "var", "boot", "opt"];
+
+/// Filesystem actually on `path`, as probed by blkid
+pub fn probe_fs_type(path: &Path) -> Option<String> {
+    let output = Command::new("blkid")
+        .args(["-o", "value", "-s", "TYPE"])
+        .arg(path)
+        .output()
+        .ok()?;
+    let fs_type = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    (output.status.success() && !fs_type.is_empty()).then_some(fs_type)
+}
+
+/// `partition` with its filesystem as probed, if the system can be installed
+/// onto it while keeping the files on it
+pub fn keep_data_partition(partition: &Partition) -> Result<Partition> {
+    let path = partition
+        .path
+        .as_deref()
+        .ok_or_else(|| anyhow!("Installer could not find the specified partition."))?;
+    let fs_type = probe_fs_type(path).unwrap_or_else(|| "unformatted".to_string());
+    if !KEEP_DATA_FS_TYPE.contains(&fs_type.as_str()) {
+        bail!(
+            "Installer can only keep the data on ext4 or btrfs partitions, {} is {fs_type}.",
+            path.display()
+        );
+    }
+
+    Ok(Partition {
+        fs_type: Some(fs_type),
+        ..partition.clone()
+    })
+}
+
+/// Space available to unprivileged users on the filesystem of `path`
+pub fn available_space(path: &Path) -> Result<u64> {
+    let stat = rustix::fs::statvfs(path)?;
+
+    Ok(stat.f_bavail * stat.f_frsize)
+}
+
+/// Directories of a previous system in `root`, which are going to be
+/// overwritten when installing onto it without formatting
+pub fn existing_system_dirs(root: &Path) -> Vec<&'static str> {
+    SYSTEM_DIRS
+        .iter()
+        .filter(|x| fs::read_dir(root.join(x)).is_ok_and(|mut x| x.next().is_some()))
+        .copied()
+        .collect()
+}
+
+/// Disks the running live system is loaded from, which must not be
+/// installed to: backing disks of loop devices (squashfs images), of
+/// dm-verity devices and of mounted ISO 9660 filesystems
+pub fn live_medium_disks() -> Vec<PathBuf> {
+    let mut res = vec![];
+    if let Ok(entries) = fs::read_dir(SYS_BLOCK_PATH) {
+        for entry in entries.flatten() {
+            let sys_path = entry.path();
+            if let Ok(backing_file) = fs::read_to_string(sys_path.join("loop/backing_file")) {
+                if let Ok(metadata) = fs::metadata(backing_file.trim()) {
+                    let dev = metadata.dev();
+                    res.extend(dev_number_disk(
+                        rustix::fs::major(dev),
+                        rustix::fs::minor(dev),
+                    ));
+                }
+            }
+            let is_verity = fs::read_to_string(sys_path.join("dm/uuid"))
+                .map(|x| x.starts_with("CRYPT-VERITY"))
+                .unwrap_or(false);
+            if is_verity {
+                res.extend(dm_backing_disk(&sys_path));
+            }
+        }
+    }
+    if let Ok(mountinfo) = fs::read_to_string("/proc/self/mountinfo") {
+        res.extend(
+            mount_sources(&mountinfo, &["iso9660"])
+                .iter()
+                .filter_map(|x| partition_parent(x).or_else(|| Some(x.clone()))),
+        );
+    }
+    res.sort();
+    res.dedup();
+
+    res
+}
+
+/// Whether `device`, a disk or a partition, is on the medium the running
+/// live system is loaded from
+pub fn is_on_live_medium(device: &Path) -> bool {
+    let disk = partition_parent(device).unwrap_or_else(|| device.to_path_buf());
+
+    live_medium_disks().contains(&disk)
+}
+
+/// Images the running live system is loaded from: the backing files of the
+/// loop devices mounted as squashfs, one for each layer of its root
+pub fn live_system_layers() -> Vec<PathBuf> {
+    let Ok(mountinfo) = fs::read_to_string("/proc/self/mountinfo") else {
+        return vec![];
+    };
+
+    mount_sources(&mountinfo, &["squashfs"])
+        .iter()
+        .filter_map(|x| {
+            let sys_path = Path::new(SYS_BLOCK_PATH).join(x.file_name()?);
+            let backing_file = fs::read_to_string(sys_path.join("loop/backing_file")).ok()?;
+
+            Some(PathBuf::from(backing_file.trim()))
+                .filter(|x| x.extension().is_some_and(|x| x == "squashfs") && x.is_file())
+        })
+        .collect()
+}
+
+/// Disk holding the block device with the given device number
+fn dev_number_disk(major: u32, minor: u32) -> Option<PathBuf> {
+    let sys_path = fs::canonicalize(format!("/sys/dev/block/{major}:{minor}")).ok()?;
+    let name = if sys_path.join("partition").exists() {
+        sys_path.parent()?.file_name()?
+    } else {
+        sys_path.file_name()?
+    };
+
+    Some(Path::new("/dev").join(name))
+}
+
+/// Sources of the mounts in `mountinfo` with one of `fs_types`
+fn mount_sources(mountinfo: &str, fs_types: &[&str]) -> Vec<PathBuf> {
+    mountinfo
+        .lines()
+        .filter_map(|x| {
+            let mut fields = x.split_once(" - ")?.1.split_whitespace();
+            let fs_type = fields.next()?;
+            let source = fields.next()?;
+
+            Some(PathBuf::from(source)).filter(|_| fs_types.contains(&fs_type))
+        })
+        .collect()
+}
+
+pub fn list_partitions(device_path: Option<PathBuf>) -> Vec<Partition> {
+    let mut partitions: Vec<Partition> = Vec::new();
+    if let Some(device_path) = device_path {
+        if let Ok(dev) = Device::new(&device_path) {
+            let sector_size = dev.sector_size();
+            loop_device_get_parts(dev, &mut partitions, device_path.clone(), sector_size);
+        }
+        if partitions.is_empty() {
+            partitions.extend(list_partitions_lsblk(&device_path));
+        }
+        partitions.extend(list_logical_volumes(Some(&device_path)));
+        partitions.extend(list_crypt_volumes(Some(&device_path)));
+        partitions.extend(list_raid_arrays(Some(&device_path)));
+    } else {
+        let live_medium = live_medium_disks();
+        for device in libparted::Device::devices(true) {
+            let device_path = device.path().to_owned();
+            if live_medium.contains(&device_path)
+                || device_path.starts_with("/dev/loop")
+                || device_is_emmc_hw_partition(&device_path)
+            {
+                continue;
+            }
+            let sector_size = device.sector_size();
+            loop_device_get_parts(device, &mut partitions, device_path, sector_size);
+        }
+        // disks libparted skipped or could not read
+        for device in list_devices() {
+            if !partitions
+                .iter()
+                .any(|x| x.parent_path.as_ref() == Some(&device.path))
+            {
+                partitions.extend(list_partitions_lsblk(&device.path));
+            }
+        }
+        partitions.extend(list_logical_volumes(None));
+        partitions.extend(list_crypt_volumes(None));
+        partitions.extend(list_raid_arrays(None));
+        partitions.retain(|x| {
+            x.parent_path
+                .as_ref()
+                .map_or(true, |x| !live_medium.contains(x))
+        });
+    }
+
+    partitions
+}
+
+/// LVM logical volumes, only those stored on `device_path` if given
+pub fn list_logical_volumes(device_path: Option<&Path>) -> Vec<Partition> {
+    // internal volumes (thin pools, mirror legs ...) have a suffix after the LV UUID
+    list_dm_devices(device_path, |uuid| {
+        uuid.strip_prefix("LVM-").is_some_and(|x| !x.contains('-'))
+    })
+}
+
+/// LUKS containers opened before the installer was started, only those
+/// stored on `device_path` if given
+pub fn list_crypt_volumes(device_path: Option<&Path>) -> Vec<Partition> {
+    list_dm_devices(device_path, |uuid| uuid.starts_with("CRYPT-LUKS"))
+}
+
+/// Device-mapper devices whose DM UUID matches `filter`, leaving out those
+/// already used by another device, such as a LUKS container holding LVM
+fn list_dm_devices(device_path: Option<&Path>, filter: impl Fn(&str) -> bool) -> Vec<Partition> {
+    let Ok(entries) = fs::read_dir(SYS_BLOCK_PATH) else {
+        return vec![];
+    };
+
+    let mut res = vec![];
+    for entry in entries.flatten() {
+        let sys_path = entry.path();
+        let matches = fs::read_to_string(sys_path.join("dm/uuid"))
+            .map(|x| filter(x.trim()))
+            .unwrap_or(false);
+        let has_holders = fs::read_dir(sys_path.join("holders"))
+            .map(|mut x| x.next().is_some())
+            .unwrap_or(false);
+        if !matches || has_holders {
+            continue;
+        }
+
+        let Ok(name) = fs::read_to_string(sys_path.join("dm/name")) else {
+            continue;
+        };
+        let parent_path = dm_backing_disk(&sys_path);
+        if device_path.is_some() && parent_path.as_deref() != device_path {
+            continue;
+        }
+
+        let sectors = fs::read_to_string(sys_path.join("size"))
+            .ok()
+            .and_then(|x| x.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        let path = Path::new("/dev/mapper").join(name.trim());
+        let fs_type = probe_fs_type(&path);
+        res.push(Partition {
+            path: Some(path),
+            parent_path,
+            fs_type,
+            size: sectors * 512,
+        });
+    }
+
+    res
+}
+
+/// Name and backing partition of the LUKS container `path` is stored in,
+/// through nested mappings such as LVM on LUKS
+pub fn crypt_backing_device(path: &Path) -> Option<(String, PathBuf)> {
+    let dev = fs::canonicalize(path).ok()?;
+
+    find_crypt_layer(&Path::new(SYS_CLASS_BLOCK_PATH).join(dev.file_name()?))
+}
+
+fn find_crypt_layer(sys_path: &Path) -> Option<(String, PathBuf)> {
+    let uuid = fs::read_to_string(sys_path.join("dm/uuid")).ok()?;
+    let slave = fs::read_dir(sys_path.join("slaves"))
+        .ok()?
+        .flatten()
+        .next()?
+        .file_name();
+    if uuid.starts_with("CRYPT-LUKS") {
+        let name = fs::read_to_string(sys_path.join("dm/name")).ok()?;
+        return Some((name.trim().to_string(), Path::new("/dev").join(slave)));
+    }
+
+    find_crypt_layer(&fs::canonicalize(Path::new(SYS_CLASS_BLOCK_PATH).join(slave)).ok()?)
+}
+
+/// mdadm software RAID arrays, only those with a member on `device_path` if
+/// given. `parent_path` is set to the disk of the first member.
+pub fn list_raid_arrays(device_path: Option<&Path>) -> Vec<Partition> {
+    let Ok(entries) = fs::read_dir(SYS_BLOCK_PATH) else {
+        return vec![];
+    };
+
+    let mut res = vec![];
+    for entry in entries.flatten() {
+        let sys_path = entry.path();
+        // inactive arrays have no level
+        let is_active = fs::read_to_string(sys_path.join("md/level"))
+            .map(|x| !x.trim().is_empty())
+            .unwrap_or(false);
+        if !is_active {
+            continue;
+        }
+
+        let path = Path::new("/dev").join(entry.file_name());
+        let members = raid_member_disks(&path);
+        if device_path.is_some_and(|x| !members.iter().any(|m| m == x)) {
+            continue;
+        }
+
+        let sectors = fs::read_to_string(sys_path.join("size"))
+            .ok()
+            .and_then(|x| x.trim().parse::<u64>().ok())
+            .unwrap_or(0);
+        let fs_type = probe_fs_type(&path);
+        res.push(Partition {
+            path: Some(path),
+            parent_path: members.into_iter().next(),
+            fs_type,
+            size: sectors * 512,
+        });
+    }
+
+    res
+}
+
+/// Whether `path` is an mdadm software RAID array
+pub fn is_raid(path: &Path) -> bool {
+    path.file_name()
+        .map(|x| Path::new(SYS_CLASS_BLOCK_PATH).join(x).join("md").is_dir())
+        .unwrap_or(false)
+}
+
+/// Disks whose master boot record GRUB is installed to on BIOS systems, for
+/// the system on `partition`: every disk of a RAID array and of a btrfs
+/// `mirror` has to be bootable
+pub fn grub_mbr_disks(partition: &Partition, mirror: Option<&Partition>) -> Vec<PathBuf> {
+    let mut disks = match partition.path.as_deref() {
+        Some(path) if is_raid(path) => raid_member_disks(path),
+        _ => partition.parent_path.clone().into_iter().collect(),
+    };
+    if let Some(mirror_disk) = mirror.and_then(|x| x.parent_path.clone()) {
+        if !disks.contains(&mirror_disk) {
+            disks.push(mirror_disk);
+        }
+    }
+
+    disks
+}
+
+/// Disks holding the members of the RAID array at `path`
+pub fn raid_member_disks(path: &Path) -> Vec<PathBuf> {
+    let Some(name) = path.file_name() else {
+        return vec![];
+    };
+    let Ok(slaves) = fs::read_dir(Path::new(SYS_CLASS_BLOCK_PATH).join(name).join("slaves")) else {
+        return vec![];
+    };
+
+    let mut res = slaves
+        .flatten()
+        .filter_map(|x| slave_disk(&x.file_name()))
+        .collect::<Vec<_>>();
+    res.sort();
+    res.dedup();
+
+    res
+}
+
+/// Disk of the block device called `slave`, itself if it is a whole disk
+fn slave_disk(slave: &std::ffi::OsStr) -> Option<PathBuf> {
+    let slave_path = fs::canonicalize(Path::new(SYS_CLASS_BLOCK_PATH).join(slave)).ok()?;
+    if slave_path.join("partition").exists() {
+        return Some(Path::new("/dev").join(slave_path.parent()?.file_name()?));
+    }
+
+    Some(Path::new("/dev").join(slave))
+}
+
+/// Disk that the device-mapper device at `sys_path` is stored on, through
+/// nested mappings such as LVM on LUKS
+fn dm_backing_disk(sys_path: &Path) -> Option<PathBuf> {
+    let slave = fs::read_dir(sys_path.join("slaves"))
+        .ok()?
+        .flatten()
+        .next()?
+        .file_name();
+    let slave_path = fs::canonicalize(Path::new(SYS_CLASS_BLOCK_PATH).join(&slave)).ok()?;
+    if slave_path.join("dm").is_dir() {
+        return dm_backing_disk(&slave_path);
+    }
+
+    slave_disk(&slave)
+}
+
+fn probe_fs_type(path: &Path) -> Option<String> {
+    let output = Command::new("blkid")
+        .args(["-o", "value", "-s", "TYPE"])
+        .arg(path)
+        .output()
+        .ok()?;
+    let fs_type = String::from_utf8_lossy(&output.stdout).trim().to_string();
+
+    Some(fs_type).filter(|x| output.status.success() && !x.is_empty())
+}
+
+/// VG and LV names of the logical volume at `path`
+pub fn logical_volume_name(path: &Path) -> Option<(String, String)> {
+    let name = path.strip_prefix("/dev/mapper").ok()?.to_str()?;
+    // LUKS containers live in /dev/mapper as well
+    if !dm_uuid(path).is_some_and(|x| x.starts_with("LVM-")) {
+        return None;
+    }
+
+    split_dm_name(name)
+}
+
+fn dm_uuid(path: &Path) -> Option<String> {
+    let dev = fs::canonicalize(path).ok()?;
+    let uuid = fs::read_to_string(
+        Path::new(SYS_CLASS_BLOCK_PATH)
+            .join(dev.file_name()?)
+            .join("dm/uuid"),
+    )
+    .ok()?;
+
+    Some(uuid.trim().to_string())
+}
+
+/// Split a device-mapper name into VG and LV names, dashes in either name
+/// are doubled
+fn split_dm_name(name: &str) -> Option<(String, String)> {
+    let bytes = name.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'-' {
+            if bytes.get(i + 1) == Some(&b'-') {
+                i += 2;
+                continue;
+            }
+            return Some((
+                name[..i].replace("--", "-"),
+                name[i + 1..].replace("--", "-"),
+            ));
+        }
+        i += 1;
+    }
+
+    None
+}
+
+/// Find a partition by its path, only probing the disk it lives on
+pub fn find_partition(path: &Path) -> Option<Partition> {
+    let partitions = list_partitions(partition_parent(path));
+
+    partitions
+        .into_iter()
+        .find(|x| x.path.as_deref() == Some(path))
+}
+
+fn partition_parent(path: &Path) -> Option<PathBuf> {
+    let name = fs::canonicalize(path).ok()?;
+    let sys_path =
+        fs::canonicalize(Path::new(SYS_CLASS_BLOCK_PATH).join(name.file_name()?)).ok()?;
+    if !sys_path.join("partition").exists() {
+        return None;
+    }
+
+    Some(Path::new("/dev").join(sys_path.parent()?.file_name()?))
+}
+
+/// Number of partition `path` on its disk
+pub fn partition_number(path: &Path) -> Option<u32> {
+    let name = fs::canonicalize(path).ok()?;
+    let number = fs::read_to_string(
+        Path::new(SYS_CLASS_BLOCK_PATH)
+            .join(name.file_name()?)
+            .join("partition"),
+    )
+    .ok()?;
+
+    number.trim().parse().ok()
+}
+
+/// Filesystem UUID of `path`, looked up from the udev symlinks
+pub fn partition_uuid(path: &Path) -> Option<String> {
+    udev_link_name(path, "/dev/disk/by-uuid")
+}
+
+/// Partition table UUID of `path`, looked up from the udev symlinks
+pub fn partition_partuuid(path: &Path) -> Option<String> {
+    udev_link_name(path, "/dev/disk/by-partuuid")
+}
+
+/// Filesystem label of `path`, escaped for fstab
+pub fn partition_label(path: &Path) -> Option<String> {
+    // udev escapes spaces as \x20, fstab as \040
+    udev_link_name(path, "/dev/disk/by-label").map(|x| x.replace("\\x20", "\\040"))
+}
+
+/// Whether a partition other than `path` already has the filesystem label
+/// `label`, which fstab could then mistake for it
+pub fn label_taken(label: &str, path: Option<&Path>) -> bool {
+    let Ok(owner) = fs::canonicalize(Path::new("/dev/disk/by-label").join(label)) else {
+        return false;
+    };
+
+    path.and_then(|x| fs::canonicalize(x).ok()) != Some(owner)
+}
+
+fn udev_link_name(path: &Path, dir: &str) -> Option<String> {
+    let target = fs::canonicalize(path).ok()?;
+
+    fs::read_dir(dir)
+        .ok()?
+        .filter_map(|x| x.ok())
+        .find(|x| fs::canonicalize(x.path()).ok().as_ref() == Some(&target))
+        .map(|x| x.file_name().to_string_lossy().to_string())
+}
+
+const GPT_SIGNATURE: &[u8] = b"EFI PART";
+/// Size of the GPT header fields covered by its CRC, at least
+const GPT_HEADER_SIZE: usize = 92;
+
+/// Whether the GPT on `dev` has a damaged backup header, or one that is not
+/// at the end of the disk, e.g. after writing an image to a larger disk.
+/// Disks without a valid primary GPT header are left to libparted.
+pub fn gpt_backup_damaged(dev: &Path) -> bool {
+    match read_gpt_backup_state(dev) {
+        Ok(damaged) => damaged,
+        Err(e) => {
+            warn!("Could not read the GPT of {}: {e}", dev.display());
+            false
+        }
+    }
+}
+
+fn read_gpt_backup_state(dev: &Path) -> io::Result<bool> {
+    let sector_size = sector_sizes(dev).logical;
+    let mut file = fs::File::open(dev)?;
+    let disk_size = file.seek(SeekFrom::End(0))?;
+    let mut primary = vec![0; sector_size as usize];
+    file.seek(SeekFrom::Start(sector_size))?;
+    file.read_exact(&mut primary)?;
+    if !is_valid_gpt_header(&primary) {
+        return Ok(false);
+    }
+
+    let backup_lba = u64::from_le_bytes(primary[32..40].try_into().unwrap());
+    if backup_lba != disk_size / sector_size - 1 {
+        return Ok(true);
     }
+    let mut backup = vec![0; sector_size as usize];
+    file.seek(SeekFrom::Start(backup_lba * sector_size))?;
+    file.read_exact(&mut backup)?;
 
-    Ok(())
+    Ok(!is_valid_gpt_header(&backup))
 }
 
-pub fn fill_fs_type(part: &Partition, use_ext4: bool) -> Partition {
-    let mut new_part = part.clone();
-    let new_fs_type: String;
-    if let Some(fs_type) = new_part.fs_type.clone() {
-        if !use_ext4 {
-            new_fs_type = get_recommended_fs_type(&fs_type).to_string();
-        } else {
-            new_fs_type = DEFAULT_FS_TYPE.to_string();
-        }
-    } else {
-        new_fs_type = DEFAULT_FS_TYPE.to_string();
+fn is_valid_gpt_header(sector: &[u8]) -> bool {
+    if sector.len() < GPT_HEADER_SIZE || &sector[..8] != GPT_SIGNATURE {
+        return false;
     }
-    new_part.fs_type = Some(new_fs_type);
+    let size = u32::from_le_bytes(sector[12..16].try_into().unwrap()) as usize;
+    if !(GPT_HEADER_SIZE..=sector.len()).contains(&size) {
+        return false;
+    }
+    let crc = u32::from_le_bytes(sector[16..20].try_into().unwrap());
+    let mut header = sector[..size].to_vec();
+    header[16..20].fill(0);
 
-    new_part
+    crc32(&header) == crc
 }
 
-pub fn find_esp_partition(device_path: &Path) -> Result<Partition> {
-    let mut device = libparted::Device::get(device_path)?;
-    if let Ok(disk) = libparted::Disk::new(&mut device) {
-        for mut part in disk.parts() {
-            if part.num() < 0 {
-                continue;
-            }
-            if part.get_flag(libparted::PartitionFlag::PED_PARTITION_ESP) {
-                let fs_type = if let Ok(type_) = part.get_geom().probe_fs() {
-                    Some(type_.name().to_owned())
-                } else {
-                    None
-                };
-                let path = part.get_path().ok_or_else(|| {
-                    anyhow!("Installer could not detect the EFI system partition.")
-                })?;
-                return Ok(Partition {
-                    path: Some(path.to_owned()),
-                    parent_path: None,
-                    size: 0,
-                    fs_type,
-                });
-            }
+/// CRC-32 as used by GPT (IEEE 802.3)
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
         }
     }
 
-    Err(anyhow!(
-        "Installer could not detect the EFI system partition."
-    ))
+    !crc
 }
 
-pub fn list_devices() -> Vec<Device<'static>> {
-    libparted::Device::devices(true)
-        .filter(|dev| {
-            let is_sata = device_is_sata(dev.path());
-            info!("{} is sata: {is_sata}", dev.path().display());
+/// Rewrite the backup GPT header and table of `dev` at the end of the disk,
+/// from the primary ones
+pub fn repair_gpt_backup(dev: &Path) -> Result<()> {
+    info!("Repairing the backup GPT of {}", dev.display());
+    command_output("sgdisk", &[OsStr::new("-e"), dev.as_os_str()])?;
 
-            let is_sdcard = device_is_sdcard(dev.path());
-            info!("{} is sdcard: {is_sdcard}", dev.path().display());
+    Ok(())
+}
 
-            let is_nvme = device_is_nvme(dev.path());
-            info!("{} is nvme: {is_nvme}", dev.path().display());
+/// Default size of disk images installed to
+pub const DEFAULT_IMAGE_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+/// Create a sparse disk image of `size` bytes at `path`, which must not exist
+/// yet, and attach it to a loop device, which is returned
+pub fn attach_image(path: &Path, size: u64) -> Result<PathBuf> {
+    let image = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::AlreadyExists => anyhow!(
+                "{} already exists, Installer will not overwrite it. Please remove it or choose another path.",
+                path.display()
+            ),
+            _ => anyhow!("Installer could not create {}: {e}", path.display()),
+        })?;
+    image.set_len(size)?;
 
-            is_sata || is_sdcard || is_nvme
-        })
-        .collect()
-}
+    let output = Command::new("losetup")
+        .args(["--find", "--show", "--partscan"])
+        .arg(path)
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "Installer could not set up a loop device for {}:\n\n{}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let dev = PathBuf::from(String::from_utf8_lossy(&output.stdout).trim());
+    info!("{} is backed by {}", dev.display(), path.display());
 
-fn device_is_sata(path: &Path) -> bool {
-    device_is_match(path, r"^([^0-9]+)$")
+    Ok(dev)
 }
 
-fn device_is_sdcard(path: &Path) -> bool {
-    device_is_match(path, r"^(mmcblk[0-9]+)$")
+/// Detach a loop device set up by [`attach_image`]
+pub fn detach_image(dev: &Path) -> Result<()> {
+    command_output("losetup", &[OsStr::new("--detach"), dev.as_os_str()])?;
+
+    Ok(())
 }
 
-fn device_is_nvme(path: &Path) -> bool {
-    device_is_match(path, r"^(nvme[0-9]+n[0-9]+)$")
+/// Loop device set up by [`attach_image`], detached when dropped so that no
+/// error path leaks it
+pub struct AttachedImage {
+    dev: Option<PathBuf>,
 }
 
-fn device_is_match(path: &Path, pattern: &str) -> bool {
-    Regex::new(pattern)
-        .ok()
-        .and_then(|x| {
-            path.display()
-                .to_string()
-                .split('/')
-                .last()
-                .and_then(|dev| x.is_match(dev).ok())
+impl AttachedImage {
+    pub fn attach(path: &Path, size: u64) -> Result<Self> {
+        Ok(AttachedImage {
+            dev: Some(attach_image(path, size)?),
         })
-        .unwrap_or(false)
-}
+    }
 
-pub fn list_partitions(device_path: Option<PathBuf>) -> Vec<Partition> {
-    let mut partitions: Vec<Partition> = Vec::new();
-    if let Some(device_path) = device_path {
-        if let Ok(dev) = Device::new(&device_path) {
-            let sector_size = dev.sector_size();
-            loop_device_get_parts(dev, &mut partitions, device_path, sector_size);
+    pub fn dev(&self) -> &Path {
+        self.dev.as_deref().unwrap_or(Path::new(""))
+    }
+
+    /// Detach the loop device, reporting if that fails
+    pub fn detach(mut self) -> Result<()> {
+        match self.dev.take() {
+            Some(dev) => detach_image(&dev),
+            None => Ok(()),
         }
-    } else {
-        for device in libparted::Device::devices(true) {
-            let device_path = device.path().to_owned();
-            let sector_size = device.sector_size();
-            loop_device_get_parts(device, &mut partitions, device_path, sector_size);
+    }
+}
+
+impl Drop for AttachedImage {
+    fn drop(&mut self) {
+        if let Some(dev) = self.dev.take() {
+            if let Err(e) = detach_image(&dev) {
+                warn!("Could not detach {}: {e}", dev.display());
+            }
         }
     }
+}
 
-    partitions
+/// Whether `dev` has a partition table, factory-blank disks have none
+pub fn has_partition_table(dev: &Path) -> bool {
+    let Ok(mut device) = libparted::Device::new(dev) else {
+        return false;
+    };
+    let disk = libparted::Disk::new(&mut device);
+
+    disk.is_ok()
+}
+
+/// Write an empty partition table to `dev`, GPT on UEFI systems and MBR
+/// otherwise, replacing any existing one
+pub fn create_partition_table(dev: &Path) -> Result<()> {
+    let table = if is_efi_booted() { "gpt" } else { "msdos" };
+    info!("Creating {table} partition table on {}", dev.display());
+
+    let mut device = libparted::Device::new(dev)?;
+    let mut disk = Disk::new_fresh(&mut device, DiskType::get(table).unwrap())?;
+    commit(&mut disk)?;
+
+    Ok(())
 }
 
 pub fn device_is_empty(dev: &Path) -> Result<bool> {
@@ -212,6 +2246,81 @@ pub fn device_is_empty(dev: &Path) -> Result<bool> {
     Ok(parts.all(|x| x.get_path().is_none()))
 }
 
+#[derive(Deserialize)]
+struct LsblkOutput {
+    blockdevices: Vec<LsblkDevice>,
+}
+
+#[derive(Deserialize)]
+struct LsblkDevice {
+    path: PathBuf,
+    /// A number, or a string with util-linux before 2.33
+    size: serde_json::Value,
+    fstype: Option<String>,
+    #[serde(rename = "type")]
+    type_: String,
+    #[serde(default)]
+    children: Vec<LsblkDevice>,
+}
+
+/// Partitions of `device_path` as lsblk sees them, for devices libparted
+/// returns nothing for
+fn list_partitions_lsblk(device_path: &Path) -> Vec<Partition> {
+    let output = Command::new("lsblk")
+        .args(["--json", "--bytes", "--output", "PATH,SIZE,FSTYPE,TYPE"])
+        .arg(device_path)
+        .output();
+    let res = match output {
+        Ok(output) if output.status.success() => parse_lsblk(&output.stdout, device_path),
+        Ok(output) => Err(anyhow!(
+            "{}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+        Err(e) => Err(e.into()),
+    };
+
+    match res {
+        Ok(partitions) => {
+            if !partitions.is_empty() {
+                warn!(
+                    "libparted found no partitions on {}, using the {} found by lsblk",
+                    device_path.display(),
+                    partitions.len()
+                );
+            }
+            partitions
+        }
+        Err(e) => {
+            warn!(
+                "Could not list partitions of {} with lsblk: {e}",
+                device_path.display()
+            );
+            vec![]
+        }
+    }
+}
+
+fn parse_lsblk(json: &[u8], device_path: &Path) -> Result<Vec<Partition>> {
+    let output: LsblkOutput = serde_json::from_slice(json)?;
+
+    Ok(output
+        .blockdevices
+        .into_iter()
+        .flat_map(|x| x.children)
+        .filter(|x| x.type_ == "part")
+        .map(|x| Partition {
+            size: x
+                .size
+                .as_u64()
+                .or_else(|| x.size.as_str().and_then(|x| x.parse().ok()))
+                .unwrap_or(0),
+            path: Some(x.path),
+            parent_path: Some(device_path.to_owned()),
+            fs_type: x.fstype,
+        })
+        .collect())
+}
+
 fn loop_device_get_parts(
     mut device: Device<'_>,
     partitions: &mut Vec<Partition>,
@@ -309,10 +2418,121 @@ pub fn right_combine(device_path: Option<&Path>) -> Result<()> {
     Ok(())
 }
 
+/// Mount options applied to every generated fstab entry that supports them
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MountOptions {
+    #[serde(default)]
+    pub noatime: bool,
+    /// Seconds between journal commits, for ext4 and btrfs
+    #[serde(default)]
+    pub commit: Option<u32>,
+    /// Continuous TRIM
+    #[serde(default)]
+    pub discard: bool,
+    /// Queue TRIM requests instead of issuing them right away, for btrfs
+    #[serde(default)]
+    pub async_discard: bool,
+    /// Transparent zstd compression, for btrfs
+    #[serde(default)]
+    pub compress: bool,
+}
+
+impl MountOptions {
+    pub fn is_default(&self) -> bool {
+        self == &MountOptions::default()
+    }
+
+    /// Append the options that make sense for `fs_type` to `base`
+    fn apply(&self, fs_type: FileSystem, base: &str) -> String {
+        let mut res = base.to_string();
+        if self.noatime && !matches!(fs_type, FileSystem::Swap) {
+            res.push_str(",noatime");
+        }
+        if let Some(commit) = self.commit {
+            if matches!(fs_type, FileSystem::Ext4 | FileSystem::Btrfs) {
+                res.push_str(&format!(",commit={commit}"));
+            }
+        }
+        if self.discard {
+            if self.async_discard && matches!(fs_type, FileSystem::Btrfs) {
+                res.push_str(",discard=async");
+            } else {
+                res.push_str(",discard");
+            }
+        }
+        if self.compress && matches!(fs_type, FileSystem::Btrfs) {
+            res.push_str(",compress=zstd");
+        }
+
+        res
+    }
+}
+
+impl std::fmt::Display for MountOptions {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut options = vec![];
+        if self.noatime {
+            options.push("noatime".to_string());
+        }
+        if let Some(commit) = self.commit {
+            options.push(format!("commit={commit}"));
+        }
+        if self.discard {
+            options.push(if self.async_discard {
+                "discard=async".to_string()
+            } else {
+                "discard".to_string()
+            });
+        }
+        if self.compress {
+            options.push("compress=zstd".to_string());
+        }
+
+        write!(f, "{}", options.join(","))
+    }
+}
+
+impl std::str::FromStr for MountOptions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut res = MountOptions::default();
+        for option in s.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+            match option.split_once('=') {
+                None if option == "noatime" => res.noatime = true,
+                None if option == "discard" => res.discard = true,
+                Some(("discard", "async")) => {
+                    res.discard = true;
+                    res.async_discard = true;
+                }
+                Some(("compress", "zstd")) => res.compress = true,
+                Some(("commit", secs)) => {
+                    res.commit = Some(
+                        secs.parse()
+                            .ok()
+                            .filter(|x| *x > 0)
+                            .ok_or_else(|| format!("{secs} is not a valid commit interval"))?,
+                    )
+                }
+                _ => {
+                    return Err(format!(
+                        "{option} is not one of noatime, commit=SECONDS, discard, discard=async or compress=zstd"
+                    ))
+                }
+            }
+        }
+
+        Ok(res)
+    }
+}
+
 pub fn fstab_entries(
     device_path: Option<&PathBuf>,
     fs_type: &str,
     mount_path: Option<&Path>,
+    mount_options: &MountOptions,
+    subvol: Option<&str>,
+    source: FstabSource,
 ) -> Result<OsString> {
     let target = device_path.ok_or_else(|| {
         anyhow!(
@@ -328,17 +2548,109 @@ pub fn fstab_entries(
         "swap" => (FileSystem::Swap, "sw"),
         _ => return Err(anyhow!("Unsupported filesystem type!")),
     };
+    let mut option = mount_options.apply(fs_type, option);
+    if let Some(subvol) = subvol {
+        option.push_str(&format!(",subvol={subvol}"));
+    }
     let root_id = BlockInfo::get_partition_id(target, fs_type).ok_or_else(|| {
         anyhow!(
             "Installer could not obtain partition UUID for {}!",
             target.display()
         )
     })?;
-    let root = BlockInfo::new(root_id, fs_type, mount_path, option);
+    let root = BlockInfo::new(root_id, fs_type, mount_path, &option);
     let fstab = &mut OsString::new();
     root.write_entry(fstab);
 
-    Ok(fstab.to_owned())
+    let id = match source {
+        FstabSource::Uuid => None,
+        FstabSource::PartUuid => Some(partition_partuuid(target).ok_or_else(|| {
+            anyhow!(
+                "Installer could not obtain the PARTUUID of {}!",
+                target.display()
+            )
+        })?),
+        FstabSource::Label => Some(partition_label(target).ok_or_else(|| {
+            anyhow!(
+                "Installer could not obtain the filesystem label of {}!",
+                target.display()
+            )
+        })?),
+    };
+    let Some(id) = id else {
+        return Ok(fstab.to_owned());
+    };
+    // replace the UUID= field generated above
+    let entry = fstab.to_string_lossy();
+    let rest = entry.find(char::is_whitespace).map_or("", |x| &entry[x..]);
+
+    Ok(format!("{}={id}{rest}", source.name()).into())
+}
+
+/// How fstab refers to partitions
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FstabSource {
+    /// Filesystem UUID, changes when the partition is re-formatted
+    #[default]
+    Uuid,
+    /// GPT/MBR partition UUID, kept when the partition is re-formatted. Only
+    /// partitions of a disk have one, not e.g. LUKS, LVM or RAID volumes.
+    PartUuid,
+    /// Filesystem label, kept when the partition is cloned. Partitions the
+    /// installer formats are labelled, the others must have a label already.
+    Label,
+}
+
+impl FstabSource {
+    pub const ALL: [FstabSource; 3] =
+        [FstabSource::Uuid, FstabSource::PartUuid, FstabSource::Label];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            FstabSource::Uuid => "UUID",
+            FstabSource::PartUuid => "PARTUUID",
+            FstabSource::Label => "LABEL",
+        }
+    }
+
+    /// `label` if fstab refers to partitions by label, for formatting them
+    pub fn mkfs_label(self, label: &str) -> Option<&str> {
+        (self == FstabSource::Label).then_some(label)
+    }
+}
+
+/// Labels of the partitions the installer formats, if fstab refers to them
+/// by label. They are short enough for any filesystem, FAT allows 11
+/// characters.
+pub const SYSTEM_LABEL: &str = "AOSC_OS";
+pub const ESP_LABEL: &str = "AOSC_ESP";
+pub const SWAP_LABEL: &str = "AOSC_SWAP";
+
+/// Label of a partition formatted to be mounted at `mount_point`, e.g.
+/// "SRV" for /srv, None if nothing of its name can be used
+pub fn mount_point_label(mount_point: &Path) -> Option<String> {
+    let label = mount_point
+        .file_name()?
+        .to_string_lossy()
+        .chars()
+        .filter(|x| x.is_ascii_alphanumeric() || *x == '_' || *x == '-')
+        .take(11)
+        .collect::<String>()
+        .to_ascii_uppercase();
+
+    (!label.is_empty()).then_some(label)
+}
+
+impl std::str::FromStr for FstabSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        FstabSource::ALL
+            .into_iter()
+            .find(|x| x.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown fstab source: {s}"))
+    }
 }
 
 pub fn get_recommend_swap_size(mem: u64) -> Result<f64> {
@@ -389,11 +2701,12 @@ pub fn auto_create_partitions(dev: &Path) -> Result<Partition> {
     if get_partition_table_type(Some(dev))
         .map(|x| x == "msdos")
         .unwrap_or(false)
-        && size > 512 * (2_u64.pow(31) - 1)
+        && size > mbr_size_limit(sector_size)
     {
         bail!(
-            r#"AOSC OS Installer has detected that you are trying to create a disk partition larger than 2TiB in the MBR partition table.
-If you want to do this, change your computer's boot mode to UEFI mode."#
+            r#"AOSC OS Installer has detected that you are trying to create a disk partition larger than {}TiB in the MBR partition table.
+If you want to do this, change your computer's boot mode to UEFI mode."#,
+            mbr_size_limit(sector_size).div_ceil(1024_u64.pow(4))
         );
     }
 
@@ -530,11 +2843,12 @@ pub fn auto_create_partitions(dev: &Path) -> Result<Partition> {
     if get_partition_table_type(Some(dev))
         .map(|x| x == "msdos")
         .unwrap_or(false)
-        && size > 512 * (2_u64.pow(31) - 1)
+        && size > mbr_size_limit(sector_size)
     {
         bail!(
-            r#"AOSC OS Installer has detected that you are trying to create a disk partition larger than 2TiB in the MBR partition table.
-If you want to do this, change your computer's boot mode to UEFI mode."#
+            r#"AOSC OS Installer has detected that you are trying to create a disk partition larger than {}TiB in the MBR partition table.
+If you want to do this, change your computer's boot mode to UEFI mode."#,
+            mbr_size_limit(sector_size).div_ceil(1024_u64.pow(4))
         );
     }
 
@@ -590,20 +2904,7 @@ If you want to do this, change your computer's boot mode to UEFI mode."#
         info!("Disk does not exists, creating new ...");
     }
 
-    let mut device = libparted::Device::new(dev)?;
-    let device = &mut device as *mut Device;
-    let device = unsafe { &mut (*device) };
-
-    let mut disk = Disk::new_fresh(
-        &mut *device,
-        if !is_efi {
-            DiskType::get("msdos").unwrap()
-        } else {
-            DiskType::get("gpt").unwrap()
-        },
-    )?;
-
-    commit(&mut disk)?;
+    create_partition_table(dev)?;
 
     let mut device = libparted::Device::new(dev)?;
     let device = &mut device as *mut Device;
@@ -935,3 +3236,268 @@ fn test_device_is_match() {
     assert!(!device_is_sata(Path::new("/dev/sr0")));
     assert!(!device_is_sdcard(Path::new("/dev/sr0")));
 }
+
+#[test]
+fn test_device_is_emmc_hw_partition() {
+    assert!(device_is_emmc_hw_partition(Path::new("/dev/mmcblk0boot0")));
+    assert!(device_is_emmc_hw_partition(Path::new("/dev/mmcblk1rpmb")));
+    assert!(!device_is_emmc_hw_partition(Path::new("/dev/mmcblk0")));
+    assert!(!device_is_sdcard(Path::new("/dev/mmcblk0boot1")));
+}
+
+#[test]
+fn test_parse_shrink_size() {
+    let ntfsresize = "ntfsresize v2022.10.3 (libntfs-3g)\nDevice name        : /dev/sda3\nNTFS volume version: 3.1\nCluster size       : 4096 bytes\nCurrent volume size: 107374178816 bytes (107375 MB)\nCurrent device size: 107374182400 bytes (107375 MB)\nChecking filesystem consistency ...\nAccounting clusters ...\nSpace in use       : 31852 MB (29.7%)\nCollecting resizing constraints ...\nYou might resize at 31851409408 bytes or 31852 MB (freeing 75523 MB).\nPlease make a test run using both the -n and -s options before real resizing!\n";
+    assert_eq!(parse_ntfsresize_min_size(ntfsresize), Some(31851409408));
+    assert_eq!(
+        parse_resize2fs_min_blocks("Estimated minimum size of the filesystem: 1234567\n"),
+        Some(1234567)
+    );
+    assert_eq!(
+        parse_ntfsresize_min_size("ERROR: Volume is scheduled for check.\n"),
+        None
+    );
+}
+
+#[test]
+fn test_mbr_size_limit() {
+    let tib = 1024_u64.pow(4);
+    assert_eq!(mbr_size_limit(512).div_ceil(tib), 2);
+    assert_eq!(mbr_size_limit(4096).div_ceil(tib), 16);
+    assert!(SectorSizes {
+        logical: 4096,
+        physical: 4096
+    }
+    .is_4kn());
+}
+
+#[test]
+fn test_is_intel_raid() {
+    assert!(is_intel_raid("0x8086\n", "0x010400\n"));
+    assert!(!is_intel_raid("0x8086\n", "0x010601\n"));
+    assert!(!is_intel_raid("0x1022\n", "0x010400\n"));
+}
+
+#[test]
+fn test_bus_from_sys_path() {
+    assert_eq!(
+        bus_from_sys_path("/sys/devices/pci0000:00/0000:00:14.0/usb2/2-1/2-1:1.0/host6/target6:0:0/6:0:0:0/block/sdb"),
+        "USB"
+    );
+    assert_eq!(
+        bus_from_sys_path("/sys/devices/pci0000:00/0000:00:1d.0/0000:03:00.0/nvme/nvme0/nvme0n1"),
+        "NVMe"
+    );
+    assert_eq!(
+        bus_from_sys_path(
+            "/sys/devices/pci0000:00/0000:00:17.0/ata3/host2/target2:0:0/2:0:0:0/block/sda"
+        ),
+        "SATA"
+    );
+    assert_eq!(
+        bus_from_sys_path("/sys/devices/virtual/block/loop0"),
+        "Unknown"
+    );
+}
+
+#[test]
+fn test_mount_sources() {
+    let mountinfo =
+        "22 1 0:21 / / rw,relatime shared:1 - overlay overlay rw,lowerdir=/run/livekit/sys\n\
+        27 22 8:16 / /run/livekit/cdrom ro,relatime shared:12 - iso9660 /dev/sdb ro,nojoliet\n\
+        28 22 7:0 / /run/livekit/sys ro,relatime shared:13 - squashfs /dev/loop0 ro\n";
+    assert_eq!(
+        mount_sources(mountinfo, &["iso9660"]),
+        vec![PathBuf::from("/dev/sdb")]
+    );
+}
+
+#[test]
+fn test_split_dm_name() {
+    assert_eq!(
+        split_dm_name("vg0-root"),
+        Some(("vg0".to_string(), "root".to_string()))
+    );
+    assert_eq!(
+        split_dm_name("my--vg-home--lv"),
+        Some(("my-vg".to_string(), "home-lv".to_string()))
+    );
+    assert_eq!(split_dm_name("luks--crypt"), None);
+}
+
+#[test]
+fn test_parse_lsblk() {
+    let json = br#"{"blockdevices": [{"path": "/dev/sda", "size": 1000, "fstype": null, "type": "disk", "children": [
+        {"path": "/dev/sda1", "size": 200, "fstype": "vfat", "type": "part"},
+        {"path": "/dev/sda2", "size": "800", "fstype": null, "type": "part"}
+    ]}]}"#;
+    let partitions = parse_lsblk(json, Path::new("/dev/sda")).unwrap();
+
+    assert_eq!(partitions.len(), 2);
+    assert_eq!(partitions[0].path.as_deref(), Some(Path::new("/dev/sda1")));
+    assert_eq!(partitions[0].fs_type.as_deref(), Some("vfat"));
+    assert_eq!(partitions[1].size, 800);
+    assert_eq!(partitions[1].fs_type, None);
+}
+
+#[test]
+fn test_gpt_header() {
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+    let mut sector = vec![0; 512];
+    sector[..8].copy_from_slice(GPT_SIGNATURE);
+    sector[12..16].copy_from_slice(&(GPT_HEADER_SIZE as u32).to_le_bytes());
+    sector[32..40].copy_from_slice(&1000u64.to_le_bytes());
+    let crc = crc32(&sector[..GPT_HEADER_SIZE]);
+    sector[16..20].copy_from_slice(&crc.to_le_bytes());
+    assert!(is_valid_gpt_header(&sector));
+
+    sector[40] = 1;
+    assert!(!is_valid_gpt_header(&sector));
+    assert!(!is_valid_gpt_header(&[0; 512]));
+}
+
+#[test]
+fn test_mkfs_command() {
+    let mut partition = Partition {
+        path: Some("/dev/sda2".into()),
+        parent_path: None,
+        fs_type: Some("xfs".to_string()),
+        size: 0,
+    };
+    let xfs = XfsOptions {
+        reflink: false,
+        ..Default::default()
+    };
+    let extra_args = MkfsArgs::from([
+        ("xfs".to_string(), "-L 'AOSC OS'".to_string()),
+        ("ext4".to_string(), "-O \"casefold".to_string()),
+    ]);
+    let cmd = mkfs_command(&partition, &xfs, &extra_args, None).unwrap();
+    let args = cmd.get_args().collect::<Vec<_>>();
+    assert_eq!(cmd.get_program(), "mkfs.xfs");
+    assert_eq!(
+        args,
+        [
+            "-f",
+            "-m",
+            "reflink=0,bigtime=1",
+            "-L",
+            "AOSC OS",
+            "/dev/sda2"
+        ]
+    );
+
+    partition.fs_type = Some("ext4".to_string());
+    assert!(mkfs_command(&partition, &xfs, &extra_args, None).is_err());
+    let cmd = mkfs_command(&partition, &xfs, &MkfsArgs::new(), None).unwrap();
+    assert_eq!(cmd.get_args().collect::<Vec<_>>(), ["-Fq", "/dev/sda2"]);
+    let cmd = mkfs_command(&partition, &xfs, &MkfsArgs::new(), Some(SYSTEM_LABEL)).unwrap();
+    assert_eq!(
+        cmd.get_args().collect::<Vec<_>>(),
+        ["-Fq", "-L", "AOSC_OS", "/dev/sda2"]
+    );
+    partition.fs_type = Some("vfat".to_string());
+    let cmd = mkfs_command(&partition, &xfs, &MkfsArgs::new(), Some(ESP_LABEL)).unwrap();
+    assert_eq!(
+        cmd.get_args().collect::<Vec<_>>(),
+        ["-F32", "-n", "AOSC_ESP", "/dev/sda2"]
+    );
+    partition.fs_type = Some("ext4".to_string());
+
+    let mirror = Partition {
+        path: Some("/dev/sdb2".into()),
+        ..partition.clone()
+    };
+    let cmd = btrfs_raid1_command(&partition, &mirror, &extra_args, None).unwrap();
+    assert_eq!(
+        cmd.get_args().collect::<Vec<_>>(),
+        ["-f", "-d", "raid1", "-m", "raid1", "/dev/sda2", "/dev/sdb2"]
+    );
+}
+
+#[test]
+fn test_fstab_source() {
+    assert_eq!("partuuid".parse::<FstabSource>(), Ok(FstabSource::PartUuid));
+    assert_eq!("UUID".parse::<FstabSource>(), Ok(FstabSource::Uuid));
+    assert_eq!("LABEL".parse::<FstabSource>(), Ok(FstabSource::Label));
+    assert!("path".parse::<FstabSource>().is_err());
+}
+
+#[test]
+fn test_mount_point_label() {
+    assert_eq!(
+        mount_point_label(Path::new("/srv")),
+        Some("SRV".to_string())
+    );
+    assert_eq!(
+        mount_point_label(Path::new("/var/lib/containers")),
+        Some("CONTAINERS".to_string())
+    );
+    assert_eq!(
+        mount_point_label(Path::new("/mnt/media library")),
+        Some("MEDIALIBRAR".to_string())
+    );
+    assert_eq!(mount_point_label(Path::new("/mnt/文件")), None);
+}
+
+#[test]
+fn test_mount_options() {
+    let options: MountOptions = "noatime,commit=60,discard".parse().unwrap();
+    assert_eq!(options.to_string(), "noatime,commit=60,discard");
+    assert_eq!(
+        options.apply(FileSystem::Ext4, "defaults"),
+        "defaults,noatime,commit=60,discard"
+    );
+    assert_eq!(
+        options.apply(FileSystem::Xfs, "defaults"),
+        "defaults,noatime,discard"
+    );
+    assert_eq!(options.apply(FileSystem::Swap, "sw"), "sw,discard");
+
+    let options: MountOptions = "discard=async,compress=zstd".parse().unwrap();
+    assert_eq!(options.to_string(), "discard=async,compress=zstd");
+    assert_eq!(
+        options.apply(FileSystem::Btrfs, "defaults"),
+        "defaults,discard=async,compress=zstd"
+    );
+    assert_eq!(
+        options.apply(FileSystem::Ext4, "defaults"),
+        "defaults,discard"
+    );
+
+    assert!("".parse::<MountOptions>().unwrap().is_default());
+    assert!("commit=0".parse::<MountOptions>().is_err());
+    assert!("relatime".parse::<MountOptions>().is_err());
+}
+
+#[test]
+fn test_parse_os_release() {
+    let aosc = "NAME=\"AOSC OS\"\nVERSION_ID=\"12.0.0\"\nPRETTY_NAME=\"AOSC OS (12.0.0)\"\n";
+    assert_eq!(parse_os_release(aosc).as_deref(), Some("AOSC OS (12.0.0)"));
+    assert_eq!(
+        parse_os_release("NAME=Gentoo\nID=gentoo\n").as_deref(),
+        Some("Gentoo")
+    );
+    assert_eq!(parse_os_release("ID=unknown\n"), None);
+}
+
+#[test]
+fn test_extra_mount() {
+    let partition = Partition {
+        path: Some(PathBuf::from("/dev/sdb1")),
+        parent_path: Some(PathBuf::from("/dev/sdb")),
+        fs_type: Some("xfs".to_string()),
+        size: 0,
+    };
+    let mount = ExtraMount::new(&partition, "/srv/", false).unwrap();
+    assert_eq!(mount.mount_point, Path::new("/srv"));
+    assert_eq!(mount.relative(), Path::new("srv"));
+    assert_eq!(mount.to_string(), "/dev/sdb1 on /srv");
+
+    assert!(ExtraMount::new(&partition, "/var/lib", true).is_ok());
+    assert!(ExtraMount::new(&partition, "srv", false).is_err());
+    assert!(ExtraMount::new(&partition, "/srv/../etc", false).is_err());
+    assert!(ExtraMount::new(&partition, "/home", false).is_err());
+    assert!(ExtraMount::new(&partition, "/", false).is_err());
+}
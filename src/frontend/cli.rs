@@ -1,5 +1,7 @@
 use std::{
     path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
@@ -9,22 +11,109 @@ use std::{
 };
 
 use crate::{
+    crypt,
     disks::{self, Partition},
     install::{self, is_acceptable_username, is_valid_hostname, umount_all},
     network::{self, fetch_mirrors, Mirror, VariantEntry},
+    parser::list_mounts,
+    preflight,
 };
 use anyhow::{anyhow, Result};
 use clap::{Parser, Subcommand};
 use indicatif::ProgressBar;
-use log::{error, info};
+use log::{error, info, warn, LevelFilter};
+use rustix::fd::OwnedFd;
+use serde::Deserialize;
 
-use super::{begin_install, tui_main, AtomicBoolWrapper, InstallConfig, DEFAULT_EMPTY_SIZE};
+use super::{
+    begin_install, cues, free_space_margin, required_space, test_mode, tui_main, AtomicBoolWrapper,
+    InstallConfig,
+};
+
+const AUTO_CONFIG_FILE: &str = "deploykit-auto.json";
+const INTEL_RAID_HINT: &str = "Your disk controller is in Intel RST (RAID) mode, which may hide disks from the installer. Please switch the SATA mode to AHCI in your firmware settings.";
+const AUTO_INSTALL_DELAY: Duration = Duration::from_secs(10);
+/// Kernel command line switch allowing an answer file to start an installation
+const AUTO_INSTALL_CMDLINE: &str = "deploykit.auto_install";
 
 #[derive(Parser, Debug)]
 #[clap(about, version, author)]
 pub struct Args {
+    /// Defaults to `tui` when no subcommand is given
     #[clap(subcommand)]
-    subcommand: DeployKitCliCommand,
+    subcommand: Option<DeployKitCliCommand>,
+    /// Seconds an external command may run before it is considered hung, 0 to disable
+    /// (also read from DK_COMMAND_TIMEOUT)
+    #[clap(long, global = true)]
+    command_timeout: Option<u64>,
+    /// Seconds to wait for a connection to a server (also read from DK_CONNECT_TIMEOUT)
+    #[clap(long, global = true)]
+    connect_timeout: Option<u64>,
+    /// Seconds to wait for data from a server before giving up (also read from DK_READ_TIMEOUT)
+    #[clap(long, global = true)]
+    read_timeout: Option<u64>,
+    /// How many times a failed request is retried (also read from DK_RETRIES)
+    #[clap(long, global = true)]
+    retries: Option<u32>,
+    /// Fetch the release recipe from this URL instead, e.g. for testing branches
+    /// (also read from DK_RECIPE_URL)
+    #[clap(long, global = true)]
+    recipe_url: Option<String>,
+    /// Send all requests through this proxy, e.g. socks5h://127.0.0.1:1080 (also read from
+    /// DK_PROXY), http_proxy, https_proxy and all_proxy are honored when unset
+    #[clap(long, global = true)]
+    proxy: Option<String>,
+    /// Send all requests through Tor running on this machine, for networks where mirrors are
+    /// blocked (onion service mirrors always go through Tor)
+    #[clap(long, global = true, conflicts_with = "proxy", action = clap::ArgAction::SetTrue)]
+    tor: bool,
+    /// Install with the answer file on the boot medium without asking, if there is one
+    /// (also enabled by deploykit.auto_install on the kernel command line)
+    #[clap(long, global = true, action = clap::ArgAction::SetTrue)]
+    auto_install: bool,
+    /// Trust this CA certificate (PEM or DER) for HTTPS in addition to the system ones, for
+    /// networks that intercept HTTPS (also read from DK_CA_CERTIFICATE)
+    #[clap(long, global = true)]
+    ca_certificate: Option<PathBuf>,
+    /// One of off, error, warn, info, debug or trace (also read from DK_LOG_LEVEL)
+    #[clap(long, global = true)]
+    log_level: Option<LevelFilter>,
+    /// Ring the terminal bell when input is needed and when the installation ends
+    /// (also read from DK_AUDIO_CUES)
+    #[clap(long, global = true, action = clap::ArgAction::SetTrue)]
+    audio_cues: bool,
+    /// Install to a fresh loop device with the --test-fixture tarball, and write the outcome
+    /// to --test-result (for QA)
+    #[clap(long, global = true, requires = "test_fixture", action = clap::ArgAction::SetTrue)]
+    test_mode: bool,
+    /// Release tarball (.tar.xz or .squashfs) to install in test mode
+    #[clap(long, global = true)]
+    test_fixture: Option<PathBuf>,
+    /// Where test mode writes its result as JSON
+    #[clap(long, global = true, default_value = "/tmp/deploykit-test-result.json")]
+    test_result: PathBuf,
+}
+
+impl Args {
+    /// Whether this invocation runs the interactive TUI
+    pub fn is_tui(&self) -> bool {
+        matches!(self.subcommand, None | Some(DeployKitCliCommand::Tui(_)))
+    }
+
+    /// Whether this invocation touches disks and therefore needs the preflight checks
+    pub fn needs_root(&self) -> bool {
+        matches!(
+            self.subcommand,
+            None | Some(DeployKitCliCommand::Tui(_))
+                | Some(DeployKitCliCommand::Install(_))
+                | Some(DeployKitCliCommand::Rescue(_))
+                | Some(DeployKitCliCommand::Provision(_))
+        )
+    }
+
+    pub fn log_level(&self) -> LevelFilter {
+        arg_or_env(self.log_level, "DK_LOG_LEVEL").unwrap_or(LevelFilter::Info)
+    }
 }
 
 #[derive(Subcommand, Debug)]
@@ -33,19 +122,33 @@ enum DeployKitCliCommand {
     Tui(Tui),
     /// Install System
     Install(Box<InstallCommand>),
+    /// Download a system release without installing it
+    Download(DownloadCommand),
+    /// Mount an installed system and open a shell inside it
+    Rescue(RescueCommand),
+    /// Benchmark all mirrors and print them from fastest to slowest
+    BenchMirrors(BenchMirrors),
     /// List of mirror
     ListMirror(ListMirror),
     /// List of locale
     ListLocale(ListLocale),
     /// List of timezone
     ListTimezone(ListTimezone),
-    /// List of tarball
-    ListTarball(ListTarball),
+    /// List of available variants
+    #[clap(alias = "list-tarball")]
+    ListVariants(ListVariants),
+    /// Validate a saved installation configuration file
+    Validate(ValidateCommand),
+    /// Install System unattended from a configuration template
+    Provision(ProvisionCommand),
 }
 
 #[derive(Parser, Debug)]
 struct Tui;
 
+#[derive(Parser, Debug)]
+struct BenchMirrors;
+
 #[derive(Parser, Debug)]
 struct ListMirror;
 
@@ -56,7 +159,53 @@ struct ListLocale;
 struct ListTimezone;
 
 #[derive(Parser, Debug)]
-struct ListTarball;
+struct ListVariants {
+    /// Print variants (with sizes, URLs and checksums) as JSON
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    json: bool,
+}
+
+#[derive(Parser, Debug)]
+struct DownloadCommand {
+    /// Select AOSC OS variant to download (e.g., Workstation, Server, Base)
+    #[clap(long, default_value = "Base")]
+    tarball: String,
+    /// Set URL for download source
+    #[clap(long, default_value = "https://repo.aosc.io/aosc-os")]
+    mirror: String,
+    /// Save the system release to this file or directory; partial files are resumed
+    #[clap(long)]
+    output: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct RescueCommand {
+    /// Set the root partition of the installed system (e.g., /dev/sda1)
+    #[clap(long)]
+    path: String,
+}
+
+#[derive(Parser, Debug)]
+struct ValidateCommand {
+    /// Path to the configuration file (e.g., /root/deploykit-config.json)
+    config: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct ProvisionCommand {
+    /// URL or path of the configuration template, a saved configuration with
+    /// {{key}} placeholders ({{serial}} and {{mac}} are filled in from this machine)
+    template: String,
+    /// Set target partition to install AOSC OS to (e.g., /dev/sda1)
+    #[clap(long)]
+    path: String,
+    /// Set a template value as KEY=VALUE (e.g., --set mirror=https://mirrors.example.org/aosc-os/)
+    #[clap(long = "set", value_parser = parse_key_value)]
+    values: Vec<(String, String)>,
+    /// Install even if the battery is low
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    ignore_battery: bool,
+}
 
 #[derive(Parser, Debug)]
 struct InstallCommand {
@@ -66,9 +215,53 @@ struct InstallCommand {
     /// Set URL for download source
     #[clap(long, default_value = "https://repo.aosc.io/aosc-os")]
     mirror: String,
+    /// Install a system release from this URL instead of a listed variant (for testing unreleased tarballs);
+    /// --sha256 is required unless it is a Metalink descriptor (.meta4)
+    #[clap(long)]
+    tarball_url: Option<String>,
+    /// Install a system release from this file, without network access; the checksum
+    /// is read from the .sha256sum file next to it unless --sha256 is given
+    #[clap(long, conflicts_with = "tarball_url")]
+    tarball_file: Option<PathBuf>,
+    /// Copy the running live system instead of downloading a release
+    #[clap(long, conflicts_with_all = ["tarball_url", "tarball_file"], action = clap::ArgAction::SetTrue)]
+    from_live: bool,
+    /// Set SHA-256 checksum of the system release given with --tarball-url or --tarball-file
+    #[clap(long)]
+    sha256: Option<String>,
     /// Set target partition to install AOSC OS to (e.g., /dev/sda1)
+    #[clap(long, required_unless_present = "image")]
+    path: Option<String>,
+    /// Install to a new raw disk image at this path instead, e.g. for virtual machines
+    #[clap(long, conflicts_with_all = ["path", "esp", "create_esp", "realign", "keep_data", "btrfs_mirror", "home"])]
+    image: Option<PathBuf>,
+    /// Set the size of the disk image in GiB [default: 16]
+    #[clap(long, requires = "image")]
+    image_size: Option<u64>,
+    /// Set EFI system partition to use when there are several (e.g., /dev/sdb1)
     #[clap(long)]
-    path: String,
+    esp: Option<String>,
+    /// Create a 512MiB EFI system partition on the target disk if it has none
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    create_esp: bool,
+    /// Move the start of the target partition to a 1MiB boundary if it is misaligned
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    realign: bool,
+    /// Rewrite the backup GPT of the target disk from the primary one if it is damaged
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    repair_gpt: bool,
+    /// Unmount or swapoff the target partition if it is in use
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    force_unmount: bool,
+    /// Install onto the existing ext4 or btrfs filesystem of the target partition, keeping its files
+    #[clap(long, conflicts_with_all = ["realign", "btrfs_mirror", "luks_passphrase_file"], action = clap::ArgAction::SetTrue)]
+    keep_data: bool,
+    /// Mirror the btrfs system partition to this partition (RAID1), which is formatted as well
+    #[clap(long)]
+    btrfs_mirror: Option<String>,
+    /// Mount an existing partition as /home, without formatting it (e.g., /dev/sda2)
+    #[clap(long)]
+    home: Option<String>,
     /// Set name of the default user
     #[clap(long)]
     user: String,
@@ -93,16 +286,177 @@ struct InstallCommand {
     /// Set custom swapfile size
     #[clap(long, conflicts_with = "no_swap")]
     swap_size: Option<f64>,
+    /// Format this partition as swap and use it instead of a swapfile (e.g., /dev/sda3)
+    #[clap(long, conflicts_with_all = ["no_swap", "new_swap_partition", "luks_passphrase_file"])]
+    swap_partition: Option<String>,
+    /// Create a swap partition in free space on the target disk instead of a swapfile
+    #[clap(long, conflicts_with = "no_swap", action = clap::ArgAction::SetTrue)]
+    new_swap_partition: bool,
+    /// Keep this percentage of the system partition free when sizing the swapfile (default: 5)
+    #[clap(long, value_parser = clap::value_parser!(u8).range(1..=50))]
+    free_space_percent: Option<u8>,
+    /// Swap to compressed memory (zram) instead of a swapfile
+    #[clap(long, conflicts_with_all = ["no_swap", "swap_size", "swap_partition", "new_swap_partition"], action = clap::ArgAction::SetTrue)]
+    zram: bool,
+    /// Install and enable a power management service (tlp or power-profiles-daemon)
+    #[clap(long)]
+    power_management: Option<install::PowerManagement>,
+    /// Install the 32-bit compatibility runtime (amd64 only)
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    optenv32: bool,
+    /// Authenticate users against this LDAP server through SSSD (e.g., ldaps://ldap.example.com)
+    #[clap(long, requires = "ldap_base_dn")]
+    ldap_uri: Option<String>,
+    /// Set base DN to search for users in (e.g., dc=example,dc=com)
+    #[clap(long, requires = "ldap_uri")]
+    ldap_base_dn: Option<String>,
+    /// Give the installed system this fixed address on --static-interface with
+    /// systemd-networkd, instead of DHCP (e.g., 192.168.1.10/24)
+    #[clap(long, requires = "static_interface")]
+    static_address: Option<String>,
+    /// Set network interface for --static-address (e.g., eth0)
+    #[clap(long, requires = "static_address")]
+    static_interface: Option<String>,
+    /// Set default gateway for --static-address
+    #[clap(long, requires = "static_address")]
+    static_gateway: Option<String>,
+    /// Set DNS servers for --static-address, separated by commas
+    #[clap(long, requires = "static_address")]
+    static_dns: Option<String>,
+    /// Do not copy the Wi-Fi networks, DNS settings and the proxy of this session to the installed system
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    skip_live_network: bool,
+    /// Download the system release with BitTorrent if it has a torrent, falling back to the mirror
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    torrent: bool,
+    /// Set extra mount options for all filesystems (any of noatime, commit=SECONDS, discard, discard=async, compress=zstd)
+    #[clap(long)]
+    mount_options: Option<disks::MountOptions>,
+    /// Append parameters to the kernel command line
+    #[clap(long, default_value = "")]
+    kernel_cmdline: String,
+    /// Set how long the GRUB menu is shown for, in seconds
+    #[clap(long)]
+    grub_timeout: Option<u32>,
+    /// Pass extra arguments to mkfs when formatting the system partition with a filesystem,
+    /// as FS=ARGS quoted as in a shell (e.g., --mkfs-args "ext4=-O casefold"); may be repeated
+    #[clap(long, value_parser = parse_mkfs_args, allow_hyphen_values = true)]
+    mkfs_args: Vec<(String, String)>,
+    /// Create XFS filesystems without reflink support
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    no_xfs_reflink: bool,
+    /// Create XFS filesystems without timestamps beyond 2038 (for older kernels)
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    no_xfs_bigtime: bool,
+    /// Add extra dracut configuration (dracut.conf syntax)
+    #[clap(long, default_value = "")]
+    dracut_conf: String,
+    /// Mount an existing partition into the installed system without formatting it (e.g., /dev/sdb1:/srv)
+    #[clap(long = "mount")]
+    mounts: Vec<String>,
+    /// Format a partition and mount it into the installed system (e.g., /dev/sdb1:/var)
+    #[clap(long = "format-mount")]
+    format_mounts: Vec<String>,
+    /// Add the default user to these groups as well, separated by commas
+    #[clap(long, value_delimiter = ',')]
+    extra_groups: Vec<String>,
+    /// Set how the installed system is booted (grub, or efistub on UEFI systems)
+    #[clap(long, default_value = "grub")]
+    bootloader: install::Bootloader,
+    /// Set where the EFI system partition is mounted in the installed system (/efi or /boot/efi)
+    #[clap(long, default_value = "/efi")]
+    esp_mount_point: install::EspMountPoint,
+    /// Set how fstab refers to partitions (uuid, partuuid or label)
+    #[clap(long, default_value = "uuid")]
+    fstab_source: disks::FstabSource,
+    /// Take a read-only snapshot of the installed system (btrfs only)
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    btrfs_snapshot: bool,
+    /// Encrypt the target partition, using LVM on LUKS, with the passphrase on the first line of
+    /// this file (- for stdin, /dev/fd/N for an inherited file descriptor)
+    #[clap(long)]
+    luks_passphrase_file: Option<PathBuf>,
+    /// Generate a recovery key that also unlocks the encrypted partition, and print it
+    #[clap(long, requires = "luks_passphrase_file", action = clap::ArgAction::SetTrue)]
+    luks_recovery_key: bool,
+    /// Also save the installation report to this file (e.g., on the live medium)
+    #[clap(long)]
+    report_copy: Option<PathBuf>,
+    /// Install even if the battery is low
+    #[clap(long, action = clap::ArgAction::SetTrue)]
+    ignore_battery: bool,
 }
 
 pub fn execute(args: Args) -> Result<()> {
+    if let Some(timeout) = arg_or_env(args.command_timeout, "DK_COMMAND_TIMEOUT") {
+        install::set_command_timeout(timeout);
+    }
+
+    let mut network_options = network::network_options();
+    if let Some(timeout) = arg_or_env(args.connect_timeout, "DK_CONNECT_TIMEOUT") {
+        network_options.connect_timeout = Duration::from_secs(timeout);
+    }
+    if let Some(timeout) = arg_or_env(args.read_timeout, "DK_READ_TIMEOUT") {
+        network_options.read_timeout = Duration::from_secs(timeout);
+    }
+    if let Some(retries) = arg_or_env(args.retries, "DK_RETRIES") {
+        network_options.retries = retries;
+    }
+    network::set_network_options(network_options);
+    if let Some(proxy) = arg_or_env(args.proxy.clone(), "DK_PROXY") {
+        network::set_proxy(Some(proxy))?;
+    }
+    if let Some(path) = arg_or_env(args.ca_certificate.clone(), "DK_CA_CERTIFICATE") {
+        network::set_ca_certificate(Some(path))?;
+    }
+    if args.tor {
+        if !network::tor_available() {
+            return Err(anyhow!(
+                "Installer could not reach Tor at {}, please start it first.",
+                network::TOR_PROXY
+            ));
+        }
+        network::set_proxy(Some(network::TOR_PROXY.to_string()))?;
+    }
+    if let Some(url) = arg_or_env(args.recipe_url.clone(), "DK_RECIPE_URL") {
+        network::set_recipe_url(url)?;
+    }
+
+    if args.audio_cues || arg_or_env(None, "DK_AUDIO_CUES").unwrap_or(false) {
+        cues::set_audio_cues(true);
+    }
+
+    if args.test_mode {
+        if let Some(fixture) = args.test_fixture.clone() {
+            test_mode::enable(fixture, args.test_result.clone());
+        }
+    }
+
     match args.subcommand {
-        DeployKitCliCommand::Tui(Tui) => tui_main(),
-        DeployKitCliCommand::Install(ic) => start_install(*ic)?,
-        DeployKitCliCommand::ListMirror(ListMirror) => list_mirror()?,
-        DeployKitCliCommand::ListLocale(ListLocale) => list_locale()?,
-        DeployKitCliCommand::ListTimezone(ListTimezone) => list_timezone()?,
-        DeployKitCliCommand::ListTarball(ListTarball) => list_tarball()?,
+        None => match find_auto_config() {
+            Some(path) if args.auto_install || auto_install_on_cmdline() => {
+                start_auto_install(&path)?
+            }
+            Some(path) => {
+                info!(
+                    "Ignoring {}, neither --auto-install nor {AUTO_INSTALL_CMDLINE} is given",
+                    path.display()
+                );
+                tui_main()
+            }
+            None => tui_main(),
+        },
+        Some(DeployKitCliCommand::Tui(Tui)) => tui_main(),
+        Some(DeployKitCliCommand::Install(ic)) => start_install(*ic)?,
+        Some(DeployKitCliCommand::Download(dc)) => start_download(dc)?,
+        Some(DeployKitCliCommand::Rescue(rc)) => start_rescue(rc)?,
+        Some(DeployKitCliCommand::BenchMirrors(BenchMirrors)) => bench_mirrors()?,
+        Some(DeployKitCliCommand::ListMirror(ListMirror)) => list_mirror()?,
+        Some(DeployKitCliCommand::ListLocale(ListLocale)) => list_locale()?,
+        Some(DeployKitCliCommand::ListTimezone(ListTimezone)) => list_timezone()?,
+        Some(DeployKitCliCommand::ListVariants(lv)) => list_variants(lv.json)?,
+        Some(DeployKitCliCommand::Validate(vc)) => validate_config(&vc.config)?,
+        Some(DeployKitCliCommand::Provision(pc)) => start_provision(pc)?,
     }
 
     Ok(())
@@ -118,6 +472,62 @@ fn list_mirror() -> Result<()> {
     Ok(())
 }
 
+/// The first line of `path`, or of stdin for "-", so that secrets do not show
+/// up in the process list
+fn read_passphrase(path: &Path) -> Result<String> {
+    let mut buf = String::new();
+    if path == Path::new("-") {
+        std::io::stdin().read_line(&mut buf)?;
+    } else {
+        buf = std::fs::read_to_string(path).map_err(|e| {
+            anyhow!(
+                "Installer could not read the passphrase from {}: {e}",
+                path.display()
+            )
+        })?;
+    }
+    let passphrase = buf.lines().next().unwrap_or_default().to_string();
+    if passphrase.is_empty() {
+        return Err(anyhow!("The passphrase in {} is empty.", path.display()));
+    }
+
+    Ok(passphrase)
+}
+
+/// Prefer the command line value, then fall back to the environment variable
+fn arg_or_env<T: FromStr>(arg: Option<T>, env: &str) -> Option<T> {
+    arg.or_else(|| std::env::var(env).ok().and_then(|x| x.parse().ok()))
+}
+
+fn bench_mirrors() -> Result<()> {
+    let recipe = network::fetch_recipe()?;
+    let mirrors = fetch_mirrors(&recipe);
+    println!(
+        "Benchmarking {} mirrors, this may take a few seconds ...",
+        mirrors.len()
+    );
+    let mirrors = network::speedtest_mirrors(mirrors, |mirror, res| match res {
+        Ok(score) => println!(
+            "{:<40}{:>6} ms {:>10.0} KiB/s",
+            mirror.name,
+            score.latency.as_millis(),
+            score.throughput / 1024.0
+        ),
+        Err(e) => println!("{:<40}{e}", mirror.name),
+    });
+    if mirrors.is_empty() {
+        return Err(anyhow!(
+            "Installer could not reach any mirror, please check your network connection!"
+        ));
+    }
+    println!();
+    for (index, i) in mirrors.iter().enumerate() {
+        println!("{:<4}{:<40}{}", index + 1, i.name, i.url);
+    }
+
+    Ok(())
+}
+
 fn list_locale() -> Result<()> {
     let locale_list = install::get_locale_list()?;
     for (lang, locale, _) in locale_list {
@@ -136,8 +546,13 @@ fn list_timezone() -> Result<()> {
     Ok(())
 }
 
-fn list_tarball() -> Result<()> {
+fn list_variants(json: bool) -> Result<()> {
     let variants = network::get_variants()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&variants)?);
+        return Ok(());
+    }
+
     for i in variants {
         println!("{}", i.name);
     }
@@ -156,12 +571,17 @@ fn get_variant(tarball: &str) -> Result<VariantEntry> {
     }
 
     Err(anyhow!(
-        "Installer could not find tarball for specified variant {}.\nPlease refer to the `aoscdk-rs list-tarball` output for a list of available tarballs.",
+        "Installer could not find tarball for specified variant {}.\nPlease refer to the `aoscdk-rs list-variants` output for a list of available variants.",
         tarball
     ))
 }
 
-fn get_partition(path: &str, variant: &VariantEntry) -> Result<Partition> {
+fn get_partition(
+    path: &str,
+    variant: &VariantEntry,
+    repair_gpt: bool,
+    force_unmount: bool,
+) -> Result<Partition> {
     let required_size = variant.install_size + variant.size;
     if cfg!(debug_assertions) {
         disks::right_combine(Some(&PathBuf::from("/dev/loop30")))?;
@@ -174,13 +594,22 @@ fn get_partition(path: &str, variant: &VariantEntry) -> Result<Partition> {
         });
     }
     let path = Path::new(path);
-    let list_part = disks::list_partitions(None);
-    let index = list_part
-        .iter()
-        .position(|x| x.path == Some(path.to_path_buf()));
-    if let Some(index) = index {
-        let partition = list_part[index].to_owned();
-        if partition.size < required_size + variant.size {
+    if let Some(partition) = disks::find_partition(path) {
+        if partition
+            .parent_path
+            .as_ref()
+            .is_some_and(|x| disks::live_medium_disks().contains(x))
+        {
+            return Err(anyhow!(
+                "{} is on the medium this installer is running from!",
+                path.display()
+            ));
+        }
+        disks::check_not_esp(&partition, required_size)?;
+        let partition = disks::fill_fs_type(&partition, false);
+        let required_size =
+            required_space(variant, partition.fs_type.as_deref(), partition.size, 0);
+        if partition.size < required_size {
             let s = format!(
                 "The specified partition does not contain enough space to install AOSC OS release!\n\nAvailable space: {:.3}GiB\nRequired space: {:.3}GiB", 
                 partition.size as f32 / 1024.0 / 1024.0 / 1024.0,
@@ -188,12 +617,49 @@ fn get_partition(path: &str, variant: &VariantEntry) -> Result<Partition> {
             );
             return Err(anyhow!(s));
         }
-        let partition = disks::fill_fs_type(&partition, false);
+        disks::check_sector_size(&partition)?;
         disks::right_combine(partition.parent_path.as_deref())?;
+        if let Some(disk) = partition
+            .parent_path
+            .as_deref()
+            .filter(|x| disks::gpt_backup_damaged(x))
+        {
+            if repair_gpt {
+                disks::repair_gpt_backup(disk)?;
+            } else {
+                warn!(
+                    "The backup GPT of {} is damaged, use --repair-gpt to rewrite it",
+                    disk.display()
+                );
+            }
+        }
+        let usage = install::partition_usage(&partition);
+        if !usage.is_empty() && !force_unmount {
+            return Err(anyhow!(
+                "{} is in use ({}), please unmount it first or use --force-unmount.",
+                path.display(),
+                usage.join(", ")
+            ));
+        }
+        if !usage.is_empty() {
+            info!(
+                "{} is in use ({}), releasing it",
+                path.display(),
+                usage.join(", ")
+            );
+            install::release_partition(&partition)?;
+        }
 
         return Ok(partition);
     }
 
+    if !disks::intel_raid_controllers().is_empty() {
+        return Err(anyhow!(
+            "Installer could not find the specified partition: {}\n{INTEL_RAID_HINT}",
+            path.display()
+        ));
+    }
+
     Err(anyhow!(
         "Installer could not find the specified partition: {}\nDid you partition your target disk?",
         path.display()
@@ -221,6 +687,7 @@ fn get_swap(
     swap_size: Option<f64>,
     partition: &Partition,
     variant: &VariantEntry,
+    free_space_percent: Option<u8>,
 ) -> Result<(bool, f64, bool)> {
     let result = if let Some(swap_size) = swap_size {
         let size = swap_size * 1024.0 * 1024.0 * 1024.0;
@@ -231,9 +698,14 @@ fn get_swap(
         let mem = sysinfo::System::new_all().total_memory();
         let size = disks::get_recommend_swap_size(mem)?;
 
-        if partition.size as f64
-            > size + variant.install_size as f64 + variant.size as f64 - DEFAULT_EMPTY_SIZE as f64
-        {
+        let margin = free_space_margin(partition.size, free_space_percent);
+        let required = required_space(
+            variant,
+            partition.fs_type.as_deref(),
+            partition.size,
+            size as u64,
+        );
+        if partition.size > required + margin {
             (true, size, true)
         } else {
             (false, size, false)
@@ -243,14 +715,170 @@ fn get_swap(
     Ok(result)
 }
 
+/// Refuse to install on a low battery unless told to
+fn check_battery(ignore_battery: bool) -> Result<()> {
+    if let Some(level) = preflight::battery_level() {
+        if level < preflight::LOW_BATTERY_PERCENT && !ignore_battery {
+            return Err(anyhow!(
+                "The battery is at {level}%, please connect the AC adapter, or pass --ignore-battery to install anyway!"
+            ));
+        }
+        warn!("Installing on battery power ({level}%)");
+    }
+
+    Ok(())
+}
+
 fn start_install(ic: InstallCommand) -> Result<()> {
-    let running = Arc::new(AtomicBool::new(true));
-    let r = running.clone();
-    let variant = get_variant(&ic.tarball)?;
-    let partition = get_partition(&ic.path, &variant)?;
-    let mirror = get_mirror(&ic.mirror);
+    check_battery(ic.ignore_battery)?;
+    // every error from here on detaches the image again when it is dropped
+    let (variant, partition, mirror, image) = if test_mode::is_enabled() {
+        info!("Test mode: ignoring --tarball, --mirror and --path");
+        (
+            test_mode::fixture_variant()?,
+            test_mode::test_partition()?,
+            test_mode::fixture_mirror(),
+            None,
+        )
+    } else {
+        let variant = match (&ic.tarball_url, &ic.tarball_file, &ic.sha256) {
+            _ if ic.from_live => network::live_variant()?,
+            (Some(url), _, Some(sha256)) => network::custom_variant(url, sha256)?,
+            (Some(url), _, None) if network::is_metalink(url) => network::custom_variant(url, "")?,
+            (Some(_), _, None) => {
+                return Err(anyhow!(
+                    "--tarball-url requires --sha256 unless it is a Metalink descriptor."
+                ));
+            }
+            (_, Some(file), sha256) => network::local_variant(file, sha256.as_deref())?,
+            _ => get_variant(&ic.tarball)?,
+        };
+        let (partition, image) = match &ic.image {
+            Some(image) => {
+                let (partition, image) = get_image_partition(image, ic.image_size, &variant)?;
+                (partition, Some(image))
+            }
+            None => (
+                get_partition(
+                    ic.path.as_deref().unwrap_or_default(),
+                    &variant,
+                    ic.repair_gpt,
+                    ic.force_unmount,
+                )?,
+                None,
+            ),
+        };
+        (variant, partition, get_mirror(&ic.mirror), image)
+    };
+    let esp = match &ic.esp {
+        Some(path) => Some(Arc::new(
+            disks::find_partition(Path::new(path))
+                .ok_or_else(|| anyhow!("Installer could not find the specified ESP: {path}"))?,
+        )),
+        None => None,
+    };
+    if let Some(esp) = &esp {
+        disks::check_sector_size(esp)?;
+    }
+    let create_bios_grub = match partition.parent_path.as_deref() {
+        Some(disk) if disks::needs_bios_grub_partition(disk) => {
+            if disks::find_free_space(disk, disks::BIOS_GRUB_SIZE)?.is_none() {
+                return Err(anyhow!(
+                    "{} is a GPT disk without a BIOS boot partition, which GRUB requires on BIOS systems. Please create a 1MiB partition with the bios_grub flag on it.",
+                    disk.display()
+                ));
+            }
+            info!(
+                "A BIOS boot partition will be created on {}",
+                disk.display()
+            );
+            true
+        }
+        _ => false,
+    };
+    if ic.create_esp && (!disks::is_efi_booted() || esp.is_some()) {
+        return Err(anyhow!(
+            "--create-esp is only available on UEFI systems, without --esp!"
+        ));
+    }
+    let home = match &ic.home {
+        Some(path) => {
+            let home = disks::find_partition(Path::new(path)).ok_or_else(|| {
+                anyhow!("Installer could not find the specified /home partition: {path}")
+            })?;
+            if home.fs_type.is_none() || disks::is_swap(&home) || home.path == partition.path {
+                return Err(anyhow!(
+                    "{path} can not be used as /home, it must be formatted and not be the system partition."
+                ));
+            }
+            Some(Arc::new(home))
+        }
+        None => None,
+    };
+    if ic.keep_data
+        && install::is_btrfs(&partition)
+        && !disks::has_btrfs_subvolumes(&partition, &install::kept_btrfs_subvolumes(home.is_some()))
+    {
+        return Err(anyhow!(
+            "--keep-data on btrfs requires the @ and @home subvolumes (@ alone with --home), the files in the top level would be hidden otherwise."
+        ));
+    }
+    let btrfs_mirror = match &ic.btrfs_mirror {
+        Some(path) => {
+            let mirror = disks::find_partition(Path::new(path)).ok_or_else(|| {
+                anyhow!("Installer could not find the specified mirror partition: {path}")
+            })?;
+            if !install::is_btrfs(&partition) || ic.luks_passphrase_file.is_some() {
+                return Err(anyhow!(
+                    "--btrfs-mirror requires an unencrypted btrfs system partition!"
+                ));
+            }
+            if mirror.path == partition.path || home.as_ref().is_some_and(|x| x.path == mirror.path)
+            {
+                return Err(anyhow!(
+                    "{path} can not be used as mirror, it is already in use."
+                ));
+            }
+            if mirror.parent_path == partition.parent_path {
+                warn!("{path} is on the same disk as the system partition, which will not survive a disk failure.");
+            }
+            warn!("If either disk of the btrfs RAID1 fails, the system will only boot with rootflags=degraded on the kernel command line.");
+            Some(Arc::new(mirror))
+        }
+        None => None,
+    };
     let tc = if ic.use_rtc { "RTC" } else { "UTC" };
-    let (use_swap, swap_size, is_hibernation) = get_swap(ic.swap_size, &partition, &variant)?;
+    let (use_swap, swap_size, is_hibernation) =
+        get_swap(ic.swap_size, &partition, &variant, ic.free_space_percent)?;
+
+    let swap_partition = match &ic.swap_partition {
+        Some(path) => {
+            let swap = disks::find_partition(Path::new(path)).ok_or_else(|| {
+                anyhow!("Installer could not find the specified swap partition: {path}")
+            })?;
+            if disks::list_esp_partitions()
+                .iter()
+                .any(|x| x.path == swap.path)
+            {
+                return Err(anyhow!(
+                    "{path} can not be used as swap, it is an EFI system partition."
+                ));
+            }
+            Some(disks::SwapPartition::Existing(swap))
+        }
+        None if ic.new_swap_partition && swap_size <= 0.0 => {
+            return Err(anyhow!(
+                "--new-swap-partition needs a swap size larger than 0, please set --swap-size."
+            ));
+        }
+        None if ic.new_swap_partition => Some(disks::SwapPartition::New {
+            disk: partition.parent_path.clone().ok_or_else(|| {
+                anyhow!("Installer could not find the disk of the specified partition.")
+            })?,
+            size: swap_size as u64,
+        }),
+        None => None,
+    };
 
     if !is_valid_hostname(&ic.hostname) {
         return Err(anyhow!("hostname {} is not valid!", ic.hostname));
@@ -260,9 +888,63 @@ fn start_install(ic: InstallCommand) -> Result<()> {
         return Err(anyhow!("username {} is not valid!", ic.user));
     }
 
-    let install_config = InstallConfig {
+    let directory = match (&ic.ldap_uri, &ic.ldap_base_dn) {
+        (Some(uri), Some(base_dn)) => Some(install::DirectoryConfig::new(uri, base_dn)?),
+        _ => None,
+    };
+
+    let static_network = match (&ic.static_interface, &ic.static_address) {
+        (Some(interface), Some(address)) => Some(install::StaticNetworkConfig::new(
+            interface,
+            address,
+            ic.static_gateway.as_deref().unwrap_or_default(),
+            ic.static_dns.as_deref().unwrap_or_default(),
+        )?),
+        _ => None,
+    };
+
+    if let Some(group) = ic.extra_groups.iter().find(|x| !is_acceptable_username(x)) {
+        return Err(anyhow!("group name {group} is not valid!"));
+    }
+
+    if ic.bootloader == install::Bootloader::Efistub && !disks::is_efi_booted() {
+        return Err(anyhow!("EFISTUB is only available on UEFI systems!"));
+    }
+    if ic.bootloader == install::Bootloader::Efistub && ic.image.is_some() {
+        return Err(anyhow!(
+            "EFISTUB boot entries can not be created for disk images, use GRUB instead!"
+        ));
+    }
+    if ic.bootloader == install::Bootloader::Efistub && disks::is_efi_ia32() {
+        return Err(anyhow!(
+            "EFISTUB is not available on 32-bit UEFI firmware, use GRUB instead!"
+        ));
+    }
+
+    if ic.optenv32 && !install::optenv32_available() {
+        return Err(anyhow!(
+            "The 32-bit compatibility runtime is only available on amd64!"
+        ));
+    }
+
+    let use_swap = !ic.no_swap && !ic.zram && use_swap && swap_partition.is_none();
+    if !ic.realign && !disks::is_aligned(&partition) {
+        warn!("{path} does not start on a 1MiB boundary, which is slow on SSDs and disks with 4K sectors. Use --realign to fix it.", path = ic.path.as_deref().unwrap_or_default());
+    }
+
+    let mut install_config = InstallConfig {
         variant: Some(Arc::new(variant)),
         partition: Some(Arc::new(partition)),
+        esp,
+        home,
+        create_esp: ic.create_esp,
+        create_bios_grub,
+        realign_partition: ic.realign,
+        keep_data: ic.keep_data,
+        image: ic.image.clone(),
+        swap_partition,
+        extra_mounts: vec![],
+        btrfs_mirror,
         mirror: Some(Arc::new(mirror)),
         user: Some(Arc::new(ic.user)),
         full_name: None,
@@ -272,15 +954,134 @@ fn start_install(ic: InstallCommand) -> Result<()> {
         timezone: Some(Arc::new(ic.timezone)),
         tc: Some(Arc::new(tc.to_string())),
         use_swap: Arc::new(AtomicBoolWrapper {
-            v: AtomicBool::new(!ic.no_swap && use_swap),
+            v: AtomicBool::new(use_swap),
         }),
         swap_size: Arc::new(Some(swap_size)),
         is_hibernation: Arc::new(AtomicBoolWrapper {
             v: AtomicBool::new(is_hibernation),
         }),
         root_password: None,
+        power_management: ic.power_management,
+        zram: ic.zram,
+        free_space_percent: ic.free_space_percent,
+        optenv32: ic.optenv32,
+        directory,
+        static_network,
+        skip_live_network: ic.skip_live_network,
+        use_torrent: ic.torrent,
+        from_live: ic.from_live,
+        mount_options: ic.mount_options.unwrap_or_default(),
+        xfs_options: disks::XfsOptions {
+            reflink: !ic.no_xfs_reflink,
+            bigtime: !ic.no_xfs_bigtime,
+        },
+        expert: install::ExpertOptions {
+            dracut_conf: ic.dracut_conf,
+            kernel_cmdline: ic.kernel_cmdline,
+            grub_timeout: ic.grub_timeout,
+            extra_groups: ic.extra_groups,
+            mkfs_args: ic.mkfs_args.into_iter().collect(),
+        },
+        bootloader: ic.bootloader,
+        esp_mount_point: ic.esp_mount_point,
+        fstab_source: ic.fstab_source,
+        btrfs_snapshot: ic.btrfs_snapshot,
+        encryption: match &ic.luks_passphrase_file {
+            Some(path) => Some(crypt::EncryptionConfig {
+                passphrase: read_passphrase(path)?,
+                recovery_key: ic.luks_recovery_key.then(crypt::generate_recovery_key),
+            }),
+            None => None,
+        },
+        report_copy: ic.report_copy,
     };
+    if let Some(disks::SwapPartition::Existing(swap)) = &install_config.swap_partition {
+        let others = InstallConfig {
+            swap_partition: None,
+            ..install_config.clone()
+        };
+        if let Some(path) = swap.path.as_deref().filter(|x| others.uses_partition(x)) {
+            return Err(anyhow!(
+                "{} can not be used as swap, it is already used.",
+                path.display()
+            ));
+        }
+    }
+    let mounts = ic.mounts.iter().map(|x| (x, false));
+    let format_mounts = ic.format_mounts.iter().map(|x| (x, true));
+    for (arg, format) in mounts.chain(format_mounts) {
+        let mount = get_extra_mount(arg, format)?;
+        if install_config.uses_partition(mount.partition.path.as_deref().unwrap()) {
+            return Err(anyhow!("{arg}: the partition is already used."));
+        }
+        if install_config
+            .extra_mounts
+            .iter()
+            .any(|x| x.mount_point == mount.mount_point)
+        {
+            return Err(anyhow!("{arg}: the mount point is already used."));
+        }
+        install_config.extra_mounts.push(mount);
+    }
 
+    if let Some(problem) = install_config.fstab_source_problem() {
+        return Err(anyhow!(problem));
+    }
+
+    // printed rather than logged, the log is kept in the installed system
+    if let Some(key) = install_config
+        .encryption
+        .as_ref()
+        .and_then(|x| x.recovery_key.as_ref())
+    {
+        println!("Recovery key of the encrypted partition, please keep it somewhere safe: {key}");
+    }
+
+    let res = run_install(install_config);
+    if let Some(image) = image {
+        image.detach()?;
+    }
+
+    res
+}
+
+/// Create a disk image of `size` GiB at `image` and partition it, it stays
+/// attached as long as the returned [`disks::AttachedImage`] lives
+fn get_image_partition(
+    image: &Path,
+    size: Option<u64>,
+    variant: &VariantEntry,
+) -> Result<(Partition, disks::AttachedImage)> {
+    let size = size.map_or(disks::DEFAULT_IMAGE_SIZE, |x| x * 1024 * 1024 * 1024);
+    let attached = disks::AttachedImage::attach(image, size)?;
+    let partition = disks::auto_create_partitions(attached.dev())?;
+    let required_size = required_space(variant, partition.fs_type.as_deref(), partition.size, 0);
+    if partition.size < required_size {
+        return Err(anyhow!(
+            "{} is too small to install AOSC OS release, at least {:.0}GiB is required. Use --image-size to make it larger.",
+            image.display(),
+            ((required_size + disks::ESP_SIZE) as f64 / 1024.0 / 1024.0 / 1024.0).ceil()
+        ));
+    }
+
+    Ok((partition, attached))
+}
+
+/// Parse DEVICE:MOUNTPOINT
+fn get_extra_mount(arg: &str, format: bool) -> Result<disks::ExtraMount> {
+    let (path, mount_point) = arg
+        .split_once(':')
+        .ok_or_else(|| anyhow!("{arg} is not in the form of DEVICE:MOUNTPOINT"))?;
+    let partition = disks::find_partition(Path::new(path))
+        .ok_or_else(|| anyhow!("Installer could not find the specified partition: {path}"))?;
+
+    disks::ExtraMount::new(&partition, mount_point, format)
+}
+
+fn run_install(install_config: InstallConfig) -> Result<()> {
+    preflight::check_tools(&install_config.required_tools())?;
+    let running = Arc::new(AtomicBool::new(true));
+    let r = running.clone();
     let root_fd = install::get_dir_fd(Path::new("/"))?;
     let rfc = root_fd.try_clone().unwrap();
 
@@ -328,7 +1129,432 @@ fn start_install(ic: InstallCommand) -> Result<()> {
     }
 }
 
+fn start_download(dc: DownloadCommand) -> Result<()> {
+    let variant = get_variant(&dc.tarball)?;
+    let mirror = get_mirror(&dc.mirror);
+    let url = format!("{}{}", mirror.url, variant.url);
+
+    let output = if dc.output.is_dir() {
+        let file_name = variant
+            .url
+            .rsplit('/')
+            .next()
+            .ok_or_else(|| anyhow!("Can not get filename from {}", variant.url))?;
+        dc.output.join(file_name)
+    } else {
+        dc.output
+    };
+
+    info!("Downloading {} to {}", url, output.display());
+    let bar = ProgressBar::new(variant.size);
+    network::download_file(&url, &output, variant.size, &variant.sha256sum, |pos| {
+        bar.set_position(pos)
+    })?;
+    bar.finish();
+
+    println!(
+        "AOSC OS {} ({}) has been saved to {}",
+        variant.name,
+        variant.date,
+        output.display()
+    );
+
+    Ok(())
+}
+
+fn start_rescue(rc: RescueCommand) -> Result<()> {
+    let path = Path::new(&rc.path);
+    let partition = disks::find_partition(path).ok_or_else(|| {
+        anyhow!(
+            "Installer could not find the specified partition: {}",
+            path.display()
+        )
+    })?;
+
+    let tempdir = tempfile::Builder::new()
+        .prefix(".dkmount")
+        .tempdir()?
+        .into_path();
+
+    info!("Mounting partition for rescue: {:?}", partition);
+    install::mount_root_path(&partition, &tempdir)?;
+
+    let root_fd = install::get_dir_fd(Path::new("/"))?;
+    let res = rescue_shell(&partition, &tempdir, root_fd.try_clone()?);
+
+    install::escape_chroot(root_fd.try_clone()?).ok();
+    install::remove_bind_mounts(&tempdir).ok();
+    umount_all(&tempdir, root_fd);
+
+    res
+}
+
+fn rescue_shell(partition: &Partition, root: &Path, root_fd: OwnedFd) -> Result<()> {
+    if disks::is_efi_booted() {
+        if let Some(parent) = partition.parent_path.as_ref() {
+            if let Ok(esp_part) = disks::find_esp_partition(parent) {
+                let esp_mount = install::EspMountPoint::detect(root);
+                let efi_path = root.join(esp_mount.relative());
+                std::fs::create_dir_all(&efi_path)?;
+                install::mount_root_path(&esp_part, &efi_path)?;
+            }
+        }
+    }
+
+    install::dive_into_guest(root)?;
+    println!("You are now inside the installed system. Exit the shell to unmount it.");
+    let status = Command::new("bash").status();
+    install::escape_chroot(root_fd)?;
+    status?;
+
+    Ok(())
+}
+
+/// Check a configuration file saved by the installer for obvious mistakes
+fn validate_config(path: &Path) -> Result<()> {
+    let config: InstallConfig = serde_json::from_slice(&std::fs::read(path)?)?;
+    let problems = config_problems(&config);
+    if problems.is_empty() {
+        println!("{} is a valid installation configuration.", path.display());
+        return Ok(());
+    }
+
+    for i in &problems {
+        println!("- {i}");
+    }
+
+    Err(anyhow!(
+        "{} has {} problem(s), see above.",
+        path.display(),
+        problems.len()
+    ))
+}
+
+fn config_problems(config: &InstallConfig) -> Vec<String> {
+    let mut problems = vec![];
+
+    if config.variant.is_none() {
+        problems.push("No variant specified.".to_string());
+    }
+
+    if config.mirror.is_none() {
+        problems.push("No mirror specified.".to_string());
+    }
+
+    match config.user.as_deref() {
+        Some(user) if is_acceptable_username(user) => {}
+        Some(user) => problems.push(format!("username {user} is not valid!")),
+        None => problems.push("No username specified.".to_string()),
+    }
+
+    if config
+        .password
+        .as_ref()
+        .map(|x| x.is_empty())
+        .unwrap_or(true)
+    {
+        problems.push("No password specified.".to_string());
+    }
+
+    match config.hostname.as_deref() {
+        Some(hostname) if is_valid_hostname(hostname) => {}
+        Some(hostname) => problems.push(format!("hostname {hostname} is not valid!")),
+        None => problems.push("No hostname specified.".to_string()),
+    }
+
+    if let Some(timezone) = config.timezone.as_deref() {
+        let zoneinfo = install::get_zoneinfo_list().unwrap_or_default();
+        if !zoneinfo.iter().any(|x| x == timezone) && timezone != "Asia/Shanghai" {
+            problems.push(format!("timezone {timezone} is not valid!"));
+        }
+    } else {
+        problems.push("No timezone specified.".to_string());
+    }
+
+    if let Some(locale) = config.locale.as_deref() {
+        if locale != "C.UTF-8" && install::find_language_by_locale(locale).is_none() {
+            problems.push(format!("locale {locale} is not valid!"));
+        }
+    } else {
+        problems.push("No locale specified.".to_string());
+    }
+
+    if config.encryption.is_some()
+        && matches!(
+            config.swap_partition,
+            Some(disks::SwapPartition::Existing(_))
+        )
+    {
+        problems.push(
+            "An existing swap partition can not be used with encryption, it would not be encrypted."
+                .to_string(),
+        );
+    }
+
+    problems
+}
+
+fn parse_key_value(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .ok_or_else(|| format!("{s} is not in KEY=VALUE form"))
+}
+
+fn parse_mkfs_args(s: &str) -> Result<(String, String), String> {
+    let (fs_type, args) = s
+        .split_once('=')
+        .ok_or_else(|| format!("{s} is not in FS=ARGS form"))?;
+    shell_words::split(args).map_err(|e| format!("{args} is not quoted properly: {e}"))?;
+
+    Ok((fs_type.to_string(), args.to_string()))
+}
+
+fn start_provision(pc: ProvisionCommand) -> Result<()> {
+    let template = if pc.template.starts_with("http://") || pc.template.starts_with("https://") {
+        network::fetch_text(&pc.template)?
+    } else {
+        std::fs::read_to_string(&pc.template)?
+    };
+
+    let mut values = machine_values();
+    values.extend(pc.values);
+    let config = fill_template(&template, &values)?;
+    let config: InstallConfig = serde_json::from_str(&config)
+        .map_err(|e| anyhow!("Installer could not parse the configuration template: {e}"))?;
+
+    install_from_config(config, &pc.path, &pc.template, pc.ignore_battery)
+}
+
+/// Fill in the partition and swap of a saved configuration and install with it
+fn install_from_config(
+    mut config: InstallConfig,
+    path: &str,
+    source: &str,
+    ignore_battery: bool,
+) -> Result<()> {
+    check_battery(ignore_battery)?;
+    let problems = config_problems(&config);
+    if !problems.is_empty() {
+        for i in &problems {
+            println!("- {i}");
+        }
+        return Err(anyhow!(
+            "{source} has {} problem(s), see above.",
+            problems.len()
+        ));
+    }
+
+    let variant = config.variant.clone().unwrap();
+    let partition = get_partition(path, &variant, false, false)?;
+    if config.use_swap.v.load(Ordering::SeqCst) {
+        let (use_swap, swap_size, is_hibernation) = match *config.swap_size {
+            Some(size) => (true, size, disks::is_enable_hibernation(size)?),
+            None => get_swap(None, &partition, &variant, config.free_space_percent)?,
+        };
+        config.use_swap = Arc::new(AtomicBoolWrapper {
+            v: AtomicBool::new(use_swap),
+        });
+        config.swap_size = Arc::new(Some(swap_size));
+        config.is_hibernation = Arc::new(AtomicBoolWrapper {
+            v: AtomicBool::new(is_hibernation),
+        });
+    }
+    config.partition = Some(Arc::new(partition));
+    if let Some(problem) = config.fstab_source_problem() {
+        return Err(anyhow!(problem));
+    }
+
+    info!(
+        "Installing {} from {}",
+        config.hostname.as_deref().unwrap_or_default(),
+        source
+    );
+
+    run_install(config)
+}
+
+/// An answer file shipped on the boot medium for zero-touch installations
+#[derive(Deserialize)]
+struct AutoConfig {
+    /// Install without asking, otherwise the file is ignored
+    #[serde(default)]
+    auto_confirm: bool,
+    /// Install even if the battery is low
+    #[serde(default)]
+    ignore_battery: bool,
+    /// Set target partition to install AOSC OS to (e.g., /dev/sda1)
+    path: String,
+    #[serde(flatten)]
+    config: InstallConfig,
+}
+
+/// Look for an answer file at the top of the filesystems on the boot medium,
+/// files on other disks are not trusted to erase one
+fn find_auto_config() -> Option<PathBuf> {
+    let buf = std::fs::read("/proc/mounts").ok()?;
+    let mounts = list_mounts(&buf).ok()?.1;
+
+    mounts
+        .iter()
+        .filter(|(device, _)| disks::is_on_live_medium(Path::new(device)))
+        .map(|(_, mount_path)| Path::new(mount_path).join(AUTO_CONFIG_FILE))
+        .find(|x| x.is_file())
+}
+
+fn auto_install_on_cmdline() -> bool {
+    std::fs::read_to_string("/proc/cmdline")
+        .is_ok_and(|x| x.split_whitespace().any(|x| x == AUTO_INSTALL_CMDLINE))
+}
+
+fn start_auto_install(path: &Path) -> Result<()> {
+    let auto_config: AutoConfig = serde_json::from_slice(&std::fs::read(path)?)
+        .map_err(|e| anyhow!("Installer could not parse {}: {e}", path.display()))?;
+    if !auto_config.auto_confirm {
+        info!(
+            "{} is not marked auto_confirm, starting the installer normally",
+            path.display()
+        );
+        tui_main();
+        return Ok(());
+    }
+
+    println!(
+        "Found {}, AOSC OS will be installed to {} in {} seconds.\nALL DATA ON {} WILL BE ERASED! Press Ctrl+C now to cancel.",
+        path.display(),
+        auto_config.path,
+        AUTO_INSTALL_DELAY.as_secs(),
+        auto_config.path
+    );
+    thread::sleep(AUTO_INSTALL_DELAY);
+
+    install_from_config(
+        auto_config.config,
+        &auto_config.path,
+        &path.display().to_string(),
+        auto_config.ignore_battery,
+    )
+}
+
+/// Values describing this machine, for use in configuration templates
+fn machine_values() -> Vec<(String, String)> {
+    let mut values = vec![];
+
+    let serial = [
+        "/sys/class/dmi/id/product_serial",
+        "/sys/firmware/devicetree/base/serial-number",
+    ]
+    .iter()
+    .filter_map(|x| std::fs::read_to_string(x).ok())
+    .map(|x| sanitize_for_hostname(&x))
+    .find(|x| !x.is_empty());
+    if let Some(serial) = serial {
+        values.push(("serial".to_string(), serial));
+    }
+
+    // the first network interface with a real address, in name order
+    let mut interfaces = std::fs::read_dir("/sys/class/net")
+        .map(|x| {
+            x.filter_map(|x| x.ok())
+                .map(|x| x.path())
+                .collect::<Vec<_>>()
+        })
+        .unwrap_or_default();
+    interfaces.sort();
+    let mac = interfaces
+        .iter()
+        .filter(|x| x.file_name().map(|x| x != "lo").unwrap_or(false))
+        .filter_map(|x| std::fs::read_to_string(x.join("address")).ok())
+        .map(|x| x.trim().replace(':', ""))
+        .find(|x| !x.is_empty() && x.chars().any(|c| c != '0'));
+    if let Some(mac) = mac {
+        values.push(("mac".to_string(), mac));
+    }
+
+    values
+}
+
+fn sanitize_for_hostname(s: &str) -> String {
+    s.trim()
+        .to_lowercase()
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '-')
+        .collect()
+}
+
+/// Replace every `{{key}}` in `template`, which is placed in JSON strings, with
+/// the escaped value. Unknown placeholders are an error.
+fn fill_template(template: &str, values: &[(String, String)]) -> Result<String> {
+    let mut res = template.to_string();
+    for (k, v) in values {
+        let escaped = serde_json::to_string(v)?;
+        res = res.replace(&format!("{{{{{k}}}}}"), &escaped[1..escaped.len() - 1]);
+    }
+
+    if let Some(start) = res.find("{{") {
+        let placeholder = res[start..]
+            .split_once("}}")
+            .map(|(x, _)| format!("{x}}}}}"))
+            .unwrap_or_else(|| res[start..].to_string());
+        return Err(anyhow!(
+            "The configuration template uses {placeholder}, but no value has been set for it."
+        ));
+    }
+
+    Ok(res)
+}
+
 #[test]
 fn test() {
-    dbg!(list_tarball().unwrap());
+    dbg!(list_variants(false).unwrap());
+}
+
+#[test]
+fn test_config_problems() {
+    let json = r#"{"variant":null,"partition":null,"mirror":null,"full_name":null,"user":"Root","password":"","root_password":null,"hostname":"aosc","locale":"C.UTF-8","timezone":"UTC","tc":"UTC","use_swap":false,"swap_size":null,"is_hibernation":false}"#;
+    let config: InstallConfig = serde_json::from_str(json).unwrap();
+    let problems = config_problems(&config);
+
+    assert_eq!(
+        problems,
+        vec![
+            "No variant specified.",
+            "No mirror specified.",
+            "username Root is not valid!",
+            "No password specified.",
+        ]
+    );
+}
+
+#[test]
+fn test_fill_template() {
+    let values = vec![
+        ("serial".to_string(), "pc0042".to_string()),
+        (
+            "mirror".to_string(),
+            "https://mirrors.example.org/".to_string(),
+        ),
+    ];
+    let template = r#"{"hostname":"lab-{{serial}}","mirror":{"url":"{{mirror}}"}}"#;
+
+    assert_eq!(
+        fill_template(template, &values).unwrap(),
+        r#"{"hostname":"lab-pc0042","mirror":{"url":"https://mirrors.example.org/"}}"#
+    );
+    assert!(fill_template(r#"{"hostname":"lab-{{mac}}"}"#, &values).is_err());
+    let values = vec![("name".to_string(), r#"Lab "A"\"#.to_string())];
+    assert_eq!(
+        fill_template(r#"{"full_name":"{{name}}"}"#, &values).unwrap(),
+        r#"{"full_name":"Lab \"A\"\\"}"#
+    );
+    assert_eq!(sanitize_for_hostname(" PC 0042_A\n"), "pc0042a");
+}
+
+#[test]
+fn test_auto_config() {
+    let json = r#"{"auto_confirm":true,"path":"/dev/sda1","variant":null,"partition":null,"mirror":null,"full_name":null,"user":"aosc","password":"anthon","root_password":null,"hostname":"aosc","locale":"C.UTF-8","timezone":"UTC","tc":"UTC","use_swap":false,"swap_size":null,"is_hibernation":false}"#;
+    let auto_config: AutoConfig = serde_json::from_str(json).unwrap();
+
+    assert!(auto_config.auto_confirm);
+    assert_eq!(auto_config.path, "/dev/sda1");
+    assert_eq!(auto_config.config.hostname.as_deref(), Some("aosc"));
 }
@@ -0,0 +1,60 @@
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::Duration,
+};
+
+static AUDIO_CUES: AtomicBool = AtomicBool::new(false);
+
+/// Gap between bells of the same cue, so that they can be counted
+const BELL_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Events worth drawing attention to, told apart by the number of bells
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Cue {
+    InputRequired,
+    DownloadFinished,
+    Finished,
+    Failed,
+}
+
+impl Cue {
+    fn bells(self) -> usize {
+        match self {
+            Cue::InputRequired => 1,
+            Cue::DownloadFinished => 2,
+            Cue::Finished => 3,
+            Cue::Failed => 5,
+        }
+    }
+}
+
+pub(crate) fn set_audio_cues(enabled: bool) {
+    AUDIO_CUES.store(enabled, Ordering::SeqCst);
+}
+
+pub(crate) fn audio_cues() -> bool {
+    AUDIO_CUES.load(Ordering::SeqCst)
+}
+
+/// Ring the terminal bell for `cue` if audio cues are enabled, does not block
+pub(crate) fn play(cue: Cue) {
+    if !audio_cues() {
+        return;
+    }
+
+    thread::spawn(move || {
+        // write to the controlling terminal, stdout may be redirected
+        let mut tty = match OpenOptions::new().write(true).open("/dev/tty") {
+            Ok(tty) => tty,
+            Err(_) => return,
+        };
+        for _ in 0..cue.bells() {
+            tty.write_all(b"\x07").ok();
+            tty.flush().ok();
+            thread::sleep(BELL_INTERVAL);
+        }
+    });
+}
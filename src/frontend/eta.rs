@@ -0,0 +1,189 @@
+use std::{
+    collections::HashMap,
+    fs,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+/// Kept next to the saved configuration so that it survives between runs
+const TIMINGS_FILE: &str = "/root/deploykit-timings.json";
+
+/// How long each installation step took on previous runs
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Timings {
+    /// Seconds, by step name
+    #[serde(default)]
+    steps: HashMap<String, f64>,
+    /// Seconds between the end of the download and the end of unpacking
+    #[serde(default)]
+    unpack_secs: Option<f64>,
+}
+
+/// Estimates the time left from previous runs and the progress of this one
+#[derive(Debug, Default)]
+pub(super) struct Eta {
+    history: Timings,
+    recorded: Timings,
+    /// Unpacking time estimated from the write speed of the target, for
+    /// the first run
+    estimated_unpack_secs: Option<f64>,
+    upcoming: Vec<&'static str>,
+    current: Option<(&'static str, Instant)>,
+    /// Steps of this run that have finished, in order
+    finished: Vec<(&'static str, Duration)>,
+}
+
+impl Eta {
+    pub fn load() -> Self {
+        let history = fs::read(TIMINGS_FILE)
+            .ok()
+            .and_then(|x| serde_json::from_slice(&x).ok())
+            .unwrap_or_default();
+
+        Eta {
+            history,
+            ..Default::default()
+        }
+    }
+
+    /// Steps that are going to run, in order
+    pub fn plan(&mut self, steps: Vec<&'static str>) {
+        self.upcoming = steps;
+    }
+
+    pub fn start(&mut self, step: &'static str) {
+        self.upcoming.retain(|x| *x != step);
+        self.current = Some((step, Instant::now()));
+    }
+
+    pub fn finish(&mut self) {
+        if let Some((step, started)) = self.current.take() {
+            let elapsed = started.elapsed();
+            self.recorded
+                .steps
+                .insert(step.to_string(), elapsed.as_secs_f64());
+            self.finished.push((step, elapsed));
+        }
+    }
+
+    /// How long each finished step of this run took
+    pub fn finished(&self) -> &[(&'static str, Duration)] {
+        &self.finished
+    }
+
+    pub fn set_unpack_secs(&mut self, secs: f64) {
+        self.recorded.unpack_secs = Some(secs);
+    }
+
+    pub fn unpack_secs(&self) -> Option<f64> {
+        self.history.unpack_secs.or(self.estimated_unpack_secs)
+    }
+
+    pub fn estimate_unpack_secs(&mut self, secs: f64) {
+        self.estimated_unpack_secs = Some(secs);
+    }
+
+    /// Time left for the whole installation. `current_left` overrides the
+    /// estimate for the current step, for steps that can measure their own
+    /// throughput. Returns `None` until every remaining step has been timed.
+    pub fn remaining(&self, current_left: Option<f64>) -> Option<Duration> {
+        let current = match (current_left, &self.current) {
+            (Some(secs), _) => secs,
+            (None, Some((step, started))) => {
+                let expected = self.history.steps.get(*step)?;
+                (expected - started.elapsed().as_secs_f64()).max(0.0)
+            }
+            (None, None) => 0.0,
+        };
+        let upcoming = self
+            .upcoming
+            .iter()
+            .map(|x| self.history.steps.get(*x).copied())
+            .sum::<Option<f64>>()?;
+
+        Some(Duration::from_secs_f64(current + upcoming))
+    }
+
+    /// Append the time left to a progress message, if it is known
+    pub fn describe(&self, msg: &str, current_left: Option<f64>) -> String {
+        match self.remaining(current_left) {
+            Some(eta) => format!("{msg} ({})", format_remaining(eta)),
+            None => msg.to_string(),
+        }
+    }
+
+    /// Merge the timings of this run into the history, smoothing out
+    /// one-off slow or fast runs
+    pub fn save(&self) -> Result<()> {
+        let mut timings = Timings {
+            steps: self.history.steps.clone(),
+            unpack_secs: self.history.unpack_secs,
+        };
+        for (step, secs) in &self.recorded.steps {
+            let secs = match timings.steps.get(step) {
+                Some(old) => (old + secs) / 2.0,
+                None => *secs,
+            };
+            timings.steps.insert(step.clone(), secs);
+        }
+        if let Some(secs) = self.recorded.unpack_secs {
+            timings.unpack_secs = Some(match timings.unpack_secs {
+                Some(old) => (old + secs) / 2.0,
+                None => secs,
+            });
+        }
+
+        fs::write(TIMINGS_FILE, serde_json::to_string(&timings)?)?;
+        info!("Saved installation timings to {TIMINGS_FILE}");
+
+        Ok(())
+    }
+
+    pub fn save_or_warn(&self) {
+        if let Err(e) = self.save() {
+            warn!("Could not save installation timings: {e}");
+        }
+    }
+}
+
+fn format_remaining(eta: Duration) -> String {
+    let minutes = (eta.as_secs_f64() / 60.0).round() as u64;
+    match minutes {
+        0 => "less than a minute remaining".to_string(),
+        1 => "about 1 minute remaining".to_string(),
+        x => format!("about {x} minutes remaining"),
+    }
+}
+
+#[test]
+fn test_eta_remaining() {
+    let mut eta = Eta::default();
+    eta.history.steps.insert("format".to_string(), 30.0);
+    eta.history.steps.insert("download".to_string(), 600.0);
+    eta.plan(vec!["format", "download", "grub"]);
+
+    // grub has never been timed
+    eta.start("format");
+    assert!(eta.remaining(None).is_none());
+
+    eta.history.steps.insert("grub".to_string(), 60.0);
+    let remaining = eta.remaining(Some(10.0)).unwrap();
+    assert_eq!(remaining, Duration::from_secs(670));
+    assert_eq!(
+        eta.describe("Formatting", Some(10.0)),
+        "Formatting (about 11 minutes remaining)"
+    );
+
+    eta.finish();
+    eta.start("download");
+    assert_eq!(eta.remaining(Some(20.0)), Some(Duration::from_secs(80)));
+    assert!(eta.recorded.steps.contains_key("format"));
+    assert_eq!(eta.finished()[0].0, "format");
+    assert_eq!(
+        format_remaining(Duration::from_secs(20)),
+        "less than a minute remaining"
+    );
+}
@@ -1,38 +1,49 @@
 use std::{
-    convert::TryInto,
+    fs,
     io::Write,
     path::{Path, PathBuf},
+    process::Command,
     sync::{
         atomic::{AtomicBool, Ordering},
-        mpsc::{self, Sender},
-        Arc,
+        mpsc::Sender,
+        Arc, Mutex,
     },
     thread,
+    time::Instant,
 };
 
 use crate::{
-    disks,
-    install::{self, log_system_info},
-    network, DEPLOYKIT_USER_AGENT, LOG_FILE,
+    crypt, disks,
+    install::{self, log_system_info, umount_all},
+    network, zfs, LOG_FILE,
 };
-use anyhow::{anyhow, Result};
-use cursive::utils::Counter;
-use log::info;
-// use nix::fcntl::FallocateFlags;
-use rand::{thread_rng, Rng};
-use rustix::{fd::AsFd, fs::FallocateFlags};
+use anyhow::Result;
+use log::{error, info};
+use once_cell::sync::Lazy;
+use rustix::fd::OwnedFd;
 use serde::{de::Visitor, Deserialize, Serialize};
 use std::sync::atomic;
 
 mod cli;
+mod cues;
+mod eta;
 mod games;
+mod preview;
+mod report;
+mod steps;
+mod test_mode;
 mod tui;
 
 pub use cli::*;
-use sha2::{Digest, Sha256};
+use steps::{install_steps, run_steps, InstallContext};
 pub use tui::tui_main;
 
-pub const DEFAULT_EMPTY_SIZE: u64 = 5 * 1024 * 1024 * 1024;
+/// Share of the system partition kept free after installing and creating
+/// the swapfile, in percent
+pub const DEFAULT_FREE_SPACE_PERCENT: u8 = 5;
+/// Space kept free on tiny partitions, whatever the percentage
+const MIN_FREE_SPACE: u64 = 1024 * 1024 * 1024;
+const LAST_USER_CONFIG_FILE: &str = "/tmp/deploykit-config.json";
 
 const STEP1: &str = "Step 1 of 8: Formatting partitions";
 const STEP2: &str = "Step 2 of 8: Downloading system release";
@@ -48,17 +59,43 @@ pub(crate) enum InstallProgress {
     Finished,
 }
 
-macro_rules! send_error {
-    ($error_channel_tx_copy:ident, $e:ident) => {
-        $error_channel_tx_copy.send($e.to_string()).unwrap();
-        return;
-    };
-}
-
 #[derive(Debug, Clone, Serialize, Deserialize)]
 struct InstallConfig {
     variant: Option<Arc<network::VariantEntry>>,
     partition: Option<Arc<disks::Partition>>,
+    /// EFI system partition picked by the user, the first one on the
+    /// disk of `partition` is used if unset
+    #[serde(default)]
+    esp: Option<Arc<disks::Partition>>,
+    /// Existing partition mounted as /home without formatting
+    #[serde(default)]
+    home: Option<Arc<disks::Partition>>,
+    /// Swap to a partition instead of a swapfile
+    #[serde(default)]
+    swap_partition: Option<disks::SwapPartition>,
+    /// Other partitions mounted into the installed system, e.g. /var
+    #[serde(default)]
+    extra_mounts: Vec<disks::ExtraMount>,
+    /// Second device of a btrfs RAID1 system partition, formatted as well
+    #[serde(default)]
+    btrfs_mirror: Option<Arc<disks::Partition>>,
+    /// Create an ESP on the disk of `partition`, as none exists
+    #[serde(default)]
+    create_esp: bool,
+    /// Create a BIOS boot partition on the GPT disk of `partition`, for GRUB
+    #[serde(default)]
+    create_bios_grub: bool,
+    /// Move the start of `partition` to a 1MiB boundary before formatting
+    #[serde(default)]
+    realign_partition: bool,
+    /// Install onto the existing filesystem of `partition` without
+    /// formatting it, e.g. to keep /home on the same partition
+    #[serde(default)]
+    keep_data: bool,
+    /// Disk image attached as the disk of `partition`, which is booted on
+    /// another machine, so no boot entry is created on this one
+    #[serde(default)]
+    image: Option<PathBuf>,
     mirror: Option<Arc<network::Mirror>>,
     full_name: Option<Arc<String>>,
     user: Option<Arc<String>>,
@@ -71,6 +108,55 @@ struct InstallConfig {
     use_swap: Arc<AtomicBoolWrapper>,
     swap_size: Arc<Option<f64>>,
     is_hibernation: Arc<AtomicBoolWrapper>,
+    /// Swap to compressed memory instead of a swapfile
+    #[serde(default)]
+    zram: bool,
+    /// Share of the system partition kept free, in percent,
+    /// `DEFAULT_FREE_SPACE_PERCENT` if unset
+    #[serde(default)]
+    free_space_percent: Option<u8>,
+    #[serde(default)]
+    power_management: Option<install::PowerManagement>,
+    #[serde(default)]
+    optenv32: bool,
+    #[serde(default)]
+    directory: Option<install::DirectoryConfig>,
+    /// Fixed address instead of DHCP through NetworkManager
+    #[serde(default)]
+    static_network: Option<install::StaticNetworkConfig>,
+    /// Do not carry the Wi-Fi networks, DNS settings and the proxy of the live
+    /// session over
+    #[serde(default)]
+    skip_live_network: bool,
+    /// Download the release with BitTorrent where it has a torrent
+    #[serde(default)]
+    use_torrent: bool,
+    /// Install a copy of the running live system instead of a release
+    #[serde(default)]
+    from_live: bool,
+    #[serde(default)]
+    mount_options: disks::MountOptions,
+    /// Used when formatting partitions as XFS
+    #[serde(default)]
+    xfs_options: disks::XfsOptions,
+    #[serde(default)]
+    expert: install::ExpertOptions,
+    #[serde(default)]
+    bootloader: install::Bootloader,
+    #[serde(default)]
+    esp_mount_point: install::EspMountPoint,
+    /// How fstab refers to the partitions of the installed system
+    #[serde(default)]
+    fstab_source: disks::FstabSource,
+    /// Take a read-only snapshot of the system after installation, on btrfs
+    #[serde(default)]
+    btrfs_snapshot: bool,
+    /// Set up LVM on LUKS on `partition`
+    #[serde(default)]
+    encryption: Option<crypt::EncryptionConfig>,
+    /// Also save the installation report here, e.g. on the live medium
+    #[serde(default)]
+    report_copy: Option<PathBuf>,
 }
 
 impl Default for InstallConfig {
@@ -78,6 +164,16 @@ impl Default for InstallConfig {
         InstallConfig {
             variant: None,
             partition: None,
+            esp: None,
+            home: None,
+            create_esp: false,
+            create_bios_grub: false,
+            realign_partition: false,
+            keep_data: false,
+            image: None,
+            swap_partition: None,
+            extra_mounts: vec![],
+            btrfs_mirror: None,
             mirror: None,
             full_name: None,
             user: None,
@@ -94,10 +190,293 @@ impl Default for InstallConfig {
                 v: AtomicBool::new(false),
             }),
             root_password: None,
+            power_management: None,
+            zram: false,
+            free_space_percent: None,
+            optenv32: false,
+            directory: None,
+            static_network: None,
+            skip_live_network: false,
+            use_torrent: false,
+            from_live: false,
+            mount_options: disks::MountOptions::default(),
+            xfs_options: disks::XfsOptions::default(),
+            expert: install::ExpertOptions::default(),
+            bootloader: install::Bootloader::default(),
+            esp_mount_point: install::EspMountPoint::default(),
+            fstab_source: disks::FstabSource::default(),
+            btrfs_snapshot: false,
+            encryption: None,
+            report_copy: None,
+        }
+    }
+}
+
+impl InstallConfig {
+    /// Commands the installation with this configuration runs on the host,
+    /// zsync is left out as delta downloads are skipped without it
+    fn required_tools(&self) -> Vec<String> {
+        let mut tools = vec![];
+        if let Some(partition) = self.partition.as_deref() {
+            if zfs::is_zfs(partition) {
+                tools.extend(["zpool", "zfs"].map(String::from));
+            } else if !self.keep_data {
+                tools.push(format!("mkfs.{}", disks::mkfs_fs_type(partition)));
+            }
+            if install::is_btrfs(partition) {
+                tools.push("btrfs".to_string());
+            }
+            if partition.path.as_deref().is_some_and(disks::is_raid) {
+                tools.push("mdadm".to_string());
+            }
+        }
+        for mount in self.extra_mounts.iter().filter(|x| x.format) {
+            tools.push(format!("mkfs.{}", disks::mkfs_fs_type(&mount.partition)));
+        }
+        // An ESP found on the disk at install time may still need formatting
+        let esp_formatted = self.esp.as_ref().is_some_and(|x| x.fs_type.is_some());
+        if disks::is_efi_booted() && !esp_formatted {
+            tools.push("mkfs.vfat".to_string());
+        }
+        if self.encryption.is_some() {
+            tools.extend(
+                ["cryptsetup", "pvcreate", "vgcreate", "lvcreate", "vgchange"].map(String::from),
+            );
+        }
+        if self.image.is_some() {
+            tools.push("losetup".to_string());
+        }
+        if self.from_live {
+            tools.push("rsync".to_string());
+        } else if self
+            .variant
+            .as_ref()
+            .is_some_and(|x| x.url.ends_with(".squashfs"))
+        {
+            tools.push("unsquashfs".to_string());
+        }
+        if self.use_torrent {
+            tools.push("aria2c".to_string());
+        }
+        tools.sort();
+        tools.dedup();
+
+        tools
+    }
+
+    /// Why the partitions can not be referred to by `fstab_source`, if so
+    fn fstab_source_problem(&self) -> Option<String> {
+        match self.fstab_source {
+            disks::FstabSource::Uuid => return None,
+            disks::FstabSource::Label => return self.fstab_label_problem(),
+            disks::FstabSource::PartUuid => (),
+        }
+        if self.encryption.is_some() {
+            return Some("An encrypted system can not be referred to by PARTUUID in fstab, please use UUID instead.".to_string());
+        }
+        let swap = match &self.swap_partition {
+            Some(disks::SwapPartition::Existing(x)) => Some(x),
+            _ => None,
+        };
+        let partitions = [
+            self.partition.as_deref(),
+            self.esp.as_deref(),
+            self.home.as_deref(),
+            swap,
+        ];
+        let paths = partitions
+            .into_iter()
+            .flatten()
+            .chain(self.extra_mounts.iter().map(|x| &x.partition))
+            .filter_map(|x| x.path.as_deref());
+        for path in paths {
+            // LUKS, LVM and RAID volumes are not partitions of a disk
+            if disks::partition_partuuid(path).is_none() {
+                return Some(format!(
+                    "{} has no PARTUUID, please refer to partitions by UUID in fstab instead.",
+                    path.display()
+                ));
+            }
+        }
+
+        None
+    }
+
+    /// Why the partitions can not be referred to by label, if so. Those the
+    /// installer formats get a label that no other partition may have, the
+    /// others must have one already.
+    fn fstab_label_problem(&self) -> Option<String> {
+        let root = self.partition.as_deref()?;
+        let mut labelled = vec![(
+            disks::SYSTEM_LABEL.to_string(),
+            root.path.as_deref(),
+            !self.keep_data,
+        )];
+        // mkswap labels existing swap partitions too
+        match &self.swap_partition {
+            Some(disks::SwapPartition::Existing(x)) => {
+                labelled.push((disks::SWAP_LABEL.to_string(), x.path.as_deref(), true))
+            }
+            Some(disks::SwapPartition::New { .. }) => {
+                labelled.push((disks::SWAP_LABEL.to_string(), None, true))
+            }
+            None => (),
+        }
+        if let Some(esp) = self.esp.as_deref() {
+            labelled.push((
+                disks::ESP_LABEL.to_string(),
+                esp.path.as_deref(),
+                esp.fs_type.is_none(),
+            ));
+        } else if self.create_esp {
+            labelled.push((disks::ESP_LABEL.to_string(), None, true));
+        }
+        if let Some(home) = &self.home {
+            labelled.push((String::new(), home.path.as_deref(), false));
+        }
+        for mount in &self.extra_mounts {
+            let label = if mount.format {
+                let Some(label) = disks::mount_point_label(&mount.mount_point) else {
+                    return Some(format!(
+                        "Installer can not make a filesystem label for {}, please refer to partitions by UUID in fstab instead.",
+                        mount.mount_point.display()
+                    ));
+                };
+                label
+            } else {
+                String::new()
+            };
+            labelled.push((label, mount.partition.path.as_deref(), mount.format));
+        }
+
+        for (i, (label, path, formatted)) in labelled.iter().enumerate() {
+            if !formatted {
+                if let Some(path) = path.filter(|x| disks::partition_label(x).is_none()) {
+                    return Some(format!(
+                        "{} has no filesystem label, please label it or refer to partitions by UUID in fstab instead.",
+                        path.display()
+                    ));
+                }
+                continue;
+            }
+            if labelled[..i].iter().any(|(x, _, _)| x == label) || disks::label_taken(label, *path)
+            {
+                return Some(format!(
+                    "Another partition is already labelled {label}, please refer to partitions by UUID in fstab instead."
+                ));
+            }
         }
+
+        None
+    }
+
+    /// Whether `path` is already used by the installed system
+    fn uses_partition(&self, path: &Path) -> bool {
+        let path = Some(path);
+        self.partition.as_ref().map(|x| x.path.as_deref()) == Some(path)
+            || self.esp.as_ref().map(|x| x.path.as_deref()) == Some(path)
+            || self.home.as_ref().map(|x| x.path.as_deref()) == Some(path)
+            || self.btrfs_mirror.as_ref().map(|x| x.path.as_deref()) == Some(path)
+            || matches!(&self.swap_partition, Some(disks::SwapPartition::Existing(x)) if x.path.as_deref() == path)
+            || self
+                .extra_mounts
+                .iter()
+                .any(|x| x.partition.path.as_deref() == path)
     }
 }
 
+/// Space to keep free on a system partition of `size` bytes, `percent` of
+/// it or `DEFAULT_FREE_SPACE_PERCENT` if unset
+fn free_space_margin(size: u64, percent: Option<u8>) -> u64 {
+    let percent = percent.unwrap_or(DEFAULT_FREE_SPACE_PERCENT) as u64;
+
+    (size / 100 * percent).max(MIN_FREE_SPACE)
+}
+
+/// Space an installation of `variant` needs on a system partition of `size`
+/// bytes formatted as `fs_type`: the unpacked system, the tarball downloaded
+/// next to it unless it is unpacked while downloading, `swap_size` bytes of
+/// swapfile created before both, and what the filesystem itself takes up
+fn required_space(
+    variant: &network::VariantEntry,
+    fs_type: Option<&str>,
+    size: u64,
+    swap_size: u64,
+) -> u64 {
+    let overhead = size / 100 * disks::fs_overhead_percent(fs_type);
+    let tarball = if install::is_streamable(&variant.url) {
+        0
+    } else {
+        variant.size
+    };
+
+    variant.install_size + tarball + swap_size + overhead
+}
+
+/// Everything the panic hook needs to clean up after a crashed installation
+struct CrashState {
+    config: InstallConfig,
+    tempdir: PathBuf,
+    root_fd: OwnedFd,
+}
+
+static CRASH_STATE: Lazy<Mutex<Option<CrashState>>> = Lazy::new(|| Mutex::new(None));
+static TUI_ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Install a panic hook that unmounts everything the installer has mounted,
+/// saves the current configuration and restores the terminal before printing
+/// the panic message.
+pub fn setup_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        // cursive owns the terminal from the main thread, other threads
+        // report back to it instead
+        let is_main_thread = thread::current().name() == Some("main");
+        if is_main_thread && TUI_ACTIVE.load(Ordering::SeqCst) {
+            restore_terminal();
+        }
+
+        default_hook(info);
+
+        let state = CRASH_STATE.try_lock().ok().and_then(|mut x| x.take());
+        if let Some(state) = state {
+            error!("Installer panicked: {info}");
+            umount_all(&state.tempdir, state.root_fd);
+            if save_user_config_to_file(state.config, LAST_USER_CONFIG_FILE).is_ok() {
+                eprintln!("\nYour installation configuration has been saved to {LAST_USER_CONFIG_FILE}, restart the installer to try again.");
+            }
+        }
+
+        if let Some(log) = LOG_FILE.get() {
+            eprintln!(
+                "Installer has crashed unexpectedly. Log file is saved to {}, please attach it when reporting this issue.",
+                log.display()
+            );
+        }
+    }));
+}
+
+fn restore_terminal() {
+    // leave the alternate screen and show the cursor again
+    print!("\x1b[?1049l\x1b[?25h");
+    std::io::stdout().flush().ok();
+    Command::new("stty").arg("sane").status().ok();
+}
+
+fn save_user_config_to_file(config: InstallConfig, path: &str) -> Result<()> {
+    let mut config_copy = config;
+    config_copy.partition = None;
+    config_copy.esp = None;
+    config_copy.home = None;
+    config_copy.extra_mounts.clear();
+    config_copy.encryption = None;
+    let file_str = serde_json::to_string(&config_copy)?;
+    fs::File::create(LAST_USER_CONFIG_FILE)?;
+    fs::write(path, file_str)?;
+
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct AtomicBoolWrapper {
     v: AtomicBool,
@@ -151,417 +530,48 @@ fn begin_install(
     config: InstallConfig,
     tempdir: PathBuf,
 ) -> Result<()> {
-    log_system_info();
-
-    info!("Prepare trying unmount before deploykit mount partition ...");
-    install::prepare_try_umount()?;
-
-    let refresh_interval = std::time::Duration::from_millis(30);
-    let counter = Counter::new(0);
-    let url;
-    let file_size: usize;
-    let right_sha256;
-    let extract_done: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
-    let download_done: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
-
-    sender.send(InstallProgress::Pending(STEP1.to_string(), 0))?;
-    info!("{}", STEP1);
-
-    let partition = &config.partition.unwrap();
-
-    info!("Formatting partitions: {:?}", partition);
-    disks::format_partition(partition)?;
-
-    info!("Mounting partitions: {:?}", partition);
-    let mount_path = install::auto_mount_root_path(&tempdir, partition)?;
-    let mount_path_copy = mount_path.clone();
-    let mut efi_path = mount_path.clone();
-    if disks::is_efi_booted() {
-        efi_path.push("efi");
-
-        info!("Finding ESP partition from: {:?}", partition.parent_path);
-        let mut esp_part = disks::find_esp_partition(partition.parent_path.as_ref().unwrap())?;
-        info!("ESP is: {:?}", esp_part);
-
-        std::fs::create_dir_all(&efi_path).unwrap();
-        if esp_part.fs_type.is_none() {
-            // format the un-formatted ESP partition
-            esp_part.fs_type = Some("vfat".to_string());
-
-            info!("Formatting ESP partition: {:?}", esp_part);
-            disks::format_partition(&esp_part)?;
-        }
-        install::mount_root_path(&esp_part, &efi_path)?;
-    }
-    if let Some(variant) = config.variant.as_ref() {
-        let mirror_url = &config.mirror.as_ref().unwrap().url;
-        file_size = variant.size.try_into().unwrap();
-        url = format!("{}{}", mirror_url, variant.url);
-        right_sha256 = variant.sha256sum.clone();
-
-        info!(
-            "Mirror URL is: {}, file_size: {}, url: {}, right_sha256: {}",
-            mirror_url, file_size, url, right_sha256
-        );
-    } else {
-        return Err(anyhow!(
-            "Installer could not parse release metadata: `variant` field not found."
-        ));
-    }
-
-    let use_swap = config.use_swap.v.load(Ordering::SeqCst);
-    if use_swap {
-        if let Some(swap_size) = config.swap_size.as_ref() {
-            info!("Creating swapfile and trying swapon swapfile ...");
-            install::create_swapfile(*swap_size, use_swap, &tempdir)?;
-        }
-    }
-
-    let extract_done_copy = extract_done.clone();
-    let download_done_copy = download_done.clone();
-    let (sha256_work_tx, sha256_work_rx) = mpsc::channel();
-    let (get_sha256_tx, get_sha256_rx) = mpsc::channel();
-    let (error_channel_tx, error_channel_rx) = mpsc::channel();
-    let error_channel_tx_copy = error_channel_tx.clone();
-
-    let (speed_tx, speed_rx) = std::sync::mpsc::channel();
-
-    let cc = counter.clone();
-
-    let worker = thread::spawn(move || {
-        let mut tarball_file = mount_path.clone();
-        tarball_file.push("tarball");
-        let mut output = match std::fs::File::create(tarball_file.clone()) {
-            Ok(file) => {
-                info!("tarball file: {:?} is created", tarball_file);
-
-                file
-            }
-            Err(e) => {
-                send_error!(error_channel_tx_copy, e);
-            }
-        };
-
-        let runtime = match tokio::runtime::Builder::new_multi_thread()
-            .enable_all()
-            .worker_threads(2)
-            .build()
-        {
-            Ok(rt) => rt,
-            Err(e) => {
-                let e = anyhow!("Failed to create tokio runtime: {e}");
-                send_error!(error_channel_tx_copy, e);
-            }
-        };
-
-        let client = match reqwest::Client::builder()
-            .user_agent(DEPLOYKIT_USER_AGENT!())
-            .build()
-        {
-            Ok(c) => c,
-            Err(e) => {
-                let e = anyhow!("Failed to create reqwest client: {e}");
-                send_error!(error_channel_tx_copy, e);
-            }
-        };
-
-        let urlc = url.clone();
-
-        let tbl_file_c = tarball_file.clone();
-
-        let ccc = cc.clone();
-
-        let error_channel_tx_copy_copy = error_channel_tx_copy.clone();
-
-        runtime.block_on(async move {
-            let mut resp = match client.get(urlc).send().await.and_then(|x| x.error_for_status()) {
-                Ok(resp) => resp,
-                Err(e) => {
-                    send_error!(error_channel_tx_copy, e);
-                }
-            };
-
-            info!("Allocating tarball file: {:?}", &tbl_file_c);
-            if let Err(e) = rustix::fs::fallocate(
-                output.as_fd(),
-                FallocateFlags::empty(),
-                0,
-                file_size.try_into().unwrap(),
-            ) {
-                let e = anyhow!(
-                    "Installer failed to create temporary file for the download process:\n\n{}",
-                    e
-                );
-                send_error!(error_channel_tx_copy, e);
-            }
-
-            info!("Flushing tarball_file: {:?}", &tbl_file_c);
-            if let Err(e) = output.flush() {
-                let e = anyhow!("Installer failed to save system release:\n\n{}\n\nPlease restart your installation environment.", e);
-                send_error!(error_channel_tx_copy, e);
-            }
-
-            let mut tarball_size = 0;
-
-            let mut timer = tokio::time::Instant::now();
-            let mut tarball_size_1s = 0;
-
-            loop {
-                if tarball_size == file_size {
-                    info!("Download complete");
-                    download_done_copy.fetch_or(true, Ordering::SeqCst);
-                    break;
-                }
-                match resp.chunk().await {
-                    Ok(v) => {
-                        if let Some(chunk) = v {
-                            let now = timer.elapsed().as_secs_f64();
-                            if now >= 1.0 {
-                                let speed = tarball_size_1s as f64 / 1024.0 / now;
-                                let eta = (file_size - tarball_size) as f64 / 1024.0 / speed;
-                                let (s, s2) = calc_speed(speed, eta);
-                                speed_tx.send((s, s2)).unwrap();
-                                tarball_size_1s = 0;
-                                timer = tokio::time::Instant::now();
-                            } else {
-                                tarball_size_1s += chunk.len();
-                            }
-
-                            if let Err(e) = output.write_all(&chunk) {
-                                send_error!(error_channel_tx_copy, e);
-                            }
-                            tarball_size += chunk.len();
-                            cc.set(tarball_size);
-                            sha256_work_tx.send((chunk.to_vec(), chunk.len())).unwrap();
-                        } else {
-                            let e = "The remote closed the connection prematurely.".to_string();
-                            send_error!(error_channel_tx_copy, e);
-                        }
-                    }
-                    Err(e) => {
-                         send_error!(error_channel_tx_copy, e);
-                    }
-                }
-                if cfg!(feature = "is_retro") {
-                    // 确保在 Retro 设备上不会因为网速过快，数据来不及写入硬盘导致的 OOM
-                    if let Err(e) = output.flush() {
-                        send_error!(error_channel_tx_copy, e);
-                    }
-                }
-            }
+    let root_fd = install::get_dir_fd(Path::new("/"))?;
+    if let Ok(mut state) = CRASH_STATE.lock() {
+        *state = Some(CrashState {
+            config: config.clone(),
+            tempdir: tempdir.clone(),
+            root_fd,
         });
-
-        info!("Trying extract tarball file: {:?}", &tarball_file);
-
-        ccc.set(0);
-
-        if let Err(e) =
-            install::extract_file(file_size as f64, url, &tarball_file, &mount_path, ccc)
-        {
-            let e = anyhow!("Installer failed to unpack system release:\n\n{}", e);
-            send_error!(error_channel_tx_copy_copy, e);
-        }
-
-        extract_done_copy.fetch_or(true, Ordering::SeqCst);
-
-        info!("Trying remove tarball file: {:?}", tarball_file);
-        std::fs::remove_file(tarball_file).ok();
-    });
-
-    let sha256sum_work = thread::spawn(move || {
-        let mut hasher = Sha256::new();
-        loop {
-            let rx = if let Ok(result) = sha256_work_rx.recv() {
-                result
-            } else {
-                // dbg!("sha256sum complete");
-                get_sha256_tx.send(hasher).unwrap();
-                return;
-            };
-            let (buf, reader_size) = rx;
-            if let Err(e) = hasher.write_all(&buf[..reader_size]) {
-                let e = anyhow!(
-                    "Installer failed to calculate checksum for system release:\n\n{}",
-                    e
-                );
-                send_error!(error_channel_tx, e);
-            }
-        }
-    });
-
-    let file_size = file_size as f64;
-    // Progress update
-    info!("{}", STEP2);
-    loop {
-        // let counter_clone = counter.clone();
-        let tarball_downloaded_size = counter.get() as f64;
-        let count = (tarball_downloaded_size / file_size * 100.0) as usize;
-        if let Ok(err) = error_channel_rx.try_recv() {
-            return Err(anyhow!(err));
-        }
-        let v = speed_rx.recv().ok();
-
-        let msg = if let Some((speed, eta)) = v {
-            format!("{STEP2} ({speed}, {eta})")
-        } else {
-            STEP2.to_string()
-        };
-
-        sender.send(InstallProgress::Pending(msg, count))?;
-        std::thread::sleep(refresh_interval);
-        if download_done.load(Ordering::SeqCst) {
-            break;
-        }
-    }
-    let mut fake_counter = 0;
-
-    info!("{}", STEP3);
-    loop {
-        sender.send(InstallProgress::Pending(STEP3.to_string(), fake_counter))?;
-        std::thread::sleep(refresh_interval);
-        if let Ok(hasher) = get_sha256_rx.try_recv() {
-            let final_hash = hex::encode(hasher.finalize());
-            if final_hash != right_sha256 {
-                return Err(anyhow!(
-                    "Installer detected a checksum mismatch in downloaded system release.\n\nExpected hash: {}\n\nCalculated hash: {}",
-                    right_sha256,
-                    final_hash
-                ));
-            }
-            break;
-        }
-        fake_counter += 1;
-        if fake_counter == 100 {
-            fake_counter = 0;
-        }
-    }
-
-    info!("{}", STEP4);
-    loop {
-        let tarball_unpack_size = counter.get() as f64;
-        let count = (tarball_unpack_size / file_size * 100.0) as usize;
-        sender.send(InstallProgress::Pending(STEP4.to_string(), count))?;
-        std::thread::sleep(refresh_interval);
-        if extract_done.load(Ordering::SeqCst) {
-            break;
-        }
     }
 
-    // GC the worker thread
-    worker.join().unwrap();
-    sha256sum_work.join().unwrap();
-    // genfstab to file
-    info!("Generating fstab ...");
-    install::genfstab_to_file(partition, &tempdir, Path::new("/"))?;
-
-    if disks::is_efi_booted() {
-        info!("Generating fstab efi entry...");
-        let esp_part = disks::find_esp_partition(partition.parent_path.as_ref().unwrap())?;
-        install::genfstab_to_file(&esp_part, &tempdir, Path::new("/efi"))?;
+    let started = Instant::now();
+    let res = install_inner(sender, config, tempdir);
+    if test_mode::is_enabled() {
+        test_mode::write_result(&res, started);
     }
-    let mut rng = thread_rng();
-    let fake_counter: usize = rng.gen_range(0..100);
-
-    sender.send(InstallProgress::Pending(STEP5.to_string(), fake_counter))?;
-    info!("{}", STEP5);
-
-    info!("Chroot to installed system ...");
-    let escape_vector = install::get_dir_fd(Path::new("/"))?;
-    install::dive_into_guest(&mount_path_copy)?;
-
-    info!("Running dracut ...");
-    install::execute_dracut()?;
-
-    let fake_counter: usize = rng.gen_range(0..100);
-    sender.send(InstallProgress::Pending(STEP6.to_string(), fake_counter))?;
-    info!("{}", STEP6);
-
-    if disks::is_efi_booted() {
-        info!("Installing grub to UEFI partition ...");
-        install::execute_grub_install(None)?;
+    cues::play(if res.is_ok() {
+        cues::Cue::Finished
     } else {
-        info!("Installing grub to MBR partition ...");
-        install::execute_grub_install(Some(partition.parent_path.as_ref().unwrap()))?;
-    };
-
-    let fake_counter: usize = rng.gen_range(0..100);
-    sender.send(InstallProgress::Pending(STEP7.to_string(), fake_counter))?;
-    info!("{}", STEP7);
-
-    info!("Generating SSH key ...");
-    install::gen_ssh_key()?;
-
-    info!("{}", STEP8);
-    let fake_counter: usize = rng.gen_range(0..100);
-    sender.send(InstallProgress::Pending(STEP8.to_string(), fake_counter))?;
-
-    if use_swap {
-        info!("Generating swapfile entry to fstab");
-        install::write_swap_entry_to_fstab()?;
-    }
-
-    let tz = config.timezone.unwrap();
-    info!("Setting timezone as {}", &tz);
-    install::set_zoneinfo(&tz)?;
-
-    let tc = config.tc.unwrap();
-    info!("Setting hwclock (hardware clock) as {}", &tc);
-    install::set_hwclock_tc(match tc.as_str() {
-        "UTC" => true,
-        "RTC" => false,
-        _ => true,
-    })?;
-
-    let hostname = config.hostname.unwrap();
-    info!("Setting hostname as {}", &hostname);
-    install::set_hostname(&hostname)?;
-
-    info!("Setting username and password ...");
-
-    install::add_new_user(&config.user.clone().unwrap(), &config.password.unwrap())?;
-
-    if cfg!(feature = "is_retro") {
-        install::chpasswd("root", &config.root_password.unwrap())?;
-    }
-
-    info!("Setting fullname ...");
-    if config.full_name.is_some() && config.full_name != Some("".to_string().into()) {
-        install::passwd_set_fullname(&config.full_name.unwrap(), &config.user.unwrap())?;
-    }
-
-    let locale = config.locale.as_ref().unwrap();
-    info!("Setting locale as {}", locale);
-    install::set_locale(locale)?;
-
-    info!("Escaping chroot ...");
-    install::escape_chroot(escape_vector)?;
+        cues::Cue::Failed
+    });
 
-    if disks::is_efi_booted() {
-        info!("Unmounting EFI partition ...");
-        install::umount_root_path(&efi_path)?;
+    if let Ok(mut state) = CRASH_STATE.lock() {
+        state.take();
     }
 
-    info!("Copy log file to main partition");
-    let logfile = LOG_FILE.get().unwrap();
-    std::fs::copy(
-        logfile,
-        tempdir.join("var").join("log").join(
-            logfile
-                .file_name()
-                .ok_or_else(|| anyhow!("Can not get filename"))?,
-        ),
-    )?;
+    res
+}
 
-    info!("Removing bind mounts ...");
-    install::remove_bind_mounts(&mount_path_copy)?;
+fn install_inner(
+    sender: Sender<InstallProgress>,
+    config: InstallConfig,
+    tempdir: PathBuf,
+) -> Result<()> {
+    log_system_info();
 
-    info!("Trying to swapoff ...");
-    install::swapoff(&tempdir);
+    info!("Prepare trying unmount before deploykit mount partition ...");
+    install::prepare_try_umount()?;
 
-    info!("Unmounting main partition ...");
-    install::umount_root_path(&mount_path_copy).ok();
+    let mut ctx = InstallContext::new(sender, config, tempdir);
+    run_steps(&install_steps(), &mut ctx)?;
+    ctx.eta.save_or_warn();
 
-    sender.send(InstallProgress::Finished)?;
+    ctx.sender.send(InstallProgress::Finished)?;
 
     Ok(())
 }
@@ -582,3 +592,47 @@ fn calc_speed(speed: f64, eta: f64) -> (String, String) {
 
     (s, s2)
 }
+
+#[test]
+fn test_required_space() {
+    let gib = 1024 * 1024 * 1024;
+    let variant = network::VariantEntry {
+        name: "Test".to_string(),
+        size: 2 * gib,
+        install_size: 8 * gib,
+        date: "19700101".to_string(),
+        sha256sum: String::new(),
+        url: String::new(),
+        description: String::new(),
+        sendstream: None,
+        metalink: None,
+        torrent: None,
+    };
+
+    assert_eq!(
+        required_space(&variant, Some("ext4"), 100 * gib, 0),
+        17 * gib
+    );
+    assert_eq!(
+        required_space(&variant, Some("xfs"), 100 * gib, gib),
+        13 * gib
+    );
+    assert_eq!(required_space(&variant, None, 100 * gib, 0), 17 * gib);
+
+    let variant = network::VariantEntry {
+        url: "aosc-os_base_20240101_amd64.tar.xz".to_string(),
+        ..variant
+    };
+    assert_eq!(
+        required_space(&variant, Some("ext4"), 100 * gib, 0),
+        15 * gib
+    );
+}
+
+#[test]
+fn test_free_space_margin() {
+    let gib = 1024 * 1024 * 1024;
+    assert_eq!(free_space_margin(200 * gib, None), 10 * gib);
+    assert_eq!(free_space_margin(8 * gib, None), gib);
+    assert_eq!(free_space_margin(200 * gib, Some(20)), 40 * gib);
+}
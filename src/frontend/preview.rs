@@ -0,0 +1,251 @@
+use std::{ffi::OsStr, path::Path, sync::atomic::Ordering};
+
+use crate::{crypt, disks, install, zfs};
+
+use super::InstallConfig;
+
+/// `program` with `args`, quoted as in a shell
+fn command_line<S: AsRef<OsStr>>(program: &str, args: impl IntoIterator<Item = S>) -> String {
+    let args = args
+        .into_iter()
+        .map(|x| x.as_ref().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+
+    format!("{program} {}", shell_words::join(args))
+}
+
+/// What an installation with `config` is going to run, in order, without
+/// running any of it. Paths in the installed system are shown from its root.
+pub(super) fn generate(config: &InstallConfig) -> Vec<String> {
+    let mut res = vec![];
+    let Some(partition) = config.partition.as_deref() else {
+        return res;
+    };
+    let display = |x: Option<&Path>| x.unwrap_or(Path::new("?")).display().to_string();
+    let path = display(partition.path.as_deref());
+    let disk = display(partition.parent_path.as_deref());
+
+    if config.realign_partition && !config.keep_data && !disks::is_aligned(partition) {
+        res.push(format!(
+            "parted {disk}: move the start of {path} to a 1MiB boundary"
+        ));
+    }
+    if config.create_esp && config.esp.is_none() {
+        res.push(format!(
+            "parted {disk}: create a 512MiB EFI System Partition"
+        ));
+        res.push("mkfs.vfat -F32 (the new EFI System Partition)".to_string());
+    }
+    if config.create_bios_grub {
+        res.push(format!("parted {disk}: create a 1MiB BIOS boot partition"));
+    }
+
+    let mut target = partition.clone();
+    if config.encryption.is_some() {
+        let luks_path = partition.path.clone().unwrap_or_default();
+        res.push(command_line(
+            "cryptsetup",
+            crypt::luks_format_args(&luks_path),
+        ));
+        res.push(format!("cryptsetup open {path}"));
+        res.push("pvcreate, vgcreate and lvcreate on the LUKS container".to_string());
+        target.path = Some(crypt::root_volume_path());
+    }
+    let swap_partition = match &config.swap_partition {
+        Some(disks::SwapPartition::New { .. }) if config.encryption.is_some() => {
+            Some(crypt::swap_volume_path().display().to_string())
+        }
+        x => x.as_ref().map(|x| x.to_string()),
+    };
+    let target_path = display(target.path.as_deref());
+
+    let source = config.fstab_source;
+    for mount in config.extra_mounts.iter().filter(|x| x.format) {
+        let label = disks::mount_point_label(&mount.mount_point).unwrap_or_default();
+        if let Ok(cmd) = disks::mkfs_command(
+            &mount.partition,
+            &config.xfs_options,
+            &disks::MkfsArgs::new(),
+            source.mkfs_label(&label),
+        ) {
+            res.push(format!("{cmd:?}"));
+        }
+    }
+    if config.keep_data {
+        if install::is_btrfs(&target) {
+            res.push("btrfs subvolume create (missing subvolumes only)".to_string());
+        }
+    } else if zfs::is_zfs(&target) {
+        let pool = format!("{}-XXXXXXXX", zfs::POOL_PREFIX);
+        let zfs_path = target.path.clone().unwrap_or_default();
+        res.push(command_line(
+            "zpool",
+            zfs::zpool_create_args(&pool, &zfs_path),
+        ));
+        res.push(format!("zfs create {pool}/{}", zfs::ROOT_DATASET));
+    } else if let Ok(cmd) = match &config.btrfs_mirror {
+        Some(mirror) => disks::btrfs_raid1_command(
+            &target,
+            mirror,
+            &config.expert.mkfs_args,
+            source.mkfs_label(disks::SYSTEM_LABEL),
+        ),
+        None => disks::mkfs_command(
+            &target,
+            &config.xfs_options,
+            &config.expert.mkfs_args,
+            source.mkfs_label(disks::SYSTEM_LABEL),
+        ),
+    } {
+        res.push(format!("{cmd:?}"));
+        if install::is_btrfs(&target) {
+            let subvols = disks::BTRFS_SUBVOLUMES
+                .iter()
+                .map(|(subvol, _)| *subvol)
+                .collect::<Vec<_>>();
+            res.push(format!("btrfs subvolume create {}", subvols.join(" ")));
+        }
+    }
+
+    res.push(format!("mount {target_path} /"));
+    if let Some(home) = &config.home {
+        res.push(format!("mount {} /home", display(home.path.as_deref())));
+    }
+    for mount in &config.extra_mounts {
+        res.push(format!(
+            "mount {} {}",
+            display(mount.partition.path.as_deref()),
+            mount.mount_point.display()
+        ));
+    }
+    let esp_mount = config.esp_mount_point.path();
+    if disks::is_efi_booted() {
+        let esp = config
+            .esp
+            .as_ref()
+            .map(|x| display(x.path.as_deref()))
+            .unwrap_or_else(|| format!("(the EFI System Partition on {disk})"));
+        res.push(format!("mount {esp} {}", esp_mount.display()));
+    }
+
+    if let Some(swap_partition) = &swap_partition {
+        match source.mkfs_label(disks::SWAP_LABEL) {
+            Some(label) => res.push(format!("mkswap -L {label} {swap_partition}")),
+            None => res.push(format!("mkswap {swap_partition}")),
+        }
+        res.push(format!("swapon {swap_partition}"));
+    }
+    if config.use_swap.v.load(Ordering::SeqCst) {
+        if let Some(size) = *config.swap_size {
+            res.push(format!("fallocate -l {} /swapfile", size as u64));
+            res.push("mkswap /swapfile".to_string());
+            res.push("swapon /swapfile".to_string());
+        }
+    }
+
+    if config.from_live {
+        res.push("rsync the live system to /".to_string());
+    } else if let (Some(variant), Some(mirror)) = (&config.variant, &config.mirror) {
+        let sendstream = variant
+            .sendstream
+            .as_ref()
+            .filter(|_| install::is_btrfs(&target));
+        let file = sendstream.map(|x| &x.url).unwrap_or(&variant.url);
+        let url = if file.contains("://") {
+            file.clone()
+        } else {
+            format!("{}{}", mirror.url, file)
+        };
+        if sendstream.is_some() {
+            res.push(format!("download {url} and btrfs receive it to /"));
+        } else if let Some(torrent) = variant.torrent.as_ref().filter(|_| config.use_torrent) {
+            res.push(format!("aria2c {torrent} and unpack it to /"));
+        } else {
+            res.push(format!("download {url} and unpack it to /"));
+        }
+    }
+    res.push("genfstab > /etc/fstab".to_string());
+    if !config.skip_live_network {
+        res.push("copy Wi-Fi and DNS profiles and proxy settings of this session".to_string());
+    }
+    res.push("chroot /".to_string());
+    res.push("update-initramfs".to_string());
+
+    match config.bootloader {
+        install::Bootloader::Efistub => {
+            res.push(format!(
+                "efibootmgr --create (EFISTUB, kernel copied to {})",
+                esp_mount.display()
+            ));
+        }
+        install::Bootloader::Grub => {
+            let targets = install::grub_targets(
+                partition,
+                config.btrfs_mirror.as_deref(),
+                config.image.is_some(),
+            );
+            for target in &targets {
+                if let Some(args) = install::grub_target_args(target, esp_mount) {
+                    res.push(command_line("grub-install", args));
+                }
+            }
+            res.push("grub-mkconfig -o /boot/grub/grub.cfg".to_string());
+        }
+    }
+
+    let mut packages = vec![];
+    if let Some(power_management) = config.power_management {
+        packages.push(power_management.package());
+    }
+    if config.optenv32 {
+        packages.push(install::OPTENV32_PACKAGE);
+    }
+    if config.directory.is_some() {
+        packages.push("sssd");
+    }
+    if config.zram {
+        packages.push(install::ZRAM_PACKAGE);
+    }
+    if zfs::is_zfs(&target) {
+        packages.extend(zfs::ZFS_PACKAGES);
+    }
+    if !packages.is_empty() {
+        res.push(format!("apt-get install {}", packages.join(" ")));
+    }
+
+    if let Some(network) = &config.static_network {
+        res.push(format!(
+            "write /etc/systemd/network/80-deploykit-static.network ({network})"
+        ));
+        res.push("systemctl enable systemd-networkd.service".to_string());
+    }
+    if let Some(user) = &config.user {
+        res.push(format!("useradd -m -s /bin/bash {user}"));
+    }
+    if let Some(hostname) = &config.hostname {
+        res.push(format!("echo {hostname} > /etc/hostname"));
+    }
+    res.push(format!("umount {target_path}"));
+
+    res
+}
+
+#[test]
+fn test_generate_preview() {
+    let config = InstallConfig {
+        partition: Some(std::sync::Arc::new(disks::Partition {
+            path: Some("/dev/sda2".into()),
+            parent_path: Some("/dev/sda".into()),
+            fs_type: Some("ext4".to_string()),
+            size: 0,
+        })),
+        user: Some(std::sync::Arc::new("aosc".to_string())),
+        ..Default::default()
+    };
+    let preview = generate(&config);
+
+    assert_eq!(preview[0], r#""mkfs.ext4" "-Fq" "/dev/sda2""#);
+    assert_eq!(preview[1], "mount /dev/sda2 /");
+    assert!(preview.contains(&"useradd -m -s /bin/bash aosc".to_string()));
+    assert!(generate(&InstallConfig::default()).is_empty());
+}
@@ -0,0 +1,188 @@
+use std::{fmt::Write, fs, path::Path, sync::atomic::Ordering, time::Duration};
+
+use log::{info, warn};
+use time::OffsetDateTime;
+
+use super::InstallConfig;
+
+/// Where the report is saved, relative to the root of the installed system
+pub(super) const REPORT_FILE: &str = "var/log/deploykit-report.txt";
+
+/// Summary of an installation for audit trails and support requests
+pub(super) fn generate(
+    config: &InstallConfig,
+    finished: &[(&'static str, Duration)],
+    now: OffsetDateTime,
+) -> String {
+    let mut report = String::new();
+    let unknown = || "(unknown)".to_string();
+
+    // writing to a String never fails
+    writeln!(report, "AOSC OS Installation Report").unwrap();
+    writeln!(report, "===========================").unwrap();
+    writeln!(report).unwrap();
+    writeln!(report, "Installer: DeployKit {}", env!("CARGO_PKG_VERSION")).unwrap();
+    writeln!(report, "Finished at: {now}").unwrap();
+    writeln!(
+        report,
+        "Variant: {}",
+        config
+            .variant
+            .as_ref()
+            .map(|x| format!("{} ({}, {})", x.name, x.date, x.sha256sum))
+            .unwrap_or_else(unknown)
+    )
+    .unwrap();
+    writeln!(
+        report,
+        "Mirror: {}",
+        config
+            .mirror
+            .as_ref()
+            .map(|x| format!("{} ({})", x.name, x.url))
+            .unwrap_or_else(unknown)
+    )
+    .unwrap();
+    writeln!(report, "Bootloader: {:?}", config.bootloader).unwrap();
+
+    writeln!(report, "\nPartitions:").unwrap();
+    if let Some(partition) = &config.partition {
+        writeln!(
+            report,
+            "  {} (system, {} as {})",
+            partition
+                .path
+                .as_ref()
+                .map(|x| x.display().to_string())
+                .unwrap_or_else(unknown),
+            if config.keep_data {
+                "kept"
+            } else {
+                "formatted"
+            },
+            partition.fs_type.as_deref().unwrap_or("ext4")
+        )
+        .unwrap();
+        if config.encryption.is_some() {
+            writeln!(
+                report,
+                "  {} (LVM on LUKS)",
+                crate::crypt::root_volume_path().display()
+            )
+            .unwrap();
+        }
+    }
+    if let Some(mirror) = config.btrfs_mirror.as_ref().and_then(|x| x.path.as_ref()) {
+        writeln!(report, "  {} (btrfs RAID1 mirror)", mirror.display()).unwrap();
+    }
+    if let Some(home) = config.home.as_ref().and_then(|x| x.path.as_ref()) {
+        writeln!(report, "  {} (/home, kept)", home.display()).unwrap();
+    }
+    if let Some(esp) = config.esp.as_ref().and_then(|x| x.path.as_ref()) {
+        writeln!(report, "  {} (EFI system partition)", esp.display()).unwrap();
+    } else if config.create_esp {
+        writeln!(report, "  (EFI system partition, created)").unwrap();
+    }
+    for mount in &config.extra_mounts {
+        writeln!(report, "  {mount}").unwrap();
+    }
+    if let Some(swap_partition) = &config.swap_partition {
+        writeln!(report, "  {swap_partition} (swap)").unwrap();
+    }
+    if config.zram {
+        writeln!(report, "  zram (swap)").unwrap();
+    }
+    if config.use_swap.v.load(Ordering::SeqCst) {
+        if let Some(size) = *config.swap_size {
+            let size = size / 1024.0 / 1024.0 / 1024.0;
+            writeln!(report, "  /swapfile ({size:.1} GiB)").unwrap();
+        }
+    }
+    if !config.mount_options.is_default() {
+        writeln!(report, "  Mount options: {}", config.mount_options).unwrap();
+    }
+
+    writeln!(report, "\nUsers:").unwrap();
+    if let Some(user) = &config.user {
+        write!(report, "  {user}").unwrap();
+        if !config.expert.extra_groups.is_empty() {
+            write!(
+                report,
+                " (also in {})",
+                config.expert.extra_groups.join(", ")
+            )
+            .unwrap();
+        }
+        writeln!(report).unwrap();
+    }
+    if cfg!(feature = "is_retro") {
+        writeln!(report, "  root").unwrap();
+    }
+
+    writeln!(report, "\nServices enabled:").unwrap();
+    if let Some(power_management) = config.power_management {
+        writeln!(report, "  {}", power_management.service()).unwrap();
+    }
+    if let Some(directory) = &config.directory {
+        writeln!(
+            report,
+            "  sssd.service ({}, {})",
+            directory.uri, directory.base_dn
+        )
+        .unwrap();
+    }
+    if let Some(network) = &config.static_network {
+        writeln!(report, "  systemd-networkd.service ({network})").unwrap();
+    }
+
+    writeln!(report, "\nDurations:").unwrap();
+    let mut total = Duration::ZERO;
+    for (step, duration) in finished {
+        writeln!(report, "  {step:<12} {:>8.1}s", duration.as_secs_f64()).unwrap();
+        total += *duration;
+    }
+    writeln!(report, "  {:<12} {:>8.1}s", "total", total.as_secs_f64()).unwrap();
+
+    report
+}
+
+/// Save the report to `path`, in the installed system or a copy outside of
+/// it, e.g. on the live medium. The installation is complete already, so a
+/// failure is only logged.
+pub(super) fn save(report: &str, path: &Path) {
+    match fs::write(path, report) {
+        Ok(()) => info!("Saved installation report to {}", path.display()),
+        Err(e) => warn!(
+            "Could not save installation report to {}: {e}",
+            path.display()
+        ),
+    }
+}
+
+#[test]
+fn test_generate_report() {
+    let config = InstallConfig {
+        user: Some(std::sync::Arc::new("aosc".to_string())),
+        power_management: Some(crate::install::PowerManagement::Tlp),
+        use_swap: std::sync::Arc::new(super::AtomicBoolWrapper {
+            v: std::sync::atomic::AtomicBool::new(true),
+        }),
+        swap_size: std::sync::Arc::new(Some(2.0 * 1024.0 * 1024.0 * 1024.0)),
+        ..Default::default()
+    };
+    let report = generate(
+        &config,
+        &[
+            ("format", Duration::from_secs(3)),
+            ("download", Duration::from_millis(120_500)),
+        ],
+        OffsetDateTime::UNIX_EPOCH,
+    );
+
+    assert!(report.contains("Variant: (unknown)"));
+    assert!(report.contains("  /swapfile (2.0 GiB)\n"));
+    assert!(report.contains("\nUsers:\n  aosc\n"));
+    assert!(report.contains("  tlp.service\n"));
+    assert!(report.contains("  download        120.5s\n"));
+    assert!(report.contains("  total           123.5s\n"));
+}
@@ -0,0 +1,2109 @@
+use std::{
+    convert::TryInto,
+    io::{Read, Seek, SeekFrom, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, RecvTimeoutError, Sender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use cursive::utils::Counter;
+use hhmmss::Hhmmss;
+use log::{error, info, warn};
+use rand::{thread_rng, Rng};
+use rustix::{
+    fd::{AsFd, OwnedFd},
+    fs::FallocateFlags,
+};
+use sha2::{Digest, Sha256};
+
+use crate::{cache, crypt, disks, install, network, torrent, zfs, zsync, LOG_FILE};
+
+use super::{
+    calc_speed,
+    cues::{self, Cue},
+    eta::Eta,
+    report, InstallConfig, InstallProgress, STEP1, STEP2, STEP3, STEP4, STEP5, STEP6, STEP7, STEP8,
+};
+
+macro_rules! send_error {
+    ($error_channel_tx_copy:ident, $e:ident) => {
+        $error_channel_tx_copy.send($e.to_string()).unwrap();
+        return;
+    };
+}
+
+/// State shared between installation steps
+pub(super) struct InstallContext {
+    pub sender: Sender<InstallProgress>,
+    pub config: InstallConfig,
+    pub tempdir: PathBuf,
+    /// Logical volume inside the encrypted partition, set by [`FormatStep`]
+    pub root_volume: Option<Arc<disks::Partition>>,
+    /// Swap partition in use, set by [`SwapStep`]
+    pub swap_partition: Option<disks::Partition>,
+    /// Swap partition made by [`SwapStep`], removed again on rollback
+    pub created_swap_partition: Option<disks::Partition>,
+    /// Where the system partition is mounted, set by [`MountStep`]
+    pub mount_path: Option<PathBuf>,
+    /// Root of the live environment, set while inside the chroot
+    pub escape_vector: Option<OwnedFd>,
+    /// DNS settings of the live environment, for installing packages
+    pub host_resolv_conf: Option<Vec<u8>>,
+    pub eta: Eta,
+}
+
+impl InstallContext {
+    pub fn new(sender: Sender<InstallProgress>, config: InstallConfig, tempdir: PathBuf) -> Self {
+        InstallContext {
+            sender,
+            config,
+            tempdir,
+            root_volume: None,
+            swap_partition: None,
+            created_swap_partition: None,
+            mount_path: None,
+            escape_vector: None,
+            host_resolv_conf: None,
+            eta: Eta::load(),
+        }
+    }
+
+    fn partition(&self) -> Result<Arc<disks::Partition>> {
+        self.config.partition.clone().ok_or_else(|| {
+            anyhow!("Installer could not parse installation configuration: `partition` field not found.")
+        })
+    }
+
+    /// Where the system is installed to, the partition picked by the user
+    /// unless it is encrypted
+    fn root_partition(&self) -> Result<Arc<disks::Partition>> {
+        match &self.root_volume {
+            Some(root_volume) => Ok(root_volume.clone()),
+            None => self.partition(),
+        }
+    }
+
+    /// Where the swapfile is, relative to the root of the installed system
+    fn swapfile(&self) -> Result<&'static Path> {
+        Ok(install::swapfile_path(install::is_btrfs(
+            &self.root_partition()?,
+        )))
+    }
+
+    /// VG and LV names, if the user picked an existing logical volume
+    fn logical_volume(&self) -> Result<Option<(String, String)>> {
+        Ok(self
+            .partition()?
+            .path
+            .as_deref()
+            .and_then(disks::logical_volume_name))
+    }
+
+    /// Name and backing partition of a LUKS container the user opened
+    /// before starting the installer, and the system is installed into
+    fn opened_container(&self) -> Result<Option<(String, PathBuf)>> {
+        if self.root_volume.is_some() {
+            return Ok(None);
+        }
+
+        Ok(self
+            .partition()?
+            .path
+            .as_deref()
+            .and_then(disks::crypt_backing_device))
+    }
+
+    /// Whether the system is installed to an mdadm RAID array
+    fn is_raid(&self) -> Result<bool> {
+        Ok(self
+            .partition()?
+            .path
+            .as_deref()
+            .is_some_and(disks::is_raid))
+    }
+
+    /// The ESP picked by the user, or the first one on the system disk
+    fn esp_partition(&self) -> Result<disks::Partition> {
+        if let Some(esp) = &self.config.esp {
+            return Ok(esp.as_ref().clone());
+        }
+
+        let partition = self.partition()?;
+        info!("Finding ESP partition from: {:?}", partition.parent_path);
+        disks::find_esp_partition(partition.parent_path.as_ref().unwrap())
+    }
+
+    fn mount_path(&self) -> Result<PathBuf> {
+        self.mount_path
+            .clone()
+            .ok_or_else(|| anyhow!("Installer has not mounted the system partition."))
+    }
+
+    /// Report a step whose progress can not be measured
+    fn pending(&self, msg: &str) -> Result<()> {
+        let fake_counter: usize = thread_rng().gen_range(0..100);
+        self.sender.send(InstallProgress::Pending(
+            self.eta.describe(msg, None),
+            fake_counter,
+        ))?;
+        info!("{}", msg);
+
+        Ok(())
+    }
+
+    /// Run `f`, which can not report its own progress, while showing an
+    /// indeterminate progress bar and the time spent on it
+    fn with_elapsed<T>(&self, msg: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+        info!("{}", msg);
+        let msg = self.eta.describe(msg, None);
+        let sender = self.sender.clone();
+        let started = Instant::now();
+        let (stop_tx, stop_rx) = mpsc::channel::<()>();
+
+        thread::scope(|scope| {
+            scope.spawn(move || {
+                let mut counter = 0;
+                loop {
+                    let elapsed = started.elapsed().hhmmss();
+                    sender
+                        .send(InstallProgress::Pending(
+                            format!("{msg}, {elapsed} elapsed"),
+                            counter,
+                        ))
+                        .ok();
+                    counter = (counter + 5) % 100;
+                    if stop_rx.recv_timeout(Duration::from_secs(1))
+                        != Err(RecvTimeoutError::Timeout)
+                    {
+                        return;
+                    }
+                }
+            });
+            let res = f();
+            drop(stop_tx);
+
+            res
+        })
+    }
+}
+
+pub(super) trait InstallStep {
+    fn name(&self) -> &'static str;
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()>;
+
+    /// Undo what [`InstallStep::run`] has done so far, this is also called on
+    /// the step that failed so it must cope with a partial run
+    fn rollback(&self, _ctx: &mut InstallContext) {}
+}
+
+/// All steps of an installation, in order
+pub(super) fn install_steps() -> Vec<Box<dyn InstallStep>> {
+    vec![
+        Box::new(FormatStep),
+        Box::new(MountStep),
+        Box::new(BenchmarkStep),
+        Box::new(SwapStep),
+        Box::new(DownloadStep),
+        Box::new(FstabStep),
+        Box::new(LiveNetworkStep),
+        Box::new(EnterChrootStep),
+        Box::new(InitramfsStep),
+        Box::new(BootloaderStep),
+        Box::new(SshKeyStep),
+        Box::new(PackagesStep),
+        Box::new(DirectoryStep),
+        Box::new(StaticNetworkStep),
+        Box::new(ConfigureSystemStep),
+        Box::new(FinishStep),
+    ]
+}
+
+/// Run `steps` in order. If one of them fails, it and every step before it
+/// are rolled back in reverse order.
+pub(super) fn run_steps(steps: &[Box<dyn InstallStep>], ctx: &mut InstallContext) -> Result<()> {
+    ctx.eta.plan(steps.iter().map(|x| x.name()).collect());
+    for (i, step) in steps.iter().enumerate() {
+        info!("Running installation step: {}", step.name());
+        ctx.eta.start(step.name());
+        let res = step.run(ctx);
+        ctx.eta.finish();
+        if let Err(e) = res {
+            error!("Installation step {} failed: {}", step.name(), e);
+            for step in steps[..=i].iter().rev() {
+                info!("Rolling back installation step: {}", step.name());
+                step.rollback(ctx);
+            }
+
+            return Err(e);
+        }
+    }
+
+    Ok(())
+}
+
+struct FormatStep;
+
+impl InstallStep for FormatStep {
+    fn name(&self) -> &'static str {
+        "format"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        let mut partition = ctx.partition()?;
+        if ctx.config.keep_data {
+            if ctx.config.encryption.is_some() || ctx.config.btrfs_mirror.is_some() {
+                return Err(anyhow!(
+                    "Installer can not keep the data on encrypted or mirrored system partitions."
+                ));
+            }
+            partition = Arc::new(disks::keep_data_partition(&partition)?);
+            ctx.config.partition = Some(partition.clone());
+            if install::is_btrfs(&partition) {
+                let separate_home = ctx.config.home.is_some();
+                install::check_kept_btrfs_subvolumes(&partition, &ctx.tempdir, separate_home)?;
+            }
+        }
+        if !disks::is_aligned(&partition) {
+            // moving the partition is not worth the risk to the data on it
+            if ctx.config.realign_partition && !ctx.config.keep_data {
+                partition =
+                    Arc::new(ctx.with_elapsed(STEP1, || disks::realign_partition(&partition))?);
+                ctx.config.partition = Some(partition.clone());
+            } else {
+                warn!("{:?} does not start on a 1MiB boundary", partition.path);
+            }
+        }
+        if ctx.config.create_esp && ctx.config.esp.is_none() {
+            let parent = partition.parent_path.clone().ok_or_else(|| {
+                anyhow!("Installer could not find the disk of the specified partition.")
+            })?;
+            if disks::find_esp_partition(&parent).is_err() {
+                let label = ctx.config.fstab_source.mkfs_label(disks::ESP_LABEL);
+                let esp = ctx.with_elapsed(STEP1, || {
+                    disks::create_esp_partition(&parent, &partition, label)
+                })?;
+                info!("Created EFI system partition: {:?}", esp);
+                // the system partition may have been shrunk to make room
+                let path = partition.path.clone().unwrap_or_default();
+                partition = Arc::new(disks::find_partition(&path).ok_or_else(|| {
+                    anyhow!(
+                        "Installer could not find {} after creating the EFI system partition.",
+                        path.display()
+                    )
+                })?);
+                ctx.config.partition = Some(partition.clone());
+            }
+        }
+        if ctx.config.create_bios_grub {
+            let parent = partition.parent_path.clone().ok_or_else(|| {
+                anyhow!("Installer could not find the disk of the specified partition.")
+            })?;
+            if disks::needs_bios_grub_partition(&parent) {
+                ctx.with_elapsed(STEP1, || disks::create_bios_grub_partition(&parent))?;
+            }
+        }
+        if let Some(encryption) = &ctx.config.encryption {
+            // A new swap partition goes into the container instead, so that
+            // nothing, including hibernation images, is written in the clear
+            let swap_size = match &ctx.config.swap_partition {
+                Some(disks::SwapPartition::New { size, .. }) => Some(*size),
+                Some(disks::SwapPartition::Existing(swap)) => {
+                    return Err(anyhow!(
+                        "Installer can not use {:?} as swap on an encrypted system, it would not be encrypted.",
+                        swap.path
+                    ));
+                }
+                None => None,
+            };
+            let root_volume = ctx.with_elapsed(STEP1, || {
+                crypt::create_lvm_on_luks(&partition, encryption, swap_size)
+            })?;
+            if let Some(size) = swap_size {
+                ctx.config.swap_partition =
+                    Some(disks::SwapPartition::Existing(disks::Partition {
+                        path: Some(crypt::swap_volume_path()),
+                        parent_path: partition.parent_path.clone(),
+                        fs_type: Some("swap".to_string()),
+                        size,
+                    }));
+            }
+            partition = Arc::new(root_volume);
+            ctx.root_volume = Some(partition.clone());
+        }
+        info!("Formatting partitions: {:?}", partition);
+        let xfs = &ctx.config.xfs_options;
+        let source = ctx.config.fstab_source;
+        for mount in ctx.config.extra_mounts.iter().filter(|x| x.format) {
+            info!("Formatting {mount}");
+            let label = disks::mount_point_label(&mount.mount_point).unwrap_or_default();
+            ctx.with_elapsed(STEP1, || {
+                disks::format_partition_with_args(
+                    &mount.partition,
+                    xfs,
+                    &disks::MkfsArgs::new(),
+                    source.mkfs_label(&label),
+                )
+            })?;
+        }
+
+        if ctx.config.keep_data {
+            info!("Keeping the existing data on {:?}", partition.path);
+            if install::is_btrfs(&partition) {
+                // only those holding no data yet, e.g. @swap
+                install::create_btrfs_subvolumes(&partition, &ctx.tempdir)?;
+            }
+            return Ok(());
+        }
+
+        if zfs::is_zfs(&partition) {
+            let with_home = ctx.config.home.is_none();
+            ctx.with_elapsed(STEP1, || zfs::create_pool(&partition, with_home))?;
+            return Ok(());
+        }
+
+        let mkfs_args = &ctx.config.expert.mkfs_args;
+        if let Some(mirror) = &ctx.config.btrfs_mirror {
+            if !install::is_btrfs(&partition) || ctx.config.encryption.is_some() {
+                return Err(anyhow!(
+                    "Installer can only mirror unencrypted btrfs system partitions."
+                ));
+            }
+            info!(
+                "Formatting {:?} as btrfs RAID1 with {:?}",
+                partition.path, mirror.path
+            );
+            ctx.with_elapsed(STEP1, || {
+                disks::format_btrfs_raid1(
+                    &partition,
+                    mirror,
+                    mkfs_args,
+                    source.mkfs_label(disks::SYSTEM_LABEL),
+                )
+            })?;
+        } else {
+            ctx.with_elapsed(STEP1, || {
+                disks::format_partition_with_args(
+                    &partition,
+                    xfs,
+                    mkfs_args,
+                    source.mkfs_label(disks::SYSTEM_LABEL),
+                )
+            })?;
+        }
+        if install::is_btrfs(&partition) {
+            info!("Creating btrfs subvolumes ...");
+            install::create_btrfs_subvolumes(&partition, &ctx.tempdir)?;
+        }
+
+        Ok(())
+    }
+
+    fn rollback(&self, ctx: &mut InstallContext) {
+        if ctx.root_volume.take().is_some() {
+            crypt::close();
+        }
+    }
+}
+
+struct MountStep;
+
+impl InstallStep for MountStep {
+    fn name(&self) -> &'static str {
+        "mount"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        let partition = ctx.root_partition()?;
+        info!("Mounting partitions: {:?}", partition);
+        let mount_path = install::auto_mount_root_path(&ctx.tempdir, &partition)?;
+        ctx.mount_path = Some(mount_path.clone());
+
+        if ctx.config.keep_data {
+            let mut required = ctx.config.variant.as_ref().map_or(0, |x| x.install_size);
+            if ctx.config.use_swap.v.load(Ordering::SeqCst) {
+                required += ctx.config.swap_size.unwrap_or_default() as u64;
+            }
+            let available = disks::available_space(&mount_path)?;
+            if available < required {
+                return Err(anyhow!(
+                    "Installer could not find enough free space on the system partition.\n\nAvailable space: {:.3}GiB\nRequired space: {:.3}GiB",
+                    available as f64 / 1024.0 / 1024.0 / 1024.0,
+                    required as f64 / 1024.0 / 1024.0 / 1024.0
+                ));
+            }
+            let dirs = disks::existing_system_dirs(&mount_path);
+            if !dirs.is_empty() {
+                warn!(
+                    "Files of a previous system in /{} will be overwritten",
+                    dirs.join(", /")
+                );
+            }
+        }
+
+        if let Some(home) = &ctx.config.home {
+            info!("Mounting /home partition: {:?}", home);
+            let home_path = mount_path.join("home");
+            if install::is_btrfs(&partition) {
+                // replaced by the /home partition, unmounted along with the
+                // other subvolumes later
+                install::umount_root_path(&home_path)?;
+            }
+            install::mount_extra_partition(home, &home_path)?;
+        }
+
+        // parents first, e.g. /var before /var/lib
+        let mut extra_mounts = ctx.config.extra_mounts.iter().collect::<Vec<_>>();
+        extra_mounts.sort_by_key(|x| x.mount_point.components().count());
+        for mount in extra_mounts {
+            info!("Mounting {mount}");
+            install::mount_extra_partition(&mount.partition, &mount_path.join(mount.relative()))?;
+        }
+
+        if disks::is_efi_booted() {
+            let efi_path = mount_path.join(ctx.config.esp_mount_point.relative());
+
+            let mut esp_part = ctx.esp_partition()?;
+            info!("ESP is: {:?}", esp_part);
+
+            std::fs::create_dir_all(&efi_path)?;
+            if esp_part.fs_type.is_none() {
+                // format the un-formatted ESP partition
+                esp_part.fs_type = Some("vfat".to_string());
+
+                info!("Formatting ESP partition: {:?}", esp_part);
+                let label = ctx.config.fstab_source.mkfs_label(disks::ESP_LABEL);
+                ctx.with_elapsed(STEP1, || {
+                    disks::format_partition_with_args(
+                        &esp_part,
+                        &disks::XfsOptions::default(),
+                        &disks::MkfsArgs::new(),
+                        label,
+                    )
+                })?;
+            }
+            install::mount_root_path(&esp_part, &efi_path)?;
+        }
+
+        Ok(())
+    }
+
+    fn rollback(&self, ctx: &mut InstallContext) {
+        if let Some(mount_path) = ctx.mount_path.take() {
+            if disks::is_efi_booted() {
+                let esp_mount = ctx.config.esp_mount_point.relative();
+                install::umount_root_path(&mount_path.join(esp_mount)).ok();
+            }
+            umount_extra_partitions(&ctx.config, &mount_path);
+            install::umount_btrfs_subvolumes(&mount_path);
+            install::umount_root_path(&mount_path).ok();
+            zfs::export().ok();
+        }
+    }
+}
+
+/// Unmount /home and the other partitions [`MountStep`] mounted below the
+/// system partition, children first
+fn umount_extra_partitions(config: &InstallConfig, mount_path: &Path) {
+    let mut extra_mounts = config.extra_mounts.iter().collect::<Vec<_>>();
+    extra_mounts.sort_by_key(|x| std::cmp::Reverse(x.mount_point.components().count()));
+    for mount in extra_mounts {
+        install::umount_root_path(&mount_path.join(mount.relative())).ok();
+    }
+    if config.home.is_some() {
+        install::umount_root_path(&mount_path.join("home")).ok();
+    }
+}
+
+/// Measure how fast the target is written to, to estimate the unpacking time.
+/// Slow media are warned about before the installation, see
+/// [`disks::benchmark_read`].
+struct BenchmarkStep;
+
+impl InstallStep for BenchmarkStep {
+    fn name(&self) -> &'static str {
+        "benchmark"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        let mount_path = ctx.mount_path()?;
+        let speed = match ctx.with_elapsed(STEP1, || disks::benchmark_write(&mount_path)) {
+            Ok(speed) => speed,
+            Err(e) => {
+                warn!("Could not measure write speed: {e}");
+                return Ok(());
+            }
+        };
+        let mib = speed / 1024.0 / 1024.0;
+        info!("Target write speed: {mib:.1} MiB/s");
+        if let Some(variant) = &ctx.config.variant {
+            ctx.eta
+                .estimate_unpack_secs(variant.install_size as f64 / speed);
+        }
+
+        Ok(())
+    }
+}
+
+struct SwapStep;
+
+impl InstallStep for SwapStep {
+    fn name(&self) -> &'static str {
+        "swap"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        if let Some(swap_partition) = &ctx.config.swap_partition {
+            info!("Setting up swap partition: {}", swap_partition);
+            let partition = ctx.with_elapsed(STEP1, || swap_partition.resolve())?;
+            if matches!(swap_partition, disks::SwapPartition::New { .. }) {
+                ctx.created_swap_partition = Some(partition.clone());
+            }
+            ctx.swap_partition = Some(partition.clone());
+            install::enable_swap_partition(
+                &partition,
+                ctx.config.fstab_source.mkfs_label(disks::SWAP_LABEL),
+            )?;
+        }
+
+        if ctx.config.use_swap.v.load(Ordering::SeqCst) && zfs::is_zfs(&ctx.root_partition()?) {
+            warn!("Swapfiles are not supported on ZFS, not creating one");
+            ctx.config.use_swap.v.store(false, Ordering::SeqCst);
+        }
+        if ctx.config.use_swap.v.load(Ordering::SeqCst) && ctx.config.btrfs_mirror.is_some() {
+            warn!("Swapfiles are not supported on multi-device btrfs, not creating one");
+            ctx.config.use_swap.v.store(false, Ordering::SeqCst);
+        }
+
+        let use_swap = ctx.config.use_swap.v.load(Ordering::SeqCst);
+        if use_swap {
+            if let Some(swap_size) = ctx.config.swap_size.as_ref() {
+                info!("Creating swapfile and trying swapon swapfile ...");
+                info!("{}", STEP1);
+                let swap_path = ctx.tempdir.join(ctx.swapfile()?);
+                let btrfs = install::is_btrfs(&ctx.root_partition()?);
+                install::create_swapfile(
+                    *swap_size,
+                    use_swap,
+                    &swap_path,
+                    btrfs,
+                    |allocated, total| {
+                        let pct = (allocated * 100 / total) as usize;
+                        ctx.sender
+                            .send(InstallProgress::Pending(STEP1.to_string(), pct))
+                            .ok();
+                    },
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn rollback(&self, ctx: &mut InstallContext) {
+        install::swapoff(&ctx.tempdir);
+        if let Some(partition) = &ctx.swap_partition {
+            install::swapoff_partition(partition);
+        }
+        // leave the partition table as it was found, a retry makes it again
+        if let Some(partition) = ctx.created_swap_partition.take() {
+            if let Err(e) = disks::remove_partition(&partition) {
+                warn!("Could not remove the swap partition made for this installation: {e}");
+            }
+        }
+    }
+}
+
+/// Where the downloader writes to: a file to unpack once the download is
+/// done, or the extractor, which unpacks while downloading
+enum DownloadSink {
+    File(std::fs::File),
+    Stream(mpsc::SyncSender<Vec<u8>>),
+}
+
+impl Write for DownloadSink {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            DownloadSink::File(f) => f.write(buf),
+            DownloadSink::Stream(tx) => {
+                tx.send(buf.to_vec())
+                    .map_err(|_| std::io::Error::from(std::io::ErrorKind::BrokenPipe))?;
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            DownloadSink::File(f) => f.flush(),
+            DownloadSink::Stream(_) => Ok(()),
+        }
+    }
+}
+
+/// Downloading, verifying and unpacking happen at the same time, the
+/// tarball is streamed to all three workers
+struct DownloadStep;
+
+impl InstallStep for DownloadStep {
+    fn name(&self) -> &'static str {
+        "download"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        self.fetch(ctx)?;
+
+        // a sendstream is received as a subvolume of its own, which replaces
+        // the system subvolume, so everything is mounted again on top of it
+        let partition = ctx.root_partition()?;
+        if install::is_btrfs(&partition) && install::has_received_root(&partition) {
+            MountStep.rollback(ctx);
+            let adopted = install::adopt_received_root(&partition);
+            MountStep.run(ctx)?;
+            adopted?;
+        }
+
+        Ok(())
+    }
+}
+
+impl DownloadStep {
+    fn fetch(&self, ctx: &mut InstallContext) -> Result<()> {
+        let sender = &ctx.sender;
+        let mount_path = ctx.mount_path()?;
+        let refresh_interval = std::time::Duration::from_millis(30);
+        let counter = Counter::new(0);
+        let url;
+        let file_size: usize;
+        let right_sha256;
+        let extract_done: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+        let download_done: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+
+        if let Some(variant) = ctx.config.variant.as_ref() {
+            let mirror_url = &ctx.config.mirror.as_ref().unwrap().url;
+            file_size = variant.size.try_into().unwrap();
+            // releases outside the recipe come with a full URL
+            url = if variant.url.contains("://") {
+                variant.url.clone()
+            } else {
+                format!("{}{}", mirror_url, variant.url)
+            };
+            right_sha256 = variant.sha256sum.clone();
+
+            info!(
+                "Mirror URL is: {}, file_size: {}, url: {}, right_sha256: {}",
+                mirror_url, file_size, url, right_sha256
+            );
+        } else {
+            return Err(anyhow!(
+                "Installer could not parse release metadata: `variant` field not found."
+            ));
+        }
+
+        let is_btrfs = install::is_btrfs(&ctx.root_partition()?);
+        if network::is_sendstream(&url) && (!is_btrfs || ctx.config.keep_data) {
+            return Err(anyhow!(
+                "Installer can only apply btrfs sendstreams to freshly formatted btrfs system partitions."
+            ));
+        }
+
+        if ctx.config.from_live {
+            let install_size = ctx.config.variant.as_ref().map_or(0, |x| x.install_size);
+            return copy_live_system(sender, install_size, &mount_path);
+        }
+
+        if let Some(tarball) = url.strip_prefix("file://") {
+            return unpack_local_tarball(
+                sender,
+                Path::new(tarball),
+                &right_sha256,
+                file_size,
+                &mount_path,
+            );
+        }
+
+        // prefer a copy on the boot medium, so that no network is needed
+        let embedded = ctx
+            .config
+            .variant
+            .as_ref()
+            .and_then(|x| network::find_embedded_release(x));
+        if let Some(tarball) = embedded {
+            info!("Found system release on local media: {}", tarball.display());
+            match verify_local_tarball(sender, &tarball, &right_sha256) {
+                Ok(()) => return extract_local_tarball(sender, &tarball, file_size, &mount_path),
+                Err(e) => warn!("Not using {}: {e}, downloading instead", tarball.display()),
+            }
+        }
+
+        // receiving a sendstream is much faster than unpacking a tarball
+        let sendstream = ctx
+            .config
+            .variant
+            .as_ref()
+            .and_then(|x| x.sendstream.clone())
+            .filter(|_| is_btrfs && !ctx.config.keep_data);
+        let metalink = ctx
+            .config
+            .variant
+            .as_ref()
+            .and_then(|x| x.metalink.clone())
+            .filter(|_| sendstream.is_none());
+        let torrent = ctx
+            .config
+            .variant
+            .as_ref()
+            .and_then(|x| x.torrent.clone())
+            .filter(|_| ctx.config.use_torrent && sendstream.is_none());
+        let (url, file_size, right_sha256) = match sendstream {
+            Some(sendstream) => {
+                info!("Using btrfs sendstream: {}", sendstream.url);
+                let url = if sendstream.url.contains("://") {
+                    sendstream.url
+                } else {
+                    format!(
+                        "{}{}",
+                        ctx.config.mirror.as_ref().unwrap().url,
+                        sendstream.url
+                    )
+                };
+                (
+                    url,
+                    sendstream.size.try_into().unwrap(),
+                    sendstream.sha256sum,
+                )
+            }
+            None => (url, file_size, right_sha256),
+        };
+
+        // other mirrors of the release, to turn to when one fails
+        let mut urls = vec![url.clone()];
+        if let Some(metalink) = metalink {
+            let metalink = if metalink.contains("://") {
+                metalink
+            } else {
+                format!("{}{}", ctx.config.mirror.as_ref().unwrap().url, metalink)
+            };
+            let name = url.rsplit('/').next();
+            match network::fetch_metalink(&metalink, name) {
+                Ok(m) => match m.sha256sum {
+                    Some(x) if x != right_sha256 => {
+                        warn!("Not using {metalink}: it describes a different release")
+                    }
+                    _ => {
+                        info!("Using {} more mirrors from {metalink}", m.urls.len());
+                        urls.extend(m.urls.into_iter().filter(|x| *x != url));
+                    }
+                },
+                Err(e) => warn!("Not using {metalink}: {e}"),
+            }
+        }
+
+        // make sure a full copy of the release fits before downloading it,
+        // a .tar.xz is unpacked while downloading instead
+        let install_size = ctx.config.variant.as_ref().map_or(0, |x| x.install_size);
+        let cache = cache::cache_path(&url).filter(|x| {
+            let existing = x.metadata().map_or(0, |x| x.len());
+            let tarball_size = (file_size as u64).saturating_sub(existing);
+            let (available, required) =
+                tarball_space(x.parent().unwrap(), &mount_path, tarball_size, install_size)
+                    .unwrap_or((0, 1));
+            if available < required {
+                warn!("Not caching {url}: {available} bytes available, {required} bytes required");
+            }
+            available >= required
+        });
+        let (available, required) =
+            tarball_space(&mount_path, &mount_path, file_size as u64, install_size)?;
+        let has_room = available >= required;
+        // an earlier run may have been interrupted while downloading into
+        // the cache, which outlives the reformatted system partition
+        let resume = cache
+            .as_ref()
+            .map_or(0, |x| cache::resume_offset(x, &right_sha256));
+        if resume > 0 {
+            info!("Resuming the download of {url} from {resume} bytes");
+        }
+        if !has_room && cache.is_none() && !install::is_streamable(&url) {
+            return Err(anyhow!(
+                "Installer could not find enough space on the system partition for both the system release and its contents.\n\nAvailable space: {:.3}GiB\nRequired space: {:.3}GiB\n\nPlease choose a larger partition, or install a .tar.xz system release, which is unpacked while downloading and needs no space for a copy of itself.",
+                available as f64 / 1024.0 / 1024.0 / 1024.0,
+                required as f64 / 1024.0 / 1024.0 / 1024.0
+            ));
+        }
+        let torrent = torrent.filter(|_| {
+            if resume > 0 {
+                info!("Not using BitTorrent, resuming the earlier download instead");
+                return false;
+            }
+            if !has_room {
+                warn!("Not using BitTorrent, there is no room for a full copy of the release");
+            }
+            has_room
+        });
+        let torrent = torrent.filter(|_| {
+            let tor = network::uses_tor(&url);
+            if tor {
+                warn!("Not using BitTorrent, peers would be reached around Tor");
+            }
+            !tor
+        });
+
+        // a release cached by an earlier installation is used as is, or for
+        // the blocks it has in common with this one
+        if let Some(cached) = cache.clone().filter(|_| resume == 0) {
+            if cached.metadata().is_ok_and(|x| x.len() == file_size as u64) {
+                match verify_local_tarball(sender, &cached, &right_sha256) {
+                    Ok(()) => {
+                        return extract_local_tarball(sender, &cached, file_size, &mount_path)
+                    }
+                    Err(e) => warn!("Not using {}: {e}", cached.display()),
+                }
+            }
+            let seeds = zsync::seeds_for(&cached);
+            // zsync would be reached around Tor
+            if !seeds.is_empty()
+                && zsync::is_usable(&url)
+                && zsync::is_available()
+                && !network::uses_tor(&url)
+            {
+                let res = zsync::download(&format!("{url}.zsync"), &seeds, &cached, |size| {
+                    let count = (size as f64 / file_size as f64 * 100.0) as usize;
+                    let msg = format!("{STEP2} (delta)");
+                    sender
+                        .send(InstallProgress::Pending(msg, count.min(100)))
+                        .ok();
+                });
+                match res {
+                    Ok(()) => {
+                        cues::play(Cue::DownloadFinished);
+                        let res = unpack_local_tarball(
+                            sender,
+                            &cached,
+                            &right_sha256,
+                            file_size,
+                            &mount_path,
+                        );
+                        if res.is_ok() {
+                            cache::prune_cache(&cached);
+                        } else {
+                            std::fs::remove_file(&cached).ok();
+                        }
+                        return res;
+                    }
+                    Err(e) => warn!("{e}, downloading in full instead"),
+                }
+            }
+        }
+
+        if let Some(torrent) = torrent {
+            let torrent = if torrent.contains("://") {
+                torrent
+            } else {
+                format!("{}{}", ctx.config.mirror.as_ref().unwrap().url, torrent)
+            };
+            let tarball = mount_path.join("tarball");
+            let res = torrent::download(&torrent, &tarball, |size| {
+                let count = (size as f64 / file_size as f64 * 100.0) as usize;
+                let msg = format!("{STEP2} (BitTorrent)");
+                sender
+                    .send(InstallProgress::Pending(msg, count.min(100)))
+                    .ok();
+            });
+            match res {
+                Ok(()) => {
+                    cues::play(Cue::DownloadFinished);
+                    let res = unpack_local_tarball(
+                        sender,
+                        &tarball,
+                        &right_sha256,
+                        file_size,
+                        &mount_path,
+                    );
+                    std::fs::remove_file(&tarball).ok();
+                    return res;
+                }
+                Err(e) => {
+                    warn!("{e}, downloading from the mirror instead");
+                    std::fs::remove_file(&tarball).ok();
+                    std::fs::remove_file(mount_path.join("tarball.aria2")).ok();
+                }
+            }
+        }
+
+        let extract_done_copy = extract_done.clone();
+        let download_done_copy = download_done.clone();
+        let (sha256_work_tx, sha256_work_rx) = mpsc::channel();
+        let (get_sha256_tx, get_sha256_rx) = mpsc::channel();
+        let (error_channel_tx, error_channel_rx) = mpsc::channel();
+        let error_channel_tx_copy = error_channel_tx.clone();
+
+        let (speed_tx, speed_rx) = std::sync::mpsc::channel();
+
+        let cc = counter.clone();
+        // a release to be cached has to be written out in full, and so has
+        // one downloaded from several mirrors at once if there is room
+        let concurrent = urls.len() > 1 && has_room && resume == 0;
+        let streaming = install::is_streamable(&url) && cache.is_none() && !concurrent;
+        let concurrent = concurrent && !streaming;
+        let sha256 = right_sha256.clone();
+        let partial = cache.clone();
+
+        let worker = thread::spawn(move || {
+            let tarball_file = cache.clone().unwrap_or_else(|| mount_path.join("tarball"));
+            let mut extractor = None;
+            let mut output = if streaming {
+                // the bounded channel keeps the download from running too far
+                // ahead of the extractor
+                let (chunk_tx, chunk_rx) = mpsc::sync_channel(64);
+                let url = url.clone();
+                let mount_path = mount_path.clone();
+                let error_channel_tx = error_channel_tx_copy.clone();
+                info!("Unpacking {url} while downloading");
+                extractor = Some(thread::spawn(move || {
+                    let mut reader = install::ChannelReader::new(chunk_rx);
+                    // report before the reader is dropped, so that this error
+                    // arrives before the one of the downloader
+                    if let Err(e) = install::extract_stream(&url, &mut reader, &mount_path) {
+                        let e = anyhow!("Installer failed to unpack system release:\n\n{}", e);
+                        error_channel_tx.send(e.to_string()).ok();
+                        return false;
+                    }
+
+                    true
+                }));
+
+                DownloadSink::Stream(chunk_tx)
+            } else {
+                match open_tarball(&tarball_file, resume, &sha256_work_tx) {
+                    Ok(file) => {
+                        info!("tarball file: {:?} is created", tarball_file);
+
+                        DownloadSink::File(file)
+                    }
+                    Err(e) => {
+                        send_error!(error_channel_tx_copy, e);
+                    }
+                }
+            };
+
+            let runtime = match tokio::runtime::Builder::new_multi_thread()
+                .enable_all()
+                .worker_threads(2)
+                .build()
+            {
+                Ok(rt) => rt,
+                Err(e) => {
+                    let e = anyhow!("Failed to create tokio runtime: {e}");
+                    send_error!(error_channel_tx_copy, e);
+                }
+            };
+
+            let client = match network::async_client() {
+                Ok(c) => c,
+                Err(e) => {
+                    let e = anyhow!("Failed to create reqwest client: {e}");
+                    send_error!(error_channel_tx_copy, e);
+                }
+            };
+
+            let tbl_file_c = tarball_file.clone();
+            let resumable = cache.is_some();
+
+            let ccc = cc.clone();
+
+            let error_channel_tx_copy_copy = error_channel_tx_copy.clone();
+
+            let network_options = network::network_options();
+            // give every mirror a chance
+            let retries = network_options.retries.max(urls.len() as u32 - 1);
+
+            runtime.block_on(async move {
+                let mut attempt = 0;
+                let mut resp = loop {
+                    let url = &urls[attempt as usize % urls.len()];
+                    match network::request_from(&client, url, resume as usize).await {
+                        Ok(resp) => break resp,
+                        Err(e) if attempt < retries => {
+                            attempt += 1;
+                            warn!("Failed to request {url}: {e}, retrying ({attempt}/{retries}) ...");
+                            tokio::time::sleep(network::RETRY_DELAY).await;
+                        }
+                        Err(e) => {
+                            send_error!(error_channel_tx_copy, e);
+                        }
+                    }
+                };
+
+                if let (DownloadSink::File(file), 0) = (&output, resume) {
+                    info!("Allocating tarball file: {:?}", &tbl_file_c);
+                    if let Err(e) = rustix::fs::fallocate(
+                        file.as_fd(),
+                        FallocateFlags::empty(),
+                        0,
+                        file_size.try_into().unwrap(),
+                    ) {
+                        let e = anyhow!(
+                            "Installer failed to create temporary file for the download process:\n\n{}",
+                            e
+                        );
+                        send_error!(error_channel_tx_copy, e);
+                    }
+                }
+
+                info!("Flushing tarball_file: {:?}", &tbl_file_c);
+                if let Err(e) = output.flush() {
+                    let e = anyhow!("Installer failed to save system release:\n\n{}\n\nPlease restart your installation environment.", e);
+                    send_error!(error_channel_tx_copy, e);
+                }
+
+                if let (DownloadSink::File(file), true) = (&output, concurrent) {
+                    info!("Downloading from {} mirrors at once", urls.len());
+                    let done = AtomicBool::new(false);
+                    let download = async {
+                        let res = network::download_from_mirrors(&client, &urls, file, file_size, |x| cc.tick(x)).await;
+                        done.store(true, Ordering::SeqCst);
+                        res
+                    };
+                    let report = async {
+                        let mut last = 0;
+                        while !done.load(Ordering::SeqCst) {
+                            tokio::time::sleep(Duration::from_secs(1)).await;
+                            let now = cc.get();
+                            let speed = (now - last) as f64 / 1024.0;
+                            let eta = (file_size - now) as f64 / 1024.0 / speed;
+                            speed_tx.send(calc_speed(speed, eta)).ok();
+                            last = now;
+                        }
+                    };
+                    if let (Err(e), _) = futures::future::join(download, report).await {
+                        send_error!(error_channel_tx_copy, e);
+                    }
+                    info!("Download complete");
+                    download_done_copy.fetch_or(true, Ordering::SeqCst);
+                    drop(speed_tx);
+                    // the pieces arrived out of order, hash them in order
+                    let res = std::fs::File::open(&tbl_file_c)
+                        .map_err(anyhow::Error::from)
+                        .and_then(|mut x| hash_prefix(&mut x, file_size as u64, &sha256_work_tx));
+                    if let Err(e) = res {
+                        send_error!(error_channel_tx_copy, e);
+                    }
+                    return;
+                }
+
+                let mut tarball_size = resume as usize;
+                cc.set(tarball_size);
+
+                let mut timer = tokio::time::Instant::now();
+                let mut tarball_size_1s = 0;
+
+                loop {
+                    if tarball_size == file_size {
+                        info!("Download complete");
+                        if resumable {
+                            cache::clear_progress(&tbl_file_c);
+                        }
+                        download_done_copy.fetch_or(true, Ordering::SeqCst);
+                        break;
+                    }
+                    let chunk = tokio::time::timeout(network_options.read_timeout, resp.chunk()).await;
+                    let e = match chunk {
+                        Ok(Ok(Some(chunk))) => {
+                            let now = timer.elapsed().as_secs_f64();
+                            if now >= 1.0 {
+                                let speed = tarball_size_1s as f64 / 1024.0 / now;
+                                let eta = (file_size - tarball_size) as f64 / 1024.0 / speed;
+                                let (s, s2) = calc_speed(speed, eta);
+                                speed_tx.send((s, s2)).unwrap();
+                                tarball_size_1s = 0;
+                                timer = tokio::time::Instant::now();
+                                // what is written is on disk before it is
+                                // recorded as such
+                                if let (DownloadSink::File(file), true) = (&output, resumable) {
+                                    let res = file.sync_data().map_err(anyhow::Error::from).and_then(|_| {
+                                        cache::save_progress(&tbl_file_c, &sha256, tarball_size as u64)
+                                    });
+                                    if let Err(e) = res {
+                                        warn!("Failed to record download progress: {e}");
+                                    }
+                                }
+                            } else {
+                                tarball_size_1s += chunk.len();
+                            }
+
+                            if let Err(e) = output.write_all(&chunk) {
+                                send_error!(error_channel_tx_copy, e);
+                            }
+                            tarball_size += chunk.len();
+                            cc.set(tarball_size);
+                            sha256_work_tx.send((chunk.to_vec(), chunk.len())).unwrap();
+                            if cfg!(feature = "is_retro") {
+                                // 确保在 Retro 设备上不会因为网速过快，数据来不及写入硬盘导致的 OOM
+                                if let Err(e) = output.flush() {
+                                    send_error!(error_channel_tx_copy, e);
+                                }
+                            }
+                            continue;
+                        }
+                        Ok(Ok(None)) => anyhow!("The remote closed the connection prematurely."),
+                        Ok(Err(e)) => anyhow!(e),
+                        Err(_) => anyhow!("Installer timed out waiting for data from the mirror, please check your network connection."),
+                    };
+
+                    // carry on from where the download stopped, what has
+                    // been written is hashed already
+                    resp = loop {
+                        if attempt >= retries {
+                            send_error!(error_channel_tx_copy, e);
+                        }
+                        attempt += 1;
+                        warn!("Download interrupted at {tarball_size} bytes: {e}, resuming ({attempt}/{retries}) ...");
+                        tokio::time::sleep(network::RETRY_DELAY).await;
+                        let url = &urls[attempt as usize % urls.len()];
+                        match network::request_from(&client, url, tarball_size).await {
+                            Ok(resp) => break resp,
+                            Err(e) => warn!("Failed to resume downloading {url}: {e}"),
+                        }
+                    };
+                }
+            });
+
+            // the download is over and the channel closed with it
+            if let Some(extractor) = extractor {
+                if extractor.join().unwrap_or(false) {
+                    extract_done_copy.fetch_or(true, Ordering::SeqCst);
+                }
+                return;
+            }
+
+            info!("Trying extract tarball file: {:?}", &tarball_file);
+
+            ccc.set(0);
+
+            if let Err(e) =
+                install::extract_file(file_size as f64, url, &tarball_file, &mount_path, ccc)
+            {
+                let e = anyhow!("Installer failed to unpack system release:\n\n{}", e);
+                send_error!(error_channel_tx_copy_copy, e);
+            }
+
+            extract_done_copy.fetch_or(true, Ordering::SeqCst);
+
+            if cache.is_some() {
+                cache::prune_cache(&tarball_file);
+            } else {
+                info!("Trying remove tarball file: {:?}", tarball_file);
+                std::fs::remove_file(tarball_file).ok();
+            }
+        });
+
+        let sha256sum_work = thread::spawn(move || {
+            let mut hasher = Sha256::new();
+            loop {
+                let rx = if let Ok(result) = sha256_work_rx.recv() {
+                    result
+                } else {
+                    // dbg!("sha256sum complete");
+                    get_sha256_tx.send(hasher).unwrap();
+                    return;
+                };
+                let (buf, reader_size) = rx;
+                if let Err(e) = hasher.write_all(&buf[..reader_size]) {
+                    let e = anyhow!(
+                        "Installer failed to calculate checksum for system release:\n\n{}",
+                        e
+                    );
+                    send_error!(error_channel_tx, e);
+                }
+            }
+        });
+
+        let file_size = file_size as f64;
+        let eta = &ctx.eta;
+        let unpack_secs = eta.unpack_secs();
+        let download_started = Instant::now();
+        // Progress update
+        info!("{}", STEP2);
+        loop {
+            // let counter_clone = counter.clone();
+            let tarball_downloaded_size = counter.get() as f64;
+            let count = (tarball_downloaded_size / file_size * 100.0) as usize;
+            if let Ok(err) = error_channel_rx.try_recv() {
+                return Err(anyhow!(err));
+            }
+            let v = speed_rx.recv().ok();
+
+            let download_rate = tarball_downloaded_size / download_started.elapsed().as_secs_f64();
+            let current_left = unpack_secs
+                .filter(|_| download_rate > 0.0)
+                .map(|x| (file_size - tarball_downloaded_size) / download_rate + x);
+            let msg = match (v, eta.remaining(current_left)) {
+                (Some((speed, _)), Some(_)) => {
+                    eta.describe(&format!("{STEP2} ({speed}"), current_left) + ")"
+                }
+                (Some((speed, eta)), None) => format!("{STEP2} ({speed}, {eta})"),
+                (None, _) => STEP2.to_string(),
+            };
+
+            sender.send(InstallProgress::Pending(msg, count))?;
+            std::thread::sleep(refresh_interval);
+            if download_done.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+        let mut fake_counter = 0;
+
+        cues::play(Cue::DownloadFinished);
+        let download_finished = Instant::now();
+        info!("{}", STEP3);
+        loop {
+            sender.send(InstallProgress::Pending(STEP3.to_string(), fake_counter))?;
+            std::thread::sleep(refresh_interval);
+            if let Ok(hasher) = get_sha256_rx.try_recv() {
+                let final_hash = hex::encode(hasher.finalize());
+                if final_hash != right_sha256 {
+                    // do not resume a corrupted download on the next run
+                    if let Some(cached) = partial {
+                        cache::clear_progress(&cached);
+                        std::fs::remove_file(&cached).ok();
+                    }
+                    return Err(anyhow!(
+                        "Installer detected a checksum mismatch in downloaded system release.\n\nExpected hash: {}\n\nCalculated hash: {}",
+                        right_sha256,
+                        final_hash
+                    ));
+                }
+                break;
+            }
+            fake_counter += 1;
+            if fake_counter == 100 {
+                fake_counter = 0;
+            }
+        }
+
+        info!("{}", STEP4);
+        let unpack_started = Instant::now();
+        loop {
+            if let Ok(err) = error_channel_rx.try_recv() {
+                return Err(anyhow!(err));
+            }
+            let tarball_unpack_size = counter.get() as f64;
+            let count = (tarball_unpack_size / file_size * 100.0) as usize;
+            let rate = tarball_unpack_size / unpack_started.elapsed().as_secs_f64();
+            let current_left = Some(rate)
+                .filter(|x| *x > 0.0)
+                .map(|x| (file_size - tarball_unpack_size) / x);
+            sender.send(InstallProgress::Pending(
+                eta.describe(STEP4, current_left),
+                count,
+            ))?;
+            std::thread::sleep(refresh_interval);
+            if extract_done.load(Ordering::SeqCst) {
+                break;
+            }
+        }
+
+        // GC the worker thread
+        worker.join().unwrap();
+        sha256sum_work.join().unwrap();
+
+        ctx.eta
+            .set_unpack_secs(download_finished.elapsed().as_secs_f64());
+
+        Ok(())
+    }
+}
+
+/// Space available in `dir` and required there for a `tarball_size` bytes
+/// download, and the `install_size` bytes it unpacks to if `mount_path` is
+/// on the same filesystem
+fn tarball_space(
+    dir: &Path,
+    mount_path: &Path,
+    tarball_size: u64,
+    install_size: u64,
+) -> Result<(u64, u64)> {
+    let available = disks::available_space(dir)?;
+    let same_fs = std::fs::metadata(dir)?.dev() == std::fs::metadata(mount_path)?.dev();
+    let required = if same_fs {
+        tarball_size + install_size
+    } else {
+        tarball_size
+    };
+
+    Ok((available, required))
+}
+
+/// Verify and unpack a release that is already on this machine
+fn unpack_local_tarball(
+    sender: &Sender<InstallProgress>,
+    tarball: &Path,
+    sha256sum: &str,
+    file_size: usize,
+    mount_path: &Path,
+) -> Result<()> {
+    verify_local_tarball(sender, tarball, sha256sum)?;
+    extract_local_tarball(sender, tarball, file_size, mount_path)
+}
+
+/// Open `path` to download a release into, keeping the first `resume` bytes
+/// written by an earlier run and passing them on to be hashed
+fn open_tarball(
+    path: &Path,
+    resume: u64,
+    sha256_work_tx: &Sender<(Vec<u8>, usize)>,
+) -> Result<std::fs::File> {
+    if resume == 0 {
+        return Ok(std::fs::File::create(path)?);
+    }
+
+    let mut file = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(path)?;
+    hash_prefix(&mut file, resume, sha256_work_tx)?;
+    file.seek(SeekFrom::Start(resume))?;
+
+    Ok(file)
+}
+
+/// Pass the first `len` bytes of `file` on to be hashed
+fn hash_prefix(
+    file: &mut std::fs::File,
+    len: u64,
+    sha256_work_tx: &Sender<(Vec<u8>, usize)>,
+) -> Result<()> {
+    let mut left = len as usize;
+    let mut buf = vec![0; 1024 * 1024];
+    while left > 0 {
+        let len = left.min(buf.len());
+        file.read_exact(&mut buf[..len])?;
+        sha256_work_tx.send((buf[..len].to_vec(), len)).ok();
+        left -= len;
+    }
+
+    Ok(())
+}
+
+fn verify_local_tarball(
+    sender: &Sender<InstallProgress>,
+    tarball: &Path,
+    sha256sum: &str,
+) -> Result<()> {
+    sender.send(InstallProgress::Pending(STEP3.to_string(), 0))?;
+    info!("{}", STEP3);
+    let final_hash = network::sha256_file(tarball)?;
+    if final_hash != sha256sum {
+        return Err(anyhow!(
+            "Installer detected a checksum mismatch in downloaded system release.\n\nExpected hash: {}\n\nCalculated hash: {}",
+            sha256sum,
+            final_hash
+        ));
+    }
+
+    Ok(())
+}
+
+fn extract_local_tarball(
+    sender: &Sender<InstallProgress>,
+    tarball: &Path,
+    file_size: usize,
+    mount_path: &Path,
+) -> Result<()> {
+    info!("{}", STEP4);
+    let counter = Counter::new(0);
+    let extract_done = Arc::new(AtomicBool::new(false));
+    let worker = {
+        let counter = counter.clone();
+        let extract_done = extract_done.clone();
+        let tarball = tarball.to_path_buf();
+        let mount_path = mount_path.to_path_buf();
+        thread::spawn(move || {
+            let res = install::extract_file(
+                file_size as f64,
+                tarball.to_string_lossy().to_string(),
+                &tarball,
+                &mount_path,
+                counter,
+            );
+            extract_done.store(true, Ordering::SeqCst);
+
+            res
+        })
+    };
+
+    while !extract_done.load(Ordering::SeqCst) {
+        let count = (counter.get() as f64 / file_size as f64 * 100.0) as usize;
+        sender.send(InstallProgress::Pending(STEP4.to_string(), count))?;
+        std::thread::sleep(std::time::Duration::from_millis(30));
+    }
+
+    worker
+        .join()
+        .map_err(|_| anyhow!("Installer failed to unpack system release."))?
+        .map_err(|e| anyhow!("Installer failed to unpack system release:\n\n{}", e))
+}
+
+/// Copy the running live system to `mount_path`, the progress being
+/// estimated from the space taken up there
+fn copy_live_system(
+    sender: &Sender<InstallProgress>,
+    install_size: u64,
+    mount_path: &Path,
+) -> Result<()> {
+    info!("{}", STEP4);
+    let available = disks::available_space(mount_path)?;
+    let copy_done = Arc::new(AtomicBool::new(false));
+    let worker = {
+        let copy_done = copy_done.clone();
+        let mount_path = mount_path.to_path_buf();
+        thread::spawn(move || {
+            let res = install::copy_live_system(&mount_path);
+            copy_done.store(true, Ordering::SeqCst);
+
+            res
+        })
+    };
+
+    while !copy_done.load(Ordering::SeqCst) {
+        let copied =
+            available.saturating_sub(disks::available_space(mount_path).unwrap_or(available));
+        let count = (copied as f64 / install_size.max(1) as f64 * 100.0) as usize;
+        sender.send(InstallProgress::Pending(STEP4.to_string(), count.min(100)))?;
+        std::thread::sleep(std::time::Duration::from_millis(200));
+    }
+
+    worker
+        .join()
+        .map_err(|_| anyhow!("Installer failed to copy the live system."))?
+        .map_err(|e| anyhow!("Installer failed to copy the live system:\n\n{}", e))
+}
+
+struct FstabStep;
+
+impl InstallStep for FstabStep {
+    fn name(&self) -> &'static str {
+        "fstab"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        let partition = ctx.root_partition()?;
+
+        info!("Generating fstab ...");
+        let mount_options = &ctx.config.mount_options;
+        let source = ctx.config.fstab_source;
+        if install::is_btrfs(&partition) {
+            for (subvol, path) in disks::BTRFS_SUBVOLUMES {
+                // a separate /home partition takes the place of @home
+                if *path == "/home" && ctx.config.home.is_some() {
+                    continue;
+                }
+                install::genfstab_to_file(
+                    &partition,
+                    &ctx.tempdir,
+                    Path::new(path),
+                    mount_options,
+                    Some(subvol),
+                    source,
+                )?;
+            }
+        } else if zfs::is_zfs(&partition) {
+            // datasets are mounted by zfs itself
+            zfs::copy_hostid(&ctx.tempdir)?;
+        } else {
+            install::genfstab_to_file(
+                &partition,
+                &ctx.tempdir,
+                Path::new("/"),
+                mount_options,
+                None,
+                source,
+            )?;
+        }
+
+        if let Some(home) = &ctx.config.home {
+            info!("Generating fstab entry for /home ...");
+            install::genfstab_to_file(
+                home,
+                &ctx.tempdir,
+                Path::new("/home"),
+                mount_options,
+                None,
+                source,
+            )?;
+        }
+
+        for mount in &ctx.config.extra_mounts {
+            info!(
+                "Generating fstab entry for {} ...",
+                mount.mount_point.display()
+            );
+            install::genfstab_to_file(
+                &mount.partition,
+                &ctx.tempdir,
+                &mount.mount_point,
+                mount_options,
+                None,
+                source,
+            )?;
+        }
+
+        if let Some(swap) = &ctx.swap_partition {
+            info!("Generating fstab entry for swap partition ...");
+            install::genfstab_to_file(
+                swap,
+                &ctx.tempdir,
+                Path::new("none"),
+                mount_options,
+                None,
+                source,
+            )?;
+        }
+
+        if ctx.is_raid()? {
+            info!("Generating mdadm.conf ...");
+            install::write_mdadm_conf(&ctx.tempdir)?;
+        }
+
+        if ctx.root_volume.is_some() {
+            info!("Generating crypttab ...");
+            crypt::write_crypttab(&ctx.partition()?, &ctx.tempdir)?;
+        } else if let Some((name, device)) = ctx.opened_container()? {
+            info!("Generating crypttab for {name} ...");
+            crypt::write_crypttab_entry(&name, &device, &ctx.tempdir)?;
+        }
+
+        if disks::is_efi_booted() {
+            info!("Generating fstab efi entry...");
+            let esp_part = ctx.esp_partition()?;
+            let esp_mount = ctx.config.esp_mount_point.path();
+            install::genfstab_to_file(
+                &esp_part,
+                &ctx.tempdir,
+                esp_mount,
+                mount_options,
+                None,
+                source,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Carry the Wi-Fi networks, DNS settings and the proxy of the live session
+/// over, while both systems can be seen
+struct LiveNetworkStep;
+
+impl InstallStep for LiveNetworkStep {
+    fn name(&self) -> &'static str {
+        "live-network"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        if ctx.config.skip_live_network {
+            return Ok(());
+        }
+
+        install::copy_live_network(&ctx.mount_path()?, network::target_proxy().as_deref())
+    }
+}
+
+struct EnterChrootStep;
+
+impl InstallStep for EnterChrootStep {
+    fn name(&self) -> &'static str {
+        "chroot"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        let mount_path = ctx.mount_path()?;
+
+        ctx.host_resolv_conf = std::fs::read("/etc/resolv.conf").ok();
+        info!("Chroot to installed system ...");
+        ctx.escape_vector = Some(install::get_dir_fd(Path::new("/"))?);
+        install::dive_into_guest(&mount_path)
+    }
+
+    fn rollback(&self, ctx: &mut InstallContext) {
+        if let Some(escape_vector) = ctx.escape_vector.take() {
+            install::escape_chroot(escape_vector).ok();
+        }
+        if let Some(mount_path) = &ctx.mount_path {
+            install::remove_bind_mounts(mount_path).ok();
+        }
+    }
+}
+
+struct InitramfsStep;
+
+impl InstallStep for InitramfsStep {
+    fn name(&self) -> &'static str {
+        "initramfs"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        ctx.pending(STEP5)?;
+        install::write_dracut_conf(&ctx.config.expert.dracut_conf)?;
+        if ctx.root_volume.is_some() || ctx.opened_container()?.is_some() {
+            crypt::write_dracut_conf()?;
+        } else if ctx.logical_volume()?.is_some() {
+            install::write_lvm_dracut_conf()?;
+        }
+        if ctx.is_raid()? {
+            install::write_mdraid_dracut_conf()?;
+        }
+        if zfs::is_zfs(&ctx.root_partition()?) {
+            info!("Installing ZFS packages ...");
+            install::install_packages(zfs::ZFS_PACKAGES, ctx.host_resolv_conf.as_deref())?;
+            zfs::write_dracut_conf()?;
+        }
+
+        info!("Running dracut ...");
+        install::execute_dracut()
+    }
+}
+
+struct BootloaderStep;
+
+impl InstallStep for BootloaderStep {
+    fn name(&self) -> &'static str {
+        "bootloader"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        ctx.pending(STEP6)?;
+        let expert = &ctx.config.expert;
+        let esp_mount = ctx.config.esp_mount_point.path();
+        let mut cmdline = expert.kernel_cmdline.clone();
+        if ctx.root_volume.is_some() {
+            let with_swap = ctx
+                .swap_partition
+                .as_ref()
+                .is_some_and(|x| x.path == Some(crypt::swap_volume_path()));
+            cmdline = format!(
+                "{} {cmdline}",
+                crypt::kernel_cmdline(&ctx.partition()?, with_swap)?
+            );
+        } else if let Some((vg, lv)) = ctx.logical_volume()? {
+            cmdline = format!("rd.lvm.lv={vg}/{lv} {cmdline}");
+        }
+        let opened_container = ctx.opened_container()?;
+        if let Some((_, device)) = &opened_container {
+            cmdline = format!("{} {cmdline}", crypt::opened_kernel_cmdline(device)?);
+        }
+        if let Some(uuid) = ctx
+            .swap_partition
+            .as_ref()
+            .and_then(|x| x.path.as_deref())
+            .and_then(disks::partition_uuid)
+        {
+            cmdline = format!("resume=UUID={uuid} {cmdline}");
+        } else if ctx.config.is_hibernation.v.load(Ordering::SeqCst)
+            && ctx.config.use_swap.v.load(Ordering::SeqCst)
+        {
+            let root = ctx.root_partition()?;
+            let swapfile = Path::new("/").join(ctx.swapfile()?);
+            match install::swapfile_resume_offset(&swapfile, install::is_btrfs(&root)) {
+                Ok(offset) => {
+                    if let Some(uuid) = root.path.as_deref().and_then(disks::partition_uuid) {
+                        cmdline = format!("resume=UUID={uuid} resume_offset={offset} {cmdline}");
+                    }
+                }
+                Err(e) => warn!("{e}, hibernation will not be available"),
+            }
+        }
+        if ctx.config.bootloader == install::Bootloader::Efistub {
+            if ctx.config.image.is_some() {
+                return Err(anyhow!(
+                    "Installer can not create EFISTUB boot entries for disk images."
+                ));
+            }
+            // grub-mkconfig finds out the subvolume by itself
+            if install::is_btrfs(&ctx.root_partition()?) {
+                cmdline = format!("rootflags=subvol=@ {cmdline}");
+            }
+            info!("Creating EFISTUB boot entry ...");
+            install::install_efistub(
+                &ctx.root_partition()?,
+                &ctx.esp_partition()?,
+                esp_mount,
+                &cmdline,
+            )?;
+            info!("Verifying EFISTUB boot entry ...");
+            return install::verify_efistub(esp_mount);
+        }
+
+        install::set_grub_defaults(&cmdline, expert.grub_timeout)?;
+        if ctx.root_volume.is_some() || opened_container.is_some() {
+            info!("Enabling GRUB cryptodisk support ...");
+            install::enable_grub_cryptodisk()?;
+        }
+        let partition = ctx.partition()?;
+        let targets = install::grub_targets(
+            &partition,
+            ctx.config.btrfs_mirror.as_deref(),
+            ctx.config.image.is_some(),
+        );
+        for target in &targets {
+            match target {
+                install::GrubTarget::Image => {
+                    info!("Installing grub to the removable media path of the disk image ...");
+                    return install::execute_grub_install_image(esp_mount);
+                }
+                install::GrubTarget::Esp => {
+                    info!("Installing grub to UEFI partition ...");
+                    install::execute_grub_install(None, esp_mount)?;
+                }
+                install::GrubTarget::EspRemovable => {
+                    info!("Installing grub to the removable media path for eMMC ...");
+                    install::execute_grub_install_removable(esp_mount)?;
+                }
+                install::GrubTarget::Mbr(mbr_dev) => {
+                    info!("Installing grub to MBR of {} ...", mbr_dev.display());
+                    install::execute_grub_install(Some(mbr_dev), esp_mount)?;
+
+                    info!("Verifying bootloader installation ...");
+                    install::verify_grub_install(Some(mbr_dev), esp_mount)?;
+                }
+            }
+        }
+        if disks::is_efi_booted() {
+            if let Some(mirror) = &ctx.config.btrfs_mirror {
+                warn!(
+                    "The EFI system partition is not mirrored, {:?} can not boot on its own",
+                    mirror.parent_path
+                );
+            }
+
+            info!("Verifying bootloader installation ...");
+            return install::verify_grub_install(None, esp_mount);
+        }
+
+        Ok(())
+    }
+}
+
+struct SshKeyStep;
+
+impl InstallStep for SshKeyStep {
+    fn name(&self) -> &'static str {
+        "ssh-keys"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        ctx.pending(STEP7)?;
+
+        info!("Generating SSH key ...");
+        install::gen_ssh_key()
+    }
+}
+
+/// Install and enable the optional software picked by the user
+struct PackagesStep;
+
+impl InstallStep for PackagesStep {
+    fn name(&self) -> &'static str {
+        "packages"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        let mut packages = vec![];
+        let mut services = vec![];
+        if let Some(power_management) = ctx.config.power_management {
+            packages.push(power_management.package());
+            services.push(power_management.service());
+        }
+        if ctx.config.optenv32 {
+            packages.push(install::OPTENV32_PACKAGE);
+        }
+        if ctx.config.directory.is_some() {
+            packages.push("sssd");
+        }
+        if ctx.config.zram {
+            packages.push(install::ZRAM_PACKAGE);
+        }
+
+        if packages.is_empty() {
+            return Ok(());
+        }
+
+        ctx.pending(STEP8)?;
+        info!("Installing additional packages: {}", packages.join(", "));
+        install::install_packages(&packages, ctx.host_resolv_conf.as_deref())?;
+        for service in services {
+            info!("Enabling {service}");
+            install::enable_service(service)?;
+        }
+        if ctx.config.zram {
+            info!("Configuring zram ...");
+            install::write_zram_generator_conf()?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Enroll the installed system into an LDAP directory
+struct DirectoryStep;
+
+impl InstallStep for DirectoryStep {
+    fn name(&self) -> &'static str {
+        "directory"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        if let Some(directory) = &ctx.config.directory {
+            info!(
+                "Configuring SSSD for {} ({})",
+                directory.uri, directory.base_dn
+            );
+            install::configure_sssd(directory)?;
+            install::enable_service("sssd.service")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Give the installed system a fixed address with systemd-networkd
+struct StaticNetworkStep;
+
+impl InstallStep for StaticNetworkStep {
+    fn name(&self) -> &'static str {
+        "network"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        if let Some(network) = &ctx.config.static_network {
+            info!("Configuring static network: {network}");
+            install::configure_networkd(network)?;
+            install::enable_service("systemd-networkd.service")?;
+            if !network.dns.is_empty() {
+                install::enable_service("systemd-resolved.service")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+struct ConfigureSystemStep;
+
+impl InstallStep for ConfigureSystemStep {
+    fn name(&self) -> &'static str {
+        "configure"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        ctx.pending(STEP8)?;
+        let config = &ctx.config;
+
+        if config.use_swap.v.load(Ordering::SeqCst) {
+            info!("Generating swapfile entry to fstab");
+            install::write_swap_entry_to_fstab(ctx.swapfile()?)?;
+        }
+
+        let tz = config.timezone.as_ref().unwrap();
+        info!("Setting timezone as {}", tz);
+        install::set_zoneinfo(tz)?;
+
+        let tc = config.tc.as_ref().unwrap();
+        info!("Setting hwclock (hardware clock) as {}", tc);
+        install::set_hwclock_tc(match tc.as_str() {
+            "UTC" => true,
+            "RTC" => false,
+            _ => true,
+        })?;
+
+        let hostname = config.hostname.as_ref().unwrap();
+        info!("Setting hostname as {}", hostname);
+        install::set_hostname(hostname)?;
+
+        info!("Setting username and password ...");
+        let user = config.user.as_ref().unwrap();
+        install::add_new_user(
+            user,
+            config.password.as_ref().unwrap(),
+            &config.expert.extra_groups,
+        )?;
+
+        if cfg!(feature = "is_retro") {
+            install::chpasswd("root", config.root_password.as_ref().unwrap())?;
+        }
+
+        info!("Setting fullname ...");
+        if let Some(full_name) = config.full_name.as_ref().filter(|x| !x.is_empty()) {
+            install::passwd_set_fullname(full_name, user)?;
+        }
+
+        let locale = config.locale.as_ref().unwrap();
+        info!("Setting locale as {}", locale);
+        install::set_locale(locale)?;
+
+        info!("Verifying installed system ...");
+        install::verify_installation(config.variant.as_ref().unwrap())
+    }
+}
+
+struct FinishStep;
+
+impl InstallStep for FinishStep {
+    fn name(&self) -> &'static str {
+        "finish"
+    }
+
+    fn run(&self, ctx: &mut InstallContext) -> Result<()> {
+        let mount_path = ctx.mount_path()?;
+
+        info!("Escaping chroot ...");
+        if let Some(escape_vector) = ctx.escape_vector.take() {
+            install::escape_chroot(escape_vector)?;
+        }
+
+        if disks::is_efi_booted() {
+            info!("Unmounting EFI partition ...");
+            install::umount_root_path(&mount_path.join(ctx.config.esp_mount_point.relative()))?;
+        }
+
+        info!("Copy log file to main partition");
+        let logfile = LOG_FILE.get().unwrap();
+        std::fs::copy(
+            logfile,
+            ctx.tempdir.join("var").join("log").join(
+                logfile
+                    .file_name()
+                    .ok_or_else(|| anyhow!("Can not get filename"))?,
+            ),
+        )?;
+
+        info!("Saving installation report ...");
+        let report = report::generate(
+            &ctx.config,
+            ctx.eta.finished(),
+            time::OffsetDateTime::now_utc(),
+        );
+        report::save(&report, &ctx.tempdir.join(report::REPORT_FILE));
+        if let Some(path) = &ctx.config.report_copy {
+            report::save(&report, path);
+        }
+
+        info!("Removing bind mounts ...");
+        install::remove_bind_mounts(&mount_path)?;
+
+        info!("Trying to swapoff ...");
+        install::swapoff(&ctx.tempdir);
+        if let Some(partition) = &ctx.swap_partition {
+            install::swapoff_partition(partition);
+        }
+
+        if ctx.config.btrfs_snapshot && install::is_btrfs(&ctx.root_partition()?) {
+            info!("Taking post-installation snapshot ...");
+            install::snapshot_btrfs_root(&mount_path, "post-install")?;
+        }
+
+        info!("Unmounting main partition ...");
+        umount_extra_partitions(&ctx.config, &mount_path);
+        install::umount_btrfs_subvolumes(&mount_path);
+        install::umount_root_path(&mount_path).ok();
+        zfs::export()?;
+
+        if ctx.root_volume.is_some() {
+            crypt::close();
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+struct RecordStep {
+    name: &'static str,
+    fail: bool,
+    log: Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+#[cfg(test)]
+impl InstallStep for RecordStep {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn run(&self, _ctx: &mut InstallContext) -> Result<()> {
+        self.log.lock().unwrap().push(format!("run {}", self.name));
+        if self.fail {
+            return Err(anyhow!("{} failed", self.name));
+        }
+
+        Ok(())
+    }
+
+    fn rollback(&self, _ctx: &mut InstallContext) {
+        self.log
+            .lock()
+            .unwrap()
+            .push(format!("rollback {}", self.name));
+    }
+}
+
+#[cfg(test)]
+fn record_steps(
+    names: &[&'static str],
+    fail: Option<&str>,
+) -> (
+    Vec<Box<dyn InstallStep>>,
+    Arc<std::sync::Mutex<Vec<String>>>,
+) {
+    let log = Arc::new(std::sync::Mutex::new(vec![]));
+    let steps = names
+        .iter()
+        .map(|name| {
+            Box::new(RecordStep {
+                name: *name,
+                fail: fail == Some(*name),
+                log: log.clone(),
+            }) as Box<dyn InstallStep>
+        })
+        .collect();
+
+    (steps, log)
+}
+
+#[test]
+fn test_run_steps_in_order() {
+    let (steps, log) = record_steps(&["format", "mount", "download"], None);
+    let mut ctx = InstallContext::new(mpsc::channel().0, InstallConfig::default(), PathBuf::new());
+
+    assert!(run_steps(&steps, &mut ctx).is_ok());
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec!["run format", "run mount", "run download"]
+    );
+}
+
+#[test]
+fn test_run_steps_rollback() {
+    let (steps, log) = record_steps(&["format", "mount", "download", "fstab"], Some("download"));
+    let mut ctx = InstallContext::new(mpsc::channel().0, InstallConfig::default(), PathBuf::new());
+
+    assert!(run_steps(&steps, &mut ctx).is_err());
+    assert_eq!(
+        *log.lock().unwrap(),
+        vec![
+            "run format",
+            "run mount",
+            "run download",
+            "rollback download",
+            "rollback mount",
+            "rollback format",
+        ]
+    );
+}
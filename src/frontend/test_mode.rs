@@ -0,0 +1,130 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use anyhow::{anyhow, Result};
+use log::{error, info};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+
+use crate::{
+    disks::{self, DkDerive, Partition},
+    network::{self, Mirror, VariantEntry},
+    LOG_FILE,
+};
+
+const TEST_IMAGE_PATH: &str = "/var/tmp/deploykit-test.img";
+/// The image is sparse, so only what is actually written takes up space
+const TEST_IMAGE_SIZE: u64 = 16 * 1024 * 1024 * 1024;
+
+struct TestMode {
+    fixture: PathBuf,
+    result: PathBuf,
+}
+
+static TEST_MODE: OnceCell<TestMode> = OnceCell::new();
+static TEST_DEVICE: OnceCell<PathBuf> = OnceCell::new();
+
+/// Install to a loop device with `fixture` instead of a downloaded release,
+/// and write the outcome to `result`
+pub(super) fn enable(fixture: PathBuf, result: PathBuf) {
+    TEST_MODE.set(TestMode { fixture, result }).ok();
+}
+
+pub(super) fn is_enabled() -> bool {
+    TEST_MODE.get().is_some()
+}
+
+fn test_mode() -> Result<&'static TestMode> {
+    TEST_MODE
+        .get()
+        .ok_or_else(|| anyhow!("Installer is not running in test mode."))
+}
+
+/// The fixture tarball, offered as the only variant
+pub(super) fn fixture_variant() -> Result<VariantEntry> {
+    let fixture = fs::canonicalize(&test_mode()?.fixture)?;
+    let size = fs::metadata(&fixture)?.len();
+
+    Ok(VariantEntry {
+        name: "Test".to_string(),
+        size,
+        install_size: size * 4,
+        date: "19700101".to_string(),
+        sha256sum: network::sha256_file(&fixture)?,
+        url: format!("file://{}", fixture.display()),
+        description: "Test fixture".to_string(),
+        sendstream: None,
+        metalink: None,
+        torrent: None,
+    })
+}
+
+/// The fixture is a local file, so the mirror does not add anything to its URL
+pub(super) fn fixture_mirror() -> Mirror {
+    let s = "test_mode";
+
+    Mirror {
+        name: s.to_string(),
+        name_tr: s.to_string(),
+        loc: s.to_string(),
+        loc_tr: s.to_string(),
+        url: String::new(),
+    }
+}
+
+/// Attach a blank disk image to a loop device, this is only done once
+pub(super) fn test_device() -> Result<DkDerive> {
+    let path = TEST_DEVICE
+        .get_or_try_init(|| disks::attach_image(Path::new(TEST_IMAGE_PATH), TEST_IMAGE_SIZE))?;
+
+    Ok(DkDerive {
+        path: path.clone(),
+        model: "DeployKit test image".to_string(),
+        size: TEST_IMAGE_SIZE,
+    })
+}
+
+/// Partition the test device from scratch and return the system partition
+pub(super) fn test_partition() -> Result<Partition> {
+    disks::auto_create_partitions(&test_device()?.path)
+}
+
+#[derive(Serialize)]
+struct TestResult<'a> {
+    success: bool,
+    error: Option<String>,
+    duration_secs: f64,
+    device: Option<&'a Path>,
+    log: Option<&'a Path>,
+}
+
+/// Write the outcome of the installation as JSON for CI to pick up
+pub(super) fn write_result(res: &Result<()>, started: Instant) {
+    let test_mode = match test_mode() {
+        Ok(test_mode) => test_mode,
+        Err(_) => return,
+    };
+
+    let result = TestResult {
+        success: res.is_ok(),
+        error: res.as_ref().err().map(|e| e.to_string()),
+        duration_secs: started.elapsed().as_secs_f64(),
+        device: TEST_DEVICE.get().map(|x| x.as_path()),
+        log: LOG_FILE.get().map(|x| x.as_path()),
+    };
+
+    let res = serde_json::to_string_pretty(&result)
+        .map_err(anyhow::Error::from)
+        .and_then(|x| Ok(fs::write(&test_mode.result, x)?));
+
+    match res {
+        Ok(()) => info!(
+            "Test mode: result written to {}",
+            test_mode.result.display()
+        ),
+        Err(e) => error!("Test mode: failed to write result: {e}"),
+    }
+}
@@ -1,30 +1,34 @@
 use crate::{
+    crypt,
     disks::{
         self, auto_create_partitions, device_is_empty, is_efi_booted, DkDerive, ALLOWED_FS_TYPE,
     },
-    install::{self, find_language_by_locale, find_locale_by_language, read_locale, umount_all},
+    install::{
+        self, find_language_by_locale, find_locale_by_language, read_locale, umount_all,
+        WatchdogAction,
+    },
     network::{self, Mirror, VariantEntry},
-    LOG_FILE,
+    preflight, torrent, wifi, LOG_FILE,
 };
 use anyhow::Result;
 use cursive::{
-    event::Event,
-    view::Selector,
+    event::{Event, Key},
+    view::{ScrollStrategy, Selector},
     views::{
-        Dialog, DummyView, EditView, LinearLayout, ListView, NamedView, Panel, ProgressBar,
-        RadioGroup, ResizedView, ScrollView, SelectView, TextContent, TextView,
+        Checkbox, Dialog, DummyView, EditView, LinearLayout, ListView, NamedView, Panel,
+        ProgressBar, RadioGroup, ResizedView, ScrollView, SelectView, TextContent, TextView,
     },
 };
 use cursive::{traits::*, utils::Counter};
 use cursive::{view::SizeConstraint, views::Button};
-use cursive::{Cursive, View};
+use cursive::{CbSink, Cursive, View};
 use cursive_async_view::AsyncView;
 use cursive_table_view::{TableView, TableViewItem};
-use libparted::Device;
-use log::{error, info};
+use log::{error, info, warn, LevelFilter};
 use number_prefix::NumberPrefix;
 use send_wrapper::SendWrapper;
 use std::rc::Rc;
+use std::time::Duration;
 use std::{cell::RefCell, path::Path, sync::Arc, thread};
 use std::{env, fs, io::Read, path::PathBuf};
 use std::{
@@ -33,14 +37,25 @@ use std::{
 };
 
 use super::{
-    begin_install, games::add_main_callback, AtomicBoolWrapper, InstallConfig, DEFAULT_EMPTY_SIZE,
+    begin_install,
+    cues::{self, Cue},
+    free_space_margin,
+    games::add_main_callback,
+    preview, required_space, save_user_config_to_file, test_mode, AtomicBoolWrapper, InstallConfig,
+    DEFAULT_FREE_SPACE_PERCENT, LAST_USER_CONFIG_FILE, TUI_ACTIVE,
 };
 
-const LAST_USER_CONFIG_FILE: &str = "/tmp/deploykit-config.json";
+/// Offer advanced settings that most users do not need
+static EXPERT_MODE: AtomicBool = AtomicBool::new(false);
+
 const SAVE_USER_CONFIG_FILE: &str = "/root/deploykit-config.json";
 const NO_ESP_ERROR: &str = r"Error: Installer has detected that you are installing AOSC OS on an EFI/UEFI system, but could not detect a supported EFI System Partition (ESP) on your storage devices.
 
 In order to continue installing AOSC OS, you would need to create an EFI System Partition (ESP) on a GPT partition map, formatted as a FAT32 filesystem.";
+const MISALIGNED_TEXT: &str = "The selected partition does not start on a 1MiB boundary, which may severely degrade performance on SSDs and disks with 4K sectors. Installer can move the start of the partition to the next 1MiB boundary before formatting it.";
+const NO_BIOS_GRUB_ERROR: &str = "The selected disk uses a GPT partition map, on which GRUB requires a BIOS boot partition to boot on BIOS systems, but there is neither one nor 1MiB of free space to create one.\n\nPlease create a 1MiB partition with the bios_grub flag on this disk, or shrink a partition to make room for it.";
+const CREATE_BIOS_GRUB_TEXT: &str = "The selected disk uses a GPT partition map, on which GRUB requires a BIOS boot partition to boot on BIOS systems. Installer can create a 1MiB BIOS boot partition in the free space on this disk.";
+const CREATE_ESP_TEXT: &str = "Installer could not detect an EFI System Partition (ESP) on your storage devices, which is required to boot AOSC OS on an EFI/UEFI system. Installer can create a 512MiB ESP, formatted as FAT32, on the disk you are installing to.";
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 enum VariantColumn {
@@ -81,25 +96,55 @@ macro_rules! SURE_FS_TYPE_INFO {
     };
 }
 
+macro_rules! WIPE_DISK_WARNING {
+    () => {
+        "You are about to wipe {}.\n\nEVERY FILE AND PARTITION ON THIS DRIVE WILL BE PERMANENTLY ERASED, INCLUDING OTHER OPERATING SYSTEMS. Erased data can NOT be recovered by any means.\n\nPlease make sure that you have selected the correct drive and backed up everything you need."
+    };
+}
+
 macro_rules! SURE_FS_FORMAT_INFO {
     () => {
         "Installer has detected an existing file system on the specified partition, {}. Please consider verifying if there is data in this partition that is yet to be backed up.\n\nAfter the final confirmation, coming up in a few steps, Installer will format this partition as {}. "
     };
 }
 
+const RECOVERY_KEY_FILE: &str = "/root/aosc-recovery-key.txt";
+const RECOVERY_KEY_TEXT: &str = "Below is the recovery key of your encrypted system partition. It unlocks the partition in place of your passphrase, should you forget it. Please write it down or save it to a file, and keep it somewhere safe, away from this device.";
+const ENCRYPTION_TEXT: &str = "Please enter the passphrase to encrypt the system partition with. It will be asked for every time this device boots, and data on this partition can not be recovered without it.";
+const POWER_MANAGEMENT_TEXT: &str = "Installer has detected that this device is a laptop. Would you like to install a power management service to extend battery life?";
+const OPTENV32_TEXT: &str = "Would you like to install the 32-bit compatibility runtime (optenv32)? It is needed by Steam, Wine and other 32-bit applications, and is a large download to make after installation.";
+const STATIC_NETWORK_TEXT: &str = "By default, the installed system gets its address from your network (DHCP). Servers may need a fixed address instead, which is brought up by systemd-networkd on first boot. The address includes the prefix length, e.g. 192.168.1.10/24. DNS servers are separated by spaces.";
+const DIRECTORY_TEXT: &str = "Installer can set up SSSD so that users from your organization's LDAP directory can log in on first boot. Leave this alone unless your administrator told you otherwise.";
+const SHRINK_PARTITION_TEXT: &str = "Shrinking a partition makes room for AOSC OS next to another operating system. Please back up your files before continuing. If this is a Windows partition, please turn off Fast Startup (hibernation) in Windows first.";
+const SHRINK_DONE_TEXT: &str = "The partition has been shrunk. Please select \"Use Free Space\" to create a partition in the free space for AOSC OS, and then select it as the system partition.";
+const SELECT_SWAP_PARTITION_TEXT: &str = "Please select a partition to swap to. Existing partitions will be formatted, all data on them will be lost.";
+const BTRFS_MIRROR_TEXT: &str = "You may mirror the system partition to a partition on another disk (btrfs RAID1), so that your system and files survive the failure of either disk. The selected partition will be formatted as well, and the usable space is that of the smaller partition. If one of the disks fails, you will need to add \"rootflags=degraded\" to the kernel command line to boot the system.";
+const GPT_BACKUP_TEXT: &str = "The backup copy of the GPT partition table on this disk is damaged, or not at the end of the disk, which happens after writing a disk image to a larger disk. Installer can rewrite it from the primary partition table, which appears intact. Your partitions and files are not changed.";
+const INTEL_RAID_TEXT: &str = "WARNING: Your disk controller is in Intel RST (RAID) mode, which may hide some or all of your disks from the installer. If your disk is missing or shows no partitions, please switch the SATA mode to AHCI in your firmware settings, and make sure that Windows, if any, is prepared for the change beforehand.";
+const SELECT_HOME_TEXT: &str = "If you would like to keep your files on an existing partition, you may select it to be mounted as /home. It will not be formatted. Files in it are owned by the user IDs of your previous installation, which may not match the new user.";
+const SELECT_ESP_TEXT: &str = "Installer has found more than one EFI System Partition (ESP) on this device. Please select the one the bootloader should be installed to.";
+const MOUNT_OPTIONS_TEXT: &str = "These options are added to every filesystem in /etc/fstab that supports them. The commit interval only applies to ext4 and Btrfs, leave it empty to use the default. Queued TRIM and compression only apply to Btrfs.";
+const MOUNT_POINTS_TEXT: &str = "Other partitions can be mounted into the installed system, e.g. /var or /srv on servers. They are added to /etc/fstab, and formatted first if requested.";
+const EXPERT_OPTIONS_TEXT: &str = "These options are passed to the installed system as is, please make sure that they are correct. Extra user groups are separated by commas and created if they do not exist. Extra mkfs arguments are used when formatting the system partition, e.g. \"-O casefold\" for ext4 or \"-m reflink=1\" for XFS.\n\nWith EFISTUB, the firmware boots the kernel directly without GRUB. Kernel updates are not copied to the EFI System Partition automatically.";
 const ADVANCED_METHOD_INFO: &str = "Installer detected an unsupported filesystem format in your system partition. If you proceed, the installer will format your system partition using the ext4 filesystem. Please refer to the manual installation guides if you prefer to use an unsupported filesystem.";
+const LOCAL_TARBALL_TEXT: &str = "Installer can install from a .tar.xz system release on this machine, e.g. on another USB drive, without an Internet connection. Please enter its full path. The SHA-256 checksum may be left empty if the .sha256sum file downloaded with the release is next to it.\n\nAlternatively, Installer can copy the live system you are running now.";
+const WIFI_TEXT: &str = "Please select the wireless network to connect to. Networks are listed from the strongest signal (top) to the weakest (bottom); hidden networks are not shown.";
+const PROXY_TEXT: &str = "If your network requires a proxy to access the Internet, enter it below, for example http://proxy.example.com:3128 or socks5h://127.0.0.1:1080. Leave it empty to use the http_proxy, https_proxy and all_proxy environment variables, if set.\n\nWhere AOSC OS mirrors are blocked, Installer can go through Tor running on this machine instead. Mirrors on onion services always go through Tor.\n\nIf your organization inspects secure (HTTPS) connections, enter the path to its CA certificate, PEM or DER encoded, to trust it as well.";
 const WELCOME_TEXT: &str = r#"Welcome to the AOSC OS Installer!
 
 In the following pages, Installer will guide you through the variant selection, partitioning, and other installation steps. The installation process should only take a few minutes, but will require more time on slower hardware.
 
-Note: The installation process requires a functional Internet connection. Please configure your Internet connection with the tray icon to the bottom right. If you are using the command-line interface, press Ctrl+C to exit now and configure your Internet connection with the 'nmtui' command."#;
+Note: The installation process requires a functional Internet connection. Please configure your Internet connection with the tray icon to the bottom right. If you are using the command-line interface, press Ctrl+C to exit now and configure your Internet connection with the 'nmtui' command.
+
+If you run into problems, press F2 at any time to turn on debug logging before reporting a bug. Press F3 to turn on audio cues."#;
+const CUSTOM_TARBALL_TEXT: &str = "This option is for distribution developers only!\nPlease enter the full URL of a .tar.xz or .squashfs system release and its SHA-256 checksum.\nFor a Metalink descriptor (.meta4), the checksum may be left empty.";
 const VARIANT_TEXT: &str =
     "Shown below is a list of available AOSC OS distributions for your device.";
 const ENTER_USER_PASSWORD_TEXT: &str = r#"Please enter and confirm your desired username and password. Please note that your username must start with a lower-cased alphabetical letter (a-z), and contain only lower-cased letters a-z, numbers 0-9, and dash ("-").
 "#;
 const ENTER_HOSTNAME_TEXT: &str = r#"Now, please input your desired hostname. A hostname may only consist letters a-z, numbers 0-9, and dash ("-")."#;
 const ENTER_TIMEZONE_TEXT: &str = r#"Finally, please select your locale, timezone, and your clock preferences. Your locale setting will affect your installation's display language. UTC system time is the default setting for Linux systems, but may result in time discrepancy with your other operating systems, such as Windows. If you wish to prevent this from happening, please select local time as system time."#;
-const BENCHMARK_TEXT: &str = "Installer will now test all mirrors for download speed, and rank them from the fastest (top) to the slowest (bottom). This may take a few minutes.";
+const BENCHMARK_TEXT: &str = "Installer will now test all mirrors for download speed, and rank them from the fastest (top) to the slowest (bottom). This may take a few seconds.";
 const FINISHED_TEXT: &str = r#"AOSC OS has been successfully installed on your device.
 
 You may reboot to your installed system by choosing "Reboot," or return to LiveKit by selecting "Exit to LiveKit.""#;
@@ -111,17 +156,139 @@ macro_rules! fill_in_all_the_fields {
     };
 }
 
+/// What sets the variants apart, for newcomers picking one
+struct VariantDetails {
+    name: &'static str,
+    desktop: &'static str,
+    /// Recommended memory in GiB
+    memory: u64,
+    summary: &'static str,
+}
+
+const VARIANT_DETAILS: &[VariantDetails] = &[
+    VariantDetails {
+        name: "Desktop",
+        desktop: "KDE Plasma",
+        memory: 4,
+        summary: "A full-featured and highly customisable desktop, recommended for most users.",
+    },
+    VariantDetails {
+        name: "Gnome",
+        desktop: "GNOME",
+        memory: 4,
+        summary: "A clean, touch-friendly desktop built around the Activities overview.",
+    },
+    VariantDetails {
+        name: "Cinnamon",
+        desktop: "Cinnamon",
+        memory: 4,
+        summary: "A traditional desktop with a start menu and taskbar, familiar to Windows users.",
+    },
+    VariantDetails {
+        name: "Mate",
+        desktop: "MATE",
+        memory: 2,
+        summary: "A classic two-panel desktop, light enough for older hardware.",
+    },
+    VariantDetails {
+        name: "Xfce",
+        desktop: "Xfce",
+        memory: 2,
+        summary: "A lightweight and modular traditional desktop.",
+    },
+    VariantDetails {
+        name: "Lxqt",
+        desktop: "LXQt",
+        memory: 1,
+        summary: "A very lightweight desktop for low-end devices.",
+    },
+    VariantDetails {
+        name: "Base",
+        desktop: "None (command line only)",
+        memory: 1,
+        summary: "A minimal system to build your own setup upon.",
+    },
+    VariantDetails {
+        name: "Server",
+        desktop: "None (command line only)",
+        memory: 1,
+        summary: "The base system with common server utilities.",
+    },
+];
+
 type PartitionButton = (&'static str, Box<dyn Fn(&mut Cursive, InstallConfig)>);
 
 fn show_error(siv: &mut Cursive, msg: &str) {
     siv.add_layer(
         Dialog::around(TextView::new(msg).max_width(80))
             .title("Error")
+            .button("View recent log", show_recent_log)
+            .button("Exit", |s| s.quit())
+            .padding_lrtb(2, 2, 1, 1),
+    );
+}
+
+/// Like [`show_error`], but also offers to run the failed installation again,
+/// which is only safe once its steps have been rolled back
+fn show_install_error(siv: &mut Cursive, msg: &str, config: InstallConfig) {
+    let retry_label = if config.keep_data {
+        "Retry"
+    } else {
+        "Erase and Retry"
+    };
+    siv.add_layer(
+        Dialog::around(TextView::new(msg).max_width(80))
+            .title("Error")
+            .button(retry_label, move |s| confirm_retry(s, config.clone()))
+            .button("View recent log", show_recent_log)
             .button("Exit", |s| s.quit())
             .padding_lrtb(2, 2, 1, 1),
     );
 }
 
+fn confirm_retry(siv: &mut Cursive, config: InstallConfig) {
+    let path = config
+        .partition
+        .as_ref()
+        .and_then(|x| x.path.as_ref())
+        .map(|x| x.display().to_string())
+        .unwrap_or_default();
+    let msg = if config.keep_data {
+        format!("Installer will restart the installation onto {path} with the same configuration, keeping the files on it. Continue?")
+    } else {
+        format!("Installer will format {path} again and restart the installation with the same configuration. All data on this partition will be lost. Continue?")
+    };
+    siv.add_layer(
+        wrap_in_dialog(TextView::new(msg), "AOSC OS Installer", None)
+            .button("Yes", move |s| {
+                // drop this dialog and the error, start_install() drops the progress dialog
+                s.pop_layer();
+                s.pop_layer();
+                start_install(s, config.clone());
+            })
+            .button("No", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+fn show_recent_log(siv: &mut Cursive) {
+    let log = crate::log::recent_log().join("\n");
+    siv.add_layer(
+        Dialog::around(
+            ScrollView::new(TextView::new(log))
+                .scroll_strategy(ScrollStrategy::StickToBottom)
+                .max_width(120)
+                .max_height(30),
+        )
+        .title("Recent Log")
+        .button("Close", |s| {
+            s.pop_layer();
+        })
+        .padding_lrtb(2, 2, 1, 1),
+    );
+}
+
 fn show_msg(siv: &mut Cursive, msg: &str) {
     siv.add_layer(
         Dialog::around(TextView::new(msg).max_width(80))
@@ -133,6 +300,32 @@ fn show_msg(siv: &mut Cursive, msg: &str) {
     );
 }
 
+fn toggle_debug_log(siv: &mut Cursive) {
+    let (level, msg) = if crate::log::log_level() < LevelFilter::Debug {
+        (
+            LevelFilter::Debug,
+            "Debug logging has been enabled. Press F2 again to turn it off.",
+        )
+    } else {
+        (LevelFilter::Info, "Debug logging has been disabled.")
+    };
+    crate::log::set_log_level(level);
+    info!("Log level changed to {level}");
+    show_msg(siv, msg);
+}
+
+fn toggle_audio_cues(siv: &mut Cursive) {
+    let enabled = !cues::audio_cues();
+    cues::set_audio_cues(enabled);
+    info!("Audio cues enabled: {enabled}");
+    if enabled {
+        cues::play(Cue::InputRequired);
+        show_msg(siv, "Audio cues have been enabled. Installer will beep when your input is needed, when the download finishes, and when the installation completes (three beeps) or fails (five beeps). Press F3 again to turn them off.");
+    } else {
+        show_msg(siv, "Audio cues have been disabled.");
+    }
+}
+
 fn show_blocking_message(siv: &mut Cursive, msg: &str) {
     siv.add_layer(
         Dialog::around(TextView::new(msg))
@@ -153,15 +346,8 @@ fn partition_button(device_path: PathBuf) -> PartitionButton {
                     Command::new("gparted").output().ok();
                     cb_sink
                         .send(Box::new(move |s| {
-                            let device_path = device_path.clone();
-                            let partitions =
-                                disks::list_partitions(Some(device_path.to_path_buf()));
-                            let (disk_list, disk_view) = make_partition_list(partitions);
-                            s.set_user_data(SendWrapper::new(disk_list));
-                            s.call_on_name("part_list", |view: &mut NamedView<LinearLayout>| {
-                                *view = disk_view;
-                            });
                             s.pop_layer();
+                            refresh_partitions_view(s, device_path);
                         }))
                         .unwrap();
                 });
@@ -180,53 +366,233 @@ fn partition_button(device_path: PathBuf) -> PartitionButton {
     )
 }
 
-#[inline]
-fn human_size(size: u64) -> String {
-    match NumberPrefix::binary(size as f64) {
-        NumberPrefix::Standalone(bytes) => format!("{bytes} B"),
-        NumberPrefix::Prefixed(prefix, n) => format!("{n:.1} {prefix}B"),
-    }
+/// Re-read the partitions of `device_path` in the background, e.g. after
+/// partitioning it from another TTY
+fn refresh_partitions_view(s: &mut Cursive, device_path: PathBuf) {
+    show_blocking_message(s, "Refreshing partitions ...");
+    let cb_sink = s.cb_sink().clone();
+    thread::spawn(move || {
+        let partitions = probe_partitions(device_path);
+        cb_sink
+            .send(Box::new(move |s| {
+                show_partition_list(s, partitions);
+                s.pop_layer();
+            }))
+            .unwrap();
+    });
 }
 
-fn make_device_list(devices: Vec<Device>) -> Vec<DkDerive> {
-    let mut res = vec![];
+/// List the partitions of `device_path` along with the systems installed on
+/// them. Probing mounts each partition, so this is not to be run on the UI
+/// thread.
+fn probe_partitions(device_path: PathBuf) -> Vec<(disks::Partition, Option<String>)> {
+    disks::list_partitions(Some(device_path))
+        .into_iter()
+        .map(|x| {
+            let os = disks::detect_os(&x);
+            (x, os)
+        })
+        .collect()
+}
 
-    for i in devices {
-        let path = i.path();
-        let p = path.to_path_buf();
-        let model = i.model();
-        let size = i.sector_size() * i.length();
+fn show_partition_list(s: &mut Cursive, partitions: Vec<(disks::Partition, Option<String>)>) {
+    let (disk_list, disk_view) = make_partition_list(partitions);
+    s.set_user_data(SendWrapper::new(disk_list));
+    s.call_on_name("part_list", |view: &mut NamedView<LinearLayout>| {
+        *view = disk_view;
+    });
+}
 
-        res.push(DkDerive {
-            path: p,
-            model: model.to_string(),
-            size,
-        });
+/// Shrink the selected partition, e.g. one holding Windows, to make room for
+/// AOSC OS
+fn shrink_partition_view(siv: &mut Cursive, device_path: PathBuf) {
+    let Some(disk_list) = siv.user_data::<SendWrapper<RadioGroup<disks::Partition>>>() else {
+        return;
+    };
+    let partition = disk_list.clone().take().selection().as_ref().clone();
+    let fs_type = partition.fs_type.clone().unwrap_or_default();
+    if !disks::SHRINKABLE_FS_TYPE.contains(&fs_type.as_str()) {
+        show_msg(
+            siv,
+            "Installer can only shrink NTFS and ext4 partitions. Please select another partition.",
+        );
+        return;
+    }
+    let min_size = match disks::min_shrink_size(&partition) {
+        Ok(size) => size + disks::SHRINK_MARGIN,
+        Err(e) => {
+            show_msg(siv, &e.to_string());
+            return;
+        }
+    };
+    if min_size >= partition.size {
+        show_msg(siv, "This partition is too full to be shrunk.");
+        return;
     }
 
-    res
+    let msg = format!(
+        "{}\n\n{} is currently {}, and can be shrunk to no less than {:.1}GiB.",
+        SHRINK_PARTITION_TEXT,
+        partition
+            .path
+            .as_deref()
+            .unwrap_or(Path::new("?"))
+            .display(),
+        human_size(partition.size),
+        min_size as f64 / 1024.0 / 1024.0 / 1024.0
+    );
+    let default_size = (min_size + (partition.size - min_size) / 2) / 1024 / 1024 / 1024;
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(msg))
+                .child(DummyView {})
+                .child(
+                    ListView::new().child(
+                        "New size (GiB): ",
+                        EditView::new()
+                            .content(default_size.max(1).to_string())
+                            .with_name("shrink_size")
+                            .fixed_width(10),
+                    ),
+                ),
+            "Shrink Partition",
+            None,
+        )
+        .button("Shrink", move |s| {
+            let size = s
+                .call_on_name("shrink_size", |v: &mut EditView| v.get_content())
+                .and_then(|x| x.trim().parse::<f64>().ok())
+                .filter(|x| *x > 0.0)
+                .map(|x| (x * 1024.0 * 1024.0 * 1024.0) as u64);
+            let Some(size) = size.filter(|x| *x >= min_size && *x < partition.size) else {
+                show_msg(s, "Invalid partition size!");
+                return;
+            };
+            s.pop_layer();
+            show_blocking_message(s, "Shrinking partition, this may take a while ...");
+
+            let cb_sink = s.cb_sink().clone();
+            let partition = partition.clone();
+            let device_path = device_path.clone();
+            thread::spawn(move || {
+                let res = disks::shrink_partition(&partition, size);
+                cb_sink
+                    .send(Box::new(move |s| {
+                        s.pop_layer();
+                        match res {
+                            Ok(()) => show_msg(s, SHRINK_DONE_TEXT),
+                            Err(e) => show_msg(s, &e.to_string()),
+                        }
+                        refresh_partitions_view(s, device_path);
+                    }))
+                    .unwrap();
+            });
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+/// Create a partition in the largest unallocated region of the disk, for
+/// those who left free space for AOSC OS
+fn free_space_partition_view(siv: &mut Cursive, device_path: PathBuf) {
+    let free = match disks::largest_free_space(&device_path) {
+        Ok(Some(free)) => free,
+        Ok(None) => {
+            show_msg(siv, "There is no unallocated space on this disk.");
+            return;
+        }
+        Err(e) => {
+            show_msg(siv, &e.to_string());
+            return;
+        }
+    };
+
+    let msg = format!(
+        "Installer will create a {} partition in the largest unallocated region on {}. You may then select it as the system partition.",
+        human_size(free.size),
+        device_path.display()
+    );
+    siv.add_layer(
+        wrap_in_dialog(TextView::new(msg), "Use Free Space", None)
+            .button("Create", move |s| {
+                s.pop_layer();
+                match disks::create_partition_in_free_space(&device_path, &free) {
+                    Ok(_) => refresh_partitions_view(s, device_path.clone()),
+                    Err(e) => show_msg(s, &e.to_string()),
+                }
+            })
+            .button("Cancel", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+#[inline]
+fn human_size(size: u64) -> String {
+    match NumberPrefix::binary(size as f64) {
+        NumberPrefix::Standalone(bytes) => format!("{bytes} B"),
+        NumberPrefix::Prefixed(prefix, n) => format!("{n:.1} {prefix}B"),
+    }
 }
 
 fn make_partition_list(
-    partitions: Vec<disks::Partition>,
+    partitions: Vec<(disks::Partition, Option<String>)>,
 ) -> (RadioGroup<disks::Partition>, NamedView<LinearLayout>) {
     let mut disk_view = LinearLayout::vertical();
     let mut disk_list = RadioGroup::new();
-    for part in &partitions {
+    let mut last_disk = None;
+    for (part, os) in &partitions {
+        if part.parent_path != last_disk {
+            if let Some(disk) = &part.parent_path {
+                disk_view.add_child(TextView::new(format!(
+                    "{}: {}",
+                    disk.display(),
+                    disks::disk_info(disk)
+                )));
+            }
+            last_disk = part.parent_path.clone();
+        }
         let path_name = if let Some(path) = &part.path {
             path.to_string_lossy().to_string()
         } else {
             "?".to_owned()
         };
+        let lv_name = part
+            .path
+            .as_deref()
+            .and_then(disks::logical_volume_name)
+            .map(|(vg, lv)| format!("LVM {vg}/{lv}, "))
+            .or_else(|| {
+                part.path
+                    .as_deref()
+                    .filter(|x| disks::is_raid(x))
+                    .map(|_| "RAID, ".to_string())
+            })
+            .or_else(|| {
+                part.path
+                    .as_deref()
+                    .and_then(disks::crypt_backing_device)
+                    .map(|(name, _)| format!("LUKS {name}, "))
+            })
+            .unwrap_or_default();
+        let os = os
+            .as_ref()
+            .map(|x| format!(", contains {x}"))
+            .unwrap_or_default();
         let radio = disk_list.button(
             part.clone(),
             format!(
-                "{} ({}, {})",
+                "{} ({}{}, {}{})",
                 path_name,
+                lv_name,
                 part.fs_type
                     .as_ref()
                     .unwrap_or(&"Unknown/Unformatted".to_owned()),
-                human_size(part.size)
+                human_size(part.size),
+                os
             ),
         );
         disk_view.add_child(radio);
@@ -261,12 +627,50 @@ pub fn wrap_in_dialog<V: View, S: Into<String>>(
     .title(title)
 }
 
+fn variant_details(variant: &VariantEntry, total_memory: u64) -> String {
+    let mut res = variant.name.clone();
+    if !variant.description.is_empty() {
+        res.push_str(&format!(": {}", variant.description));
+    }
+    res.push('\n');
+
+    let details = VARIANT_DETAILS
+        .iter()
+        .find(|x| x.name.eq_ignore_ascii_case(&variant.name));
+    if let Some(details) = details {
+        res.push_str(&format!("\nDesktop environment: {}", details.desktop));
+        res.push_str(&format!("\nRecommended memory: {} GiB", details.memory));
+        if total_memory < details.memory * 1024 * 1024 * 1024 {
+            res.push_str(&format!(" (this device has {})", human_size(total_memory)));
+        }
+    }
+    res.push_str(&format!(
+        "\nInstalled size: {}",
+        human_size(variant.install_size)
+    ));
+    if let Some(details) = details {
+        res.push_str(&format!("\n\n{}", details.summary));
+    }
+
+    res
+}
+
 fn build_variant_list(
     mirrors: Vec<Mirror>,
     variants: Vec<VariantEntry>,
     config: InstallConfig,
 ) -> Dialog {
     let mut config_view = LinearLayout::vertical();
+    let config_clone = config.clone();
+    let total_memory = sysinfo::System::new_all().total_memory();
+    let details = TextContent::new(
+        variants
+            .first()
+            .map(|x| variant_details(x, total_memory))
+            .unwrap_or_default(),
+    );
+    let details_copy = details.clone();
+    let variants_copy = variants.clone();
 
     let variant_view = TableView::<network::VariantEntry, VariantColumn>::new()
         .column(VariantColumn::Name, "Available Distributions", |c| {
@@ -275,114 +679,442 @@ fn build_variant_list(
         .column(VariantColumn::Date, "Last Updated", |c| c.width(22))
         .column(VariantColumn::Size, "Download Size", |c| c.width(22))
         .items(variants.clone())
+        .on_select(move |_, _row, index| {
+            if let Some(variant) = variants_copy.get(index) {
+                details_copy.set_content(variant_details(variant, total_memory));
+            }
+        })
         .on_submit(move |siv, _row, index| {
             let mut config = config.clone();
             config.variant = Some(Arc::new(variants.get(index).unwrap().clone()));
             select_mirrors(siv, mirrors.clone(), config);
         })
         .min_width(80)
-        .min_height(30);
+        .min_height(22);
     let variant_view = Panel::new(variant_view).title("Variant");
+    let details_view =
+        Panel::new(TextView::new_with_content(details).fixed_height(8)).title("Details");
     config_view.add_child(TextView::new(VARIANT_TEXT));
     config_view.add_child(variant_view);
+    config_view.add_child(details_view);
     config_view.add_child(DummyView {});
 
-    wrap_in_dialog(config_view, "AOSC OS Installation", Some(128)).button("Exit", |s| s.quit())
+    wrap_in_dialog(config_view, "AOSC OS Installation", Some(128))
+        .button("Custom Tarball", move |s| {
+            select_custom_tarball(s, config_clone.clone())
+        })
+        .button("Exit", |s| s.quit())
 }
 
-fn select_variant(siv: &mut Cursive, config: InstallConfig) {
-    siv.pop_layer();
-    let loader = AsyncView::new_with_bg_creator(
-        siv,
-        move || {
-            let manifest = network::fetch_recipe().map_err(|e| e.to_string())?;
-            let mirrors = network::fetch_mirrors(&manifest);
-            let variants = network::find_variant_candidates(manifest).map_err(|e| e.to_string())?;
-            Ok((mirrors, variants))
-        },
-        move |(mirrors, variants)| build_variant_list(mirrors, variants, config.clone()),
-    );
+fn select_custom_tarball(siv: &mut Cursive, config: InstallConfig) {
+    let url_input = Rc::new(RefCell::new(String::new()));
+    let url_input_copy = url_input.clone();
+    let sha256_input = Rc::new(RefCell::new(String::new()));
+    let sha256_input_copy = sha256_input.clone();
 
-    siv.add_layer(loader);
-}
+    siv.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(CUSTOM_TARBALL_TEXT))
+                .child(DummyView {})
+                .child(
+                    ListView::new()
+                        .child(
+                            "URL",
+                            EditView::new()
+                                .on_edit_mut(move |_, c, _| {
+                                    url_input_copy.replace(c.trim().to_owned());
+                                })
+                                .min_width(60),
+                        )
+                        .child(
+                            "SHA-256",
+                            EditView::new()
+                                .on_edit_mut(move |_, c, _| {
+                                    sha256_input_copy.replace(c.trim().to_owned());
+                                })
+                                .min_width(60),
+                        ),
+                ),
+        )
+        .title("Install from Custom Tarball")
+        .button("Continue", move |s| {
+            let url = url_input.borrow().clone();
+            let sha256 = sha256_input.borrow().clone();
+            let variant = match network::custom_variant(&url, &sha256) {
+                Ok(variant) => variant,
+                Err(e) => {
+                    show_msg(s, &e.to_string());
+                    return;
+                }
+            };
 
-fn select_mirrors(siv: &mut Cursive, mirrors: Vec<Mirror>, config: InstallConfig) {
-    siv.pop_layer();
-    let (config_view, repo_list) = select_mirror_view_base(&mirrors);
-    siv.add_layer(select_mirrors_view(config_view, config, repo_list, mirrors));
+            let mut config = config.clone();
+            config.variant = Some(Arc::new(variant));
+            config.mirror = Some(Arc::new(custom_tarball_mirror()));
+            s.pop_layer();
+            select_disk(s, config);
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        })
+        .padding_lrtb(2, 2, 1, 1),
+    );
 }
 
-fn select_mirror_view_base(mirrors: &[Mirror]) -> (LinearLayout, RadioGroup<Mirror>) {
-    let mut config_view = LinearLayout::vertical();
-    let mut repo_list = RadioGroup::new();
-    let mirror_list = mirrors;
-    let mut repo_view = LinearLayout::vertical()
-        .child(TextView::new(
-            "Please select a mirror to download AOSC OS. Generally, a mirror closest to you geographically would be the best bet for download speeds.",
-        ))
-        .child(DummyView {});
-    for mirror in mirror_list {
-        let radio = repo_list.button(mirror.clone(), format!("{} ({})", mirror.name, mirror.loc));
-        repo_view.add_child(radio);
+/// The URL of a custom tarball is complete, nothing to take from a mirror
+fn custom_tarball_mirror() -> Mirror {
+    Mirror {
+        name: String::from("Custom tarball"),
+        name_tr: String::from("custom-name"),
+        loc: String::from("Custom tarball"),
+        loc_tr: String::from("custom-loc"),
+        url: String::new(),
     }
-    let repo_view = Panel::new(repo_view).title("Mirrors");
-    config_view.add_child(repo_view);
-    config_view.add_child(DummyView {});
-
-    (config_view, repo_list)
 }
 
-fn select_mirrors_view(
-    config_view: LinearLayout,
-    config: InstallConfig,
-    repo_list: RadioGroup<Mirror>,
-    mirrors: Vec<Mirror>,
-) -> Dialog {
-    let config_clone = config.clone();
-    let config_clone_2 = config.clone();
-    let config_clone_3 = config.clone();
-    wrap_in_dialog(config_view, "AOSC OS Installation", None)
-        .button("Continue", move |s| {
-            let mut config = config.clone();
-            let mirror = repo_list.selection();
-            config.mirror = Some(Arc::new(Rc::as_ref(&mirror).clone()));
-            if config.partition.is_some() {
-                s.pop_layer();
-                s.add_layer(select_user_password(config));
-            } else {
-                select_disk(s, config);
-            }
-        })
-        .button("Benchmark Mirrors", move |s| {
-            let config_clone_2 = config_clone.clone();
-            let config_clone_3 = config_clone.clone();
-            let mirrors_clone = mirrors.clone();
-            let mirrors_clone_2 = mirrors.clone();
+fn select_local_tarball(siv: &mut Cursive) {
+    let path_input = Rc::new(RefCell::new(String::new()));
+    let path_input_copy = path_input.clone();
+    let sha256_input = Rc::new(RefCell::new(String::new()));
+    let sha256_input_copy = sha256_input.clone();
+
+    siv.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(LOCAL_TARBALL_TEXT))
+                .child(DummyView {})
+                .child(
+                    ListView::new()
+                        .child(
+                            "Path",
+                            EditView::new()
+                                .on_edit_mut(move |_, c, _| {
+                                    path_input_copy.replace(c.trim().to_owned());
+                                })
+                                .min_width(60),
+                        )
+                        .child(
+                            "SHA-256",
+                            EditView::new()
+                                .on_edit_mut(move |_, c, _| {
+                                    sha256_input_copy.replace(c.trim().to_owned());
+                                })
+                                .min_width(60),
+                        ),
+                ),
+        )
+        .title("Install from Local Tarball")
+        .button("Copy Live System", |s| {
+            let variant = match network::live_variant() {
+                Ok(variant) => variant,
+                Err(e) => {
+                    show_msg(s, &e.to_string());
+                    return;
+                }
+            };
+
+            let config = InstallConfig {
+                variant: Some(Arc::new(variant)),
+                mirror: Some(Arc::new(custom_tarball_mirror())),
+                from_live: true,
+                ..Default::default()
+            };
             s.pop_layer();
-            s.add_layer(
-                Dialog::around(TextView::new(BENCHMARK_TEXT).max_width(80))
-                    .title("AOSC OS Installer")
+            select_disk(s, config);
+        })
+        .button("Continue", move |s| {
+            let path = path_input.borrow().clone();
+            let sha256 = sha256_input.borrow().clone();
+            let sha256 = (!sha256.is_empty()).then_some(sha256.as_str());
+            let variant = match network::local_variant(Path::new(&path), sha256) {
+                Ok(variant) => variant,
+                Err(e) => {
+                    show_msg(s, &e.to_string());
+                    return;
+                }
+            };
+
+            let config = InstallConfig {
+                variant: Some(Arc::new(variant)),
+                mirror: Some(Arc::new(custom_tarball_mirror())),
+                ..Default::default()
+            };
+            s.pop_layer();
+            select_disk(s, config);
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        })
+        .padding_lrtb(2, 2, 1, 1),
+    );
+}
+
+fn select_variant(siv: &mut Cursive, config: InstallConfig) {
+    siv.pop_layer();
+    let status = TextContent::new("Fetching the list of system releases ...");
+    siv.add_layer(wrap_in_dialog(
+        TextView::new_with_content(status.clone()),
+        "AOSC OS Installer",
+        None,
+    ));
+    let cb_sink = siv.cb_sink().clone();
+    thread::spawn(move || {
+        let sink = cb_sink.clone();
+        let res = fetch_variants(|retry| {
+            let msg = format!(
+                "Installer could not fetch the list of system releases:\n\n{}\n\nRetrying in {} seconds (attempt {}/{}) ...",
+                retry.error,
+                retry.delay.as_secs(),
+                retry.attempt,
+                retry.retries
+            );
+            let status = status.clone();
+            sink.send(Box::new(move |_| status.set_content(msg))).ok();
+        });
+        cb_sink
+            .send(Box::new(move |s| {
+                s.pop_layer();
+                match res {
+                    Ok((mirrors, variants)) => {
+                        cues::play(Cue::InputRequired);
+                        s.add_layer(build_variant_list(mirrors, variants, config));
+                    }
+                    // a flaky connection should not end the installation
+                    Err(e) => {
+                        let saved_age = network::cached_recipe().ok().map(|(_, age)| age);
+                        let mut msg = e.to_string();
+                        if let Some(age) = saved_age {
+                            msg.push_str(&format!(
+                                "\n\nInstaller has a list of system releases saved {} ago, which may not include the latest releases.",
+                                describe_age(age)
+                            ));
+                        }
+                        let config_copy = config.clone();
+                        let mut dialog = Dialog::around(TextView::new(msg).max_width(80))
+                            .title("Error")
+                            .button("Retry", move |s| select_variant(s, config_copy.clone()));
+                        if saved_age.is_some() {
+                            dialog.add_button("Use Saved List", move |s| {
+                                use_saved_recipe(s, config.clone())
+                            });
+                        }
+                        s.add_layer(
+                            dialog
+                                .button("Diagnostics", network_diagnostics)
+                                .button("Wi-Fi", select_wifi)
+                                .button("Proxy", set_proxy)
+                                .button("Exit", |s| s.quit())
+                                .padding_lrtb(2, 2, 1, 1),
+                        );
+                    }
+                }
+            }))
+            .ok();
+    });
+}
+
+/// Carry on with the recipe saved when it was last fetched
+fn use_saved_recipe(siv: &mut Cursive, config: InstallConfig) {
+    let res = network::cached_recipe().and_then(|(recipe, _)| {
+        let mirrors = network::fetch_mirrors(&recipe);
+        Ok((mirrors, network::find_variant_candidates(recipe)?))
+    });
+    match res {
+        Ok((mirrors, variants)) => {
+            siv.pop_layer();
+            cues::play(Cue::InputRequired);
+            siv.add_layer(build_variant_list(mirrors, variants, config));
+        }
+        Err(e) => show_msg(siv, &e.to_string()),
+    }
+}
+
+/// `age` in the largest unit that makes sense, e.g. "3 hours"
+fn describe_age(age: Duration) -> String {
+    let minutes = age.as_secs() / 60;
+    match minutes {
+        0..=1 => "a minute".to_string(),
+        2..=119 => format!("{minutes} minutes"),
+        120..=2879 => format!("{} hours", minutes / 60),
+        _ => format!("{} days", minutes / 60 / 24),
+    }
+}
+
+#[test]
+fn test_describe_age() {
+    assert_eq!(describe_age(Duration::from_secs(30)), "a minute");
+    assert_eq!(describe_age(Duration::from_secs(45 * 60)), "45 minutes");
+    assert_eq!(describe_age(Duration::from_secs(5 * 3600)), "5 hours");
+    assert_eq!(describe_age(Duration::from_secs(3 * 86400)), "3 days");
+}
+
+fn fetch_variants(
+    on_retry: impl FnMut(&network::Retry),
+) -> Result<(Vec<Mirror>, Vec<VariantEntry>)> {
+    if test_mode::is_enabled() {
+        return Ok((
+            vec![test_mode::fixture_mirror()],
+            vec![test_mode::fixture_variant()?],
+        ));
+    }
+    let manifest = network::fetch_recipe_reporting(on_retry)?;
+    let mirrors = network::fetch_mirrors(&manifest);
+    let variants = network::find_variant_candidates(manifest)?;
+
+    Ok((mirrors, variants))
+}
+
+fn select_mirrors(siv: &mut Cursive, mirrors: Vec<Mirror>, config: InstallConfig) {
+    siv.pop_layer();
+    let (config_view, repo_list) = select_mirror_view_base(&mirrors);
+    siv.add_layer(select_mirrors_view(config_view, config, repo_list, mirrors));
+}
+
+fn select_mirror_view_base(mirrors: &[Mirror]) -> (LinearLayout, RadioGroup<Mirror>) {
+    let mut config_view = LinearLayout::vertical();
+    let mut repo_list = RadioGroup::new();
+    let mirror_list = mirrors;
+    let mut repo_view = LinearLayout::vertical()
+        .child(TextView::new(
+            "Please select a mirror to download AOSC OS. Generally, a mirror closest to you geographically would be the best bet for download speeds.",
+        ))
+        .child(DummyView {});
+    for mirror in mirror_list {
+        let tor = if network::is_onion(&mirror.url) {
+            ", Tor"
+        } else {
+            ""
+        };
+        let radio = repo_list.button(
+            mirror.clone(),
+            format!("{} ({}{tor})", mirror.name, mirror.loc),
+        );
+        repo_view.add_child(radio);
+    }
+    let repo_view = Panel::new(repo_view).title("Mirrors");
+    config_view.add_child(repo_view);
+    config_view.add_child(DummyView {});
+
+    (config_view, repo_list)
+}
+
+fn select_mirrors_view(
+    config_view: LinearLayout,
+    config: InstallConfig,
+    repo_list: RadioGroup<Mirror>,
+    mirrors: Vec<Mirror>,
+) -> Dialog {
+    let config_clone = config.clone();
+    let config_clone_2 = config.clone();
+    let config_clone_3 = config.clone();
+    let has_torrent = config.variant.as_ref().is_some_and(|x| x.torrent.is_some());
+    let config_view = if has_torrent && torrent::is_available() {
+        config_view.child(
+            LinearLayout::horizontal()
+                .child(
+                    Checkbox::new()
+                        .with_checked(config.use_torrent)
+                        .with_name("use_torrent"),
+                )
+                .child(TextView::new(
+                    " Download with BitTorrent, taking load off the mirror",
+                )),
+        )
+    } else {
+        config_view
+    };
+    wrap_in_dialog(config_view, "AOSC OS Installation", None)
+        .button("Continue", move |s| {
+            let mut config = config.clone();
+            config.use_torrent = s
+                .call_on_name("use_torrent", |v: &mut Checkbox| v.is_checked())
+                .unwrap_or(false);
+            let mirror = repo_list.selection();
+            if network::is_onion(&mirror.url) && !network::tor_available() {
+                show_msg(
+                    s,
+                    &format!(
+                        "{} is an onion service, which can only be reached through Tor. Installer could not reach Tor at {}, please start it first, e.g. with `systemctl start tor`.",
+                        mirror.name,
+                        network::TOR_PROXY
+                    ),
+                );
+                return;
+            }
+            // BitTorrent peers would be reached around Tor
+            config.use_torrent &= !network::uses_tor(&mirror.url);
+            config.mirror = Some(Arc::new(Rc::as_ref(&mirror).clone()));
+            if config.partition.is_some() {
+                s.pop_layer();
+                s.add_layer(select_user_password(config));
+            } else {
+                select_disk(s, config);
+            }
+        })
+        .button("Benchmark Mirrors", move |s| {
+            let config_clone_2 = config_clone.clone();
+            let config_clone_3 = config_clone.clone();
+            let mirrors_clone = mirrors.clone();
+            let mirrors_clone_2 = mirrors.clone();
+            s.pop_layer();
+            s.add_layer(
+                Dialog::around(TextView::new(BENCHMARK_TEXT).max_width(80))
+                    .title("AOSC OS Installer")
                     .button("OK", move |s| {
                         let config_clone_3 = config_clone_2.clone();
                         let mirrors_clone_2 = mirrors_clone.clone();
-                        let loader = AsyncView::new_with_bg_creator(
-                            s,
-                            move || {
-                                let new_mirrors = network::speedtest_mirrors(mirrors_clone_2);
-                                Ok(new_mirrors)
-                            },
-                            move |mirrors| {
-                                let (config_view, repo_list) = select_mirror_view_base(&mirrors);
-                                select_mirrors_view(
-                                    config_view,
-                                    config_clone_3.clone(),
-                                    repo_list,
-                                    mirrors,
-                                )
-                            },
-                        );
+                        let unranked = mirrors_clone.clone();
+                        let results = TextContent::new("");
+                        let results_copy = results.clone();
+                        let cb_sink = s.cb_sink().clone();
                         s.pop_layer();
-                        s.add_layer(loader);
+                        s.add_layer(
+                            Dialog::around(
+                                TextView::new_with_content(results)
+                                    .scrollable()
+                                    .min_width(60)
+                                    .max_height(20),
+                            )
+                            .title(format!(
+                                "Benchmarking Mirrors (up to {} seconds)",
+                                network::SPEEDTEST_BUDGET.as_secs()
+                            )),
+                        );
+                        thread::spawn(move || {
+                            let sink = cb_sink.clone();
+                            let mirrors = network::speedtest_mirrors(mirrors_clone_2, |mirror, res| {
+                                let line = match res {
+                                    Ok(score) => format!(
+                                        "{}: {} ms, {}/s\n",
+                                        mirror.name,
+                                        score.latency.as_millis(),
+                                        human_size(score.throughput as u64)
+                                    ),
+                                    Err(_) => format!("{}: failed\n", mirror.name),
+                                };
+                                let results = results_copy.clone();
+                                sink.send(Box::new(move |_| results.append(line))).ok();
+                            });
+                            cb_sink
+                                .send(Box::new(move |s| {
+                                    s.pop_layer();
+                                    cues::play(Cue::InputRequired);
+                                    let ranked = !mirrors.is_empty();
+                                    let mirrors = if ranked { mirrors } else { unranked };
+                                    let (config_view, repo_list) = select_mirror_view_base(&mirrors);
+                                    s.add_layer(select_mirrors_view(
+                                        config_view,
+                                        config_clone_3.clone(),
+                                        repo_list,
+                                        mirrors,
+                                    ));
+                                    if !ranked {
+                                        show_msg(s, "Installer could not reach any mirror, please check your network connection!");
+                                    }
+                                }))
+                                .ok();
+                        });
                     })
                     .button("Cancel", move |s| {
                         let mirrors_clone_3 = mirrors_clone_2.clone();
@@ -474,7 +1206,7 @@ fn select_partition(siv: &mut Cursive, config: InstallConfig, dev: Rc<DkDerive>)
 
     let view = AsyncView::new_with_bg_creator(
         siv,
-        move || Ok(disks::list_partitions(Some(path))),
+        move || Ok(probe_partitions(path)),
         move |partitions| {
             let (disk_list, disk_view) = make_partition_list(partitions);
             let disk_list = SendWrapper::new(disk_list);
@@ -500,10 +1232,18 @@ fn select_partition(siv: &mut Cursive, config: InstallConfig, dev: Rc<DkDerive>)
         .child(view);
 
     let config_view = LinearLayout::vertical()
-        .child(Panel::new(dest_view).title("Select System Partition"))
+        .child(Panel::new(dest_view).title(format!(
+            "Select System Partition on {} ({}, {})",
+            dev.path.display(),
+            dev.model,
+            human_size(dev.size)
+        )))
         .child(DummyView {});
 
     let (btn_label, btn_cb) = partition_button(dev.path.to_path_buf());
+    let shrink_device_path = dev.path.to_path_buf();
+    let free_device_path = dev.path.to_path_buf();
+    let refresh_device_path = dev.path.to_path_buf();
     let config_copy = config.clone();
     let config_copy_2 = config.clone();
     let config_clone_3 = config.clone();
@@ -532,6 +1272,16 @@ fn select_partition(siv: &mut Cursive, config: InstallConfig, dev: Rc<DkDerive>)
                     show_msg(s, "Please specify a system partition.");
                     return;
                 }
+                if let Err(e) = disks::check_not_esp(&current_partition, required_size) {
+                    show_msg(s, &e.to_string());
+                    return;
+                }
+                let required_size = required_space(
+                    variant,
+                    current_partition.fs_type.as_deref(),
+                    current_partition.size,
+                    0,
+                );
                 if current_partition.size < required_size {
                     show_msg(
                         s,
@@ -542,90 +1292,27 @@ fn select_partition(siv: &mut Cursive, config: InstallConfig, dev: Rc<DkDerive>)
                         ));
                     return;
                 }
-                let mut config = config.clone();
-                let config_copy = config.clone();
-                let config_copy_2 = config.clone();
-                let fs_type = current_partition.fs_type.clone();
-                let current_partition_clone = current_partition.clone();
-
-                if let Err(e) = disks::right_combine(current_partition.parent_path.as_deref()) {
-                    let view = wrap_in_dialog(LinearLayout::vertical()
-                    .child(TextView::new(e.to_string())), "AOSC OS Installer", None)
-                    .button("OK", |s| {
-                        s.pop_layer();
-                    })
-                    .button("Exit", |s| s.quit());
-                    s.add_layer(view);
+                let usage = install::partition_usage(&current_partition);
+                if !usage.is_empty() {
+                    release_partition_view(s, config.clone(), current_partition, required_size, usage);
                     return;
                 }
 
-                if is_efi_booted() {
-                    let has_efi = disks::find_esp_partition(current_partition.parent_path.as_ref().unwrap()).is_ok();
-                    if !has_efi {
-                        show_msg(s, NO_ESP_ERROR);
-                        return;
-                    }
-                }
-
-                if let Some(fs_type) = fs_type {
-                    if fs_type != "ext4" && ALLOWED_FS_TYPE.contains(&fs_type.as_str()) {
-                        let view = wrap_in_dialog(LinearLayout::vertical()
-                        .child(TextView::new(format!(SURE_FS_TYPE_INFO!(), &fs_type))), "AOSC OS Installer", None)
-                        .button("Use Ext4", move |s| {
-                            let new_part = disks::fill_fs_type(current_partition.as_ref(), true);
-                            let mut config_clone = config_copy_2.clone();
-                            config_clone.partition = Some(Arc::new(new_part.clone()));
-                            s.pop_layer();
-                            continue_to_format_hdd(s, config_clone, new_part.fs_type.expect("Must unwrap success"));
-                        })
-                        .button(format!("Use {fs_type}"), move |s| {
-                            let new_part = disks::fill_fs_type(current_partition_clone.as_ref(), false);
-                            let mut config_clone = config_copy.clone();
-                            config_clone.partition = Some(Arc::new(new_part.clone()));
-                            s.pop_layer();
-                            continue_to_format_hdd(s, config_clone, new_part.fs_type.expect("Must unwrap success"));
-                        })
-                        .button("Cancel", move |s| {
-                            s.cb_sink()
-                            .send(Box::new(|s| {
-                                s.pop_layer();
-                            }))
-                            .unwrap()
-                        });
-                        s.add_layer(view);
-                    } else if fs_type == "ext4" {
-                        let new_part = disks::fill_fs_type(current_partition_clone.as_ref(), true);
-                        config.partition = Some(Arc::new(new_part.clone()));
-                        continue_to_format_hdd(s, config, new_part.fs_type.expect("Must unwrap success"));
-                    } else if !ALLOWED_FS_TYPE.contains(&fs_type.as_str()) {
-                        let view = wrap_in_dialog(LinearLayout::vertical()
-                        .child(TextView::new(ADVANCED_METHOD_INFO)), "AOSC OS Installer", None)
-                        .button("OK", move |s| {
-                            let new_part = disks::fill_fs_type(current_partition_clone.as_ref(), true);
-                            let mut config_clone = config_copy.clone();
-                            config_clone.partition = Some(Arc::new(new_part.clone()));
-                            s.pop_layer();
-                            continue_to_format_hdd(s, config_clone, new_part.fs_type.expect("Must unwrap success"));
-                        })
-                        .button("Cancel", move |s| {
-                            s.cb_sink()
-                            .send(Box::new(|s| {
-                                s.pop_layer();
-                            }))
-                            .unwrap()
-                        });
-                        s.add_layer(view);
-                    }
-                } else {
-                    let new_part = disks::fill_fs_type(current_partition_clone.as_ref(), true);
-                    config.partition = Some(Arc::new(new_part.clone()));
-                    continue_to_format_hdd(s, config, new_part.fs_type.expect("Must success unwrap"));
-                }
+                continue_with_partition(s, config.clone(), current_partition, required_size);
             }
         })
         .button(btn_label, move |s| {
             btn_cb(s, config_copy.clone());
         })
+        .button("Refresh", move |s| {
+            refresh_partitions_view(s, refresh_device_path.clone());
+        })
+        .button("Shrink", move |s| {
+            shrink_partition_view(s, shrink_device_path.clone());
+        })
+        .button("Use Free Space", move |s| {
+            free_space_partition_view(s, free_device_path.clone());
+        })
         .button("Partition for Me", move |s| {
             let dev_clone = dev_clone.clone();
             let path = dev.path.clone();
@@ -656,24 +1343,41 @@ fn select_partition(siv: &mut Cursive, config: InstallConfig, dev: Rc<DkDerive>)
 fn select_disk(siv: &mut Cursive, config: InstallConfig) {
     siv.pop_layer();
     let config_clone = config.clone();
+    let config_refresh = config.clone();
     let cb_sink = siv.cb_sink().clone();
 
     let disk_view = AsyncView::new_with_bg_creator(
         siv,
         move || {
-            let devices = disks::list_devices();
-            let devices = make_device_list(devices);
+            if test_mode::is_enabled() {
+                return Ok(vec![test_mode::test_device().map_err(|e| e.to_string())?]);
+            }
 
-            Ok(devices)
+            Ok(disks::list_devices())
         },
         move |devices| {
             let mut disk_view = LinearLayout::vertical();
             let mut disk_list = RadioGroup::new();
 
             for i in devices {
+                // so that the right disk can be told apart from others of
+                // the same model
+                let contents = if disks::has_partition_table(&i.path) {
+                    match disks::list_partitions(Some(i.path.clone())).len() {
+                        1 => ", 1 partition".to_string(),
+                        n => format!(", {n} partitions"),
+                    }
+                } else {
+                    ", no partition table".to_string()
+                };
                 let radio = disk_list.button(
                     i.clone(),
-                    format!("{} ({}, {})", i.path.display(), i.model, human_size(i.size)),
+                    format!(
+                        "{} ({}, {}{contents})",
+                        i.path.display(),
+                        disks::disk_info(&i.path),
+                        human_size(i.size)
+                    ),
                 );
                 disk_view.add_child(radio);
             }
@@ -689,12 +1393,17 @@ fn select_disk(siv: &mut Cursive, config: InstallConfig) {
         },
     );
 
-    let dest_view = LinearLayout::vertical()
-        .child(TextView::new(
-            "Please select a device as AOSC OS system drive.",
-        ))
-        .child(DummyView {})
-        .child(disk_view);
+    let mut dest_view = LinearLayout::vertical().child(TextView::new(
+        "Please select a device as AOSC OS system drive.",
+    ));
+    let controllers = disks::intel_raid_controllers();
+    if !controllers.is_empty() {
+        warn!("Intel RAID controllers found: {}", controllers.join(", "));
+        dest_view.add_child(DummyView {});
+        dest_view.add_child(TextView::new(INTEL_RAID_TEXT));
+    }
+    dest_view.add_child(DummyView {});
+    dest_view.add_child(disk_view);
 
     let config_view = LinearLayout::vertical()
         .child(Panel::new(dest_view).title("Select System Disk"))
@@ -716,9 +1425,17 @@ fn select_disk(siv: &mut Cursive, config: InstallConfig) {
                     };
 
                     siv.pop_layer();
-                    select_auto_make_partitions(siv, config_clone.clone(), device.to_owned());
+                    if disks::gpt_backup_damaged(&device.path) {
+                        repair_gpt_view(siv, config_clone.clone(), device.to_owned());
+                    } else {
+                        select_auto_make_partitions(siv, config_clone.clone(), device.to_owned());
+                    }
                 }
             })
+            .button("Refresh", move |s| {
+                // replaces this dialog, picking up disks plugged in since
+                select_disk(s, config_refresh.clone());
+            })
             .button("Back", move |s| {
                 s.pop_layer();
                 select_variant(s, config.clone());
@@ -729,6 +1446,37 @@ fn select_disk(siv: &mut Cursive, config: InstallConfig) {
     );
 }
 
+/// Offer to rewrite a damaged backup GPT before the partitions on the disk
+/// are read and changed
+fn repair_gpt_view(siv: &mut Cursive, config: InstallConfig, device: Rc<DkDerive>) {
+    let config_clone = config.clone();
+    let config_clone_2 = config.clone();
+    let device_clone = device.clone();
+    let view = wrap_in_dialog(
+        TextView::new(format!("{}\n\n{GPT_BACKUP_TEXT}", device.path.display())),
+        "AOSC OS Installer",
+        None,
+    )
+    .button("Repair", move |s| {
+        if let Err(e) = disks::repair_gpt_backup(&device.path) {
+            show_msg(s, &e.to_string());
+            return;
+        }
+        s.pop_layer();
+        select_auto_make_partitions(s, config.clone(), device.clone());
+    })
+    .button("Continue Without Repairing", move |s| {
+        s.pop_layer();
+        select_auto_make_partitions(s, config_clone.clone(), device_clone.clone());
+    })
+    .button("Back", move |s| {
+        s.pop_layer();
+        select_disk(s, config_clone_2.clone());
+    });
+
+    siv.add_layer(view);
+}
+
 fn select_auto_make_partitions(s: &mut Cursive, config: InstallConfig, device: Rc<DkDerive>) {
     let is_empty = device_is_empty(&device.path).unwrap_or(true);
 
@@ -760,30 +1508,46 @@ If you continue, the contents of your hard disk will be erased. Please make sure
     };
 
     if is_empty {
-        s.add_layer(
-            wrap_in_dialog(
-                TextView::new(format!(
-                    "{tips}\n\nSelect device: {select_device}\n\n{desc}"
-                )),
-                "AOSC OS Installer",
-                None,
-            )
-            .button("Continue", move |s| {
-                let device_path = device_path_1.clone();
-                let config_clone = config.clone();
-                s.pop_layer();
-                auto_partition_view(s, config_clone, &select_device, &desc, device_path);
-            })
-            .button(btn_label, move |s| {
-                select_partition(s, config_clone_3.clone(), device.clone());
-                btn_cb(s, config_clone_3.clone());
-            })
-            .button("Back", move |s| {
-                s.pop_layer();
-                select_disk(s, config_clone_2.clone());
-            })
-            .button("Quit", |s| s.quit()),
+        let has_table = disks::has_partition_table(&device.path);
+        let device_clone = device.clone();
+        let config_clone_4 = config.clone();
+        let mut dialog = wrap_in_dialog(
+            TextView::new(format!(
+                "{tips}\n\nSelect device: {select_device}\n\n{desc}"
+            )),
+            "AOSC OS Installer",
+            None,
         )
+        .button("Continue", move |s| {
+            let device_path = device_path_1.clone();
+            let config_clone = config.clone();
+            s.pop_layer();
+            auto_partition_view(s, config_clone, &select_device, &desc, device_path);
+        });
+        if !has_table {
+            // for those who would like to lay out partitions themselves,
+            // starting from "Use Free Space"
+            dialog.add_button(
+                "Create Partition Table",
+                move |s| match disks::create_partition_table(&device_clone.path) {
+                    Ok(()) => {
+                        s.pop_layer();
+                        select_partition(s, config_clone_4.clone(), device_clone.clone());
+                    }
+                    Err(e) => show_msg(s, &e.to_string()),
+                },
+            );
+        }
+        dialog.add_button(btn_label, move |s| {
+            select_partition(s, config_clone_3.clone(), device.clone());
+            btn_cb(s, config_clone_3.clone());
+        });
+        dialog.add_button("Back", move |s| {
+            s.pop_layer();
+            select_disk(s, config_clone_2.clone());
+        });
+        dialog.add_button("Quit", |s| s.quit());
+        s.add_layer(dialog);
     } else {
         select_partition(s, config, device);
     }
@@ -797,67 +1561,160 @@ fn auto_partition_view(
     device_path: PathBuf,
 ) {
     let tips = format!("WARNING: This will DESTROY ALL DATA ON THE SPECIFIED DRIVE, are you sure that you would want to proceed?\n\nSelect device: {select_device}\n\n{desc}");
+    let mut wipe_view = SelectView::new().popup();
+    for method in disks::WipeMethod::ALL {
+        wipe_view.add_item(method.description(), method);
+    }
+    let select_device = select_device.to_string();
     s.add_layer(
-        wrap_in_dialog(TextView::new(tips), "AOSC OS Installer", None)
-            .button("Yes, Please Partition My Drive!", move |s| {
-                let config_clone = config_clone.clone();
-                let config = config_clone.clone();
-                let variant = config.variant.unwrap();
-                let device_path = device_path.clone();
-                let required_size = variant.install_size + variant.size;
-                let view = AsyncView::new_with_bg_creator(
-                    s,
-                    move || match auto_create_partitions(&device_path) {
-                        Ok(part) => {
-                            if required_size > part.size {
-                                return Err(format!(
-                                    "The specified partition does not contain enough space to install AOSC OS release!\n\nAvailable space: {:.3}GiB\nRequired space: {:.3}GiB", 
-                                    part.size as f32 / 1024.0 / 1024.0 / 1024.0,
-                                    required_size as f32 / 1024.0 / 1024.0 / 1024.0
-                                ));
-                            }
-
-                            Ok(part)
-                        },
-                        Err(e) => Err(e.to_string()),
-                    },
-                    move |res| {
-                        let mut config = config_clone.clone();
-                        config.partition = Some(Arc::new(res));
-                        select_user_password(config)
-                    },
-                );
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(tips))
+                .child(DummyView {})
+                .child(
+                    ListView::new()
+                        .child("Before partitioning: ", wipe_view.with_name("wipe_method")),
+                ),
+            "AOSC OS Installer",
+            None,
+        )
+        .button("Yes, Please Partition My Drive!", move |s| {
+            let wipe = s
+                .call_on_name("wipe_method", |v: &mut SelectView<disks::WipeMethod>| {
+                    v.selection()
+                })
+                .flatten()
+                .map(|x| *x)
+                .unwrap_or_default();
+            s.pop_layer();
+            if wipe == disks::WipeMethod::None {
+                auto_partition(s, config_clone.clone(), device_path.clone(), wipe);
+                return;
+            }
 
-                s.pop_layer();
-                s.add_layer(view);
-            })
-            .button("No", move |s| {
-                s.pop_layer();
-            }),
+            let config_clone = config_clone.clone();
+            let device_path = device_path.clone();
+            s.add_layer(
+                wrap_in_dialog(
+                    TextView::new(format!(WIPE_DISK_WARNING!(), select_device)),
+                    "WARNING",
+                    None,
+                )
+                .button("Erase Everything", move |s| {
+                    s.pop_layer();
+                    auto_partition(s, config_clone.clone(), device_path.clone(), wipe);
+                })
+                .button("Cancel", |s| {
+                    s.pop_layer();
+                }),
+            );
+        })
+        .button("No", move |s| {
+            s.pop_layer();
+        }),
     );
 }
 
-fn continue_to_format_hdd(s: &mut Cursive, config_clone: InstallConfig, fs_type: String) {
-    let path = config_clone
-        .partition
-        .as_ref()
-        .expect("Must unwrap success")
-        .path
-        .as_ref()
-        .expect("Must unwrap success")
-        .to_str()
-        .expect("Must as string");
-
-    let dialog = LinearLayout::vertical().child(TextView::new(format!(
-        SURE_FS_FORMAT_INFO!(),
-        path, fs_type
-    )));
-
-    let view = wrap_in_dialog(dialog, "AOSC OS Installer", None)
-        .button("OK", move |s| {
-            partition_view_to_next(s, config_clone.clone())
-        })
-        .button("Cancel", move |s| {
+fn auto_partition(
+    s: &mut Cursive,
+    config_clone: InstallConfig,
+    device_path: PathBuf,
+    wipe: disks::WipeMethod,
+) {
+    let config = config_clone.clone();
+    let variant = config.variant.unwrap();
+    let view = AsyncView::new_with_bg_creator(
+        s,
+        move || {
+            disks::wipe_disk(&device_path, wipe).map_err(|e| e.to_string())?;
+            match auto_create_partitions(&device_path) {
+                Ok(part) => {
+                    let required_size =
+                        required_space(&variant, part.fs_type.as_deref(), part.size, 0);
+                    if required_size > part.size {
+                        return Err(format!(
+                            "The specified partition does not contain enough space to install AOSC OS release!\n\nAvailable space: {:.3}GiB\nRequired space: {:.3}GiB", 
+                            part.size as f32 / 1024.0 / 1024.0 / 1024.0,
+                            required_size as f32 / 1024.0 / 1024.0 / 1024.0
+                        ));
+                    }
+
+                    Ok(part)
+                }
+                Err(e) => Err(e.to_string()),
+            }
+        },
+        move |res| {
+            let mut config = config_clone.clone();
+            config.partition = Some(Arc::new(res));
+            // the new ESP is on the same disk
+            config.esp = None;
+            select_user_password(config)
+        },
+    );
+
+    s.add_layer(view);
+}
+
+fn continue_to_format_hdd(s: &mut Cursive, config_clone: InstallConfig, fs_type: String) {
+    let path = config_clone
+        .partition
+        .as_ref()
+        .expect("Must unwrap success")
+        .path
+        .as_ref()
+        .expect("Must unwrap success")
+        .to_str()
+        .expect("Must as string");
+
+    let dialog = LinearLayout::vertical()
+        .child(TextView::new(format!(
+            SURE_FS_FORMAT_INFO!(),
+            path, fs_type
+        )))
+        .child(DummyView {})
+        .child(
+            LinearLayout::horizontal()
+                .child(
+                    Checkbox::new()
+                        .with_checked(config_clone.encryption.is_some())
+                        .with_name("encrypt"),
+                )
+                .child(TextView::new(" Encrypt this partition (LVM on LUKS)")),
+        );
+    let dialog = if fs_type == "btrfs" {
+        dialog.child(
+            LinearLayout::horizontal()
+                .child(
+                    Checkbox::new()
+                        .with_checked(config_clone.btrfs_snapshot)
+                        .with_name("btrfs_snapshot"),
+                )
+                .child(TextView::new(
+                    " Take a read-only snapshot after installation",
+                )),
+        )
+    } else {
+        dialog
+    };
+
+    let view = wrap_in_dialog(dialog, "AOSC OS Installer", None)
+        .button("OK", move |s| {
+            let encrypt = s
+                .call_on_name("encrypt", |v: &mut Checkbox| v.is_checked())
+                .unwrap_or(false);
+            let mut config = config_clone.clone();
+            config.btrfs_snapshot = s
+                .call_on_name("btrfs_snapshot", |v: &mut Checkbox| v.is_checked())
+                .unwrap_or(false);
+            if encrypt {
+                select_encryption_passphrase(s, config);
+            } else {
+                config.encryption = None;
+                partition_view_to_next(s, config);
+            }
+        })
+        .button("Cancel", move |s| {
             s.cb_sink()
                 .send(Box::new(|s| {
                     s.pop_layer();
@@ -868,783 +1725,2634 @@ fn continue_to_format_hdd(s: &mut Cursive, config_clone: InstallConfig, fs_type:
     s.add_layer(view);
 }
 
-fn partition_view_to_next(s: &mut Cursive, config_clone: InstallConfig) {
-    s.pop_layer();
-    if config_clone.user.is_some() {
-        is_use_last_config(s, config_clone);
-    } else {
-        s.add_layer(select_user_password(config_clone));
-    }
-}
-
-fn select_user_password(config: InstallConfig) -> Dialog {
-    let password = Rc::new(RefCell::new(String::new()));
-    let password_copy = Rc::clone(&password);
-    let password_confirm = Rc::new(RefCell::new(String::new()));
-    let password_confirm_copy = Rc::clone(&password_confirm);
-    let name = Rc::new(RefCell::new(String::new()));
-    let name_copy = Rc::clone(&name);
-    let full_name = Rc::new(RefCell::new(String::new()));
-    let full_name_copy = Rc::clone(&full_name);
-    let user_password_textview = TextView::new(ENTER_USER_PASSWORD_TEXT).max_width(80);
-    let root_password = Rc::new(RefCell::new(String::new()));
-    let root_password_copy = Rc::clone(&root_password);
-    let root_password_confirm = Rc::new(RefCell::new(String::new()));
-    let root_password_confirm_copy = Rc::clone(&root_password_confirm);
+fn select_encryption_passphrase(siv: &mut Cursive, config: InstallConfig) {
+    let passphrase = Rc::new(RefCell::new(String::new()));
+    let passphrase_copy = Rc::clone(&passphrase);
+    let passphrase_confirm = Rc::new(RefCell::new(String::new()));
+    let passphrase_confirm_copy = Rc::clone(&passphrase_confirm);
 
-    let mut user_password_view = ListView::new()
-        .child(
-            "Full name",
-            EditView::new()
-                .on_edit_mut(move |_, c, _| {
-                    full_name_copy.replace(c.to_owned());
-                })
-                .min_width(20)
-                .with_name("full_name"),
-        )
+    let view = ListView::new()
         .child(
-            "Username",
+            "Passphrase",
             EditView::new()
+                .secret()
                 .on_edit_mut(move |_, c, _| {
-                    name_copy.replace(c.to_owned());
+                    passphrase_copy.replace(c.to_owned());
                 })
-                .min_width(20)
-                .with_name("user"),
+                .min_width(20),
         )
         .child(
-            "Password",
+            "Confirm Passphrase",
             EditView::new()
                 .secret()
                 .on_edit_mut(move |_, c, _| {
-                    password_copy.replace(c.to_owned());
+                    passphrase_confirm_copy.replace(c.to_owned());
                 })
-                .min_width(20)
-                .with_name("pwd"),
+                .min_width(20),
         )
         .child(
-            "Confirm Password",
-            EditView::new()
-                .secret()
-                .on_edit_mut(move |_, c, _| {
-                    password_confirm_copy.replace(c.to_owned());
-                })
-                .min_width(20)
-                .with_name("pwd2"),
+            "Generate a recovery key",
+            Checkbox::new()
+                .with_checked(
+                    config
+                        .encryption
+                        .as_ref()
+                        .is_some_and(|x| x.recovery_key.is_some()),
+                )
+                .with_name("recovery_key"),
         );
 
-    if cfg!(feature = "is_retro") {
-        user_password_view = user_password_view
-            .child(
-                "Root Password",
-                EditView::new()
-                    .secret()
-                    .on_edit_mut(move |_, c, _| {
-                        root_password_copy.replace(c.to_owned());
-                    })
-                    .min_width(20)
-                    .with_name("root_pwd"),
-            )
-            .child(
-                "Root Password Confirm",
-                EditView::new()
-                    .secret()
-                    .on_edit_mut(move |_, c, _| {
-                        root_password_confirm_copy.replace(c.to_owned());
-                    })
-                    .min_width(20)
-                    .with_name("root_pwd2"),
-            )
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(ENCRYPTION_TEXT))
+                .child(DummyView {})
+                .child(view),
+            "Disk Encryption",
+            None,
+        )
+        .button("Continue", move |s| {
+            let passphrase = passphrase.as_ref().to_owned().into_inner();
+            let passphrase_confirm = passphrase_confirm.as_ref().to_owned().into_inner();
+            if passphrase.is_empty() || passphrase_confirm.is_empty() {
+                fill_in_all_the_fields!(s);
+            }
+            if passphrase != passphrase_confirm {
+                show_msg(s, "Passphrases do not match.");
+                return;
+            }
+
+            let recovery_key = s
+                .call_on_name("recovery_key", |v: &mut Checkbox| v.is_checked())
+                .unwrap_or(false)
+                .then(crypt::generate_recovery_key);
+
+            let mut config = config.clone();
+            config.encryption = Some(crypt::EncryptionConfig {
+                passphrase,
+                recovery_key: recovery_key.clone(),
+            });
+            s.pop_layer();
+            match recovery_key {
+                Some(key) => show_recovery_key(s, config, key),
+                // partition_view_to_next() drops the format confirmation
+                None => partition_view_to_next(s, config),
+            }
+        })
+        .button("Back", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+fn show_recovery_key(siv: &mut Cursive, config: InstallConfig, key: String) {
+    let key_copy = key.clone();
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(RECOVERY_KEY_TEXT))
+                .child(DummyView {})
+                .child(TextView::new(key).center()),
+            "Recovery Key",
+            None,
+        )
+        .button("Save to File", move |s| {
+            match fs::write(RECOVERY_KEY_FILE, format!("{key_copy}\n")) {
+                Ok(()) => show_msg(
+                    s,
+                    &format!("The recovery key has been saved to {RECOVERY_KEY_FILE}, which is lost after rebooting. Please copy it to another device."),
+                ),
+                Err(e) => show_msg(s, &e.to_string()),
+            }
+        })
+        .button("Continue", move |s| {
+            // partition_view_to_next() drops the format confirmation
+            s.pop_layer();
+            partition_view_to_next(s, config.clone());
+        }),
+    );
+}
+
+/// The selected partition is mounted or swapped to, it has to be released
+/// before it can be formatted
+fn release_partition_view(
+    siv: &mut Cursive,
+    config: InstallConfig,
+    current_partition: Rc<disks::Partition>,
+    required_size: u64,
+    usage: Vec<String>,
+) {
+    let msg = format!(
+        "{} is currently in use:\n\n{}\n\nInstaller has to unmount it before it can be formatted. Please make sure that no program is using files on it.",
+        current_partition
+            .path
+            .as_deref()
+            .unwrap_or(Path::new("?"))
+            .display(),
+        usage
+            .iter()
+            .map(|x| format!("- {x}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    );
+    siv.add_layer(
+        wrap_in_dialog(TextView::new(msg), "AOSC OS Installer", None)
+            .button("Unmount", move |s| {
+                s.pop_layer();
+                if let Err(e) = install::release_partition(&current_partition) {
+                    show_msg(s, &e.to_string());
+                    return;
+                }
+                continue_with_partition(
+                    s,
+                    config.clone(),
+                    current_partition.clone(),
+                    required_size,
+                );
+            })
+            .button("Cancel", |s| {
+                s.pop_layer();
+            }),
+    );
+}
+
+fn continue_with_partition(
+    s: &mut Cursive,
+    config: InstallConfig,
+    current_partition: Rc<disks::Partition>,
+    required_size: u64,
+) {
+    if let Err(e) = disks::right_combine(current_partition.parent_path.as_deref()) {
+        let view = wrap_in_dialog(
+            LinearLayout::vertical().child(TextView::new(e.to_string())),
+            "AOSC OS Installer",
+            None,
+        )
+        .button("OK", |s| {
+            s.pop_layer();
+        })
+        .button("Exit", |s| s.quit());
+        s.add_layer(view);
+        return;
+    }
+    if let Err(e) = disks::check_sector_size(&current_partition) {
+        show_msg(s, &e.to_string());
+        return;
     }
 
-    let config_clone = config.clone();
-    let mut user_password_dialog = wrap_in_dialog(
-        LinearLayout::vertical()
-            .child(user_password_textview)
-            .child(DummyView {})
-            .child(user_password_view),
-        "AOSC OS Installer",
-        None,
-    )
-    .button("Continue", move |s| {
-        let password = password.as_ref().to_owned().into_inner();
-        let password_confirm = password_confirm.as_ref().to_owned().into_inner();
-        let name = name.as_ref().to_owned().into_inner();
-        let full_name = full_name.as_ref().to_owned().into_inner();
-        let root_password = root_password.as_ref().to_owned().into_inner();
-        let root_password_confirm = root_password_confirm.as_ref().to_owned().into_inner();
+    let mut config = config;
+    config.realign_partition = false;
+    if !disks::is_aligned(&current_partition) {
+        realign_partition_view(s, config, current_partition, required_size);
+        return;
+    }
 
-        if full_name.contains('\n') || full_name.contains(':') {
-            show_msg(s, "Full name is not valid, please refer to the criteria specified on top of the dialog.");
-            return;
-        }
+    continue_to_create_esp(s, config, current_partition, required_size);
+}
 
-        if !install::is_acceptable_username(&name) {
-            show_msg(s, "Username is not valid, please refer to the criteria specified on top of the dialog.");
+fn realign_partition_view(
+    siv: &mut Cursive,
+    config: InstallConfig,
+    current_partition: Rc<disks::Partition>,
+    required_size: u64,
+) {
+    let config_copy = config.clone();
+    let current_partition_copy = current_partition.clone();
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical().child(TextView::new(MISALIGNED_TEXT)),
+            "AOSC OS Installer",
+            None,
+        )
+        .button("Realign", move |s| {
+            let mut config = config.clone();
+            config.realign_partition = true;
+            s.pop_layer();
+            continue_to_create_esp(s, config, current_partition.clone(), required_size);
+        })
+        .button("Continue Anyway", move |s| {
+            s.pop_layer();
+            continue_to_create_esp(
+                s,
+                config_copy.clone(),
+                current_partition_copy.clone(),
+                required_size,
+            );
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+fn continue_to_create_esp(
+    s: &mut Cursive,
+    config: InstallConfig,
+    current_partition: Rc<disks::Partition>,
+    required_size: u64,
+) {
+    let mut config = config;
+    config.create_esp = false;
+    config.create_bios_grub = false;
+    let parent = current_partition.parent_path.clone().unwrap();
+    if is_efi_booted() {
+        let has_efi =
+            disks::find_esp_partition(&parent).is_ok() || !disks::list_esp_partitions().is_empty();
+        if !has_efi {
+            create_esp_view(s, config, current_partition, &parent, required_size);
             return;
         }
+    } else if disks::needs_bios_grub_partition(&parent) {
+        create_bios_grub_view(s, config, current_partition, &parent);
+        return;
+    }
 
-        if password.is_empty() || password_confirm.is_empty() || name.is_empty() || (cfg!(feature = "is_retro") && (root_password.is_empty() || root_password_confirm.is_empty())) {
-            fill_in_all_the_fields!(s);
-        }
+    select_fs_type(s, config, current_partition);
+}
 
-        if password != password_confirm {
-            show_msg(s, "Passwords password do not match.");
+fn create_bios_grub_view(
+    siv: &mut Cursive,
+    config: InstallConfig,
+    current_partition: Rc<disks::Partition>,
+    parent: &Path,
+) {
+    match disks::find_free_space(parent, disks::BIOS_GRUB_SIZE) {
+        Ok(Some(_)) => (),
+        Ok(None) => {
+            show_msg(siv, NO_BIOS_GRUB_ERROR);
             return;
         }
-
-        if root_password != root_password_confirm {
-            show_msg(s, "Passwords (root) password do not match.");
+        Err(e) => {
+            error!("Could not inspect {}: {e}", parent.display());
+            show_msg(siv, NO_BIOS_GRUB_ERROR);
             return;
         }
+    }
 
-        let mut config = config.clone();
-        config.password = Some(Arc::new(password));
-        config.user = Some(Arc::new(name));
-        config.full_name = Some(Arc::new(full_name));
-        config.root_password = if !root_password.is_empty() {
-            Some(Arc::new(root_password))
-        } else {
-            None
-        };
-        s.pop_layer();
-        select_hostname(s, config);
-    })
-    .button("Back", move |s| {
-        s.pop_layer();
-        select_disk(s, config_clone.clone());
-    })
-    .button("Exit", |s| s.quit());
-
-    user_password_dialog
-        .focus_view(&Selector::Name("full_name"))
-        .ok();
-
-    user_password_dialog
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical().child(TextView::new(CREATE_BIOS_GRUB_TEXT)),
+            "AOSC OS Installer",
+            None,
+        )
+        .button("Create", move |s| {
+            let mut config = config.clone();
+            config.create_bios_grub = true;
+            s.pop_layer();
+            select_fs_type(s, config, current_partition.clone());
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        }),
+    );
 }
 
-fn select_hostname(siv: &mut Cursive, config: InstallConfig) {
-    siv.pop_layer();
-    let hostname = Rc::new(RefCell::new(String::new()));
-    let hostname_copy = Rc::clone(&hostname);
-    let hostname_textview = TextView::new(ENTER_HOSTNAME_TEXT);
-    let hostname_view = ListView::new()
-        .child(
-            "Hostname",
-            EditView::new()
-                .on_edit_mut(move |_, c, _| {
-                    hostname_copy.replace(c.to_owned());
-                })
-                .min_width(20)
-                .with_name("hostname"),
-        )
-        .delimiter();
-    let config_clone = config.clone();
-    let hostname_dialog = wrap_in_dialog(
-        LinearLayout::vertical()
-            .child(hostname_textview)
-            .child(DummyView {})
-            .child(hostname_view),
-        "AOSC OS Installer",
-        None,
-    )
-    .button("Continue", move |s| {
-        let hostname = hostname.as_ref().to_owned().into_inner();
-        if hostname.is_empty() {
-            fill_in_all_the_fields!(s);
-        }
-        if !install::is_valid_hostname(&hostname) {
-            show_msg(s, "Hostname is not vaild!");
+fn create_esp_view(
+    siv: &mut Cursive,
+    config: InstallConfig,
+    current_partition: Rc<disks::Partition>,
+    parent: &Path,
+    required_size: u64,
+) {
+    let shrink = match disks::esp_free_space(parent) {
+        Ok(free) => free.is_none(),
+        Err(e) => {
+            error!("Could not inspect {}: {e}", parent.display());
+            show_msg(siv, NO_ESP_ERROR);
             return;
         }
-        let mut config = config.clone();
-        config.hostname = Some(hostname);
-        select_timezone(s, config);
-    })
-    .button("Back", move |s| {
-        s.pop_layer();
-        s.add_layer(select_user_password(config_clone.clone()));
-    })
-    .button("Exit", |s| s.quit());
-
-    siv.add_layer(hostname_dialog);
-}
-
-fn select_timezone(siv: &mut Cursive, config: InstallConfig) {
-    siv.pop_layer();
-
-    let now_locale = read_locale().unwrap_or_else(|| "C.UTF-8".to_string());
-    let now_language = find_language_by_locale(&now_locale).unwrap_or("No localization (UTF-8)");
-
-    let locale = Rc::new(RefCell::new(String::from(now_language)));
-    let locale_copy = Rc::clone(&locale);
-    let timezone = Rc::new(RefCell::new(String::from("UTC")));
-    let timezone_copy = Rc::clone(&timezone);
-    // RTC/UTC default is UTC
-    let tc = Rc::new(RefCell::new(String::from("UTC")));
-    let tc_copy = Rc::clone(&tc);
-    let locales = Arc::new(install::get_locale_list().unwrap());
-    let timezone_textview = TextView::new(ENTER_TIMEZONE_TEXT);
-    let mut timezone_selected_status = TextView::new("UTC");
-    let timezone_status_text = Arc::new(timezone_selected_status.get_shared_content());
-    let mut locale_selected_status = TextView::new(now_language);
-    let locale_status_text = Arc::new(locale_selected_status.get_shared_content());
+    };
+    if shrink && current_partition.size < required_size + disks::ESP_SIZE {
+        show_msg(siv, NO_ESP_ERROR);
+        return;
+    }
 
-    let timezone_view = ListView::new()
-        .child(
-            "Timezone",
-            Button::new("Select timezone", move |s| {
-                let zoneinfo = install::get_zoneinfo_list().unwrap();
-                s.add_layer(set_timezone(
-                    zoneinfo,
-                    timezone_copy.clone(),
-                    timezone_status_text.clone(),
-                ))
-            }),
+    let msg = if shrink {
+        format!(
+            "{CREATE_ESP_TEXT}\n\nThere is not enough free space on {}, the last 512MiB of {} will be used instead.",
+            parent.display(),
+            current_partition.path.as_deref().unwrap_or(Path::new("?")).display()
         )
-        .child("Selected Timezone", timezone_selected_status.center())
-        .child(
-            "Locale",
-            Button::new("Select locale", move |s| {
-                s.add_layer(set_locales(
-                    locales.to_vec(),
-                    locale_copy.clone(),
-                    locale_status_text.clone(),
-                ))
-            }),
+    } else {
+        format!(
+            "{CREATE_ESP_TEXT}\n\nThe free space on {} will be used.",
+            parent.display()
         )
-        .child("Selected locale", locale_selected_status.center())
-        .child(
-            "RTC Timezone",
-            SelectView::new()
-                .autojump()
-                .popup()
-                .with_all_str(vec!["UTC (Recommended)", "Local time (like Windows)"])
-                .on_submit(move |_, c: &str| {
-                    let selected = match c {
-                        "UTC (Recommended)" => "UTC",
-                        "Local time (like Windows)" => "RTC",
-                        _ => unreachable!(),
-                    };
-                    tc_copy.replace(selected.to_string());
-                })
-                .min_width(20),
-        );
-    let config_clone = config.clone();
-    let timezone_dialog = wrap_in_dialog(
-        LinearLayout::vertical()
-            .child(timezone_textview)
-            .child(DummyView {})
-            .child(timezone_view),
-        "AOSC OS Installer",
-        None,
-    )
-    .button("Continue", move |s| {
-        // language to locale
-        let locale = locale.as_ref().to_owned().into_inner();
-        let locale = find_locale_by_language(&locale).unwrap_or("C.UTF-8");
+    };
 
-        let mut timezone = timezone.as_ref().to_owned().into_inner();
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical().child(TextView::new(msg)),
+            "AOSC OS Installer",
+            None,
+        )
+        .button("Create ESP", move |s| {
+            let mut config = config.clone();
+            config.create_esp = true;
+            s.pop_layer();
+            select_fs_type(s, config, current_partition.clone());
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        }),
+    );
+}
 
-        // tzdata 的北京时间是 Asia/Shanghai
-        if timezone == "Asia/Beijing" {
-            timezone = "Asia/Shanghai".to_string();
+fn select_fs_type(s: &mut Cursive, config: InstallConfig, current_partition: Rc<disks::Partition>) {
+    let mut config = config;
+    let config_copy = config.clone();
+    let config_copy_2 = config.clone();
+    let fs_type = current_partition.fs_type.clone();
+    let current_partition_clone = current_partition.clone();
+
+    if let Some(fs_type) = fs_type {
+        if fs_type != "ext4" && ALLOWED_FS_TYPE.contains(&fs_type.as_str()) {
+            let view = wrap_in_dialog(
+                LinearLayout::vertical()
+                    .child(TextView::new(format!(SURE_FS_TYPE_INFO!(), &fs_type))),
+                "AOSC OS Installer",
+                None,
+            )
+            .button("Use Ext4", move |s| {
+                let new_part = disks::fill_fs_type(current_partition.as_ref(), true);
+                let mut config_clone = config_copy_2.clone();
+                config_clone.partition = Some(Arc::new(new_part.clone()));
+                s.pop_layer();
+                continue_to_format_hdd(
+                    s,
+                    config_clone,
+                    new_part.fs_type.expect("Must unwrap success"),
+                );
+            })
+            .button(format!("Use {fs_type}"), move |s| {
+                let new_part = disks::fill_fs_type(current_partition_clone.as_ref(), false);
+                let mut config_clone = config_copy.clone();
+                config_clone.partition = Some(Arc::new(new_part.clone()));
+                s.pop_layer();
+                continue_to_format_hdd(
+                    s,
+                    config_clone,
+                    new_part.fs_type.expect("Must unwrap success"),
+                );
+            })
+            .button("Cancel", move |s| {
+                s.cb_sink()
+                    .send(Box::new(|s| {
+                        s.pop_layer();
+                    }))
+                    .unwrap()
+            });
+            s.add_layer(view);
+        } else if fs_type == "ext4" {
+            let new_part = disks::fill_fs_type(current_partition_clone.as_ref(), true);
+            config.partition = Some(Arc::new(new_part.clone()));
+            continue_to_format_hdd(s, config, new_part.fs_type.expect("Must unwrap success"));
+        } else if !ALLOWED_FS_TYPE.contains(&fs_type.as_str()) {
+            let view = wrap_in_dialog(
+                LinearLayout::vertical().child(TextView::new(ADVANCED_METHOD_INFO)),
+                "AOSC OS Installer",
+                None,
+            )
+            .button("OK", move |s| {
+                let new_part = disks::fill_fs_type(current_partition_clone.as_ref(), true);
+                let mut config_clone = config_copy.clone();
+                config_clone.partition = Some(Arc::new(new_part.clone()));
+                s.pop_layer();
+                continue_to_format_hdd(
+                    s,
+                    config_clone,
+                    new_part.fs_type.expect("Must unwrap success"),
+                );
+            })
+            .button("Cancel", move |s| {
+                s.cb_sink()
+                    .send(Box::new(|s| {
+                        s.pop_layer();
+                    }))
+                    .unwrap()
+            });
+            s.add_layer(view);
         }
+    } else {
+        let new_part = disks::fill_fs_type(current_partition_clone.as_ref(), true);
+        config.partition = Some(Arc::new(new_part.clone()));
+        continue_to_format_hdd(s, config, new_part.fs_type.expect("Must success unwrap"));
+    }
+}
 
-        let tc = tc.as_ref().to_owned().into_inner();
-        if locale.is_empty() || timezone.is_empty() || tc.is_empty() {
-            fill_in_all_the_fields!(s);
+fn partition_view_to_next(s: &mut Cursive, config_clone: InstallConfig) {
+    s.pop_layer();
+    if EXPERT_MODE.load(Ordering::SeqCst) {
+        let is_btrfs = config_clone
+            .partition
+            .as_ref()
+            .is_some_and(|x| install::is_btrfs(x));
+        if is_btrfs && config_clone.encryption.is_none() {
+            select_btrfs_mirror(s, config_clone);
+        } else {
+            let mut config_clone = config_clone;
+            config_clone.btrfs_mirror = None;
+            select_home(s, config_clone);
         }
-
-        let mut config = config.clone();
-        config.locale = Some(Arc::new(locale.to_string()));
-        config.timezone = Some(Arc::new(timezone));
-        config.tc = Some(Arc::new(tc));
-        select_swap(s, config);
-    })
-    .button("Back", move |s| {
-        s.pop_layer();
-        select_hostname(s, config_clone.clone());
-    })
-    .button("Exit", |s| s.quit());
-
-    siv.add_layer(timezone_dialog);
+    } else {
+        let mut config_clone = config_clone;
+        config_clone.home = None;
+        continue_to_esp(s, config_clone);
+    }
 }
 
-// Filter cities with names containing query string. You can implement your own logic here!
-fn search_fn<T: std::iter::IntoIterator<Item = String>>(items: T, query: &str) -> Vec<String> {
-    items
+/// Offer to mirror a btrfs system partition to a partition on another disk
+fn select_btrfs_mirror(siv: &mut Cursive, config: InstallConfig) {
+    let root = config.partition.as_ref().and_then(|x| x.path.clone());
+    let root_disk = config
+        .partition
+        .as_ref()
+        .and_then(|x| x.parent_path.clone());
+    let esps = disks::list_esp_partitions()
         .into_iter()
-        .filter(|item| {
-            let item = item.to_lowercase();
-            let query = query.to_lowercase();
-            item.contains(&query)
+        .map(|x| x.path)
+        .collect::<Vec<_>>();
+    let others = InstallConfig {
+        btrfs_mirror: None,
+        ..config.clone()
+    };
+    // only partitions that may be formatted, not e.g. Windows volumes
+    let candidates = disks::list_partitions(None).into_iter().filter(|x| {
+        x.path.as_deref().is_some_and(|x| !others.uses_partition(x))
+            && x.path != root
+            && x.parent_path != root_disk
+            && !esps.contains(&x.path)
+            && x.fs_type
+                .as_deref()
+                .map_or(true, |x| ALLOWED_FS_TYPE.contains(&x))
+    });
+    let selected_path = config.btrfs_mirror.as_ref().and_then(|x| x.path.clone());
+
+    let mut view = SelectView::new().item("Do not mirror the system partition", None);
+    let mut selected = 0;
+    for (i, part) in candidates.enumerate() {
+        if part.path == selected_path {
+            selected = i + 1;
+        }
+        let label = format!(
+            "{} ({}, {})",
+            part.path.as_deref().unwrap_or(Path::new("?")).display(),
+            part.fs_type.as_deref().unwrap_or("unformatted"),
+            human_size(part.size)
+        );
+        view.add_item(label, Some(part));
+    }
+
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(BTRFS_MIRROR_TEXT))
+                .child(DummyView {})
+                .child(view.selected(selected).with_name("select_mirror")),
+            "Mirror System Partition",
+            None,
+        )
+        .button("Continue", move |s| {
+            let mirror = s
+                .call_on_name(
+                    "select_mirror",
+                    |v: &mut SelectView<Option<disks::Partition>>| v.selection(),
+                )
+                .flatten()
+                .and_then(|x| x.as_ref().clone())
+                .map(Arc::new);
+            let mut config = config.clone();
+            config.btrfs_mirror = mirror;
+            s.pop_layer();
+            select_home(s, config);
         })
-        .collect()
+        .button("Back", |s| {
+            s.pop_layer();
+        }),
+    );
 }
 
-fn search_fn_locales(items: Vec<(String, String, String)>, query: &str) -> Vec<String> {
-    items
+/// Offer to keep an existing partition as /home
+fn select_home(siv: &mut Cursive, config: InstallConfig) {
+    let root = config.partition.as_ref().and_then(|x| x.path.clone());
+    let esps = disks::list_esp_partitions()
         .into_iter()
-        .filter(|item| {
-            let (lang, locale, lang_english) = item;
-            let lang = lang.to_lowercase();
-            let locale = locale.to_lowercase();
-            let lang_english = lang_english.to_lowercase();
-            let query = query.to_lowercase();
+        .map(|x| x.path)
+        .collect::<Vec<_>>();
+    let others = InstallConfig {
+        home: None,
+        ..config.clone()
+    };
+    let selected_path = config.home.as_ref().and_then(|x| x.path.clone());
+    // the root of another system is no /home either, finding out mounts
+    // every partition
+    let view = AsyncView::new_with_bg_creator(
+        siv,
+        move || {
+            Ok(disks::list_partitions(None)
+                .into_iter()
+                .filter(|x| {
+                    x.fs_type.is_some()
+                        && !disks::is_swap(x)
+                        && x.path != root
+                        && !esps.contains(&x.path)
+                        && x.path.as_deref().is_some_and(|x| !others.uses_partition(x))
+                        && disks::detect_os(x).is_none()
+                })
+                .collect::<Vec<_>>())
+        },
+        move |candidates| {
+            let mut view = SelectView::new().item("Do not use a separate /home partition", None);
+            let mut selected = 0;
+            for (i, part) in candidates.into_iter().enumerate() {
+                if part.path.is_some() && part.path == selected_path {
+                    selected = i + 1;
+                }
+                let label = format!(
+                    "{} ({}, {})",
+                    part.path.as_deref().unwrap_or(Path::new("?")).display(),
+                    part.fs_type.as_deref().unwrap_or_default(),
+                    human_size(part.size)
+                );
+                view.add_item(label, Some(part));
+            }
 
-            lang.contains(&query) || locale.contains(&query) || lang_english.contains(&query)
+            view.selected(selected).with_name("select_home")
+        },
+    );
+
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(SELECT_HOME_TEXT))
+                .child(DummyView {})
+                .child(view),
+            "Select /home Partition",
+            None,
+        )
+        .button("Continue", move |s| {
+            // still looking for partitions
+            let Some(selection) = s.call_on_name(
+                "select_home",
+                |v: &mut SelectView<Option<disks::Partition>>| v.selection(),
+            ) else {
+                return;
+            };
+            let home = selection.and_then(|x| x.as_ref().clone()).map(Arc::new);
+            let mut config = config.clone();
+            config.home = home;
+            s.pop_layer();
+            continue_to_esp(s, config);
         })
-        .map(|x| x.0.to_string())
-        .collect()
+        .button("Back", |s| {
+            s.pop_layer();
+        }),
+    );
 }
 
-fn replace_item(
-    siv: &mut Cursive,
-    item: &str,
-    timezone: Rc<RefCell<String>>,
-    status_text: Arc<TextContent>,
-) {
-    siv.pop_layer();
-    timezone.replace(item.to_string());
-    status_text.set_content(item);
+fn continue_to_esp(s: &mut Cursive, config_clone: InstallConfig) {
+    let mut config_clone = config_clone;
+    config_clone.esp = None;
+    if is_efi_booted() {
+        let esps = disks::list_esp_partitions();
+        match esps.len() {
+            0 => (),
+            1 => {
+                let esp = esps.into_iter().next().unwrap();
+                if let Err(e) = disks::check_sector_size(&esp) {
+                    show_msg(s, &e.to_string());
+                    return;
+                }
+                config_clone.esp = Some(Arc::new(esp));
+            }
+            _ => {
+                select_esp(s, config_clone, esps);
+                return;
+            }
+        }
+    }
+
+    continue_to_user(s, config_clone);
 }
 
-fn on_submit(
-    siv: &mut Cursive,
-    query: &str,
-    timezone_clone: Rc<RefCell<String>>,
-    status_text: Arc<TextContent>,
-) {
-    let matches = siv.find_name::<SelectView>("matches").unwrap();
-    if matches.is_empty() {
-        // not all people live in big cities. If none of the cities in the list matches, use the value of the query.
-        replace_item(siv, query, timezone_clone, status_text);
+/// Ask which ESP to use when there are several, the one on the disk of the
+/// system partition is selected by default
+fn select_esp(siv: &mut Cursive, config: InstallConfig, esps: Vec<disks::Partition>) {
+    let parent = config
+        .partition
+        .as_ref()
+        .and_then(|x| x.parent_path.clone());
+    let selected = esps
+        .iter()
+        .position(|x| x.parent_path == parent)
+        .unwrap_or(0);
+    let items = esps.into_iter().map(|x| {
+        let label = format!(
+            "{} ({}, on {})",
+            x.path.as_deref().unwrap_or(Path::new("")).display(),
+            human_size(x.size),
+            x.parent_path.as_deref().unwrap_or(Path::new("")).display(),
+        );
+        (label, x)
+    });
+
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(SELECT_ESP_TEXT))
+                .child(DummyView {})
+                .child(
+                    SelectView::new()
+                        .with_all(items)
+                        .selected(selected)
+                        .with_name("select_esp"),
+                ),
+            "Select EFI System Partition",
+            None,
+        )
+        .button("Continue", move |s| {
+            let esp = s
+                .call_on_name("select_esp", |v: &mut SelectView<disks::Partition>| {
+                    v.selection()
+                })
+                .flatten()
+                .map(|x| Arc::new(x.as_ref().clone()));
+            if let Some(Err(e)) = esp.as_deref().map(disks::check_sector_size) {
+                show_msg(s, &e.to_string());
+                return;
+            }
+            let mut config = config.clone();
+            config.esp = esp;
+            s.pop_layer();
+            continue_to_user(s, config);
+        })
+        .button("Back", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+fn continue_to_user(s: &mut Cursive, config_clone: InstallConfig) {
+    if config_clone.user.is_some() {
+        is_use_last_config(s, config_clone);
     } else {
-        // pressing "Enter" without moving the focus into the `matches` view will submit the first match result
-        let item = &*matches.selection().unwrap();
-        replace_item(siv, item, timezone_clone, status_text);
-    };
+        s.add_layer(select_user_password(config_clone));
+    }
 }
 
-fn seatch_select_view_by_timezone(
-    list: Vec<String>,
-    status_text: Arc<TextContent>,
-    result: Rc<RefCell<String>>,
-    name: &str,
-) -> Dialog {
-    let list_clone = list.clone();
-    let locale_clone = result.clone();
-    let status_text_clone = status_text.clone();
-    let on_edit = move |siv: &mut Cursive, query: &str, _cursor: usize| {
-        let matches = search_fn(list_clone.clone(), query);
-        // Update the `matches` view with the filtered array of cities
-        siv.call_on_name("matches", |v: &mut SelectView| {
-            v.clear();
-            v.add_all_str(matches);
-        });
-    };
+fn select_user_password(config: InstallConfig) -> Dialog {
+    let password = Rc::new(RefCell::new(String::new()));
+    let password_copy = Rc::clone(&password);
+    let password_confirm = Rc::new(RefCell::new(String::new()));
+    let password_confirm_copy = Rc::clone(&password_confirm);
+    let name = Rc::new(RefCell::new(String::new()));
+    let name_copy = Rc::clone(&name);
+    let full_name = Rc::new(RefCell::new(String::new()));
+    let full_name_copy = Rc::clone(&full_name);
+    let user_password_textview = TextView::new(ENTER_USER_PASSWORD_TEXT).max_width(80);
+    let root_password = Rc::new(RefCell::new(String::new()));
+    let root_password_copy = Rc::clone(&root_password);
+    let root_password_confirm = Rc::new(RefCell::new(String::new()));
+    let root_password_confirm_copy = Rc::clone(&root_password_confirm);
 
-    wrap_in_dialog(
-        LinearLayout::vertical()
-            .child(TextView::new(format!("Search {name}")))
+    let mut user_password_view = ListView::new()
+        .child(
+            "Full name",
+            EditView::new()
+                .on_edit_mut(move |_, c, _| {
+                    full_name_copy.replace(c.to_owned());
+                })
+                .min_width(20)
+                .with_name("full_name"),
+        )
+        .child(
+            "Username",
+            EditView::new()
+                .on_edit_mut(move |_, c, _| {
+                    name_copy.replace(c.to_owned());
+                })
+                .min_width(20)
+                .with_name("user"),
+        )
+        .child(
+            "Password",
+            EditView::new()
+                .secret()
+                .on_edit_mut(move |_, c, _| {
+                    password_copy.replace(c.to_owned());
+                })
+                .min_width(20)
+                .with_name("pwd"),
+        )
+        .child(
+            "Confirm Password",
+            EditView::new()
+                .secret()
+                .on_edit_mut(move |_, c, _| {
+                    password_confirm_copy.replace(c.to_owned());
+                })
+                .min_width(20)
+                .with_name("pwd2"),
+        );
+
+    if cfg!(feature = "is_retro") {
+        user_password_view = user_password_view
             .child(
+                "Root Password",
                 EditView::new()
-                    // update results every time the query changes
-                    .on_edit(on_edit)
-                    // submit the focused (first) item of the matches
-                    .on_submit(move |s: &mut Cursive, c| {
-                        on_submit(s, c, locale_clone.clone(), status_text.clone())
+                    .secret()
+                    .on_edit_mut(move |_, c, _| {
+                        root_password_copy.replace(c.to_owned());
                     })
-                    .with_name("query"),
+                    .min_width(20)
+                    .with_name("root_pwd"),
             )
-            .child(DummyView {})
             .child(
-                SelectView::new()
-                    .with_all_str(list)
-                    .on_submit(move |s: &mut Cursive, item| {
-                        replace_item(s, item, result.clone(), status_text_clone.clone())
+                "Root Password Confirm",
+                EditView::new()
+                    .secret()
+                    .on_edit_mut(move |_, c, _| {
+                        root_password_confirm_copy.replace(c.to_owned());
                     })
-                    .with_name("matches")
-                    .scrollable(),
+                    .min_width(20)
+                    .with_name("root_pwd2"),
             )
-            .fixed_height(10),
-        format!("Select Your {name}"),
-        None,
-    )
-}
-
-fn seatch_select_view_by_locales(
-    list: Vec<(String, String, String)>,
-    status_text: Arc<TextContent>,
-    result: Rc<RefCell<String>>,
-    name: &str,
-) -> Dialog {
-    let list_clone = list.clone();
-    let locale_clone = result.clone();
-    let status_text_clone = status_text.clone();
-    let on_edit = move |siv: &mut Cursive, query: &str, _cursor: usize| {
-        let matches = search_fn_locales(list_clone.clone(), query);
-        // Update the `matches` view with the filtered array of cities
-        siv.call_on_name("matches", |v: &mut SelectView| {
-            v.clear();
-            v.add_all_str(matches);
-        });
-    };
+    }
 
-    wrap_in_dialog(
+    let config_clone = config.clone();
+    let mut user_password_dialog = wrap_in_dialog(
         LinearLayout::vertical()
-            .child(TextView::new(format!("Search {name}")))
-            .child(
-                EditView::new()
-                    // update results every time the query changes
-                    .on_edit(on_edit)
-                    // submit the focused (first) item of the matches
-                    .on_submit(move |s: &mut Cursive, c| {
-                        on_submit(s, c, locale_clone.clone(), status_text.clone())
-                    })
-                    .with_name("query"),
-            )
+            .child(user_password_textview)
             .child(DummyView {})
-            .child(
-                SelectView::new()
-                    .with_all_str(list.iter().map(|x| x.0.to_string()).collect::<Vec<_>>())
-                    .on_submit(move |s: &mut Cursive, item| {
-                        replace_item(s, item, result.clone(), status_text_clone.clone())
-                    })
-                    .with_name("matches")
-                    .scrollable(),
-            )
-            .fixed_height(10),
-        format!("Select Your {name}"),
+            .child(user_password_view),
+        "AOSC OS Installer",
         None,
     )
-}
-
-fn set_timezone(
-    zoneinfo: Vec<String>,
-    timezone_result: Rc<RefCell<String>>,
-    status_text: Arc<TextContent>,
+    .button("Continue", move |s| {
+        let password = password.as_ref().to_owned().into_inner();
+        let password_confirm = password_confirm.as_ref().to_owned().into_inner();
+        let name = name.as_ref().to_owned().into_inner();
+        let full_name = full_name.as_ref().to_owned().into_inner();
+        let root_password = root_password.as_ref().to_owned().into_inner();
+        let root_password_confirm = root_password_confirm.as_ref().to_owned().into_inner();
+
+        if full_name.contains('\n') || full_name.contains(':') {
+            show_msg(s, "Full name is not valid, please refer to the criteria specified on top of the dialog.");
+            return;
+        }
+
+        if !install::is_acceptable_username(&name) {
+            show_msg(s, "Username is not valid, please refer to the criteria specified on top of the dialog.");
+            return;
+        }
+
+        if password.is_empty() || password_confirm.is_empty() || name.is_empty() || (cfg!(feature = "is_retro") && (root_password.is_empty() || root_password_confirm.is_empty())) {
+            fill_in_all_the_fields!(s);
+        }
+
+        if password != password_confirm {
+            show_msg(s, "Passwords password do not match.");
+            return;
+        }
+
+        if root_password != root_password_confirm {
+            show_msg(s, "Passwords (root) password do not match.");
+            return;
+        }
+
+        let mut config = config.clone();
+        config.password = Some(Arc::new(password));
+        config.user = Some(Arc::new(name));
+        config.full_name = Some(Arc::new(full_name));
+        config.root_password = if !root_password.is_empty() {
+            Some(Arc::new(root_password))
+        } else {
+            None
+        };
+        s.pop_layer();
+        select_hostname(s, config);
+    })
+    .button("Back", move |s| {
+        s.pop_layer();
+        select_disk(s, config_clone.clone());
+    })
+    .button("Exit", |s| s.quit());
+
+    user_password_dialog
+        .focus_view(&Selector::Name("full_name"))
+        .ok();
+
+    user_password_dialog
+}
+
+fn select_hostname(siv: &mut Cursive, config: InstallConfig) {
+    siv.pop_layer();
+    let hostname = Rc::new(RefCell::new(String::new()));
+    let hostname_copy = Rc::clone(&hostname);
+    let hostname_textview = TextView::new(ENTER_HOSTNAME_TEXT);
+    let hostname_view = ListView::new()
+        .child(
+            "Hostname",
+            EditView::new()
+                .on_edit_mut(move |_, c, _| {
+                    hostname_copy.replace(c.to_owned());
+                })
+                .min_width(20)
+                .with_name("hostname"),
+        )
+        .delimiter();
+    let config_clone = config.clone();
+    let hostname_dialog = wrap_in_dialog(
+        LinearLayout::vertical()
+            .child(hostname_textview)
+            .child(DummyView {})
+            .child(hostname_view),
+        "AOSC OS Installer",
+        None,
+    )
+    .button("Continue", move |s| {
+        let hostname = hostname.as_ref().to_owned().into_inner();
+        if hostname.is_empty() {
+            fill_in_all_the_fields!(s);
+        }
+        if !install::is_valid_hostname(&hostname) {
+            show_msg(s, "Hostname is not vaild!");
+            return;
+        }
+        let mut config = config.clone();
+        config.hostname = Some(hostname);
+        select_timezone(s, config);
+    })
+    .button("Back", move |s| {
+        s.pop_layer();
+        s.add_layer(select_user_password(config_clone.clone()));
+    })
+    .button("Exit", |s| s.quit());
+
+    siv.add_layer(hostname_dialog);
+}
+
+fn select_timezone(siv: &mut Cursive, config: InstallConfig) {
+    siv.pop_layer();
+
+    let now_locale = read_locale().unwrap_or_else(|| "C.UTF-8".to_string());
+    let now_language = find_language_by_locale(&now_locale).unwrap_or("No localization (UTF-8)");
+
+    let locale = Rc::new(RefCell::new(String::from(now_language)));
+    let locale_copy = Rc::clone(&locale);
+    let timezone = Rc::new(RefCell::new(String::from("UTC")));
+    let timezone_copy = Rc::clone(&timezone);
+    // RTC/UTC default is UTC
+    let tc = Rc::new(RefCell::new(String::from("UTC")));
+    let tc_copy = Rc::clone(&tc);
+    let locales = Arc::new(install::get_locale_list().unwrap());
+    let timezone_textview = TextView::new(ENTER_TIMEZONE_TEXT);
+    let mut timezone_selected_status = TextView::new("UTC");
+    let timezone_status_text = Arc::new(timezone_selected_status.get_shared_content());
+    let mut locale_selected_status = TextView::new(now_language);
+    let locale_status_text = Arc::new(locale_selected_status.get_shared_content());
+
+    let timezone_view = ListView::new()
+        .child(
+            "Timezone",
+            Button::new("Select timezone", move |s| {
+                let zoneinfo = install::get_zoneinfo_list().unwrap();
+                s.add_layer(set_timezone(
+                    zoneinfo,
+                    timezone_copy.clone(),
+                    timezone_status_text.clone(),
+                ))
+            }),
+        )
+        .child("Selected Timezone", timezone_selected_status.center())
+        .child(
+            "Locale",
+            Button::new("Select locale", move |s| {
+                s.add_layer(set_locales(
+                    locales.to_vec(),
+                    locale_copy.clone(),
+                    locale_status_text.clone(),
+                ))
+            }),
+        )
+        .child("Selected locale", locale_selected_status.center())
+        .child(
+            "RTC Timezone",
+            SelectView::new()
+                .autojump()
+                .popup()
+                .with_all_str(vec!["UTC (Recommended)", "Local time (like Windows)"])
+                .on_submit(move |_, c: &str| {
+                    let selected = match c {
+                        "UTC (Recommended)" => "UTC",
+                        "Local time (like Windows)" => "RTC",
+                        _ => unreachable!(),
+                    };
+                    tc_copy.replace(selected.to_string());
+                })
+                .min_width(20),
+        );
+    let config_clone = config.clone();
+    let timezone_dialog = wrap_in_dialog(
+        LinearLayout::vertical()
+            .child(timezone_textview)
+            .child(DummyView {})
+            .child(timezone_view),
+        "AOSC OS Installer",
+        None,
+    )
+    .button("Continue", move |s| {
+        // language to locale
+        let locale = locale.as_ref().to_owned().into_inner();
+        let locale = find_locale_by_language(&locale).unwrap_or("C.UTF-8");
+
+        let mut timezone = timezone.as_ref().to_owned().into_inner();
+
+        // tzdata 的北京时间是 Asia/Shanghai
+        if timezone == "Asia/Beijing" {
+            timezone = "Asia/Shanghai".to_string();
+        }
+
+        let tc = tc.as_ref().to_owned().into_inner();
+        if locale.is_empty() || timezone.is_empty() || tc.is_empty() {
+            fill_in_all_the_fields!(s);
+        }
+
+        let mut config = config.clone();
+        config.locale = Some(Arc::new(locale.to_string()));
+        config.timezone = Some(Arc::new(timezone));
+        config.tc = Some(Arc::new(tc));
+        select_swap(s, config);
+    })
+    .button("Back", move |s| {
+        s.pop_layer();
+        select_hostname(s, config_clone.clone());
+    })
+    .button("Exit", |s| s.quit());
+
+    siv.add_layer(timezone_dialog);
+}
+
+// Filter cities with names containing query string. You can implement your own logic here!
+fn search_fn<T: std::iter::IntoIterator<Item = String>>(items: T, query: &str) -> Vec<String> {
+    items
+        .into_iter()
+        .filter(|item| {
+            let item = item.to_lowercase();
+            let query = query.to_lowercase();
+            item.contains(&query)
+        })
+        .collect()
+}
+
+fn search_fn_locales(items: Vec<(String, String, String)>, query: &str) -> Vec<String> {
+    items
+        .into_iter()
+        .filter(|item| {
+            let (lang, locale, lang_english) = item;
+            let lang = lang.to_lowercase();
+            let locale = locale.to_lowercase();
+            let lang_english = lang_english.to_lowercase();
+            let query = query.to_lowercase();
+
+            lang.contains(&query) || locale.contains(&query) || lang_english.contains(&query)
+        })
+        .map(|x| x.0.to_string())
+        .collect()
+}
+
+fn replace_item(
+    siv: &mut Cursive,
+    item: &str,
+    timezone: Rc<RefCell<String>>,
+    status_text: Arc<TextContent>,
+) {
+    siv.pop_layer();
+    timezone.replace(item.to_string());
+    status_text.set_content(item);
+}
+
+fn on_submit(
+    siv: &mut Cursive,
+    query: &str,
+    timezone_clone: Rc<RefCell<String>>,
+    status_text: Arc<TextContent>,
+) {
+    let matches = siv.find_name::<SelectView>("matches").unwrap();
+    if matches.is_empty() {
+        // not all people live in big cities. If none of the cities in the list matches, use the value of the query.
+        replace_item(siv, query, timezone_clone, status_text);
+    } else {
+        // pressing "Enter" without moving the focus into the `matches` view will submit the first match result
+        let item = &*matches.selection().unwrap();
+        replace_item(siv, item, timezone_clone, status_text);
+    };
+}
+
+fn seatch_select_view_by_timezone(
+    list: Vec<String>,
+    status_text: Arc<TextContent>,
+    result: Rc<RefCell<String>>,
+    name: &str,
+) -> Dialog {
+    let list_clone = list.clone();
+    let locale_clone = result.clone();
+    let status_text_clone = status_text.clone();
+    let on_edit = move |siv: &mut Cursive, query: &str, _cursor: usize| {
+        let matches = search_fn(list_clone.clone(), query);
+        // Update the `matches` view with the filtered array of cities
+        siv.call_on_name("matches", |v: &mut SelectView| {
+            v.clear();
+            v.add_all_str(matches);
+        });
+    };
+
+    wrap_in_dialog(
+        LinearLayout::vertical()
+            .child(TextView::new(format!("Search {name}")))
+            .child(
+                EditView::new()
+                    // update results every time the query changes
+                    .on_edit(on_edit)
+                    // submit the focused (first) item of the matches
+                    .on_submit(move |s: &mut Cursive, c| {
+                        on_submit(s, c, locale_clone.clone(), status_text.clone())
+                    })
+                    .with_name("query"),
+            )
+            .child(DummyView {})
+            .child(
+                SelectView::new()
+                    .with_all_str(list)
+                    .on_submit(move |s: &mut Cursive, item| {
+                        replace_item(s, item, result.clone(), status_text_clone.clone())
+                    })
+                    .with_name("matches")
+                    .scrollable(),
+            )
+            .fixed_height(10),
+        format!("Select Your {name}"),
+        None,
+    )
+}
+
+fn seatch_select_view_by_locales(
+    list: Vec<(String, String, String)>,
+    status_text: Arc<TextContent>,
+    result: Rc<RefCell<String>>,
+    name: &str,
+) -> Dialog {
+    let list_clone = list.clone();
+    let locale_clone = result.clone();
+    let status_text_clone = status_text.clone();
+    let on_edit = move |siv: &mut Cursive, query: &str, _cursor: usize| {
+        let matches = search_fn_locales(list_clone.clone(), query);
+        // Update the `matches` view with the filtered array of cities
+        siv.call_on_name("matches", |v: &mut SelectView| {
+            v.clear();
+            v.add_all_str(matches);
+        });
+    };
+
+    wrap_in_dialog(
+        LinearLayout::vertical()
+            .child(TextView::new(format!("Search {name}")))
+            .child(
+                EditView::new()
+                    // update results every time the query changes
+                    .on_edit(on_edit)
+                    // submit the focused (first) item of the matches
+                    .on_submit(move |s: &mut Cursive, c| {
+                        on_submit(s, c, locale_clone.clone(), status_text.clone())
+                    })
+                    .with_name("query"),
+            )
+            .child(DummyView {})
+            .child(
+                SelectView::new()
+                    .with_all_str(list.iter().map(|x| x.0.to_string()).collect::<Vec<_>>())
+                    .on_submit(move |s: &mut Cursive, item| {
+                        replace_item(s, item, result.clone(), status_text_clone.clone())
+                    })
+                    .with_name("matches")
+                    .scrollable(),
+            )
+            .fixed_height(10),
+        format!("Select Your {name}"),
+        None,
+    )
+}
+
+fn set_timezone(
+    zoneinfo: Vec<String>,
+    timezone_result: Rc<RefCell<String>>,
+    status_text: Arc<TextContent>,
 ) -> Dialog {
     seatch_select_view_by_timezone(zoneinfo, status_text, timezone_result, "timezone")
 }
 
-fn set_locales(
-    locales: Vec<(&str, &str, &str)>,
-    locale_result: Rc<RefCell<String>>,
-    status_text: Arc<TextContent>,
-) -> Dialog {
-    seatch_select_view_by_locales(
-        locales
-            .iter()
-            .map(|x| (x.0.to_string(), x.1.to_string(), x.2.to_string()))
-            .collect(),
-        status_text,
-        locale_result,
-        "locale",
+fn set_locales(
+    locales: Vec<(&str, &str, &str)>,
+    locale_result: Rc<RefCell<String>>,
+    status_text: Arc<TextContent>,
+) -> Dialog {
+    seatch_select_view_by_locales(
+        locales
+            .iter()
+            .map(|x| (x.0.to_string(), x.1.to_string(), x.2.to_string()))
+            .collect(),
+        status_text,
+        locale_result,
+        "locale",
+    )
+}
+
+fn select_swap(siv: &mut Cursive, config: InstallConfig) {
+    let mut config = config;
+    config.swap_partition = None;
+    config.zram = false;
+    let config_clone_2 = config.clone();
+    let partition = config.partition.as_ref().unwrap();
+    let partition_size = partition.size;
+    // everything but the swapfile
+    let installed_size = required_space(
+        config.variant.as_ref().unwrap(),
+        partition.fs_type.as_deref(),
+        partition_size,
+        0,
+    );
+    siv.pop_layer();
+    let swap_size = Rc::new(RefCell::new(None));
+    let swap_size_copy = Rc::clone(&swap_size);
+    let is_hibernation = Arc::new(AtomicBool::new(false));
+    let is_hibernation_clone_2 = is_hibernation;
+    let use_swap = Arc::new(AtomicBool::new(false));
+    let use_swap_clone = use_swap.clone();
+
+    let mut view = ListView::new().child(
+        "Swapfile Size",
+        SelectView::new()
+            .popup()
+            .autojump()
+            .with_all_str(vec![
+                "Automatic",
+                "Custom",
+                "Swap Partition",
+                "zram",
+                "Disabled",
+            ])
+            .with_name("select_swap_config"),
+    );
+    if EXPERT_MODE.load(Ordering::SeqCst) {
+        let percent = config
+            .free_space_percent
+            .unwrap_or(DEFAULT_FREE_SPACE_PERCENT);
+        view.add_child(
+            "Keep free (%)",
+            EditView::new()
+                .content(percent.to_string())
+                .min_width(10)
+                .with_name("free_space_percent"),
+        );
+    }
+
+    let textview = TextView::new("Would you like to create a swapfile?\n");
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical().child(textview).child(view),
+            "AOSC OS Installer",
+            None,
+        )
+        .button("Continue", move |s| {
+            let selected = s
+                .find_name::<SelectView>("select_swap_config")
+                .expect("select_swap_config must have value")
+                .selected_id()
+                .expect("select_swap_config must have value");
+            let mut config = config.clone();
+            if let Some(percent) =
+                s.call_on_name("free_space_percent", |v: &mut EditView| v.get_content())
+            {
+                match percent.trim().parse::<u8>() {
+                    Ok(x) if (1..=50).contains(&x) => config.free_space_percent = Some(x),
+                    _ => {
+                        show_msg(s, "The share of free space must be between 1% and 50%!");
+                        return;
+                    }
+                }
+            }
+            match selected {
+                0 => auto_swap(
+                    installed_size,
+                    partition_size,
+                    s,
+                    swap_size_copy.clone(),
+                    use_swap_clone.clone(),
+                    config.clone(),
+                ),
+                1 => custom_swap_size(
+                    installed_size,
+                    partition_size,
+                    s,
+                    swap_size_copy.clone(),
+                    config.clone(),
+                    is_hibernation_clone_2.clone(),
+                    use_swap.clone(),
+                ),
+                2 => select_swap_partition(s, config.clone()),
+                3 => {
+                    let mut config = config.clone();
+                    config.zram = true;
+                    disable_swap(config, s)
+                }
+                4 => disable_swap(config.clone(), s),
+                _ => unreachable!(),
+            }
+        })
+        .button("Back", move |s| {
+            s.pop_layer();
+            select_timezone(s, config_clone_2.clone());
+        })
+        .button("Exit", move |s| s.quit()),
+    );
+}
+
+fn auto_swap(
+    installed_size: u64,
+    partition_size: u64,
+    s: &mut Cursive,
+    swap_size: Rc<RefCell<Option<f64>>>,
+    use_swap: Arc<AtomicBool>,
+    config: InstallConfig,
+) {
+    let mut config = config;
+    let mem = sysinfo::System::new_all().total_memory();
+    let auto_size = disks::get_recommend_swap_size(mem);
+
+    match auto_size {
+        Ok(auto_size) => {
+            let margin = free_space_margin(partition_size, config.free_space_percent);
+            if installed_size + auto_size as u64 + margin > partition_size {
+                show_msg(s, &format!("There is not enough available space in the system partition to create a swapfile! Default swapfile size: {} GiB", (auto_size / 1024.0 / 1024.0 / 1024.0).round()));
+                return;
+            }
+
+            swap_size.replace(Some(auto_size));
+            use_swap.store(true, Ordering::SeqCst);
+        }
+        Err(e) => {
+            show_msg(s, &e.to_string());
+            return;
+        }
+    }
+
+    let swap_size = swap_size.as_ref().to_owned().into_inner();
+    config.swap_size = Arc::new(swap_size);
+    config.use_swap = Arc::new(AtomicBoolWrapper {
+        v: AtomicBool::new(use_swap.load(Ordering::SeqCst)),
+    });
+    config.is_hibernation = Arc::new(AtomicBoolWrapper {
+        v: AtomicBool::new(true),
+    });
+
+    select_extras(s, config);
+}
+
+fn custom_swap_size(
+    installed_size: u64,
+    partition_size: u64,
+    s: &mut Cursive,
+    swap_size: Rc<RefCell<Option<f64>>>,
+    config: InstallConfig,
+    is_hibernation_clone_2: Arc<AtomicBool>,
+    use_swap: Arc<AtomicBool>,
+) {
+    let swap_size_input = Rc::new(RefCell::new(String::new()));
+    let swap_size_input_clone = swap_size_input.clone();
+
+    let swap_size_clone = swap_size.clone();
+    let use_swap_clone = use_swap.clone();
+
+    let is_hibernation_clone_3 = is_hibernation_clone_2.clone();
+
+    s.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(
+                    "Please enter your desired swapfile size (GiB): ",
+                ))
+                .child(
+                    EditView::new()
+                        .on_edit_mut(move |_, c, _| {
+                            swap_size_input_clone.replace(c.to_owned());
+                        })
+                        .min_width(20)
+                        .with_name("size"),
+                ),
+            "Customize Swapfile Size",
+            None,
+        )
+        .button("OK", move |s| {
+            let mut config = config.clone();
+            let size = swap_size_input.as_ref().to_owned().into_inner();
+            let size = size.parse::<f64>();
+            if size.is_err() {
+                show_msg(s, "Invalid custom swapfile size!");
+                return;
+            }
+
+            let is_hibernation_clone = is_hibernation_clone_2.clone();
+            let size = size.unwrap() * 1024.0 * 1024.0 * 1024.0;
+            let margin = free_space_margin(partition_size, config.free_space_percent);
+            if installed_size + size as u64 + margin > partition_size {
+                show_msg(s, &format!("There is not enough space available in the system partition to create a custom swapfile! Custom swapfile size: {} GiB",  (size / 1024.0 / 1024.0 / 1024.0).round()));
+                return;
+            }
+
+            let is_h = match disks::is_enable_hibernation(size) {
+                Ok(is_h) => {
+                    is_h
+                }
+                Err(e) => {
+                    show_msg(s, &e.to_string());
+                    false
+                }
+            };
+
+            is_hibernation_clone.store(is_h, Ordering::SeqCst);
+            swap_size_clone.replace(Some(size));
+            use_swap_clone.store(true, Ordering::SeqCst);
+
+            let swap_size = swap_size.as_ref().to_owned().into_inner();
+            config.swap_size = Arc::new(swap_size);
+            config.use_swap = Arc::new(AtomicBoolWrapper { v: AtomicBool::new(use_swap.load(Ordering::SeqCst) )});
+            config.is_hibernation = Arc::new(AtomicBoolWrapper { v: AtomicBool::new(is_hibernation_clone_3.load(Ordering::SeqCst) )});
+
+            select_extras(s, config);
+        })
+        .button("Cancel", move |s| s.cb_sink().send(Box::new(|s| {
+            s.pop_layer();
+        }))
+        .unwrap()),
+    );
+}
+
+/// Swap to a partition, which unlike a swapfile can always be resumed from
+fn select_swap_partition(siv: &mut Cursive, config: InstallConfig) {
+    let partition = config.partition.as_ref().unwrap();
+    let mem = sysinfo::System::new_all().total_memory();
+    let size = match disks::get_recommend_swap_size(mem) {
+        Ok(size) => size as u64,
+        Err(e) => {
+            show_msg(siv, &e.to_string());
+            return;
+        }
+    };
+
+    let mut view = SelectView::new();
+    if let Some(disk) = partition.parent_path.as_ref().filter(|_| size > 0) {
+        if let Ok(Some(_)) = disks::find_free_space(disk, size) {
+            let new = disks::SwapPartition::New {
+                disk: disk.clone(),
+                size,
+            };
+            view.add_item(format!("Create {new}"), new);
+        }
+    }
+    let esps = disks::list_esp_partitions()
+        .into_iter()
+        .map(|x| x.path)
+        .collect::<Vec<_>>();
+    let others = InstallConfig {
+        swap_partition: None,
+        ..config.clone()
+    };
+    // an existing partition would not be encrypted along with the system
+    let partitions = match config.encryption {
+        Some(_) => vec![],
+        None => disks::list_partitions(None),
+    };
+    for part in partitions {
+        // the btrfs mirror, /home and the like
+        if part
+            .path
+            .as_deref()
+            .map_or(true, |x| others.uses_partition(x))
+            || part.path == partition.path
+            || esps.contains(&part.path)
+        {
+            continue;
+        }
+        let label = format!(
+            "{} ({}, {})",
+            part.path.as_deref().unwrap_or(Path::new("?")).display(),
+            part.fs_type.as_deref().unwrap_or("unknown"),
+            human_size(part.size)
+        );
+        view.add_item(label, disks::SwapPartition::Existing(part));
+    }
+    if view.is_empty() {
+        show_msg(
+            siv,
+            "Installer could not find a partition or enough free space for swap.",
+        );
+        return;
+    }
+
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(SELECT_SWAP_PARTITION_TEXT))
+                .child(DummyView {})
+                .child(view.with_name("select_swap_partition")),
+            "Select Swap Partition",
+            None,
+        )
+        .button("Continue", move |s| {
+            let swap_partition = s
+                .call_on_name(
+                    "select_swap_partition",
+                    |v: &mut SelectView<disks::SwapPartition>| v.selection(),
+                )
+                .flatten();
+            let mut config = config.clone();
+            config.swap_partition = swap_partition.map(|x| x.as_ref().clone());
+            if let Some(disks::SwapPartition::Existing(part)) = &config.swap_partition {
+                let msg = format!(
+                    "{} ({}) will be formatted as swap, all data on it will be lost. Continue?",
+                    part.path.as_deref().unwrap_or(Path::new("?")).display(),
+                    part.fs_type.as_deref().unwrap_or("unknown")
+                );
+                s.add_layer(
+                    wrap_in_dialog(TextView::new(msg), "Select Swap Partition", None)
+                        .button("Continue", move |s| {
+                            s.pop_layer();
+                            use_swap_partition(s, config.clone());
+                        })
+                        .button("Back", |s| {
+                            s.pop_layer();
+                        }),
+                );
+                return;
+            }
+            use_swap_partition(s, config);
+        })
+        .button("Back", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+/// Swap to the selected partition instead of a swapfile, the selection dialog
+/// is on top
+fn use_swap_partition(s: &mut Cursive, config: InstallConfig) {
+    let mut config = config;
+    config.swap_size = Arc::new(None);
+    config.use_swap = Arc::new(AtomicBoolWrapper {
+        v: AtomicBool::new(false),
+    });
+    config.is_hibernation = Arc::new(AtomicBoolWrapper {
+        v: AtomicBool::new(true),
+    });
+    s.pop_layer();
+    select_extras(s, config);
+}
+
+fn disable_swap(config: InstallConfig, s: &mut Cursive) {
+    let mut config = config;
+    config.swap_size = Arc::new(None);
+    config.use_swap = Arc::new(AtomicBoolWrapper {
+        v: AtomicBool::new(false),
+    });
+    config.is_hibernation = Arc::new(AtomicBoolWrapper {
+        v: AtomicBool::new(false),
+    });
+
+    select_extras(s, config);
+}
+
+/// Offer optional software that only makes sense on some machines, skipped
+/// entirely when none of it applies
+fn select_extras(siv: &mut Cursive, config: InstallConfig) {
+    let has_battery = install::has_battery();
+    let has_optenv32 = install::optenv32_available();
+    if !has_battery && !has_optenv32 {
+        let mut config = config;
+        config.power_management = None;
+        config.optenv32 = false;
+        show_summary(siv, config);
+        return;
+    }
+
+    let mut layout = LinearLayout::vertical();
+    if has_battery {
+        // desktop environments integrate with power-profiles-daemon
+        let is_desktop = config
+            .variant
+            .as_ref()
+            .map(|x| !["Base", "Server"].contains(&x.name.as_str()))
+            .unwrap_or(true);
+        let options = [
+            (
+                "power-profiles-daemon",
+                Some(install::PowerManagement::PowerProfilesDaemon),
+            ),
+            ("TLP", Some(install::PowerManagement::Tlp)),
+            ("None", None),
+        ];
+        let selected = if is_desktop { 0 } else { 1 };
+
+        layout.add_child(TextView::new(POWER_MANAGEMENT_TEXT));
+        layout.add_child(DummyView {});
+        layout.add_child(
+            ListView::new().child(
+                "Power Management",
+                SelectView::new()
+                    .popup()
+                    .autojump()
+                    .with_all(options.into_iter().map(|(k, v)| (k.to_string(), v)))
+                    .selected(selected)
+                    .with_name("select_power_management"),
+            ),
+        );
+    }
+    if has_optenv32 {
+        if has_battery {
+            layout.add_child(DummyView {});
+        }
+        layout.add_child(TextView::new(OPTENV32_TEXT));
+        layout.add_child(DummyView {});
+        layout.add_child(
+            ListView::new().child(
+                "32-bit Runtime",
+                Checkbox::new()
+                    .with_checked(config.optenv32)
+                    .with_name("optenv32"),
+            ),
+        );
+    }
+
+    siv.add_layer(
+        wrap_in_dialog(layout, "Additional Software", None)
+            .button("Continue", move |s| {
+                let mut config = config.clone();
+                config.power_management = s
+                    .call_on_name(
+                        "select_power_management",
+                        |v: &mut SelectView<Option<install::PowerManagement>>| {
+                            v.selection().and_then(|x| *x)
+                        },
+                    )
+                    .flatten();
+                config.optenv32 = s
+                    .call_on_name("optenv32", |v: &mut Checkbox| v.is_checked())
+                    .unwrap_or(false);
+                show_summary(s, config);
+            })
+            .button("Back", |s| {
+                s.pop_layer();
+            })
+            .button("Exit", |s| s.quit()),
+    );
+}
+
+fn is_use_last_config(siv: &mut Cursive, config: InstallConfig) {
+    siv.pop_layer();
+    let config_copy = config.clone();
+    siv.add_layer(
+        wrap_in_dialog(
+            TextView::new(
+                "Would you like to load your previous AOSC OS installation configuration?",
+            ),
+            "AOSC OS Installer",
+            None,
+        )
+        .button("Yes", move |s| show_summary(s, config_copy.clone()))
+        .button("No", move |s| {
+            fs::remove_file(LAST_USER_CONFIG_FILE).ok();
+            let new_config = InstallConfig {
+                partition: config.clone().partition,
+                ..Default::default()
+            };
+            select_variant(s, new_config);
+        })
+        .button("Exit", |s| s.quit()),
+    );
+}
+
+fn show_summary(siv: &mut Cursive, config: InstallConfig) {
+    let mut path = String::new();
+    let mut fs = String::new();
+    let config_copy = config.clone();
+    let config_copy_2 = config.clone();
+    if let Some(partition) = config.partition {
+        if let Some(partition) = &partition.path {
+            path = partition.to_string_lossy().to_string();
+        }
+        if let Some(fs_type) = &partition.fs_type {
+            fs = fs_type.clone();
+        }
+    }
+    let swap_size = if let Some(swap_size) = *config.swap_size {
+        swap_size
+    } else {
+        0.0
+    };
+    let swap_str;
+    let mem = sysinfo::System::new_all().total_memory();
+    match disks::get_recommend_swap_size(mem) {
+        Ok(rs) => {
+            if swap_size == rs {
+                swap_str = "installer default"
+            } else if swap_size == 0.0 {
+                swap_str = "No swapfile will be created."
+            } else {
+                swap_str = "custom size"
+            };
+        }
+        Err(e) => {
+            show_error(siv, &e.to_string());
+            return;
+        }
+    };
+    let mut s = format!(
+        SUMMARY_TEXT!(),
+        path,
+        fs,
+        config.variant.unwrap().name,
+        config.mirror.unwrap().name,
+        config.user.unwrap(),
+        config.locale.unwrap(),
+        config.timezone.unwrap(),
+        config.tc.unwrap(),
+    );
+    if config.keep_data {
+        let dirs = config_copy
+            .partition
+            .as_deref()
+            .map(disks::partition_system_dirs)
+            .unwrap_or_default();
+        let overwritten = if dirs.is_empty() {
+            String::new()
+        } else {
+            format!(
+                " Files of a previous system in /{} will be overwritten.",
+                dirs.join(", /")
+            )
+        };
+        s = s.replacen(
+            &format!("{path} will be erased and formatted as {fs}."),
+            &format!("{path} will not be formatted, files on it are kept.{overwritten}"),
+            1,
+        );
+    }
+    let swap_s = if let Some(swap_partition) = &config.swap_partition {
+        format!("- {swap_partition} will be formatted and used as swap.")
+    } else if config.zram {
+        "- Swap will be compressed in memory (zram), no swapfile will be created.".to_string()
+    } else if swap_size != 0.0 {
+        format!(
+            "- A {}GiB swapfile will be created and enabled ({}).",
+            (swap_size / 1024.0 / 1024.0 / 1024.0).round(),
+            swap_str
+        )
+    } else {
+        format!("- {swap_str}")
+    };
+    let power_s = match config.power_management {
+        Some(power_management) => format!(
+            "\n- {} will be installed and enabled.",
+            power_management.package()
+        ),
+        None => String::new(),
+    };
+    let optenv32_s = if config.optenv32 {
+        "\n- The 32-bit compatibility runtime will be installed."
+    } else {
+        ""
+    };
+    let directory_s = match &config.directory {
+        Some(directory) => format!(
+            "\n- Users will be authenticated against {} ({}).",
+            directory.uri, directory.base_dn
+        ),
+        None => String::new(),
+    };
+    let static_network_s = match &config.static_network {
+        Some(network) => format!("\n- The network will be configured with {network}."),
+        None => String::new(),
+    };
+    let live_network_s = if config.skip_live_network {
+        ""
+    } else {
+        match (
+            install::live_network_connections().len(),
+            network::target_proxy(),
+        ) {
+            (0, None) => "",
+            (0, Some(_)) => "\n- The proxy of this session will be kept.",
+            (_, None) => "\n- Wi-Fi networks and DNS settings of this session will be kept.",
+            (_, Some(_)) => {
+                "\n- Wi-Fi networks, DNS settings and the proxy of this session will be kept."
+            }
+        }
+    };
+    let torrent_s = if config.use_torrent {
+        "\n- The system release will be downloaded with BitTorrent, or from the mirror if that fails."
+    } else {
+        ""
+    };
+    let mount_options_s = if config.mount_options.is_default() {
+        String::new()
+    } else {
+        format!(
+            "\n- Filesystems will be mounted with {}.",
+            config.mount_options
+        )
+    };
+    let bootloader_s = match config.bootloader {
+        install::Bootloader::Grub => "",
+        install::Bootloader::Efistub => {
+            "\n- The kernel will be booted directly by the firmware (EFISTUB)."
+        }
+    };
+    let esp_s = if config.create_esp {
+        format!(
+            "\n- A 512MiB EFI System Partition (ESP) will be created on {}.",
+            config
+                .partition
+                .as_ref()
+                .and_then(|x| x.parent_path.as_ref())
+                .map(|x| x.display().to_string())
+                .unwrap_or_default()
+        )
+    } else if config.create_bios_grub {
+        format!(
+            "\n- A 1MiB BIOS boot partition will be created on {}.",
+            config
+                .partition
+                .as_ref()
+                .and_then(|x| x.parent_path.as_ref())
+                .map(|x| x.display().to_string())
+                .unwrap_or_default()
+        )
+    } else {
+        String::new()
+    };
+    let realign_s = if config.realign_partition {
+        "\n- The system partition will be realigned to a 1MiB boundary."
+    } else {
+        ""
+    };
+    let home_s = match config.home.as_ref().and_then(|x| x.path.as_ref()) {
+        Some(home) => format!(
+            "\n- {} will be mounted as /home without formatting.",
+            home.display()
+        ),
+        None => String::new(),
+    };
+    let mirror_s = match config.btrfs_mirror.as_ref().and_then(|x| x.path.as_ref()) {
+        Some(path) => format!(
+            "\n- {} will be formatted as a mirror of the system partition (btrfs RAID1). If either disk fails, add \"rootflags=degraded\" to the kernel command line to boot.",
+            path.display()
+        ),
+        None => String::new(),
+    };
+    let extra_mounts_s = config
+        .extra_mounts
+        .iter()
+        .map(|x| {
+            format!(
+                "\n- {} will be {}mounted at {}.",
+                x.partition
+                    .path
+                    .as_deref()
+                    .unwrap_or(Path::new("?"))
+                    .display(),
+                if x.format { "formatted and " } else { "" },
+                x.mount_point.display()
+            )
+        })
+        .collect::<String>();
+    let encryption_s = match &config.encryption {
+        Some(x) if x.recovery_key.is_some() => {
+            "\n- The system partition will be encrypted (LVM on LUKS), with a recovery key."
+        }
+        Some(_) => "\n- The system partition will be encrypted (LVM on LUKS).",
+        None => "",
+    };
+    let esp_mount_s = match config.esp_mount_point {
+        install::EspMountPoint::Efi => String::new(),
+        x => format!(
+            "\n- The EFI System Partition will be mounted at {}.",
+            x.path().display()
+        ),
+    };
+    let fstab_source_s = match config.fstab_source {
+        disks::FstabSource::Uuid => String::new(),
+        x => format!(
+            "\n- Partitions will be referred to by {} in fstab.",
+            x.name()
+        ),
+    };
+    let expert_s = if config.expert.is_default() {
+        ""
+    } else {
+        "\n- Advanced boot and user options will be applied."
+    };
+    let mut dialog = wrap_in_dialog(
+        TextView::new(format!(
+            "{s}{swap_s}{encryption_s}{realign_s}{mirror_s}{esp_s}{home_s}{extra_mounts_s}{power_s}{optenv32_s}{directory_s}{static_network_s}{live_network_s}{torrent_s}{mount_options_s}{expert_s}{bootloader_s}{esp_mount_s}{fstab_source_s}"
+        )),
+        "Pre-Installation Confirmation",
+        None,
     )
+    .button("Proceed", move |s| {
+        if let Err(e) = preflight::check_tools(&config_copy.required_tools()) {
+            show_msg(s, &e.to_string());
+            return;
+        }
+        check_speed(s, config_copy.clone());
+    })
+    .button("Save Configuration", move |s| {
+        if let Err(e) = save_user_config_to_file(config_copy_2.clone(), SAVE_USER_CONFIG_FILE) {
+            show_error(s, &e.to_string())
+        } else {
+            show_msg(
+                s,
+                &format!(
+                    "Installer has successfully saved your installation configuration: {SAVE_USER_CONFIG_FILE}."
+                ),
+            )
+        }
+    })
+    .button("Preview", summary_action(&config_copy, |s, config| {
+        show_preview(s, &config)
+    }));
+    if EXPERT_MODE.load(Ordering::SeqCst) {
+        dialog.add_button(
+            "Advanced",
+            summary_action(&config_copy, select_expert_options),
+        );
+        dialog.add_button(
+            "Mount Options",
+            summary_action(&config_copy, select_mount_options),
+        );
+        dialog.add_button("Directory", summary_action(&config_copy, select_directory));
+        dialog.add_button(
+            "Network",
+            summary_action(&config_copy, select_static_network),
+        );
+        dialog.add_button(
+            "Mount Points",
+            summary_action(&config_copy, select_mount_points),
+        );
+    }
+    dialog.add_button("Cancel", |s| {
+        s.pop_layer();
+    });
+    siv.add_layer(dialog);
+}
+
+/// Button callback of the summary, opening `f` with a copy of `config`
+fn summary_action(
+    config: &InstallConfig,
+    f: fn(&mut Cursive, InstallConfig),
+) -> impl Fn(&mut Cursive) + Send + Sync + 'static {
+    let config = config.clone();
+    move |s| f(s, config.clone())
+}
+
+/// Show the summary of the changed `config`, in place of the dialog that
+/// changed it and the outdated summary below it
+fn update_summary(siv: &mut Cursive, config: InstallConfig) {
+    siv.pop_layer();
+    siv.pop_layer();
+    show_summary(siv, config);
+}
+
+/// Show what the installation is going to run, without running anything
+fn show_preview(siv: &mut Cursive, config: &InstallConfig) {
+    let commands = preview::generate(config).join("\n");
+    siv.add_layer(
+        wrap_in_dialog(
+            TextView::new(commands).scrollable().max_height(20),
+            "Installation Preview",
+            None,
+        )
+        .button("OK", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+/// Advanced: enroll the installed system into an LDAP directory, then show
+/// the summary again with the new settings
+fn select_directory(siv: &mut Cursive, config: InstallConfig) {
+    let (uri, base_dn) = match &config.directory {
+        Some(directory) => (directory.uri.clone(), directory.base_dn.clone()),
+        None => (String::new(), String::new()),
+    };
+    let uri_input = Rc::new(RefCell::new(uri.clone()));
+    let uri_input_copy = uri_input.clone();
+    let base_dn_input = Rc::new(RefCell::new(base_dn.clone()));
+    let base_dn_input_copy = base_dn_input.clone();
+    let config_copy = config.clone();
+
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(DIRECTORY_TEXT))
+                .child(DummyView {})
+                .child(
+                    ListView::new()
+                        .child(
+                            "Server URI",
+                            EditView::new()
+                                .content(uri)
+                                .on_edit_mut(move |_, c, _| {
+                                    uri_input_copy.replace(c.to_owned());
+                                })
+                                .min_width(40),
+                        )
+                        .child(
+                            "Base DN",
+                            EditView::new()
+                                .content(base_dn)
+                                .on_edit_mut(move |_, c, _| {
+                                    base_dn_input_copy.replace(c.to_owned());
+                                })
+                                .min_width(40),
+                        ),
+                ),
+            "Enterprise Directory",
+            None,
+        )
+        .button("OK", move |s| {
+            let directory =
+                match install::DirectoryConfig::new(&uri_input.borrow(), &base_dn_input.borrow()) {
+                    Ok(directory) => directory,
+                    Err(e) => {
+                        show_msg(s, &e.to_string());
+                        return;
+                    }
+                };
+            let mut config = config.clone();
+            config.directory = Some(directory);
+            update_summary(s, config);
+        })
+        .button("Disable", move |s| {
+            let mut config = config_copy.clone();
+            config.directory = None;
+            update_summary(s, config);
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        }),
+    );
+}
+
+fn select_static_network(siv: &mut Cursive, config: InstallConfig) {
+    let (interface, address, gateway, dns) = match &config.static_network {
+        Some(network) => (
+            network.interface.clone(),
+            network.address.clone(),
+            network.gateway.clone().unwrap_or_default(),
+            network.dns.join(" "),
+        ),
+        None => (
+            network::default_interface().unwrap_or_default(),
+            String::new(),
+            String::new(),
+            String::new(),
+        ),
+    };
+    let interface_input = Rc::new(RefCell::new(interface.clone()));
+    let interface_input_copy = interface_input.clone();
+    let address_input = Rc::new(RefCell::new(address.clone()));
+    let address_input_copy = address_input.clone();
+    let gateway_input = Rc::new(RefCell::new(gateway.clone()));
+    let gateway_input_copy = gateway_input.clone();
+    let dns_input = Rc::new(RefCell::new(dns.clone()));
+    let dns_input_copy = dns_input.clone();
+    let config_copy = config.clone();
+
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(TextView::new(STATIC_NETWORK_TEXT))
+                .child(DummyView {})
+                .child(
+                    ListView::new()
+                        .child(
+                            "Interface",
+                            EditView::new()
+                                .content(interface)
+                                .on_edit_mut(move |_, c, _| {
+                                    interface_input_copy.replace(c.to_owned());
+                                })
+                                .min_width(40),
+                        )
+                        .child(
+                            "Address",
+                            EditView::new()
+                                .content(address)
+                                .on_edit_mut(move |_, c, _| {
+                                    address_input_copy.replace(c.to_owned());
+                                })
+                                .min_width(40),
+                        )
+                        .child(
+                            "Gateway",
+                            EditView::new()
+                                .content(gateway)
+                                .on_edit_mut(move |_, c, _| {
+                                    gateway_input_copy.replace(c.to_owned());
+                                })
+                                .min_width(40),
+                        )
+                        .child(
+                            "DNS servers",
+                            EditView::new()
+                                .content(dns)
+                                .on_edit_mut(move |_, c, _| {
+                                    dns_input_copy.replace(c.to_owned());
+                                })
+                                .min_width(40),
+                        ),
+                ),
+            "Static Network",
+            None,
+        )
+        .button("OK", move |s| {
+            let network = match install::StaticNetworkConfig::new(
+                &interface_input.borrow(),
+                &address_input.borrow(),
+                &gateway_input.borrow(),
+                &dns_input.borrow(),
+            ) {
+                Ok(network) => network,
+                Err(e) => {
+                    show_msg(s, &e.to_string());
+                    return;
+                }
+            };
+            let mut config = config.clone();
+            config.static_network = Some(network);
+            update_summary(s, config);
+        })
+        .button("Use DHCP", move |s| {
+            let mut config = config_copy.clone();
+            config.static_network = None;
+            update_summary(s, config);
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        }),
+    );
 }
 
-fn select_swap(siv: &mut Cursive, config: InstallConfig) {
-    let config_clone = config.clone();
-    let config_clone_2 = config.clone();
-    let partition_size = config.partition.as_ref().unwrap().size;
-    let installed_size = config.variant.as_ref().unwrap().install_size;
-    siv.pop_layer();
-    let swap_size = Rc::new(RefCell::new(None));
-    let swap_size_copy = Rc::clone(&swap_size);
-    let is_hibernation = Arc::new(AtomicBool::new(false));
-    let is_hibernation_clone_2 = is_hibernation;
-    let use_swap = Arc::new(AtomicBool::new(false));
-    let use_swap_clone = use_swap.clone();
-
-    let view = ListView::new().child(
-        "Swapfile Size",
-        SelectView::new()
-            .popup()
-            .autojump()
-            .with_all_str(vec!["Automatic", "Custom", "Disabled"])
-            .with_name("select_swap_config"),
-    );
+/// Advanced: mount other partitions into the installed system, then show
+/// the summary again with the new settings
+fn select_mount_points(siv: &mut Cursive, config: InstallConfig) {
+    let current = if config.extra_mounts.is_empty() {
+        "No additional mount points.".to_string()
+    } else {
+        config
+            .extra_mounts
+            .iter()
+            .map(|x| format!("- {x}"))
+            .collect::<Vec<_>>()
+            .join("\n")
+    };
+    let config_copy = config.clone();
+    let config_copy_2 = config.clone();
 
-    let textview = TextView::new("Would you like to create a swapfile?\n");
     siv.add_layer(
         wrap_in_dialog(
-            LinearLayout::vertical().child(textview).child(view),
-            "AOSC OS Installer",
+            LinearLayout::vertical()
+                .child(TextView::new(MOUNT_POINTS_TEXT))
+                .child(DummyView {})
+                .child(TextView::new(current)),
+            "Mount Points",
             None,
         )
-        .button("Continue", move |s| {
-            let selected = s
-                .find_name::<SelectView>("select_swap_config")
-                .expect("select_swap_config must have value")
-                .selected_id()
-                .expect("select_swap_config must have value");
-            match selected {
-                0 => auto_swap(
-                    installed_size,
-                    partition_size,
-                    s,
-                    swap_size_copy.clone(),
-                    use_swap_clone.clone(),
-                    config_clone.clone(),
-                ),
-                1 => custom_swap_size(
-                    installed_size,
-                    partition_size,
-                    s,
-                    swap_size_copy.clone(),
-                    config.clone(),
-                    is_hibernation_clone_2.clone(),
-                    use_swap.clone(),
-                ),
-                2 => disable_swap(config.clone(), s),
-                _ => unreachable!(),
-            }
-        })
-        .button("Back", move |s| {
+        .button("Add", move |s| add_mount_point(s, config.clone()))
+        .button("Clear", move |s| {
+            let mut config = config_copy.clone();
+            config.extra_mounts.clear();
             s.pop_layer();
-            select_timezone(s, config_clone_2.clone());
+            select_mount_points(s, config);
         })
-        .button("Exit", move |s| s.quit()),
+        .button("Done", move |s| {
+            update_summary(s, config_copy_2.clone());
+        }),
     );
 }
 
-fn auto_swap(
-    installed_size: u64,
-    partition_size: u64,
-    s: &mut Cursive,
-    swap_size: Rc<RefCell<Option<f64>>>,
-    use_swap: Arc<AtomicBool>,
-    config: InstallConfig,
-) {
-    let mut config = config;
-    let mem = sysinfo::System::new_all().total_memory();
-    let auto_size = disks::get_recommend_swap_size(mem);
+fn add_mount_point(siv: &mut Cursive, config: InstallConfig) {
+    let esps = disks::list_esp_partitions()
+        .into_iter()
+        .map(|x| x.path)
+        .collect::<Vec<_>>();
+    let candidates = disks::list_partitions(None).into_iter().filter(|x| {
+        x.path
+            .as_deref()
+            .is_some_and(|path| !config.uses_partition(path))
+            && !esps.contains(&x.path)
+    });
+    let mut view = SelectView::new();
+    for part in candidates {
+        let label = format!(
+            "{} ({}, {})",
+            part.path.as_deref().unwrap_or(Path::new("?")).display(),
+            part.fs_type.as_deref().unwrap_or("Unknown/Unformatted"),
+            human_size(part.size)
+        );
+        view.add_item(label, part);
+    }
+    if view.is_empty() {
+        show_msg(siv, "There are no other partitions to mount.");
+        return;
+    }
 
-    match auto_size {
-        Ok(auto_size) => {
-            if installed_size + auto_size as u64 > partition_size - DEFAULT_EMPTY_SIZE {
-                show_msg(s, &format!("There is not enough available space in the system partition to create a swapfile! Default swapfile size: {} GiB", (auto_size / 1024.0 / 1024.0 / 1024.0).round()));
+    siv.add_layer(
+        wrap_in_dialog(
+            LinearLayout::vertical()
+                .child(view.with_name("mount_partition"))
+                .child(DummyView {})
+                .child(
+                    ListView::new()
+                        .child(
+                            "Mount point",
+                            EditView::new().min_width(20).with_name("mount_point"),
+                        )
+                        .child(
+                            "Format partition",
+                            Checkbox::new().with_name("mount_format"),
+                        ),
+                ),
+            "Add Mount Point",
+            None,
+        )
+        .button("OK", move |s| {
+            let Some(partition) = s
+                .call_on_name("mount_partition", |v: &mut SelectView<disks::Partition>| {
+                    v.selection()
+                })
+                .flatten()
+            else {
+                return;
+            };
+            let mount_point = s
+                .call_on_name("mount_point", |v: &mut EditView| v.get_content())
+                .unwrap_or_default();
+            let format = s
+                .call_on_name("mount_format", |v: &mut Checkbox| v.is_checked())
+                .unwrap_or(false);
+            let mount = match disks::ExtraMount::new(&partition, &mount_point, format) {
+                Ok(mount) => mount,
+                Err(e) => {
+                    show_msg(s, &e.to_string());
+                    return;
+                }
+            };
+            let mut config = config.clone();
+            if config
+                .extra_mounts
+                .iter()
+                .any(|x| x.mount_point == mount.mount_point)
+            {
+                show_msg(s, "This mount point is already used!");
                 return;
             }
+            config.extra_mounts.push(mount);
+            // drop this dialog and the outdated list
+            s.pop_layer();
+            s.pop_layer();
+            select_mount_points(s, config);
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        }),
+    );
+}
 
-            swap_size.replace(Some(auto_size));
-            use_swap.store(true, Ordering::SeqCst);
-        }
-        Err(e) => {
-            show_msg(s, &e.to_string());
-            return;
+/// Expert mode: initramfs, kernel command line, GRUB and user group
+/// settings, then show the summary again with the new settings
+fn select_expert_options(siv: &mut Cursive, config: InstallConfig) {
+    let expert = config.expert.clone();
+    let fs_type = config
+        .partition
+        .as_deref()
+        .map_or(disks::DEFAULT_FS_TYPE, disks::mkfs_fs_type)
+        .to_string();
+    let mkfs_args = expert.mkfs_args.get(&fs_type).cloned().unwrap_or_default();
+    let timeout = expert
+        .grub_timeout
+        .map(|x| x.to_string())
+        .unwrap_or_default();
+    let groups = expert.extra_groups.join(",");
+
+    let mut options = ListView::new()
+        .child(
+            "Kernel command line",
+            EditView::new()
+                .content(expert.kernel_cmdline)
+                .min_width(40)
+                .with_name("kernel_cmdline"),
+        )
+        .child(
+            "GRUB timeout (seconds)",
+            EditView::new()
+                .content(timeout)
+                .min_width(10)
+                .with_name("grub_timeout"),
+        )
+        .child(
+            "Extra user groups",
+            EditView::new()
+                .content(groups)
+                .min_width(40)
+                .with_name("extra_groups"),
+        )
+        .child(
+            &format!("Extra mkfs.{fs_type} arguments"),
+            EditView::new()
+                .content(mkfs_args)
+                .min_width(40)
+                .with_name("mkfs_args"),
+        )
+        .child(
+            "Refer to partitions in fstab by",
+            SelectView::new()
+                .popup()
+                .with_all(disks::FstabSource::ALL.map(|x| (x.name().to_string(), x)))
+                .selected(
+                    disks::FstabSource::ALL
+                        .iter()
+                        .position(|x| *x == config.fstab_source)
+                        .unwrap_or(0),
+                )
+                .with_name("fstab_source"),
+        )
+        .child(
+            "Keep Wi-Fi networks, DNS and proxy of this session",
+            Checkbox::new()
+                .with_checked(!config.skip_live_network)
+                .with_name("live_network"),
+        );
+    // the subvolume layout is only for btrfs filesystems that have it already
+    let kept_subvols = install::kept_btrfs_subvolumes(config.home.is_some());
+    let can_keep_data = config.encryption.is_none()
+        && config.btrfs_mirror.is_none()
+        && config.partition.as_ref().is_some_and(|x| {
+            x.fs_type
+                .as_deref()
+                .is_some_and(|x| disks::KEEP_DATA_FS_TYPE.contains(&x))
+                && (!install::is_btrfs(x) || disks::has_btrfs_subvolumes(x, &kept_subvols))
+        });
+    if can_keep_data {
+        options.add_child(
+            "Keep existing files (do not format)",
+            Checkbox::new()
+                .with_checked(config.keep_data)
+                .with_name("keep_data"),
+        );
+    }
+    let is_xfs = config
+        .partition
+        .as_ref()
+        .is_some_and(|x| x.fs_type.as_deref() == Some("xfs"));
+    if is_xfs {
+        options.add_child(
+            "XFS reflink",
+            Checkbox::new()
+                .with_checked(config.xfs_options.reflink)
+                .with_name("xfs_reflink"),
+        );
+        options.add_child(
+            "XFS timestamps beyond 2038 (bigtime)",
+            Checkbox::new()
+                .with_checked(config.xfs_options.bigtime)
+                .with_name("xfs_bigtime"),
+        );
+    }
+    if is_efi_booted() {
+        let selected = match config.bootloader {
+            install::Bootloader::Grub => 0,
+            install::Bootloader::Efistub => 1,
+        };
+        let mut bootloaders = SelectView::new()
+            .popup()
+            .item("GRUB".to_string(), install::Bootloader::Grub);
+        // 32-bit firmware cannot load the 64-bit kernel directly
+        if !disks::is_efi_ia32() {
+            bootloaders.add_item(
+                "EFISTUB (no bootloader)".to_string(),
+                install::Bootloader::Efistub,
+            );
         }
+        options.add_child(
+            "Boot method",
+            bootloaders.selected(selected).with_name("bootloader"),
+        );
+        let selected = match config.esp_mount_point {
+            install::EspMountPoint::Efi => 0,
+            install::EspMountPoint::BootEfi => 1,
+        };
+        options.add_child(
+            "ESP mount point",
+            SelectView::new()
+                .popup()
+                .with_all(install::EspMountPoint::ALL.map(|x| (x.path().display().to_string(), x)))
+                .selected(selected)
+                .with_name("esp_mount_point"),
+        );
     }
 
-    let swap_size = swap_size.as_ref().to_owned().into_inner();
-    config.swap_size = Arc::new(swap_size);
-    config.use_swap = Arc::new(AtomicBoolWrapper {
-        v: AtomicBool::new(use_swap.load(Ordering::SeqCst)),
-    });
-    config.is_hibernation = Arc::new(AtomicBoolWrapper {
-        v: AtomicBool::new(true),
-    });
-
-    show_summary(s, config);
-}
-
-fn custom_swap_size(
-    installed_size: u64,
-    partition_size: u64,
-    s: &mut Cursive,
-    swap_size: Rc<RefCell<Option<f64>>>,
-    config: InstallConfig,
-    is_hibernation_clone_2: Arc<AtomicBool>,
-    use_swap: Arc<AtomicBool>,
-) {
-    let swap_size_input = Rc::new(RefCell::new(String::new()));
-    let swap_size_input_clone = swap_size_input.clone();
-
-    let swap_size_clone = swap_size.clone();
-    let use_swap_clone = use_swap.clone();
-
-    let is_hibernation_clone_3 = is_hibernation_clone_2.clone();
-
-    s.add_layer(
+    siv.add_layer(
         wrap_in_dialog(
             LinearLayout::vertical()
-                .child(TextView::new(
-                    "Please enter your desired swapfile size (GiB): ",
-                ))
+                .child(TextView::new(EXPERT_OPTIONS_TEXT))
+                .child(DummyView {})
+                .child(options)
+                .child(DummyView {})
+                .child(TextView::new("Extra dracut configuration:"))
                 .child(
-                    EditView::new()
-                        .on_edit_mut(move |_, c, _| {
-                            swap_size_input_clone.replace(c.to_owned());
-                        })
-                        .min_width(20)
-                        .with_name("size"),
+                    TextArea::new()
+                        .content(expert.dracut_conf)
+                        .with_name("dracut_conf")
+                        .min_height(4),
                 ),
-            "Customize Swapfile Size",
+            "Advanced Options",
             None,
         )
         .button("OK", move |s| {
-            let mut config = config.clone();
-            let size = swap_size_input.as_ref().to_owned().into_inner();
-            let size = size.parse::<f64>();
-            if size.is_err() {
-                show_msg(s, "Invalid custom swapfile size!");
+            let get = |s: &mut Cursive, name: &str| {
+                s.call_on_name(name, |v: &mut EditView| v.get_content().trim().to_string())
+                    .unwrap_or_default()
+            };
+            let timeout = get(s, "grub_timeout");
+            let grub_timeout = if timeout.is_empty() {
+                None
+            } else if let Ok(timeout) = timeout.parse::<u32>() {
+                Some(timeout)
+            } else {
+                show_msg(s, "Invalid GRUB timeout!");
                 return;
-            }
-
-            let is_hibernation_clone = is_hibernation_clone_2.clone();
-            let size = size.unwrap() * 1024.0 * 1024.0 * 1024.0;
-            if installed_size + size as u64 > partition_size - DEFAULT_EMPTY_SIZE {
-                show_msg(s, &format!("There is not enough space available in the system partition to create a custom swapfile! Custom swapfile size: {} GiB",  (size / 1024.0 / 1024.0 / 1024.0).round()));
+            };
+            let extra_groups = get(s, "extra_groups")
+                .split(',')
+                .map(|x| x.trim().to_string())
+                .filter(|x| !x.is_empty())
+                .collect::<Vec<_>>();
+            if let Some(group) = extra_groups
+                .iter()
+                .find(|x| !install::is_acceptable_username(x))
+            {
+                show_msg(s, &format!("Group name {group} is not valid!"));
                 return;
             }
 
-            let is_h = match disks::is_enable_hibernation(size) {
-                Ok(is_h) => {
-                    is_h
-                }
-                Err(e) => {
-                    show_msg(s, &e.to_string());
-                    false
-                }
+            let mut config = config.clone();
+            config.expert = install::ExpertOptions {
+                dracut_conf: s
+                    .call_on_name("dracut_conf", |v: &mut TextArea| {
+                        v.get_content().to_string()
+                    })
+                    .unwrap_or_default(),
+                kernel_cmdline: get(s, "kernel_cmdline"),
+                grub_timeout,
+                extra_groups,
+                mkfs_args: config.expert.mkfs_args.clone(),
             };
-
-            is_hibernation_clone.store(is_h, Ordering::SeqCst);
-            swap_size_clone.replace(Some(size));
-            use_swap_clone.store(true, Ordering::SeqCst);
-
-            let swap_size = swap_size.as_ref().to_owned().into_inner();
-            config.swap_size = Arc::new(swap_size);
-            config.use_swap = Arc::new(AtomicBoolWrapper { v: AtomicBool::new(use_swap.load(Ordering::SeqCst) )});
-            config.is_hibernation = Arc::new(AtomicBoolWrapper { v: AtomicBool::new(is_hibernation_clone_3.load(Ordering::SeqCst) )});
-
-            show_summary(s, config);
+            let mkfs_args = get(s, "mkfs_args");
+            if let Err(e) = shell_words::split(&mkfs_args) {
+                show_msg(s, &format!("Invalid mkfs arguments: {e}"));
+                return;
+            }
+            if mkfs_args.is_empty() {
+                config.expert.mkfs_args.remove(&fs_type);
+            } else {
+                config.expert.mkfs_args.insert(fs_type.clone(), mkfs_args);
+            }
+            config.bootloader = s
+                .call_on_name("bootloader", |v: &mut SelectView<install::Bootloader>| {
+                    v.selection().map(|x| *x)
+                })
+                .flatten()
+                .unwrap_or_default();
+            config.esp_mount_point = s
+                .call_on_name(
+                    "esp_mount_point",
+                    |v: &mut SelectView<install::EspMountPoint>| v.selection().map(|x| *x),
+                )
+                .flatten()
+                .unwrap_or_default();
+            config.keep_data = s
+                .call_on_name("keep_data", |v: &mut Checkbox| v.is_checked())
+                .unwrap_or(false);
+            config.skip_live_network = !s
+                .call_on_name("live_network", |v: &mut Checkbox| v.is_checked())
+                .unwrap_or(true);
+            if let Some(reflink) = s.call_on_name("xfs_reflink", |v: &mut Checkbox| v.is_checked())
+            {
+                config.xfs_options.reflink = reflink;
+            }
+            if let Some(bigtime) = s.call_on_name("xfs_bigtime", |v: &mut Checkbox| v.is_checked())
+            {
+                config.xfs_options.bigtime = bigtime;
+            }
+            config.fstab_source = s
+                .call_on_name("fstab_source", |v: &mut SelectView<disks::FstabSource>| {
+                    v.selection().map(|x| *x)
+                })
+                .flatten()
+                .unwrap_or_default();
+            if let Some(problem) = config.fstab_source_problem() {
+                show_msg(s, &problem);
+                return;
+            }
+            update_summary(s, config);
         })
-        .button("Cancel", move |s| s.cb_sink().send(Box::new(|s| {
+        .button("Cancel", |s| {
             s.pop_layer();
-        }))
-        .unwrap()),
+        }),
     );
 }
 
-fn disable_swap(config: InstallConfig, s: &mut Cursive) {
-    let mut config = config;
-    config.swap_size = Arc::new(None);
-    config.use_swap = Arc::new(AtomicBoolWrapper {
-        v: AtomicBool::new(false),
-    });
-    config.is_hibernation = Arc::new(AtomicBoolWrapper {
-        v: AtomicBool::new(false),
-    });
-
-    show_summary(s, config);
-}
+/// Advanced: mount options for every filesystem in fstab, then show the
+/// summary again with the new settings
+fn select_mount_options(siv: &mut Cursive, config: InstallConfig) {
+    let options = config.mount_options.clone();
+    let commit = options.commit.map(|x| x.to_string()).unwrap_or_default();
+    let commit_input = Rc::new(RefCell::new(commit.clone()));
+    let commit_input_copy = commit_input.clone();
 
-fn is_use_last_config(siv: &mut Cursive, config: InstallConfig) {
-    siv.pop_layer();
-    let config_copy = config.clone();
     siv.add_layer(
         wrap_in_dialog(
-            TextView::new(
-                "Would you like to load your previous AOSC OS installation configuration?",
-            ),
-            "AOSC OS Installer",
+            LinearLayout::vertical()
+                .child(TextView::new(MOUNT_OPTIONS_TEXT))
+                .child(DummyView {})
+                .child(
+                    ListView::new()
+                        .child(
+                            "Do not update access times (noatime)",
+                            Checkbox::new()
+                                .with_checked(options.noatime)
+                                .with_name("noatime"),
+                        )
+                        .child(
+                            "Continuous TRIM (discard)",
+                            Checkbox::new()
+                                .with_checked(options.discard)
+                                .with_name("discard"),
+                        )
+                        .child(
+                            "Queue TRIM on btrfs (discard=async)",
+                            Checkbox::new()
+                                .with_checked(options.async_discard)
+                                .with_name("async_discard"),
+                        )
+                        .child(
+                            "Compress btrfs with zstd (compress=zstd)",
+                            Checkbox::new()
+                                .with_checked(options.compress)
+                                .with_name("compress"),
+                        )
+                        .child(
+                            "Commit interval (seconds)",
+                            EditView::new()
+                                .content(commit)
+                                .on_edit_mut(move |_, c, _| {
+                                    commit_input_copy.replace(c.trim().to_owned());
+                                })
+                                .min_width(10),
+                        ),
+                ),
+            "Mount Options",
             None,
         )
-        .button("Yes", move |s| show_summary(s, config_copy.clone()))
-        .button("No", move |s| {
-            fs::remove_file(LAST_USER_CONFIG_FILE).ok();
-            let new_config = InstallConfig {
-                partition: config.clone().partition,
-                ..Default::default()
+        .button("OK", move |s| {
+            let commit = commit_input.borrow().clone();
+            let commit = if commit.is_empty() {
+                None
+            } else {
+                match commit.parse::<u32>() {
+                    Ok(x) if x > 0 => Some(x),
+                    _ => {
+                        show_msg(s, "Invalid commit interval!");
+                        return;
+                    }
+                }
             };
-            select_variant(s, new_config);
+            let is_checked = |s: &mut Cursive, name: &str| {
+                s.call_on_name(name, |v: &mut Checkbox| v.is_checked())
+                    .unwrap_or(false)
+            };
+            let mut config = config.clone();
+            let async_discard = is_checked(s, "async_discard");
+            config.mount_options = disks::MountOptions {
+                noatime: is_checked(s, "noatime"),
+                commit,
+                discard: async_discard || is_checked(s, "discard"),
+                async_discard,
+                compress: is_checked(s, "compress"),
+            };
+            update_summary(s, config);
         })
-        .button("Exit", |s| s.quit()),
+        .button("Cancel", |s| {
+            s.pop_layer();
+        }),
     );
 }
 
-fn show_summary(siv: &mut Cursive, config: InstallConfig) {
-    let mut path = String::new();
-    let mut fs = String::new();
-    let config_copy = config.clone();
-    let config_copy_2 = config.clone();
-    if let Some(partition) = config.partition {
-        if let Some(partition) = &partition.path {
-            path = partition.to_string_lossy().to_string();
-        }
-        if let Some(fs_type) = &partition.fs_type {
-            fs = fs_type.clone();
-        }
-    }
-    let swap_size = if let Some(swap_size) = *config.swap_size {
-        swap_size
-    } else {
-        0.0
+/// Measure how fast the system partition reads, and if it is slow, warn that
+/// installing will take long while it can still be called off
+fn check_speed(siv: &mut Cursive, config: InstallConfig) {
+    let Some(path) = config.partition.as_ref().and_then(|x| x.path.clone()) else {
+        check_power(siv, config);
+        return;
     };
-    let swap_str;
-    let mem = sysinfo::System::new_all().total_memory();
-    match disks::get_recommend_swap_size(mem) {
-        Ok(rs) => {
-            if swap_size == rs {
-                swap_str = "installer default"
-            } else if swap_size == 0.0 {
-                swap_str = "No swapfile will be created."
-            } else {
-                swap_str = "custom size"
-            };
-        }
-        Err(e) => {
-            show_error(siv, &e.to_string());
-            return;
-        }
+
+    siv.add_layer(wrap_in_dialog(
+        TextView::new("Measuring the speed of the target disk ..."),
+        "AOSC OS Installer",
+        None,
+    ));
+    let cb_sink = siv.cb_sink().clone();
+    thread::spawn(move || {
+        let speed = disks::benchmark_read(&path);
+        cb_sink
+            .send(Box::new(move |s| {
+                s.pop_layer();
+                let speed = match speed {
+                    Ok(speed) if speed < disks::SLOW_READ_SPEED => speed,
+                    Ok(_) => return check_power(s, config),
+                    Err(e) => {
+                        warn!("Could not measure read speed: {e}");
+                        return check_power(s, config);
+                    }
+                };
+                let mib = speed / 1024.0 / 1024.0;
+                warn!("Target is slow ({mib:.1} MiB/s), installation may take a long time");
+                s.add_layer(
+                    wrap_in_dialog(
+                        TextView::new(format!("The target disk is slow ({mib:.1} MiB/s), as SD cards and USB 2.0 drives often are. Installing AOSC OS onto it may take a long time. Continue?")),
+                        "AOSC OS Installer",
+                        None,
+                    )
+                    .button("Continue", move |s| {
+                        s.pop_layer();
+                        check_power(s, config.clone());
+                    })
+                    .button("Cancel", |s| {
+                        s.pop_layer();
+                    }),
+                );
+            }))
+            .unwrap();
+    });
+}
+
+/// Start the installation from the summary, unless running on battery power,
+/// then ask to connect the AC adapter first
+fn check_power(siv: &mut Cursive, config: InstallConfig) {
+    let Some(level) = preflight::battery_level() else {
+        siv.pop_layer();
+        start_install(siv, config);
+        return;
     };
-    let s = format!(
-        SUMMARY_TEXT!(),
-        path,
-        fs,
-        config.variant.unwrap().name,
-        config.mirror.unwrap().name,
-        config.user.unwrap(),
-        config.locale.unwrap(),
-        config.timezone.unwrap(),
-        config.tc.unwrap(),
-    );
-    let swap_s = if swap_size != 0.0 {
-        format!(
-            "- A {}GiB swapfile will be created and enabled ({}).",
-            (swap_size / 1024.0 / 1024.0 / 1024.0).round(),
-            swap_str
+
+    let config_clone = config.clone();
+    let mut dialog = if level < preflight::LOW_BATTERY_PERCENT {
+        wrap_in_dialog(
+            TextView::new(format!("Your computer is running on battery power, at {level}%. Running out of power during the installation would leave your system unbootable, please connect the AC adapter to continue.")),
+            "AOSC OS Installer",
+            None,
         )
     } else {
-        format!("- {swap_str}")
-    };
-    siv.add_layer(
         wrap_in_dialog(
-            TextView::new(format!("{s}{swap_s}")),
-            "Pre-Installation Confirmation",
+            TextView::new(format!("Your computer is running on battery power, at {level}%. Running out of power during the installation would leave your system unbootable, please consider connecting the AC adapter.")),
+            "AOSC OS Installer",
             None,
         )
-        .button("Proceed", move |s| {
+        .button("Continue", move |s| {
             s.pop_layer();
-            start_install(s, config_copy.clone());
-        })
-        .button("Save Configuration", move |s| {
-            if let Err(e) = save_user_config_to_file(config_copy_2.clone(), SAVE_USER_CONFIG_FILE) {
-                show_error(s, &e.to_string())
-            } else {
-                show_msg(
-                    s,
-                    &format!(
-                        "Installer has successfully saved your installation configuration: {SAVE_USER_CONFIG_FILE}."
-                    ),
-                )
-            }
-        })
-        .button("Cancel", |s| {
             s.pop_layer();
-        }),
-    );
+            start_install(s, config.clone());
+        })
+    };
+    dialog.add_button("Retry", move |s| {
+        s.pop_layer();
+        check_power(s, config_clone.clone());
+    });
+    dialog.add_button("Cancel", |s| {
+        s.pop_layer();
+    });
+
+    siv.add_layer(dialog);
 }
 
 fn start_install(siv: &mut Cursive, config: InstallConfig) {
@@ -1697,9 +4405,15 @@ fn start_install(siv: &mut Cursive, config: InstallConfig) {
     let tempdir_copy = tempdir.clone();
     let tempdir_copy_2 = tempdir.clone();
 
+    let watchdog_sink = siv.cb_sink().clone();
+    install::set_watchdog_handler(Box::new(move |cmd: &str, elapsed: Duration| {
+        ask_watchdog(&watchdog_sink, cmd, elapsed)
+    }));
+
     let root_fd = install::get_dir_fd(Path::new("/"))
         .expect("Installer failed to get root file descriptor.\n\nPlease restart your installation environment.");
     let rfc = root_fd.try_clone().unwrap();
+    let retry_config = config.clone();
     let install_thread = thread::spawn(move || begin_install(tx, config, tempdir_copy));
     thread::spawn(move || {
         let user_exit = user_interrup_rx.recv();
@@ -1725,20 +4439,27 @@ fn start_install(siv: &mut Cursive, config: InstallConfig) {
                 }
             }
         } else {
-            let err = install_thread.join().unwrap().unwrap_err();
+            // only a failed step has been rolled back, along with the swap
+            // partition it may have made, a crash leaves it all behind
+            let (err, rolled_back) = match install_thread.join() {
+                Ok(res) => (res.unwrap_err(), true),
+                Err(_) => (anyhow::anyhow!("Installer has crashed unexpectedly. Your installation configuration has been saved to {LAST_USER_CONFIG_FILE}, please restart the installer to try again."), false),
+            };
             error!("{}", err);
 
             umount_all(&tempdir, rfc);
             cb_sink
                 .send(Box::new(move |s| {
-                    show_error(
-                        s,
-                        &format!(
-                            "{}\n\nPress <~> to see installer log.\n\nLog file is save to {}",
-                            err,
-                            LOG_FILE.get().unwrap().display()
-                        ),
+                    let msg = format!(
+                        "{}\n\nPress <~> to see installer log.\n\nLog file is save to {}",
+                        err,
+                        LOG_FILE.get().unwrap().display()
                     );
+                    if rolled_back {
+                        show_install_error(s, &msg, retry_config);
+                    } else {
+                        show_error(s, &msg);
+                    }
                 }))
                 .unwrap();
             return;
@@ -1746,14 +4467,36 @@ fn start_install(siv: &mut Cursive, config: InstallConfig) {
     });
 }
 
-fn save_user_config_to_file(config: InstallConfig, path: &str) -> Result<()> {
-    let mut config_copy = config;
-    config_copy.partition = None;
-    let file_str = serde_json::to_string(&config_copy)?;
-    fs::File::create(LAST_USER_CONFIG_FILE)?;
-    fs::write(path, file_str)?;
+/// Ask the user what to do with a hung command, blocks until they answer
+fn ask_watchdog(cb_sink: &CbSink, cmd: &str, elapsed: Duration) -> WatchdogAction {
+    let (tx, rx) = std::sync::mpsc::channel();
+    let msg = format!(
+        "The following command has been running for {} minute(s) without finishing:\n\n{}\n\nIt may be stuck (for instance, probing an unresponsive drive). Would you like to kill it, or keep waiting?",
+        elapsed.as_secs() / 60,
+        cmd
+    );
+
+    cues::play(Cue::InputRequired);
+    let res = cb_sink.send(Box::new(move |s| {
+        let tx_wait = tx.clone();
+        s.add_layer(
+            wrap_in_dialog(TextView::new(msg), "AOSC OS Installer", None)
+                .button("Kill", move |s| {
+                    s.pop_layer();
+                    tx.send(WatchdogAction::Kill).ok();
+                })
+                .button("Wait", move |s| {
+                    s.pop_layer();
+                    tx_wait.send(WatchdogAction::Wait).ok();
+                }),
+        );
+    }));
+
+    if res.is_err() {
+        return WatchdogAction::Kill;
+    }
 
-    Ok(())
+    rx.recv().unwrap_or(WatchdogAction::Kill)
 }
 
 fn read_user_config_on_file() -> Result<InstallConfig> {
@@ -1766,34 +4509,254 @@ fn read_user_config_on_file() -> Result<InstallConfig> {
 
 fn show_finished(siv: &mut Cursive) {
     siv.pop_layer();
+    let dialog = wrap_in_dialog(TextView::new(FINISHED_TEXT), "Installation Complete", None);
+    // QA runs must not reboot the machine running them
+    let dialog = if test_mode::is_enabled() {
+        dialog
+    } else {
+        dialog.button("Reboot", |s| {
+            install::sync_and_reboot().ok();
+            s.quit();
+        })
+    };
+    siv.add_layer(dialog.button("Exit to LiveKit", |s| s.quit()));
+}
+
+fn network_diagnostics(siv: &mut Cursive) {
+    let loader = AsyncView::new_with_bg_creator(
+        siv,
+        || Ok::<_, String>(network::diagnose()),
+        |results| {
+            let mut view = LinearLayout::vertical();
+            for diagnostic in results {
+                let (status, detail) = match diagnostic.result {
+                    Ok(x) => ("OK", x),
+                    Err(x) => ("FAILED", x),
+                };
+                view.add_child(TextView::new(format!(
+                    "{}: {status}\n{detail}\n",
+                    diagnostic.name
+                )));
+            }
+
+            wrap_in_dialog(view, "Network Diagnostics", Some(72))
+                .button("Run Again", |s| {
+                    s.pop_layer();
+                    network_diagnostics(s);
+                })
+                .button("Close", |s| {
+                    s.pop_layer();
+                })
+        },
+    );
+    siv.add_layer(loader);
+}
+
+fn select_wifi(siv: &mut Cursive) {
+    if !wifi::has_wifi() {
+        show_msg(siv, "Installer could not find a wireless network adapter.");
+        return;
+    }
+
+    let loader = AsyncView::new_with_bg_creator(
+        siv,
+        || wifi::scan().map_err(|e| e.to_string()),
+        wifi_list_view,
+    );
+    siv.add_layer(loader);
+}
+
+fn wifi_list_view(networks: Vec<wifi::WifiNetwork>) -> Dialog {
+    let mut network_list = SelectView::new();
+    for network in networks {
+        let security = if network.secured { ", secured" } else { "" };
+        let label = format!("{} ({}%{security})", network.ssid, network.signal);
+        network_list.add_item(label, network);
+    }
+    network_list.set_on_submit(|s, network: &wifi::WifiNetwork| {
+        if network.secured {
+            ask_wifi_password(s, network.ssid.clone());
+        } else {
+            connect_wifi(s, network.ssid.clone(), None);
+        }
+    });
+    let view = LinearLayout::vertical()
+        .child(TextView::new(WIFI_TEXT))
+        .child(DummyView {})
+        .child(Panel::new(network_list.scrollable().max_height(12)).title("Wireless Networks"));
+
+    wrap_in_dialog(view, "Wi-Fi", None)
+        .button("Rescan", |s| {
+            s.pop_layer();
+            select_wifi(s);
+        })
+        .button("Close", |s| {
+            s.pop_layer();
+        })
+}
+
+fn ask_wifi_password(siv: &mut Cursive, ssid: String) {
+    let password_input = Rc::new(RefCell::new(String::new()));
+    let password_input_copy = password_input.clone();
+
     siv.add_layer(
-        wrap_in_dialog(TextView::new(FINISHED_TEXT), "Installation Complete", None)
-            .button("Reboot", |s| {
-                install::sync_and_reboot().ok();
-                s.quit();
-            })
-            .button("Exit to LiveKit", |s| s.quit()),
+        Dialog::around(
+            ListView::new().child(
+                "Password",
+                EditView::new()
+                    .secret()
+                    .on_edit_mut(move |_, c, _| {
+                        password_input_copy.replace(c.to_owned());
+                    })
+                    .min_width(30),
+            ),
+        )
+        .title(format!("Connect to {ssid}"))
+        .button("Connect", move |s| {
+            let password = password_input.borrow().clone();
+            s.pop_layer();
+            connect_wifi(s, ssid.clone(), Some(password));
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        })
+        .padding_lrtb(2, 2, 1, 1),
+    );
+}
+
+fn connect_wifi(siv: &mut Cursive, ssid: String, password: Option<String>) {
+    show_blocking_message(siv, &format!("Connecting to {ssid} ..."));
+    let cb_sink = siv.cb_sink().clone();
+    thread::spawn(move || {
+        let res = wifi::connect(&ssid, password.as_deref());
+        cb_sink
+            .send(Box::new(move |s| {
+                s.pop_layer();
+                match res {
+                    Ok(()) => {
+                        // the network list
+                        s.pop_layer();
+                        show_msg(s, &format!("Connected to {ssid}."));
+                    }
+                    Err(e) => show_msg(s, &e.to_string()),
+                }
+            }))
+            .unwrap();
+    });
+}
+
+fn set_proxy(siv: &mut Cursive) {
+    let proxy_input = Rc::new(RefCell::new(network::proxy().unwrap_or_default()));
+    let proxy_input_copy = proxy_input.clone();
+    let ca_input = Rc::new(RefCell::new(
+        network::ca_certificate()
+            .map(|x| x.display().to_string())
+            .unwrap_or_default(),
+    ));
+    let ca_input_copy = ca_input.clone();
+
+    siv.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(PROXY_TEXT))
+                .child(DummyView {})
+                .child(
+                    ListView::new()
+                        .child(
+                            "Proxy",
+                            EditView::new()
+                                .content(proxy_input.borrow().clone())
+                                .on_edit_mut(move |_, c, _| {
+                                    proxy_input_copy.replace(c.trim().to_owned());
+                                })
+                                .min_width(50),
+                        )
+                        .child(
+                            "CA certificate",
+                            EditView::new()
+                                .content(ca_input.borrow().clone())
+                                .on_edit_mut(move |_, c, _| {
+                                    ca_input_copy.replace(c.trim().to_owned());
+                                })
+                                .min_width(50),
+                        ),
+                ),
+        )
+        .title("Network Proxy")
+        .button("Use Tor", |s| {
+            if !network::tor_available() {
+                show_msg(
+                    s,
+                    &format!(
+                        "Installer could not reach Tor at {}, please start it first, e.g. with `systemctl start tor`.",
+                        network::TOR_PROXY
+                    ),
+                );
+                return;
+            }
+            network::set_proxy(Some(network::TOR_PROXY.to_string())).ok();
+            s.pop_layer();
+        })
+        .button("Save", move |s| {
+            let proxy = proxy_input.borrow().clone();
+            let proxy = (!proxy.is_empty()).then_some(proxy);
+            if let Err(e) = network::set_proxy(proxy) {
+                show_msg(s, &e.to_string());
+                return;
+            }
+            let ca = ca_input.borrow().clone();
+            let ca = (!ca.is_empty()).then(|| PathBuf::from(ca));
+            if let Err(e) = network::set_ca_certificate(ca) {
+                show_msg(s, &e.to_string());
+                return;
+            }
+            s.pop_layer();
+        })
+        .button("Cancel", |s| {
+            s.pop_layer();
+        })
+        .padding_lrtb(2, 2, 1, 1),
     );
 }
 
 pub fn tui_main() {
     let mut siv = cursive::default();
+    TUI_ACTIVE.store(true, Ordering::SeqCst);
 
     siv.add_global_callback('~', cursive::Cursive::toggle_debug_console);
+    siv.add_global_callback(Key::F2, toggle_debug_log);
+    siv.add_global_callback(Key::F3, toggle_audio_cues);
 
     siv.add_layer(
-        Dialog::around(TextView::new(WELCOME_TEXT))
-            .title("Welcome")
-            .button("Let's Go", |s| {
-                if let Ok(config) = read_user_config_on_file() {
-                    select_disk(s, config);
-                } else {
-                    let config = InstallConfig::default();
-                    select_variant(s, config);
-                }
-            })
-            .padding_lrtb(2, 2, 1, 1)
-            .max_width(80),
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(TextView::new(WELCOME_TEXT))
+                .child(DummyView {})
+                .child(
+                    LinearLayout::horizontal()
+                        .child(Checkbox::new().on_change(|_, checked| {
+                            EXPERT_MODE.store(checked, Ordering::SeqCst);
+                        }))
+                        .child(TextView::new(
+                            " Expert mode (advanced installation options)",
+                        )),
+                ),
+        )
+        .title("Welcome")
+        .button("Wi-Fi", select_wifi)
+        .button("Proxy", set_proxy)
+        .button("Diagnostics", network_diagnostics)
+        .button("Local Tarball", select_local_tarball)
+        .button("Let's Go", |s| {
+            if let Ok(config) = read_user_config_on_file() {
+                select_disk(s, config);
+            } else {
+                let config = InstallConfig::default();
+                select_variant(s, config);
+            }
+        })
+        .padding_lrtb(2, 2, 1, 1)
+        .max_width(80),
     );
 
     siv.run();
@@ -1,33 +1,71 @@
 use anyhow::{anyhow, bail, Context, Result};
 use cursive::utils::ProgressReader;
-use log::info;
+use log::{info, warn};
+use once_cell::sync::Lazy;
 use rustix::fd::{AsFd, OwnedFd};
 use rustix::fs::{self, FallocateFlags, Mode, OFlags};
-use rustix::io::Errno;
 use rustix::{mount, process};
+use serde::{Deserialize, Serialize};
 use std::ffi::OsStr;
 use std::fmt::Debug;
 use std::io::{prelude::*, SeekFrom, Write};
-use std::os::unix::io::AsRawFd;
-use std::os::unix::prelude::{OsStrExt, PermissionsExt};
+use std::net::IpAddr;
+use std::os::unix::prelude::{OpenOptionsExt, OsStrExt, PermissionsExt};
 use std::path::PathBuf;
-use std::process::{Command, Stdio};
-use std::sync::atomic::AtomicBool;
-use std::sync::Arc;
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{mpsc::Receiver, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
 use std::{fs::File, path::Path};
 use sysinfo::System;
 
-use crate::disks::{fstab_entries, is_efi_booted, Partition};
+use crate::disks::{
+    fstab_entries, is_efi_booted, FstabSource, MkfsArgs, MountOptions, Partition, BTRFS_SUBVOLUMES,
+};
 use crate::network;
-use crate::parser::{list_mounts, list_zoneinfo, parse_languagelist};
+use crate::parser::{list_mounts, list_zoneinfo, parse_languagelist, parse_os_release};
 
 const BIND_MOUNTS: &[&str] = &["/dev", "/proc", "/sys", "/run/udev"];
 const EFIVARS_PATH: &str = "/sys/firmware/efi/efivars";
 const SYSTEM_ZONEINFO1970_PATH: &str = "/usr/share/zoneinfo/zone1970.tab";
 const BUNDLED_ZONEINFO_LIST: &[u8] = include_bytes!("../res/zone1970.tab");
 pub const LANGUAGE_LIST: &[u8] = include_bytes!("../res/languagelist");
+/// 32-bit compatibility runtime, only built for amd64
+pub const OPTENV32_PACKAGE: &str = "optenv32";
+/// Sets up compressed swap in memory at boot
+pub const ZRAM_PACKAGE: &str = "zram-generator";
+/// Half of the memory, up to 4GiB, compressed with zstd
+const ZRAM_GENERATOR_CONF: &str =
+    "[zram0]\nzram-size = min(ram / 2, 4096)\ncompression-algorithm = zstd\n";
+const DEFAULT_COMMAND_TIMEOUT: u64 = 30 * 60;
+
+/// What to do with an external command that has been running for too long
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchdogAction {
+    Kill,
+    Wait,
+}
+
+/// Asked with the command line and how long it has been running so far
+pub type WatchdogHandler = Box<dyn Fn(&str, Duration) -> WatchdogAction + Send + Sync>;
+
+/// Seconds an external command may run before the watchdog steps in, 0 disables it
+static COMMAND_TIMEOUT: AtomicU64 = AtomicU64::new(DEFAULT_COMMAND_TIMEOUT);
+static WATCHDOG_HANDLER: Lazy<Mutex<Option<WatchdogHandler>>> = Lazy::new(|| Mutex::new(None));
+
+pub fn set_command_timeout(secs: u64) {
+    COMMAND_TIMEOUT.store(secs, Ordering::SeqCst);
+}
+
+/// Set how to decide on hung commands; without a handler they are killed
+pub fn set_watchdog_handler(handler: WatchdogHandler) {
+    if let Ok(mut h) = WATCHDOG_HANDLER.lock() {
+        *h = Some(handler);
+    }
+}
 
-fn run_command<I, S>(command: &str, args: I) -> Result<()>
+pub(crate) fn run_command<I, S>(command: &str, args: I) -> Result<()>
 where
     I: IntoIterator<Item = S> + Debug,
     S: AsRef<OsStr>,
@@ -35,14 +73,60 @@ where
     let cmd_str = format!("{command} {args:?}");
     info!("Running {}", cmd_str);
 
-    let cmd = Command::new(command).args(args).output()?;
+    let child = Command::new(command)
+        .args(args)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
 
-    if !cmd.status.success() {
-        return Err(anyhow!(
-            "Run {} failed!\n\n{}",
-            cmd_str,
-            String::from_utf8_lossy(&cmd.stderr)
-        ));
+    wait_for_command(child, command, &cmd_str)
+}
+
+/// Like [`run_command`], with `input` fed to the standard input of the
+/// command, which keeps secrets out of the command line and the log
+pub(crate) fn run_command_with_input<I, S>(command: &str, args: I, input: &[u8]) -> Result<()>
+where
+    I: IntoIterator<Item = S> + Debug,
+    S: AsRef<OsStr>,
+{
+    let cmd_str = format!("{command} {args:?}");
+    info!("Running {}", cmd_str);
+
+    let mut child = Command::new(command)
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    // closing stdin afterwards lets the command see the end of the input
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(input)?;
+    }
+
+    wait_for_command(child, command, &cmd_str)
+}
+
+fn wait_for_command(mut child: Child, command: &str, cmd_str: &str) -> Result<()> {
+    // stream output into the log as it appears, which also keeps a chatty
+    // command from blocking on a full pipe
+    let stdout = child
+        .stdout
+        .take()
+        .map(|x| forward_pipe(x, command.to_string()));
+    let stderr = child
+        .stderr
+        .take()
+        .map(|x| forward_pipe(x, command.to_string()));
+
+    let status = wait_with_watchdog(&mut child, cmd_str);
+    if let Some(stdout) = stdout {
+        stdout.join().ok();
+    }
+    let stderr = stderr.and_then(|x| x.join().ok()).unwrap_or_default();
+
+    if !status?.success() {
+        return Err(anyhow!("Run {} failed!\n\n{}", cmd_str, stderr));
     }
 
     info!("Run {} Successfully!", cmd_str);
@@ -50,6 +134,60 @@ where
     Ok(())
 }
 
+/// Log every line read from `pipe` as it arrives, returning the whole output once it is closed
+fn forward_pipe<R: Read + Send + 'static>(pipe: R, name: String) -> JoinHandle<String> {
+    thread::spawn(move || {
+        let mut output = String::new();
+        let lines = std::io::BufReader::new(pipe).split(b'\n');
+        for line in lines.map_while(|x| x.ok()) {
+            let line = String::from_utf8_lossy(&line);
+            info!("{name}: {line}");
+            output.push_str(&line);
+            output.push('\n');
+        }
+
+        output
+    })
+}
+
+fn wait_with_watchdog(child: &mut Child, cmd_str: &str) -> Result<ExitStatus> {
+    let start = Instant::now();
+    let mut last_asked = start;
+
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        let timeout = Duration::from_secs(COMMAND_TIMEOUT.load(Ordering::SeqCst));
+        if !timeout.is_zero() && last_asked.elapsed() >= timeout {
+            let elapsed = start.elapsed();
+            warn!("{cmd_str} has been running for {}s", elapsed.as_secs());
+            let action = WATCHDOG_HANDLER
+                .lock()
+                .ok()
+                .and_then(|h| h.as_ref().map(|h| h(cmd_str, elapsed)))
+                .unwrap_or(WatchdogAction::Kill);
+
+            match action {
+                WatchdogAction::Wait => last_asked = Instant::now(),
+                WatchdogAction::Kill => {
+                    warn!("Killing {cmd_str}");
+                    child.kill().ok();
+                    child.wait().ok();
+                    bail!(
+                        "Run {} failed!\n\nThe command did not finish after {}s and was killed.",
+                        cmd_str,
+                        elapsed.as_secs()
+                    );
+                }
+            }
+        }
+
+        thread::sleep(Duration::from_millis(100));
+    }
+}
+
 fn no_need_to_run_info(s: &str, str_is_retro: bool) {
     if str_is_retro {
         info!("Retro system no need to run {}", s);
@@ -62,6 +200,79 @@ fn no_need_to_run_info(s: &str, str_is_retro: bool) {
 pub enum ExtractFileType {
     Tar,
     Squashfs,
+    Sendstream,
+}
+
+impl ExtractFileType {
+    pub fn from_url(url: &str) -> Result<Self> {
+        if url.ends_with(".squashfs") {
+            Ok(ExtractFileType::Squashfs)
+        } else if url.ends_with(".tar.xz") {
+            Ok(ExtractFileType::Tar)
+        } else if url.ends_with(".sendstream.xz") {
+            Ok(ExtractFileType::Sendstream)
+        } else {
+            Err(anyhow!("Unsupport format!"))
+        }
+    }
+}
+
+/// Whether the release at `url` can be unpacked while it is downloaded,
+/// squashfs images have to be complete first
+pub fn is_streamable(url: &str) -> bool {
+    matches!(
+        ExtractFileType::from_url(url),
+        Ok(ExtractFileType::Tar | ExtractFileType::Sendstream)
+    )
+}
+
+/// Reads the chunks the downloader sends, ends when the sender is dropped
+pub struct ChannelReader {
+    rx: Receiver<Vec<u8>>,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ChannelReader {
+    pub fn new(rx: Receiver<Vec<u8>>) -> Self {
+        ChannelReader {
+            rx,
+            buf: vec![],
+            pos: 0,
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+        while self.pos == self.buf.len() {
+            match self.rx.recv() {
+                Ok(buf) => {
+                    self.buf = buf;
+                    self.pos = 0;
+                }
+                Err(_) => return Ok(0),
+            }
+        }
+        let len = out.len().min(self.buf.len() - self.pos);
+        out[..len].copy_from_slice(&self.buf[self.pos..self.pos + len]);
+        self.pos += len;
+
+        Ok(len)
+    }
+}
+
+#[test]
+fn test_channel_reader() {
+    let (tx, rx) = std::sync::mpsc::channel();
+    tx.send(b"AOSC ".to_vec()).unwrap();
+    tx.send(vec![]).unwrap();
+    tx.send(b"OS".to_vec()).unwrap();
+    drop(tx);
+
+    let mut res = String::new();
+    ChannelReader::new(rx).read_to_string(&mut res).unwrap();
+    assert_eq!(res, "AOSC OS");
 }
 
 /// Get the list of available locales
@@ -175,7 +386,206 @@ fn extract_squashfs<P: AsRef<Path>>(
     Ok(())
 }
 
-/// Extract .tar.xz or .squashfs
+/// Where sendstreams are received to, at the top level of the filesystem
+const RECEIVE_DIR: &str = ".deploykit-receive";
+/// Where the system subvolume is moved aside to while it is replaced
+const REPLACED_ROOT_SUBVOL: &str = "@.deploykit-replaced";
+
+/// Device mounted at `path`, from /proc/mounts
+fn mount_source(path: &Path) -> Result<PathBuf> {
+    let buf = std::fs::read("/proc/mounts")?;
+    let mounts = list_mounts(&buf)
+        .map_err(|e| anyhow!("Failed to get mounts, {}", e))?
+        .1;
+
+    mounts
+        .iter()
+        .rev()
+        .find(|(_, mount_path)| Path::new(mount_path) == path)
+        .map(|(dev, _)| PathBuf::from(dev))
+        .ok_or_else(|| {
+            anyhow!(
+                "Installer could not find what is mounted at {}.",
+                path.display()
+            )
+        })
+}
+
+/// Mount the top level of the btrfs filesystem on `partition` at a temporary
+/// directory for `f` to work in
+fn with_btrfs_top_level<T>(partition: &Partition, f: impl FnOnce(&Path) -> Result<T>) -> Result<T> {
+    let top = tempfile::Builder::new().prefix(".dktop").tempdir()?;
+    mount_with_data(partition, top.path(), "subvolid=5")?;
+    let res = f(top.path());
+    umount_root_path(top.path())?;
+
+    res
+}
+
+/// Delete the subvolumes received into `receive_path` and the directory
+fn remove_received(receive_path: &Path) {
+    if let Ok(entries) = std::fs::read_dir(receive_path) {
+        for entry in entries.flatten() {
+            run_command(
+                "btrfs",
+                [
+                    OsStr::new("subvolume"),
+                    OsStr::new("delete"),
+                    entry.path().as_os_str(),
+                ],
+            )
+            .ok();
+        }
+    }
+    std::fs::remove_dir(receive_path).ok();
+}
+
+/// Receive an xz-compressed btrfs sendstream at the top level of the btrfs
+/// filesystem mounted at `path`, to be made the system subvolume by
+/// [`adopt_received_root`]. Nothing is left behind if this fails.
+fn receive_sendstream<R: Read>(reader: R, path: &Path) -> Result<()> {
+    let partition = Partition {
+        path: Some(mount_source(path)?),
+        parent_path: None,
+        fs_type: Some("btrfs".to_string()),
+        size: 0,
+    };
+
+    with_btrfs_top_level(&partition, |top| {
+        let receive_path = top.join(RECEIVE_DIR);
+        std::fs::create_dir_all(&receive_path)?;
+        let res = receive_into(reader, &receive_path);
+        if res.is_err() {
+            remove_received(&receive_path);
+        }
+
+        res
+    })
+}
+
+fn receive_into<R: Read>(reader: R, receive_path: &Path) -> Result<()> {
+    let mut child = Command::new("btrfs")
+        .arg("receive")
+        .arg(receive_path)
+        .stdin(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+    let mut stdin = child
+        .stdin
+        .take()
+        .context("Failed to open btrfs receive stdin")?;
+    let copied = std::io::copy(&mut xz2::read::XzDecoder::new(reader), &mut stdin);
+    drop(stdin);
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        bail!(
+            "Installer could not receive the btrfs sendstream: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    copied?;
+
+    std::fs::read_dir(receive_path)?
+        .next()
+        .context("The btrfs sendstream did not contain a subvolume")??;
+
+    Ok(())
+}
+
+/// Subvolume received by [`receive_sendstream`] below `top`, if any
+fn received_subvolume(top: &Path) -> Result<Option<PathBuf>> {
+    let received = std::fs::read_dir(top.join(RECEIVE_DIR))
+        .ok()
+        .and_then(|mut x| x.next())
+        .transpose()?
+        .map(|x| x.path());
+
+    Ok(received)
+}
+
+/// Whether a sendstream has been received on `partition`
+pub fn has_received_root(partition: &Partition) -> bool {
+    with_btrfs_top_level(partition, received_subvolume).is_ok_and(|x| x.is_some())
+}
+
+/// Make the subvolume received by [`receive_sendstream`] the system subvolume
+/// of `partition` in place of the empty one. It is snapshotted writable
+/// rather than copied, so the data is written only once. The system
+/// subvolume must not be mounted.
+pub fn adopt_received_root(partition: &Partition) -> Result<()> {
+    let root_subvol = BTRFS_SUBVOLUMES[0].0;
+    with_btrfs_top_level(partition, |top| {
+        let receive_path = top.join(RECEIVE_DIR);
+        let received =
+            received_subvolume(top)?.context("The btrfs sendstream did not contain a subvolume")?;
+
+        info!("Using {} as the system subvolume", received.display());
+        let root = top.join(root_subvol);
+        let replaced = top.join(REPLACED_ROOT_SUBVOL);
+        if let Err(e) = std::fs::rename(&root, &replaced) {
+            remove_received(&receive_path);
+            return Err(e.into());
+        }
+        let res = run_command(
+            "btrfs",
+            [
+                OsStr::new("subvolume"),
+                OsStr::new("snapshot"),
+                received.as_os_str(),
+                root.as_os_str(),
+            ],
+        );
+        if res.is_err() {
+            std::fs::rename(&replaced, &root).ok();
+        }
+        remove_received(&receive_path);
+        res?;
+        run_command(
+            "btrfs",
+            [
+                OsStr::new("subvolume"),
+                OsStr::new("delete"),
+                replaced.as_os_str(),
+            ],
+        )
+        .map_err(|e| warn!("Could not delete the replaced system subvolume: {e}"))
+        .ok();
+
+        Ok(())
+    })
+}
+
+/// Parts of the live system that are not copied to the installed one, besides
+/// other filesystems such as /proc and the target itself
+const LIVE_COPY_EXCLUDES: &[&str] = &[
+    "/dev/*",
+    "/proc/*",
+    "/sys/*",
+    "/run/*",
+    "/tmp/*",
+    "/mnt/*",
+    "/media/*",
+    "/lost+found",
+    "/var/cache/deploykit/*",
+];
+
+/// Copy the root of the running live system to `target`, with all the layers
+/// of its overlay merged as the live session sees them
+pub fn copy_live_system(target: &Path) -> Result<()> {
+    let mut args = vec![
+        "-aAXH".to_string(),
+        "--numeric-ids".to_string(),
+        "--one-file-system".to_string(),
+        format!("--exclude={}", target.display()),
+    ];
+    args.extend(LIVE_COPY_EXCLUDES.iter().map(|x| format!("--exclude={x}")));
+    args.push("/".to_string());
+    args.push(format!("{}/", target.display()));
+
+    run_command("rsync", args)
+}
+
+/// Extract .tar.xz, .squashfs or apply .sendstream.xz
 pub fn extract_file(
     file_size: f64,
     url: String,
@@ -183,15 +593,7 @@ pub fn extract_file(
     extract_path: &Path,
     counter: cursive::utils::Counter,
 ) -> Result<()> {
-    let extract_file_type = if url.ends_with(".squashfs") {
-        ExtractFileType::Squashfs
-    } else if url.ends_with(".tar.xz") {
-        ExtractFileType::Tar
-    } else {
-        return Err(anyhow!("Unsupport format!"));
-    };
-
-    match extract_file_type {
+    match ExtractFileType::from_url(&url)? {
         ExtractFileType::Tar => extract_tar_xz(
             ProgressReader::new(counter, std::fs::File::open(archive_path)?),
             extract_path,
@@ -199,15 +601,136 @@ pub fn extract_file(
         ExtractFileType::Squashfs => {
             extract_squashfs(file_size, archive_path, extract_path, counter)
         }
+        ExtractFileType::Sendstream => receive_sendstream(
+            ProgressReader::new(counter, std::fs::File::open(archive_path)?),
+            extract_path,
+        ),
+    }
+}
+
+/// Unpack a .tar.xz or apply a .sendstream.xz as it is read from `reader`,
+/// then read whatever is left after the end of the archive
+pub fn extract_stream<R: Read>(url: &str, mut reader: R, extract_path: &Path) -> Result<()> {
+    match ExtractFileType::from_url(url)? {
+        ExtractFileType::Tar => extract_tar_xz(&mut reader, extract_path)?,
+        ExtractFileType::Sendstream => receive_sendstream(&mut reader, extract_path)?,
+        ExtractFileType::Squashfs => {
+            bail!("Installer could not unpack a squashfs image while downloading it.")
+        }
     }
+    std::io::copy(&mut reader, &mut std::io::sink())?;
+
+    Ok(())
 }
 
 pub fn auto_mount_root_path(tmp_path: &Path, partition: &Partition) -> Result<PathBuf> {
     mount_root_path(partition, tmp_path)?;
 
+    if is_btrfs(partition) {
+        for (subvol, path) in BTRFS_SUBVOLUMES.iter().skip(1) {
+            let target = tmp_path.join(path.trim_start_matches('/'));
+            std::fs::create_dir_all(&target)?;
+            mount_with_data(partition, &target, &format!("subvol={subvol}"))?;
+        }
+    }
+
     Ok(tmp_path.to_path_buf())
 }
 
+pub fn is_btrfs(partition: &Partition) -> bool {
+    partition.fs_type.as_deref() == Some("btrfs")
+}
+
+/// Create the subvolumes in [`BTRFS_SUBVOLUMES`] on a btrfs partition that
+/// lacks them, using `tmp_path` to mount its top level
+pub fn create_btrfs_subvolumes(partition: &Partition, tmp_path: &Path) -> Result<()> {
+    mount_with_data(partition, tmp_path, "subvolid=5")?;
+    let res = BTRFS_SUBVOLUMES.iter().try_for_each(|(subvol, _)| {
+        if tmp_path.join(subvol).exists() {
+            return Ok(());
+        }
+        run_command(
+            "btrfs",
+            [
+                Path::new("subvolume"),
+                Path::new("create"),
+                &tmp_path.join(subvol),
+            ],
+        )
+    });
+    umount_root_path(tmp_path)?;
+
+    res
+}
+
+/// Subvolumes in [`BTRFS_SUBVOLUMES`] that hold data, and so must already
+/// exist on a btrfs partition that is kept. @home is not mounted when /home
+/// is a separate partition.
+pub fn kept_btrfs_subvolumes(separate_home: bool) -> Vec<&'static str> {
+    BTRFS_SUBVOLUMES
+        .iter()
+        .filter(|(_, path)| *path == "/" || (*path == "/home" && !separate_home))
+        .map(|(subvol, _)| *subvol)
+        .collect()
+}
+
+/// Refuse to keep the data on a btrfs partition without the subvolumes of
+/// [`kept_btrfs_subvolumes`]: its data would be in the top level, hidden by
+/// newly created subvolumes mounted over it
+pub fn check_kept_btrfs_subvolumes(
+    partition: &Partition,
+    tmp_path: &Path,
+    separate_home: bool,
+) -> Result<()> {
+    mount_with_data(partition, tmp_path, "subvolid=5")?;
+    let missing = kept_btrfs_subvolumes(separate_home)
+        .into_iter()
+        .filter(|x| !tmp_path.join(x).is_dir())
+        .collect::<Vec<_>>();
+    umount_root_path(tmp_path)?;
+
+    if !missing.is_empty() {
+        bail!(
+            "Installer can not keep the data on {}: it has no {} subvolume, so the files in its top level would be hidden by the new subvolumes. Please move them into {} or format the partition.",
+            partition.path.as_deref().unwrap_or(Path::new("?")).display(),
+            missing.join(" or "),
+            missing.join(" and ")
+        );
+    }
+
+    Ok(())
+}
+
+/// Mount a partition that is kept as is, such as an existing /home
+pub fn mount_extra_partition(partition: &Partition, target: &Path) -> Result<()> {
+    std::fs::create_dir_all(target)?;
+
+    mount_with_data(partition, target, "")
+}
+
+/// Unmount the subvolumes mounted below the system subvolume
+pub fn umount_btrfs_subvolumes(mount_path: &Path) {
+    for (_, path) in BTRFS_SUBVOLUMES.iter().skip(1).rev() {
+        umount_root_path(&mount_path.join(path.trim_start_matches('/'))).ok();
+    }
+}
+
+/// Take a read-only snapshot of the system subvolume, to roll back to later
+pub fn snapshot_btrfs_root(mount_path: &Path, name: &str) -> Result<()> {
+    let snapshots = mount_path.join(".snapshots");
+    std::fs::create_dir_all(&snapshots)?;
+    run_command(
+        "btrfs",
+        [
+            Path::new("subvolume"),
+            Path::new("snapshot"),
+            Path::new("-r"),
+            mount_path,
+            &snapshots.join(name),
+        ],
+    )
+}
+
 /// Sync the filesystem and then reboot IMMEDIATELY (ignores init)
 pub fn sync_and_reboot() -> Result<()> {
     fs::sync();
@@ -223,6 +746,22 @@ pub fn mount_root_path(partition: &Partition, target: &Path) -> Result<()> {
             "Installer failed to determine user-specified partition."
         ));
     }
+
+    if crate::zfs::is_zfs(partition) {
+        return crate::zfs::import(partition, target);
+    }
+
+    if is_btrfs(partition) {
+        // systems installed before subvolumes were used have none
+        if mount_with_data(partition, target, "subvol=@").is_ok() {
+            return Ok(());
+        }
+    }
+
+    mount_with_data(partition, target, "")
+}
+
+fn mount_with_data(partition: &Partition, target: &Path, data: &str) -> Result<()> {
     let source = partition.path.as_ref();
     let mut fs_type = partition.fs_type.as_ref().unwrap().as_str();
     if fs_type.starts_with("fat") {
@@ -234,21 +773,36 @@ pub fn mount_root_path(partition: &Partition, target: &Path) -> Result<()> {
         target,
         fs_type,
         mount::MountFlags::empty(),
-        "",
+        data,
     )?;
 
     Ok(())
 }
 
 /// Gen fstab to /etc/fstab
-pub fn genfstab_to_file(partition: &Partition, root_path: &Path, mount_path: &Path) -> Result<()> {
+/// `subvol` is the btrfs subvolume to mount, if any
+pub fn genfstab_to_file(
+    partition: &Partition,
+    root_path: &Path,
+    mount_path: &Path,
+    mount_options: &MountOptions,
+    subvol: Option<&str>,
+    source: FstabSource,
+) -> Result<()> {
     if cfg!(debug_assertions) {
         return Ok(());
     }
     let fs_type = partition.fs_type.as_ref().ok_or_else(|| {
         anyhow!("Installer failed to detect filesystem type for the specified partition.")
     })?;
-    let s = fstab_entries(partition.path.as_ref(), fs_type, Some(mount_path))?;
+    let s = fstab_entries(
+        partition.path.as_ref(),
+        fs_type,
+        Some(mount_path),
+        mount_options,
+        subvol,
+        source,
+    )?;
     let mut f = std::fs::OpenOptions::new()
         .append(true)
         .open(root_path.join("etc/fstab"))?;
@@ -327,6 +881,161 @@ pub fn dive_into_guest(root: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Settings only offered in expert mode
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ExpertOptions {
+    /// Extra dracut configuration, in dracut.conf syntax
+    #[serde(default)]
+    pub dracut_conf: String,
+    /// Appended to the kernel command line
+    #[serde(default)]
+    pub kernel_cmdline: String,
+    /// Seconds the GRUB menu is shown for
+    #[serde(default)]
+    pub grub_timeout: Option<u32>,
+    /// Supplementary groups for the default user
+    #[serde(default)]
+    pub extra_groups: Vec<String>,
+    /// Appended to mkfs when formatting the system partition, by filesystem
+    #[serde(default)]
+    pub mkfs_args: MkfsArgs,
+}
+
+impl ExpertOptions {
+    pub fn is_default(&self) -> bool {
+        self == &ExpertOptions::default()
+    }
+}
+
+/// Writes extra dracut configuration for update-initramfs to pick up
+/// Must be used in a chroot context
+pub fn write_dracut_conf(conf: &str) -> Result<()> {
+    if conf.trim().is_empty() {
+        return Ok(());
+    }
+
+    std::fs::create_dir_all("/etc/dracut.conf.d")?;
+    std::fs::write(
+        "/etc/dracut.conf.d/90-deploykit.conf",
+        format!("{}\n", conf.trim()),
+    )?;
+
+    Ok(())
+}
+
+/// Set or replace `key` in a shell-style configuration file
+fn set_shell_var(content: &str, key: &str, value: &str) -> String {
+    let prefix = format!("{key}=");
+    let line = format!("{key}={value}");
+    let mut found = false;
+    let mut res = content
+        .lines()
+        .map(|x| {
+            if x.starts_with(&prefix) {
+                found = true;
+                line.clone()
+            } else {
+                x.to_string()
+            }
+        })
+        .collect::<Vec<_>>();
+    if !found {
+        res.push(line);
+    }
+
+    res.join("\n") + "\n"
+}
+
+/// Apply the kernel command line and menu timeout to /etc/default/grub
+fn update_grub_defaults(content: &str, cmdline: &str, timeout: Option<u32>) -> String {
+    let mut res = content.to_string();
+    if let Some(timeout) = timeout {
+        res = set_shell_var(&res, "GRUB_TIMEOUT", &timeout.to_string());
+    }
+
+    let cmdline = cmdline.trim();
+    if !cmdline.is_empty() {
+        let current = res
+            .lines()
+            .find_map(|x| x.strip_prefix("GRUB_CMDLINE_LINUX_DEFAULT="))
+            .map(|x| x.trim_matches('"').to_string())
+            .unwrap_or_default();
+        let value = format!("\"{}\"", format!("{current} {cmdline}").trim());
+        res = set_shell_var(&res, "GRUB_CMDLINE_LINUX_DEFAULT", &value);
+    }
+
+    res
+}
+
+/// Must be used in a chroot context
+pub fn set_grub_defaults(cmdline: &str, timeout: Option<u32>) -> Result<()> {
+    if cmdline.trim().is_empty() && timeout.is_none() {
+        return Ok(());
+    }
+
+    let path = Path::new("/etc/default/grub");
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    std::fs::write(path, update_grub_defaults(&content, cmdline, timeout))?;
+
+    Ok(())
+}
+
+/// Make sure the initramfs can activate the logical volume holding the system
+/// Must be used in a chroot context
+pub fn write_lvm_dracut_conf() -> Result<()> {
+    std::fs::create_dir_all("/etc/dracut.conf.d")?;
+    std::fs::write(
+        "/etc/dracut.conf.d/80-deploykit-lvm.conf",
+        "add_dracutmodules+=\" lvm \"\n",
+    )?;
+
+    Ok(())
+}
+
+/// Record the running RAID arrays in /etc/mdadm.conf of the system at
+/// `root_path`, so that the initramfs assembles them under the same names
+pub fn write_mdadm_conf(root_path: &Path) -> Result<()> {
+    let output = Command::new("mdadm")
+        .args(["--detail", "--scan"])
+        .output()?;
+    if !output.status.success() {
+        bail!(
+            "Installer could not scan RAID arrays: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+
+    let mut f = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(root_path.join("etc/mdadm.conf"))?;
+    f.write_all(&output.stdout)?;
+
+    Ok(())
+}
+
+/// Make sure the initramfs can assemble the RAID array holding the system
+/// Must be used in a chroot context
+pub fn write_mdraid_dracut_conf() -> Result<()> {
+    std::fs::create_dir_all("/etc/dracut.conf.d")?;
+    std::fs::write(
+        "/etc/dracut.conf.d/80-deploykit-mdraid.conf",
+        "add_dracutmodules+=\" mdraid \"\nmdadmconf=\"yes\"\n",
+    )?;
+
+    Ok(())
+}
+
+/// Let GRUB unlock an encrypted /boot
+/// Must be used in a chroot context
+pub fn enable_grub_cryptodisk() -> Result<()> {
+    let path = Path::new("/etc/default/grub");
+    let content = std::fs::read_to_string(path).unwrap_or_default();
+    std::fs::write(path, set_shell_var(&content, "GRUB_ENABLE_CRYPTODISK", "y"))?;
+
+    Ok(())
+}
+
 /// Runs dracut
 /// Must be used in a chroot context
 #[cfg(not(feature = "is_retro"))]
@@ -355,11 +1064,498 @@ pub fn gen_ssh_key() -> Result<()> {
     Ok(())
 }
 
-/// Runs ssh-keygen -A
+/// Runs ssh-keygen -A
+/// Must be used in a chroot context
+#[cfg(feature = "is_retro")]
+pub fn gen_ssh_key() -> Result<()> {
+    run_command("ssh-keygen", &["-A"])?;
+
+    Ok(())
+}
+
+/// Why the os-release fields from `get` do not match `variant`, if so. Only
+/// what both sides carry is compared: releases from a custom URL, a file or
+/// the live system have no known date, and older releases no BUILD_ID.
+fn release_mismatch<'a>(
+    get: impl Fn(&str) -> Option<&'a str>,
+    variant: &network::VariantEntry,
+) -> Option<String> {
+    let date = &variant.date;
+    if date.len() == 8 && date.bytes().all(|x| x.is_ascii_digit()) {
+        if let Some(build_id) = get("BUILD_ID").filter(|x| !x.contains(date.as_str())) {
+            return Some(format!(
+                "build {build_id} was installed instead of the selected {date}"
+            ));
+        }
+    }
+    if let Some(id) = get("VARIANT_ID").filter(|x| !x.eq_ignore_ascii_case(&variant.name)) {
+        return Some(format!(
+            "variant {id} was installed instead of the selected {}",
+            variant.name
+        ));
+    }
+
+    None
+}
+
+/// Checks that the guest environment is the selected AOSC OS release, by the
+/// BUILD_ID and VARIANT_ID in os-release where it has them, and that /boot
+/// contains a kernel (and an initramfs, where one is generated)
+/// Must be used in a chroot context
+pub fn verify_installation(variant: &network::VariantEntry) -> Result<()> {
+    let buf = std::fs::read("/etc/os-release").map_err(|e| {
+        anyhow!("Installer could not read /etc/os-release from the installed system: {e}")
+    })?;
+    let os_release = parse_os_release(&buf)
+        .map_err(|e| anyhow!("Failed to parse os-release, {}", e))?
+        .1;
+    let get = |key: &str| os_release.iter().find(|(k, _)| *k == key).map(|(_, v)| *v);
+
+    let name = get("NAME").unwrap_or_default();
+    if !name.starts_with("AOSC OS") && get("ID") != Some("aosc") {
+        bail!(
+            "Installer found an unexpected system ({}) after unpacking the system release. The system release may be corrupted.",
+            get("PRETTY_NAME").unwrap_or(name)
+        );
+    }
+    if let Some(mismatch) = release_mismatch(get, variant) {
+        bail!("Installer found an unexpected system release after unpacking it: {mismatch}. The mirror may be serving an outdated or wrong release.");
+    }
+    info!(
+        "Installed {} (variant: {}, date: {}, build: {})",
+        get("PRETTY_NAME").unwrap_or(name),
+        variant.name,
+        variant.date,
+        get("BUILD_ID").unwrap_or("unknown")
+    );
+
+    let boot_files = std::fs::read_dir("/boot")?
+        .filter_map(|x| x.ok())
+        .map(|x| x.file_name().to_string_lossy().to_string())
+        .collect::<Vec<_>>();
+    info!("Files in /boot: {:?}", boot_files);
+
+    if !boot_files.iter().any(|x| x.starts_with("vmlinu")) {
+        bail!("Installer could not find a kernel in /boot of the installed system. The system would not be able to boot.");
+    }
+
+    // Retro releases do not run dracut
+    if cfg!(not(feature = "is_retro"))
+        && !boot_files
+            .iter()
+            .any(|x| x.starts_with("initramfs") || x.starts_with("initrd"))
+    {
+        bail!("Installer could not find an initramfs in /boot of the installed system. The system would not be able to boot.");
+    }
+
+    Ok(())
+}
+
+/// Daemons that manage power on laptops
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PowerManagement {
+    Tlp,
+    PowerProfilesDaemon,
+}
+
+impl PowerManagement {
+    pub fn package(self) -> &'static str {
+        match self {
+            PowerManagement::Tlp => "tlp",
+            PowerManagement::PowerProfilesDaemon => "power-profiles-daemon",
+        }
+    }
+
+    pub fn service(self) -> &'static str {
+        match self {
+            PowerManagement::Tlp => "tlp.service",
+            PowerManagement::PowerProfilesDaemon => "power-profiles-daemon.service",
+        }
+    }
+}
+
+impl std::str::FromStr for PowerManagement {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "tlp" => Ok(PowerManagement::Tlp),
+            "power-profiles-daemon" => Ok(PowerManagement::PowerProfilesDaemon),
+            _ => Err(format!("{s} is not one of tlp or power-profiles-daemon")),
+        }
+    }
+}
+
+pub fn optenv32_available() -> bool {
+    network::get_arch_name() == Some("amd64")
+}
+
+/// Whether this machine has a battery of its own, that is, whether it is a
+/// laptop. Batteries of wireless peripherals do not count.
+pub fn has_battery() -> bool {
+    let dir = match std::fs::read_dir("/sys/class/power_supply") {
+        Ok(dir) => dir,
+        Err(_) => return false,
+    };
+
+    dir.filter_map(|x| x.ok()).any(|x| {
+        let read = |name: &str| {
+            std::fs::read_to_string(x.path().join(name))
+                .map(|x| x.trim().to_string())
+                .unwrap_or_default()
+        };
+
+        read("type") == "Battery" && read("scope") != "Device"
+    })
+}
+
+/// Installs packages from the AOSC OS repository, using the DNS settings of
+/// the live environment while doing so
+/// Must be used in a chroot context
+pub fn install_packages(packages: &[&str], host_resolv_conf: Option<&[u8]>) -> Result<()> {
+    if packages.is_empty() {
+        return Ok(());
+    }
+
+    let resolv_conf = Path::new("/etc/resolv.conf");
+    let backup = Path::new("/etc/resolv.conf.dkbak");
+    // resolv.conf may be a dangling symlink in the chroot
+    let has_original = resolv_conf.symlink_metadata().is_ok();
+    if let Some(host_resolv_conf) = host_resolv_conf {
+        if has_original {
+            std::fs::rename(resolv_conf, backup)?;
+        }
+        std::fs::write(resolv_conf, host_resolv_conf)?;
+    }
+
+    let res = run_command("apt-get", ["update"]).and_then(|_| {
+        let mut args = vec!["install", "--yes"];
+        args.extend(packages);
+        run_command("apt-get", args)
+    });
+
+    if host_resolv_conf.is_some() {
+        std::fs::remove_file(resolv_conf).ok();
+        if has_original {
+            std::fs::rename(backup, resolv_conf)?;
+        }
+    }
+
+    res.map_err(|e| {
+        anyhow!(
+            "Installer failed to install {}:\n\n{e}",
+            packages.join(", ")
+        )
+    })
+}
+
+/// Enables a systemd unit in the guest environment
+/// Must be used in a chroot context
+pub fn enable_service(unit: &str) -> Result<()> {
+    run_command("systemctl", ["enable", unit])
+}
+
+/// Swap to zram instead of a swapfile
+/// Must be used in a chroot context
+pub fn write_zram_generator_conf() -> Result<()> {
+    std::fs::create_dir_all("/etc/systemd")?;
+    std::fs::write("/etc/systemd/zram-generator.conf", ZRAM_GENERATOR_CONF)?;
+
+    Ok(())
+}
+
+/// LDAP directory the installed system authenticates against through SSSD
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DirectoryConfig {
+    pub uri: String,
+    pub base_dn: String,
+}
+
+impl DirectoryConfig {
+    pub fn new(uri: &str, base_dn: &str) -> Result<Self> {
+        let uri = uri.trim();
+        let base_dn = base_dn.trim();
+        if !(uri.starts_with("ldap://") || uri.starts_with("ldaps://"))
+            || uri.contains(char::is_whitespace)
+        {
+            bail!("Installer could not use {uri} as directory server: it must start with ldap:// or ldaps://.");
+        }
+        if !base_dn.contains('=') || base_dn.contains('\n') {
+            bail!("Installer could not use {base_dn} as base DN, expected something like dc=example,dc=com.");
+        }
+
+        Ok(DirectoryConfig {
+            uri: uri.to_string(),
+            base_dn: base_dn.to_string(),
+        })
+    }
+
+    fn sssd_conf(&self) -> String {
+        format!(
+            "[sssd]\nservices = nss, pam\ndomains = default\n\n[domain/default]\nid_provider = ldap\nauth_provider = ldap\nldap_uri = {}\nldap_search_base = {}\ncache_credentials = true\n",
+            self.uri, self.base_dn
+        )
+    }
+}
+
+/// Fixed address of the installed system, configured with systemd-networkd
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StaticNetworkConfig {
+    pub interface: String,
+    /// Address with prefix length, e.g. 192.168.1.10/24
+    pub address: String,
+    pub gateway: Option<String>,
+    pub dns: Vec<String>,
+}
+
+impl StaticNetworkConfig {
+    /// `dns` is a list of addresses separated by spaces or commas
+    pub fn new(interface: &str, address: &str, gateway: &str, dns: &str) -> Result<Self> {
+        let interface = interface.trim();
+        let address = address.trim();
+        let gateway = gateway.trim();
+        if interface.is_empty() || interface.contains(|c: char| c.is_whitespace() || c == '/') {
+            bail!("Installer could not use {interface} as network interface.");
+        }
+        let valid_address = address.split_once('/').is_some_and(|(ip, prefix)| {
+            let max_prefix = match ip.parse::<IpAddr>() {
+                Ok(IpAddr::V4(_)) => 32,
+                Ok(IpAddr::V6(_)) => 128,
+                Err(_) => return false,
+            };
+            prefix.parse::<u8>().is_ok_and(|x| x <= max_prefix)
+        });
+        if !valid_address {
+            bail!("Installer could not use {address} as address, expected something like 192.168.1.10/24.");
+        }
+        if !gateway.is_empty() && gateway.parse::<IpAddr>().is_err() {
+            bail!("Installer could not use {gateway} as gateway: it is not an IP address.");
+        }
+        let dns = dns
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                x.parse::<IpAddr>().map(|_| x.to_string()).map_err(|_| {
+                    anyhow!("Installer could not use {x} as DNS server: it is not an IP address.")
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(StaticNetworkConfig {
+            interface: interface.to_string(),
+            address: address.to_string(),
+            gateway: Some(gateway.to_string()).filter(|x| !x.is_empty()),
+            dns,
+        })
+    }
+
+    fn networkd_conf(&self) -> String {
+        let mut res = format!(
+            "[Match]\nName={}\n\n[Network]\nAddress={}\n",
+            self.interface, self.address
+        );
+        if let Some(gateway) = &self.gateway {
+            res.push_str(&format!("Gateway={gateway}\n"));
+        }
+        for dns in &self.dns {
+            res.push_str(&format!("DNS={dns}\n"));
+        }
+
+        res
+    }
+}
+
+impl std::fmt::Display for StaticNetworkConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} on {}", self.address, self.interface)?;
+        if let Some(gateway) = &self.gateway {
+            write!(f, ", gateway {gateway}")?;
+        }
+        if !self.dns.is_empty() {
+            write!(f, ", DNS {}", self.dns.join(" "))?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Configures systemd-networkd to bring up `network`, and keeps
+/// NetworkManager away from its interface
+/// Must be used in a chroot context
+pub fn configure_networkd(network: &StaticNetworkConfig) -> Result<()> {
+    std::fs::create_dir_all("/etc/systemd/network")?;
+    std::fs::write(
+        "/etc/systemd/network/80-deploykit-static.network",
+        network.networkd_conf(),
+    )?;
+    std::fs::create_dir_all("/etc/NetworkManager/conf.d")?;
+    std::fs::write(
+        "/etc/NetworkManager/conf.d/80-deploykit-unmanaged.conf",
+        format!(
+            "[keyfile]\nunmanaged-devices=interface-name:{}\n",
+            network.interface
+        ),
+    )?;
+    if !network.dns.is_empty() {
+        // DNS servers of networkd only reach programs through resolved
+        std::fs::remove_file("/etc/resolv.conf").ok();
+        std::os::unix::fs::symlink("/run/systemd/resolve/stub-resolv.conf", "/etc/resolv.conf")?;
+    }
+
+    Ok(())
+}
+
+const NM_CONNECTIONS_DIR: &str = "etc/NetworkManager/system-connections";
+
+/// NetworkManager profiles of the live system worth keeping, those for
+/// wireless networks, which include their passwords, and those with DNS
+/// servers set by hand
+pub fn live_network_connections() -> Vec<PathBuf> {
+    let Ok(entries) = std::fs::read_dir(Path::new("/").join(NM_CONNECTIONS_DIR)) else {
+        return vec![];
+    };
+    let mut res = entries
+        .flatten()
+        .map(|x| x.path())
+        .filter(|x| {
+            std::fs::read_to_string(x).is_ok_and(|x| is_wifi_connection(&x) || has_dns_servers(&x))
+        })
+        .collect::<Vec<_>>();
+    res.sort();
+
+    res
+}
+
+/// Value of `key` in `section` of a NetworkManager keyfile
+fn keyfile_value<'a>(keyfile: &'a str, section: &str, key: &str) -> Option<&'a str> {
+    let mut current = "";
+    for line in keyfile.lines().map(|x| x.trim()) {
+        if let Some(name) = line.strip_prefix('[').and_then(|x| x.strip_suffix(']')) {
+            current = name;
+        } else if let Some((k, value)) = line.split_once('=').filter(|_| current == section) {
+            if k.trim() == key {
+                return Some(value.trim());
+            }
+        }
+    }
+
+    None
+}
+
+fn is_wifi_connection(keyfile: &str) -> bool {
+    keyfile_value(keyfile, "connection", "type")
+        .is_some_and(|x| matches!(x, "wifi" | "802-11-wireless"))
+}
+
+fn has_dns_servers(keyfile: &str) -> bool {
+    ["ipv4", "ipv6"]
+        .iter()
+        .any(|x| keyfile_value(keyfile, x, "dns").is_some_and(|x| !x.trim_matches(';').is_empty()))
+}
+
+/// Copy the Wi-Fi and DNS profiles of the live system and `proxy` into the
+/// system mounted at `root`, so that it is online again after the first boot
+pub fn copy_live_network(root: &Path, proxy: Option<&str>) -> Result<()> {
+    let connections = root.join(NM_CONNECTIONS_DIR);
+    for path in live_network_connections() {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        info!("Copying network profile {}", path.display());
+        std::fs::create_dir_all(&connections)?;
+        let target = connections.join(name);
+        std::fs::copy(&path, &target)?;
+        // NetworkManager ignores profiles others can read
+        std::fs::set_permissions(&target, std::fs::Permissions::from_mode(0o600))?;
+    }
+
+    if let Some(proxy) = proxy {
+        info!("Setting proxy {proxy}");
+        let quoted = shell_words::quote(proxy);
+        std::fs::create_dir_all(root.join("etc/profile.d"))?;
+        std::fs::write(
+            root.join("etc/profile.d/80-deploykit-proxy.sh"),
+            format!(
+                "export http_proxy={quoted}\nexport https_proxy={quoted}\nexport all_proxy={quoted}\n"
+            ),
+        )?;
+        std::fs::create_dir_all(root.join("etc/apt/apt.conf.d"))?;
+        std::fs::write(
+            root.join("etc/apt/apt.conf.d/80-deploykit-proxy"),
+            format!("Acquire::http::Proxy \"{proxy}\";\nAcquire::https::Proxy \"{proxy}\";\n"),
+        )?;
+    }
+
+    Ok(())
+}
+
+/// PAM stacks to hook pam_sss.so into, with the control flag to use
+const PAM_SSS_ENTRIES: &[(&str, &str, &str)] = &[
+    ("/etc/pam.d/system-auth", "auth", "sufficient"),
+    ("/etc/pam.d/system-account", "account", "sufficient"),
+    ("/etc/pam.d/system-session", "session", "optional"),
+];
+
+/// Add `source` to the passwd, group and shadow databases of nsswitch.conf
+fn add_nss_source(nsswitch: &str, source: &str) -> String {
+    let mut res = String::new();
+    for line in nsswitch.lines() {
+        let database = line.trim_start().split(':').next().unwrap_or("");
+        let has_source = line.split_whitespace().any(|x| x == source);
+        res.push_str(line);
+        if ["passwd", "group", "shadow"].contains(&database) && line.contains(':') && !has_source {
+            res.push(' ');
+            res.push_str(source);
+        }
+        res.push('\n');
+    }
+
+    res
+}
+
+/// Insert a pam_sss.so line of `kind` before the first pam_unix.so line of
+/// the same kind, or append it if there is none
+fn add_pam_sss(pam: &str, kind: &str, control: &str) -> String {
+    let entry = format!("{kind} {control} pam_sss.so");
+    let mut lines: Vec<&str> = pam.lines().collect();
+    if lines.iter().any(|x| x.contains("pam_sss.so")) {
+        return pam.to_string();
+    }
+
+    let pos = lines.iter().position(|x| {
+        let mut fields = x.split_whitespace();
+        fields.next() == Some(kind) && x.contains("pam_unix.so")
+    });
+    match pos {
+        Some(pos) => lines.insert(pos, &entry),
+        None => lines.push(&entry),
+    }
+
+    lines.join("\n") + "\n"
+}
+
+/// Configures SSSD to look users up in `directory`
 /// Must be used in a chroot context
-#[cfg(feature = "is_retro")]
-pub fn gen_ssh_key() -> Result<()> {
-    run_command("ssh-keygen", &["-A"])?;
+pub fn configure_sssd(directory: &DirectoryConfig) -> Result<()> {
+    std::fs::create_dir_all("/etc/sssd")?;
+    let mut f = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open("/etc/sssd/sssd.conf")?;
+    f.write_all(directory.sssd_conf().as_bytes())?;
+
+    let nsswitch = std::fs::read_to_string("/etc/nsswitch.conf")?;
+    std::fs::write("/etc/nsswitch.conf", add_nss_source(&nsswitch, "sss"))?;
+
+    for (path, kind, control) in PAM_SSS_ENTRIES {
+        match std::fs::read_to_string(path) {
+            Ok(pam) => std::fs::write(path, add_pam_sss(&pam, kind, control))?,
+            Err(e) => warn!("Could not read {path}, skipping pam_sss setup: {e}"),
+        }
+    }
 
     Ok(())
 }
@@ -428,9 +1624,17 @@ pub fn set_hwclock_tc(utc: bool) -> Result<()> {
 
 /// Adds a new normal user to the guest environment
 /// Must be used in a chroot context
-pub fn add_new_user(name: &str, password: &str) -> Result<()> {
+pub fn add_new_user(name: &str, password: &str, extra_groups: &[String]) -> Result<()> {
     run_command("useradd", ["-m", "-s", "/bin/bash", name])?;
-    run_command("usermod", ["-aG", "audio,cdrom,video,wheel,plugdev", name])?;
+    let mut groups = vec!["audio", "cdrom", "video", "wheel", "plugdev"];
+    for group in extra_groups {
+        // groups that do not exist make usermod fail
+        if run_command("getent", ["group", group.as_str()]).is_err() {
+            run_command("groupadd", [group.as_str()])?;
+        }
+        groups.push(group);
+    }
+    run_command("usermod", ["-aG", &groups.join(","), name])?;
 
     chpasswd(name, password)?;
 
@@ -561,51 +1765,396 @@ pub fn passwd_set_fullname(full_name: &str, username: &str) -> Result<()> {
     Ok(())
 }
 
+/// How the installed system is booted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Bootloader {
+    #[default]
+    Grub,
+    /// The firmware loads the kernel directly, UEFI only
+    Efistub,
+}
+
+impl std::str::FromStr for Bootloader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "grub" => Ok(Bootloader::Grub),
+            "efistub" => Ok(Bootloader::Efistub),
+            _ => Err(format!("{s} is not one of grub or efistub")),
+        }
+    }
+}
+
+/// Where the ESP is mounted in the installed system
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EspMountPoint {
+    #[default]
+    Efi,
+    /// The traditional location, expected by some tools
+    BootEfi,
+}
+
+impl EspMountPoint {
+    pub const ALL: [EspMountPoint; 2] = [EspMountPoint::Efi, EspMountPoint::BootEfi];
+
+    pub fn path(self) -> &'static Path {
+        Path::new(match self {
+            EspMountPoint::Efi => "/efi",
+            EspMountPoint::BootEfi => "/boot/efi",
+        })
+    }
+
+    /// Relative to the root of the installed system
+    pub fn relative(self) -> &'static Path {
+        self.path().strip_prefix("/").unwrap()
+    }
+
+    /// Guess where an installed system mounts its ESP
+    pub fn detect(root: &Path) -> Self {
+        let fstab = std::fs::read_to_string(root.join("etc/fstab")).unwrap_or_default();
+        let mounts_boot_efi = fstab
+            .lines()
+            .any(|x| x.split_whitespace().nth(1) == Some("/boot/efi"));
+
+        if mounts_boot_efi {
+            EspMountPoint::BootEfi
+        } else {
+            EspMountPoint::Efi
+        }
+    }
+}
+
+impl std::str::FromStr for EspMountPoint {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "/efi" => Ok(EspMountPoint::Efi),
+            "/boot/efi" => Ok(EspMountPoint::BootEfi),
+            _ => Err(format!("{s} is not one of /efi or /boot/efi")),
+        }
+    }
+}
+
+/// Where the kernel is copied to on the ESP, as the firmware can only read FAT
+const EFISTUB_DIR: &str = "EFI/aosc";
+
+/// Compare kernel versions such as 6.10.2-aosc-main, numbers by their value
+fn cmp_kernel_versions(a: &str, b: &str) -> std::cmp::Ordering {
+    fn parts(version: &str) -> Vec<(bool, &str)> {
+        let mut parts = vec![];
+        let mut rest = version;
+        while let Some(first) = rest.chars().next() {
+            let is_digit = first.is_ascii_digit();
+            let len = rest
+                .find(|x: char| x.is_ascii_digit() != is_digit)
+                .unwrap_or(rest.len());
+            parts.push((is_digit, &rest[..len]));
+            rest = &rest[len..];
+        }
+
+        parts
+    }
+
+    for (a, b) in parts(a).into_iter().zip(parts(b)) {
+        let ord = match (a, b) {
+            ((true, a), (true, b)) => a
+                .trim_start_matches('0')
+                .len()
+                .cmp(&b.trim_start_matches('0').len())
+                .then_with(|| a.trim_start_matches('0').cmp(b.trim_start_matches('0'))),
+            ((_, a), (_, b)) => a.cmp(b),
+        };
+        if ord.is_ne() {
+            return ord;
+        }
+    }
+
+    a.len().cmp(&b.len())
+}
+
+/// The newest kernel in /boot and its version
+fn find_boot_kernel() -> Result<(PathBuf, String)> {
+    std::fs::read_dir("/boot")?
+        .filter_map(|x| x.ok())
+        .filter(|x| x.path().is_file())
+        .filter_map(|x| {
+            let name = x.file_name().to_string_lossy().to_string();
+            let version = name.strip_prefix("vmlinuz-")?.to_string();
+
+            Some((x.path(), version))
+        })
+        .max_by(|(_, a), (_, b)| cmp_kernel_versions(a, b))
+        .ok_or_else(|| {
+            anyhow!("Installer could not find vmlinuz-* in /boot of the installed system.")
+        })
+}
+
+/// The initramfs in /boot built for the kernel `version`
+fn find_boot_initramfs(version: &str) -> Result<PathBuf> {
+    [
+        format!("initramfs-{version}.img"),
+        format!("initrd.img-{version}"),
+        format!("initrd-{version}"),
+    ]
+    .iter()
+    .map(|x| Path::new("/boot").join(x))
+    .find(|x| x.is_file())
+    .ok_or_else(|| {
+        anyhow!("Installer could not find the initramfs of kernel {version} in /boot of the installed system.")
+    })
+}
+
+/// Create a UEFI boot entry that loads the kernel directly, with no
+/// bootloader in between. Kernel updates are not copied to the ESP.
+/// Must be used in a chroot context, with the ESP mounted at `esp_mount`
+pub fn install_efistub(
+    root: &Partition,
+    esp: &Partition,
+    esp_mount: &Path,
+    cmdline: &str,
+) -> Result<()> {
+    let (kernel, version) = find_boot_kernel()?;
+    let initramfs = find_boot_initramfs(&version)?;
+    info!(
+        "Using {} and {} for EFISTUB",
+        kernel.display(),
+        initramfs.display()
+    );
+
+    let stub_dir = esp_mount.join(EFISTUB_DIR);
+    std::fs::create_dir_all(&stub_dir)?;
+    std::fs::copy(&kernel, stub_dir.join("vmlinuz.efi"))?;
+    std::fs::copy(&initramfs, stub_dir.join("initramfs.img"))?;
+
+    let root_spec = if crate::zfs::is_zfs(root) {
+        crate::zfs::root_spec(root)?
+    } else {
+        let root_path = root
+            .path
+            .as_ref()
+            .ok_or_else(|| anyhow!("Installer could not find the system partition."))?;
+        let uuid = crate::disks::partition_uuid(root_path).ok_or_else(|| {
+            anyhow!(
+                "Installer could not obtain the UUID of {}.",
+                root_path.display()
+            )
+        })?;
+
+        format!("UUID={uuid}")
+    };
+    let esp_path = esp
+        .path
+        .as_ref()
+        .ok_or_else(|| anyhow!("Installer could not detect the EFI system partition."))?;
+    let esp_disk = esp.parent_path.as_ref().ok_or_else(|| {
+        anyhow!(
+            "Installer could not find the disk of {}.",
+            esp_path.display()
+        )
+    })?;
+    let esp_number = crate::disks::partition_number(esp_path).ok_or_else(|| {
+        anyhow!(
+            "Installer could not find the partition number of {}.",
+            esp_path.display()
+        )
+    })?;
+
+    let efi_dir = EFISTUB_DIR.replace('/', "\\");
+    let cmdline = format!(
+        "root={root_spec} rw initrd=\\{efi_dir}\\initramfs.img {}",
+        cmdline.trim()
+    );
+    let disk = esp_disk.to_string_lossy();
+    let part = esp_number.to_string();
+    let loader = format!("\\{efi_dir}\\vmlinuz.efi");
+    run_command(
+        "efibootmgr",
+        [
+            "--create",
+            "--disk",
+            &disk,
+            "--part",
+            &part,
+            "--label",
+            "AOSC OS",
+            "--loader",
+            &loader,
+            "--unicode",
+            cmdline.trim(),
+        ],
+    )
+}
+
 /// Runs grub-install and grub-mkconfig
 /// Must be used in a chroot context
 #[cfg(not(target_arch = "powerpc64"))]
-pub fn execute_grub_install(mbr_dev: Option<&PathBuf>) -> Result<()> {
-    use log::warn;
+pub fn execute_grub_install(mbr_dev: Option<&PathBuf>, esp_mount: &Path) -> Result<()> {
+    let Some(grub_install_args) = grub_install_args(mbr_dev.map(|x| x.as_path()), esp_mount) else {
+        info!(
+            "This architecture {:?} does not support grub",
+            network::get_arch_name()
+        );
+        return Ok(());
+    };
+
+    run_command("grub-install", &grub_install_args)?;
+    run_command("grub-mkconfig", ["-o", "/boot/grub/grub.cfg"])?;
 
-    let mut grub_install_args = vec![];
+    Ok(())
+}
 
+/// Arguments of grub-install, None if GRUB is not supported on this
+/// architecture
+pub fn grub_install_args(mbr_dev: Option<&Path>, esp_mount: &Path) -> Option<Vec<String>> {
     if let Some(mbr_dev) = mbr_dev {
-        grub_install_args.push("--target=i386-pc");
-        grub_install_args.push(
-            mbr_dev
-                .to_str()
-                .ok_or_else(|| anyhow!("Can not mbr_dev path to str!"))?,
-        );
+        return Some(vec![
+            "--target=i386-pc".to_string(),
+            mbr_dev.display().to_string(),
+        ]);
+    }
+
+    let target: &[&str] = match network::get_arch_name()? {
+        "amd64" if crate::disks::is_efi_ia32() => &["--target=i386-efi"],
+        "amd64" => &["--target=x86_64-efi"],
+        "arm64" => &["--target=arm64-efi", "--removable"],
+        "riscv64" => &["--target=riscv64-efi", "--removable"],
+        "loongarch64" => &["--target=loongarch64-efi", "--removable"],
+        _ => return None,
+    };
+    let mut args = vec!["--bootloader-id=AOSC OS".to_string()];
+    args.extend(target.iter().map(|x| x.to_string()));
+    args.push(format!("--efi-directory={}", esp_mount.display()));
+
+    Some(args)
+}
+
+/// Arguments of grub-install for the removable media path of the ESP only,
+/// without a boot entry on this machine
+pub fn grub_install_image_args(esp_mount: &Path) -> Option<Vec<String>> {
+    let mut args = grub_install_args(None, esp_mount)?;
+    args.retain(|x| x != "--removable" && !x.starts_with("--bootloader-id"));
+    args.push("--removable".to_string());
+    args.push("--no-nvram".to_string());
+
+    Some(args)
+}
+
+/// Arguments of grub-install for the removable media path of the ESP next to
+/// the boot entry, None where that is where GRUB is installed to anyway
+pub fn grub_install_removable_args(esp_mount: &Path) -> Option<Vec<String>> {
+    // the other architectures are always installed to the removable media path
+    if network::get_arch_name() != Some("amd64") {
+        return None;
+    }
+
+    let target = if crate::disks::is_efi_ia32() {
+        "--target=i386-efi"
     } else {
-        let (target, is_efi) = match network::get_arch_name() {
-            Some("amd64") => (&["--target=x86_64-efi"][..], true),
-            Some("arm64") => (&["--target=arm64-efi", "--removable"][..], true),
-            Some("riscv64") => (&["--target=riscv64-efi", "--removable"][..], true),
-            Some("loongarch64") => (&["--target=loongarch64-efi", "--removable"][..], true),
-            Some(arch) => {
-                info!("This architecture {arch} does not support grub");
-                return Ok(());
-            }
-            None => {
-                warn!("Install GRUB: What is this architecture???");
-                return Ok(());
-            }
-        };
-        grub_install_args.push("--bootloader-id=AOSC OS");
-        grub_install_args.extend(target);
-        if is_efi {
-            grub_install_args.push("--efi-directory=/efi");
-        }
+        "--target=x86_64-efi"
     };
 
-    run_command("grub-install", &grub_install_args)?;
+    Some(vec![
+        target.to_string(),
+        "--removable".to_string(),
+        format!("--efi-directory={}", esp_mount.display()),
+    ])
+}
+
+/// Where GRUB is installed to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum GrubTarget {
+    /// The removable media path of a disk image's ESP
+    Image,
+    /// The ESP, with a boot entry
+    Esp,
+    /// The removable media path of the ESP as well, for firmware that forgets
+    /// its boot entries, as seen on many eMMC laptops
+    EspRemovable,
+    /// The master boot record of a disk, on BIOS systems
+    Mbr(PathBuf),
+}
+
+/// Where GRUB is installed to, in order, for the system on `partition`
+pub fn grub_targets(
+    partition: &Partition,
+    mirror: Option<&Partition>,
+    image: bool,
+) -> Vec<GrubTarget> {
+    if is_efi_booted() {
+        if image {
+            return vec![GrubTarget::Image];
+        }
+        let mut targets = vec![GrubTarget::Esp];
+        if partition
+            .parent_path
+            .as_deref()
+            .is_some_and(crate::disks::is_emmc)
+        {
+            targets.push(GrubTarget::EspRemovable);
+        }
+
+        return targets;
+    }
+
+    crate::disks::grub_mbr_disks(partition, mirror)
+        .into_iter()
+        .map(GrubTarget::Mbr)
+        .collect()
+}
+
+/// Arguments of grub-install for `target`
+pub fn grub_target_args(target: &GrubTarget, esp_mount: &Path) -> Option<Vec<String>> {
+    match target {
+        GrubTarget::Image => grub_install_image_args(esp_mount),
+        GrubTarget::Esp => grub_install_args(None, esp_mount),
+        GrubTarget::EspRemovable => grub_install_removable_args(esp_mount),
+        GrubTarget::Mbr(disk) => grub_install_args(Some(disk), esp_mount),
+    }
+}
+
+/// Install GRUB to the removable media path of the ESP only, without a boot
+/// entry on this machine, for disk images that are booted elsewhere
+/// Must be used in a chroot context
+#[cfg(not(target_arch = "powerpc64"))]
+pub fn execute_grub_install_image(esp_mount: &Path) -> Result<()> {
+    let Some(args) = grub_install_image_args(esp_mount) else {
+        return Ok(());
+    };
+    run_command("grub-install", &args)?;
     run_command("grub-mkconfig", ["-o", "/boot/grub/grub.cfg"])?;
 
+    let boot_dir = esp_mount.join("EFI/BOOT");
+    if !dir_has_efi_binary(&boot_dir) {
+        bail!(
+            "Installer could not find the GRUB EFI binary in {}.",
+            boot_dir.display()
+        );
+    }
+
     Ok(())
 }
 
 #[cfg(target_arch = "powerpc64")]
-pub fn execute_grub_install(_mbr_dev: Option<&PathBuf>) -> Result<()> {
+pub fn execute_grub_install_image(esp_mount: &Path) -> Result<()> {
+    execute_grub_install(None, esp_mount)
+}
+
+/// Also install GRUB to the removable media path of the ESP, for firmware
+/// that forgets its boot entries, as seen on many eMMC laptops
+/// Must be used in a chroot context
+pub fn execute_grub_install_removable(esp_mount: &Path) -> Result<()> {
+    match grub_install_removable_args(esp_mount) {
+        Some(args) => run_command("grub-install", &args),
+        None => Ok(()),
+    }
+}
+
+#[cfg(target_arch = "powerpc64")]
+pub fn execute_grub_install(_mbr_dev: Option<&PathBuf>, _esp_mount: &Path) -> Result<()> {
     use std::io::BufReader;
 
     let target = network::get_arch_name();
@@ -640,6 +2189,165 @@ pub fn execute_grub_install(_mbr_dev: Option<&PathBuf>) -> Result<()> {
     Ok(())
 }
 
+/// Whether `efibootmgr` output lists an entry called `label`
+fn has_boot_entry(efibootmgr: &str, label: &str) -> bool {
+    efibootmgr.lines().any(|x| {
+        let (id, name) = match x.split_once(' ') {
+            Some(x) => x,
+            None => return false,
+        };
+        // entries look like "Boot0001* AOSC OS", unlike "BootOrder: ..."
+        let is_entry = id
+            .strip_prefix("Boot")
+            .map(|x| x.trim_end_matches('*'))
+            .map(|x| x.len() == 4 && x.chars().all(|c| c.is_ascii_hexdigit()))
+            .unwrap_or(false);
+
+        is_entry && name.trim_start().starts_with(label)
+    })
+}
+
+/// Check that the firmware has a boot entry called `label`
+fn verify_boot_entry(label: &str) -> Result<()> {
+    let output = Command::new("efibootmgr")
+        .output()
+        .map_err(|e| anyhow!("Installer could not run efibootmgr to check the boot entry: {e}"))?;
+    let output = String::from_utf8_lossy(&output.stdout);
+    if !has_boot_entry(&output, label) {
+        bail!("Installer could not find the \"{label}\" boot entry in the firmware (NVRAM). Your firmware may not allow adding boot entries, please try again after resetting the firmware settings, or add an entry for the EFI System Partition manually.");
+    }
+
+    Ok(())
+}
+
+/// Whether `dir` has a file ending in `.efi`, in any case
+fn dir_has_efi_binary(dir: &Path) -> bool {
+    std::fs::read_dir(dir)
+        .map(|x| {
+            x.filter_map(|x| x.ok()).any(|x| {
+                x.file_name()
+                    .to_string_lossy()
+                    .to_ascii_lowercase()
+                    .ends_with(".efi")
+            })
+        })
+        .unwrap_or(false)
+}
+
+/// Check that grub-install has actually installed GRUB
+/// Must be used in a chroot context
+#[cfg(not(target_arch = "powerpc64"))]
+pub fn verify_grub_install(mbr_dev: Option<&PathBuf>, esp_mount: &Path) -> Result<()> {
+    if let Some(mbr_dev) = mbr_dev {
+        let mut mbr = [0u8; 512];
+        File::open(mbr_dev)?.read_exact(&mut mbr)?;
+        // boot.img carries the string "GRUB" for its error messages
+        if !mbr.windows(4).any(|x| x == b"GRUB") {
+            bail!("Installer could not find GRUB in the master boot record of {}. Please make sure that the disk uses an MBR partition table, or has a BIOS boot partition if it uses GPT.", mbr_dev.display());
+        }
+
+        return Ok(());
+    }
+
+    if matches!(
+        network::get_arch_name(),
+        Some("arm64") | Some("riscv64") | Some("loongarch64")
+    ) {
+        // installed to the removable media path, no boot entry is created
+        let boot_dir = esp_mount.join("EFI/BOOT");
+        if !dir_has_efi_binary(&boot_dir) {
+            bail!("Installer could not find the GRUB EFI binary in {}. Please check that the EFI System Partition is formatted as FAT32 and has enough free space.", boot_dir.display());
+        }
+
+        return Ok(());
+    }
+
+    let grub_dir = esp_mount.join("EFI/AOSC OS");
+    if !dir_has_efi_binary(&grub_dir) {
+        bail!("Installer could not find the GRUB EFI binary in {}. Please check that the EFI System Partition is formatted as FAT32 and has enough free space.", grub_dir.display());
+    }
+
+    verify_boot_entry("AOSC OS")
+}
+
+#[cfg(target_arch = "powerpc64")]
+pub fn verify_grub_install(_mbr_dev: Option<&PathBuf>, _esp_mount: &Path) -> Result<()> {
+    Ok(())
+}
+
+/// Check that the kernel has been copied to the ESP and a boot entry created
+/// Must be used in a chroot context
+pub fn verify_efistub(esp_mount: &Path) -> Result<()> {
+    let kernel = esp_mount.join(EFISTUB_DIR).join("vmlinuz.efi");
+    if !kernel.is_file() {
+        bail!(
+            "Installer could not find the kernel at {}.",
+            kernel.display()
+        );
+    }
+
+    verify_boot_entry("AOSC OS")
+}
+
+/// Where `partition` is in use, as its mount points, or "swap" if it is
+/// swapped to
+pub fn partition_usage(partition: &Partition) -> Vec<String> {
+    let Some(path) = partition
+        .path
+        .as_deref()
+        .and_then(|x| std::fs::canonicalize(x).ok())
+    else {
+        return vec![];
+    };
+    let is_partition =
+        |dev: &str| dev.starts_with('/') && std::fs::canonicalize(dev).is_ok_and(|x| x == path);
+
+    let mut res = vec![];
+    if let Ok(buf) = std::fs::read("/proc/mounts") {
+        if let Ok((_, mounts)) = list_mounts(&buf) {
+            res.extend(
+                mounts
+                    .iter()
+                    .filter(|(dev, _)| is_partition(dev))
+                    .map(|(_, mount_path)| mount_path.to_string()),
+            );
+        }
+    }
+    if let Ok(swaps) = std::fs::read_to_string("/proc/swaps") {
+        if swaps
+            .lines()
+            .skip(1)
+            .filter_map(|x| x.split_whitespace().next())
+            .any(is_partition)
+        {
+            res.push("swap".to_string());
+        }
+    }
+
+    res
+}
+
+/// Unmount and swapoff `partition` wherever it is in use
+pub fn release_partition(partition: &Partition) -> Result<()> {
+    let mut usage = partition_usage(partition);
+    // nested mount points first
+    usage.sort_by_key(|x| std::cmp::Reverse(x.len()));
+    for mount_path in usage {
+        if mount_path == "swap" {
+            if let Some(path) = &partition.path {
+                run_command("swapoff", [path])?;
+            }
+            continue;
+        }
+        info!("Unmounting {mount_path}");
+        mount::unmount(mount_path.as_str(), mount::UnmountFlags::empty()).map_err(|e| {
+            anyhow!("Installer could not unmount {mount_path}: {e}, it may still be in use.")
+        })?;
+    }
+
+    Ok(())
+}
+
 pub fn prepare_try_umount() -> Result<()> {
     let mut mounts = std::fs::File::open("/proc/mounts")?;
     let mut buf = Vec::new();
@@ -677,49 +2385,156 @@ pub fn log_system_info() {
     );
 }
 
-/// Create swapfile
-pub fn create_swapfile(size: f64, use_swap: bool, tempdir: &Path) -> Result<()> {
+/// Swapfiles are allocated in chunks of this size to report progress
+const SWAPFILE_CHUNK_SIZE: u64 = 256 * 1024 * 1024;
+
+/// Where the swapfile is, relative to the root of the installed system. On
+/// btrfs it has a subvolume of its own, as swapon refuses files that share
+/// extents with a snapshot.
+pub fn swapfile_path(btrfs: bool) -> &'static Path {
+    Path::new(if btrfs { "swap/swapfile" } else { "swapfile" })
+}
+
+/// Create swapfile on a filesystem that is btrfs if `btrfs`, `progress` is
+/// called with the number of bytes allocated so far and the total size
+pub fn create_swapfile(
+    size: f64,
+    use_swap: bool,
+    swap_path: &Path,
+    btrfs: bool,
+    progress: impl Fn(u64, u64),
+) -> Result<()> {
     if !use_swap {
         return Ok(());
     }
 
-    let swap_path = tempdir.join("swapfile");
+    let size = size as u64;
 
     info!("Creating swapfile");
-    let mut swapfile = std::fs::File::create(&swap_path)?;
-
-    let res = unsafe {
-        libc::fallocate64(
-            swapfile.as_raw_fd(),
-            FallocateFlags::empty().bits() as i32,
-            0,
-            size as i64,
-        )
-    };
+    let swapfile = std::fs::File::create(swap_path)?;
+    // btrfs can only swap to files without copy-on-write, which must be
+    // set while the file is still empty
+    if btrfs {
+        run_command("chattr", [OsStr::new("+C"), swap_path.as_os_str()])?;
+    }
 
-    if res != 0 {
-        let res = Errno::from_raw_os_error(res).kind();
-        return Err(anyhow!("{res}"));
+    let mut allocated = 0;
+    while allocated < size {
+        let len = SWAPFILE_CHUNK_SIZE.min(size - allocated);
+        fs::fallocate(&swapfile, FallocateFlags::empty(), allocated, len)
+            .map_err(|e| anyhow!("Installer could not allocate swapfile: {}", e.kind()))?;
+        allocated += len;
+        progress(allocated, size);
     }
 
-    swapfile.flush()?;
+    let actual = swapfile.metadata()?.len();
+    if actual != size {
+        bail!("Installer could not allocate swapfile: expected {size} bytes, got {actual} bytes.");
+    }
 
     info!("Set swapfile permission as 600");
-    std::fs::set_permissions(&swap_path, std::fs::Permissions::from_mode(0o600))?;
+    std::fs::set_permissions(swap_path, std::fs::Permissions::from_mode(0o600))?;
 
-    run_command("mkswap", [&swap_path])?;
+    run_command("mkswap", [swap_path])?;
     run_command("swapon", [swap_path]).ok();
 
     Ok(())
 }
 
+/// Where `swapfile` starts on its filesystem, in pages, as the resume_offset
+/// kernel parameter expects for hibernating to a swapfile
+/// Must be used in a chroot context
+pub fn swapfile_resume_offset(swapfile: &Path, btrfs: bool) -> Result<u64> {
+    let (command, args): (&str, &[&str]) = if btrfs {
+        ("btrfs", &["inspect-internal", "map-swapfile", "-r"])
+    } else {
+        ("filefrag", &["-v"])
+    };
+    let output = Command::new(command).args(args).arg(swapfile).output()?;
+    if !output.status.success() {
+        bail!(
+            "Installer could not locate the swapfile: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let output = String::from_utf8_lossy(&output.stdout);
+
+    let offset = if btrfs {
+        output.trim().parse().ok()
+    } else {
+        // filefrag counts in filesystem blocks
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as u64;
+        parse_filefrag_offset(&output).map(|(offset, block_size)| offset * block_size / page_size)
+    };
+
+    offset.ok_or_else(|| anyhow!("Installer could not locate the swapfile: {output}"))
+}
+
+/// Physical offset of the first extent and the block size, from `filefrag -v`
+fn parse_filefrag_offset(output: &str) -> Option<(u64, u64)> {
+    let block_size = output
+        .lines()
+        .find_map(|x| x.split(" blocks of ").nth(1))?
+        .split_whitespace()
+        .next()?
+        .parse()
+        .ok()?;
+    let extent = output.lines().find(|x| x.trim_start().starts_with("0:"))?;
+    let offset = extent
+        .split(':')
+        .nth(2)?
+        .split("..")
+        .next()?
+        .trim()
+        .parse()
+        .ok()?;
+
+    Some((offset, block_size))
+}
+
 pub fn swapoff(tempdir: &Path) {
-    run_command("swapoff", [tempdir.join("swapfile")]).ok();
+    for btrfs in [false, true] {
+        let swap_path = tempdir.join(swapfile_path(btrfs));
+        if swap_path.exists() {
+            run_command("swapoff", [swap_path]).ok();
+        }
+    }
+}
+
+/// Format `partition` as swap and enable it
+pub fn enable_swap_partition(partition: &Partition, label: Option<&str>) -> Result<()> {
+    let path = partition
+        .path
+        .as_deref()
+        .ok_or_else(|| anyhow!("Installer could not find the specified swap partition."))?;
+
+    info!("Formatting {} as swap", path.display());
+    match label {
+        Some(label) => run_command(
+            "mkswap",
+            [OsStr::new("-L"), OsStr::new(label), path.as_os_str()],
+        )?,
+        None => run_command("mkswap", [path])?,
+    };
+    // the new UUID is looked up from udev for resume= and fstab
+    run_command("udevadm", ["settle"]).ok();
+    run_command("swapon", [path]).ok();
+
+    Ok(())
+}
+
+pub fn swapoff_partition(partition: &Partition) {
+    if let Some(path) = &partition.path {
+        run_command("swapoff", [path]).ok();
+    }
 }
 
 /// Must be used in a chroot context
-pub fn write_swap_entry_to_fstab() -> Result<()> {
-    let s = "/swapfile none swap defaults,nofail 0 0\n";
+pub fn write_swap_entry_to_fstab(swapfile: &Path) -> Result<()> {
+    let s = format!(
+        "{} none swap defaults,nofail 0 0\n",
+        Path::new("/").join(swapfile).display()
+    );
     let mut fstab = std::fs::OpenOptions::new()
         .append(true)
         .open("/etc/fstab")?;
@@ -733,12 +2548,16 @@ pub fn umount_all<F: AsFd>(mount_path: &Path, root_fd: F) {
     info!("Cleaning up mount path ...");
 
     escape_chroot(root_fd).ok();
-    let efi_path = mount_path.join("efi");
     if is_efi_booted() {
-        umount_root_path(&efi_path).ok();
+        for esp_mount in EspMountPoint::ALL {
+            umount_root_path(&mount_path.join(esp_mount.relative())).ok();
+        }
     }
     swapoff(mount_path);
+    umount_btrfs_subvolumes(mount_path);
     umount_root_path(mount_path).ok();
+    crate::zfs::export().ok();
+    crate::crypt::close();
 }
 
 pub fn is_valid_hostname(hostname: &str) -> bool {
@@ -858,3 +2677,165 @@ _apt:x:976:976::/var/lib/apt:/sbin/nologin
     assert!(set_full_name("Mag Mell\n", "saki", passwd.clone()).is_err());
     assert!(set_full_name("Mag Mell:", "saki", passwd.clone()).is_err());
 }
+
+#[test]
+fn test_release_mismatch() {
+    let variant = network::VariantEntry {
+        name: "Base".to_string(),
+        size: 0,
+        install_size: 0,
+        date: "20240801".to_string(),
+        sha256sum: String::new(),
+        url: String::new(),
+        description: String::new(),
+        sendstream: None,
+        metalink: None,
+        torrent: None,
+    };
+    let os_release = |fields: &'static [(&'static str, &'static str)]| {
+        move |key: &str| fields.iter().find(|(k, _)| *k == key).map(|(_, v)| *v)
+    };
+    assert_eq!(release_mismatch(os_release(&[]), &variant), None);
+    assert_eq!(
+        release_mismatch(
+            os_release(&[("BUILD_ID", "20240801"), ("VARIANT_ID", "base")]),
+            &variant
+        ),
+        None
+    );
+    assert!(release_mismatch(os_release(&[("BUILD_ID", "20240701")]), &variant).is_some());
+    assert!(release_mismatch(os_release(&[("VARIANT_ID", "desktop")]), &variant).is_some());
+
+    let custom = network::VariantEntry {
+        date: "Unknown".to_string(),
+        ..variant
+    };
+    assert_eq!(
+        release_mismatch(os_release(&[("BUILD_ID", "20240701")]), &custom),
+        None
+    );
+}
+
+#[test]
+fn test_directory_config() {
+    assert!(DirectoryConfig::new("ldaps://ldap.example.com", "dc=example,dc=com").is_ok());
+    assert!(DirectoryConfig::new("ldap.example.com", "dc=example,dc=com").is_err());
+    assert!(DirectoryConfig::new("ldap://ldap.example.com", "example.com").is_err());
+
+    let nsswitch = "# comment\npasswd: files\ngroup: files sss\nshadow:  files\nhosts: files dns\n";
+    assert_eq!(
+        add_nss_source(nsswitch, "sss"),
+        "# comment\npasswd: files sss\ngroup: files sss\nshadow:  files sss\nhosts: files dns\n"
+    );
+
+    let pam = "auth required pam_env.so\nauth required pam_unix.so\n";
+    assert_eq!(
+        add_pam_sss(pam, "auth", "sufficient"),
+        "auth required pam_env.so\nauth sufficient pam_sss.so\nauth required pam_unix.so\n"
+    );
+    assert_eq!(
+        add_pam_sss("session required pam_limits.so", "session", "optional"),
+        "session required pam_limits.so\nsession optional pam_sss.so\n"
+    );
+}
+
+#[test]
+fn test_is_wifi_connection() {
+    assert!(is_wifi_connection(
+        "[connection]\nid=AOSC\ntype=wifi\n\n[wifi]\nssid=AOSC\n"
+    ));
+    assert!(!is_wifi_connection(
+        "[connection]\nid=Wired\ntype=ethernet\n\n[wifi]\ntype=wifi\n"
+    ));
+    assert!(!is_wifi_connection("[wifi]\ntype=wifi\n"));
+}
+
+#[test]
+fn test_has_dns_servers() {
+    assert!(has_dns_servers(
+        "[connection]\nid=Wired\ntype=ethernet\n\n[ipv4]\ndns=1.1.1.1;9.9.9.9;\nmethod=auto\n"
+    ));
+    assert!(has_dns_servers("[ipv6]\ndns=2606:4700:4700::1111;\n"));
+    assert!(!has_dns_servers(
+        "[connection]\nid=Wired\ntype=ethernet\n\n[ipv4]\nmethod=auto\n"
+    ));
+    assert!(!has_dns_servers("[ipv4]\ndns=\n"));
+}
+
+#[test]
+fn test_static_network_config() {
+    let network = StaticNetworkConfig::new(
+        "eth0",
+        "192.168.1.10/24",
+        "192.168.1.1",
+        "1.1.1.1, 2606:4700::1111",
+    )
+    .unwrap();
+    assert_eq!(
+        network.networkd_conf(),
+        "[Match]\nName=eth0\n\n[Network]\nAddress=192.168.1.10/24\nGateway=192.168.1.1\nDNS=1.1.1.1\nDNS=2606:4700::1111\n"
+    );
+    assert!(StaticNetworkConfig::new("eth0", "2001:db8::10/64", "", "").is_ok());
+    assert!(StaticNetworkConfig::new("eth0", "192.168.1.10", "", "").is_err());
+    assert!(StaticNetworkConfig::new("eth0", "192.168.1.10/33", "", "").is_err());
+    assert!(StaticNetworkConfig::new("eth0", "192.168.1.10/24", "gateway", "").is_err());
+    assert!(StaticNetworkConfig::new("", "192.168.1.10/24", "", "").is_err());
+}
+
+#[test]
+fn test_update_grub_defaults() {
+    let grub = "GRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash\"\n";
+    assert_eq!(
+        update_grub_defaults(grub, "mitigations=off", Some(0)),
+        "GRUB_TIMEOUT=0\nGRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash mitigations=off\"\n"
+    );
+    assert_eq!(
+        update_grub_defaults("", "nomodeset", None),
+        "GRUB_CMDLINE_LINUX_DEFAULT=\"nomodeset\"\n"
+    );
+    assert_eq!(update_grub_defaults(grub, " ", None), grub);
+}
+
+#[test]
+fn test_has_boot_entry() {
+    let output = "BootCurrent: 0001\nTimeout: 1 seconds\nBootOrder: 0001,0000\nBoot0000* Windows Boot Manager\nBoot0001* AOSC OS\tHD(1,GPT,...)\n";
+    assert!(has_boot_entry(output, "AOSC OS"));
+    assert!(has_boot_entry(output, "Windows"));
+    assert!(!has_boot_entry(output, "Fedora"));
+    assert!(!has_boot_entry("BootOrder: AOSC OS", "AOSC OS"));
+}
+
+#[test]
+fn test_parse_filefrag_offset() {
+    let output = r#"Filesystem type is: ef53
+File size of /swapfile is 4294967296 (1048576 blocks of 4096 bytes)
+ ext:     logical_offset:        physical_offset: length:   expected: flags:
+   0:        0..   32767:      34816..     67583:  32768:
+   1:    32768..   65535:      98304..    131071:  32768:      67584:
+/swapfile: 2 extents found
+"#;
+    assert_eq!(parse_filefrag_offset(output), Some((34816, 4096)));
+    assert_eq!(parse_filefrag_offset(""), None);
+}
+
+#[test]
+fn test_cmp_kernel_versions() {
+    use std::cmp::Ordering;
+
+    assert_eq!(
+        cmp_kernel_versions("6.10.2-aosc-main", "6.9.12-aosc-main"),
+        Ordering::Greater
+    );
+    assert_eq!(
+        cmp_kernel_versions("6.6.9-aosc-main", "6.6.10-aosc-main"),
+        Ordering::Less
+    );
+    assert_eq!(
+        cmp_kernel_versions("6.6.10-aosc-main", "6.6.10-aosc-main"),
+        Ordering::Equal
+    );
+    assert_eq!(
+        cmp_kernel_versions("6.6.10-aosc-main", "6.6.10"),
+        Ordering::Greater
+    );
+}
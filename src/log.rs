@@ -1,11 +1,25 @@
-use std::path::{Path, PathBuf};
+use std::{
+    collections::VecDeque,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
 
 use anyhow::Result;
-use log::info;
+use log::{info, LevelFilter, Record};
+use once_cell::sync::Lazy;
 use time::OffsetDateTime;
 
+/// How many log records are kept in memory
+const RECENT_LOG_SIZE: usize = 500;
+
+static RECENT_LOG: Lazy<Mutex<VecDeque<String>>> =
+    Lazy::new(|| Mutex::new(VecDeque::with_capacity(RECENT_LOG_SIZE)));
+
 /// Log message to console and file
-pub fn setup_logger(is_cli: bool) -> Result<PathBuf> {
+///
+/// Every record is dispatched, `level` only sets the initial maximum so that it
+/// can be raised later with [`set_log_level`].
+pub fn setup_logger(is_cli: bool, level: LevelFilter) -> Result<PathBuf> {
     let now = OffsetDateTime::now_utc();
     let path = Path::new(&format!("/var/log/dklog-{now}.log")).to_path_buf();
 
@@ -20,8 +34,11 @@ pub fn setup_logger(is_cli: bool) -> Result<PathBuf> {
                 message
             ))
         })
-        .level(log::LevelFilter::Info)
-        .chain(fern::log_file(&path)?);
+        .level(LevelFilter::Trace)
+        .level_for("hyper", LevelFilter::Info)
+        .level_for("hyper_util", LevelFilter::Info)
+        .chain(fern::log_file(&path)?)
+        .chain(fern::Output::call(push_recent_log));
 
     let fern_log = if !is_cli {
         fern_log.chain(Box::new(cursive::logger::get_logger()) as Box<dyn log::Log>)
@@ -30,11 +47,38 @@ pub fn setup_logger(is_cli: bool) -> Result<PathBuf> {
     };
 
     fern_log.apply()?;
+    set_log_level(level);
 
     info!(
         "Using AOSC Deplotkit {} mode",
         if is_cli { "CLI" } else { "TUI" }
     );
+    info!("Log level: {level}");
 
     Ok(path)
 }
+
+pub fn set_log_level(level: LevelFilter) {
+    log::set_max_level(level);
+}
+
+pub fn log_level() -> LevelFilter {
+    log::max_level()
+}
+
+fn push_recent_log(record: &Record) {
+    if let Ok(mut log) = RECENT_LOG.lock() {
+        if log.len() == RECENT_LOG_SIZE {
+            log.pop_front();
+        }
+        log.push_back(record.args().to_string());
+    }
+}
+
+/// The last few log records, oldest first
+pub fn recent_log() -> Vec<String> {
+    RECENT_LOG
+        .lock()
+        .map(|log| log.iter().cloned().collect())
+        .unwrap_or_default()
+}
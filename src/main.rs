@@ -1,84 +1,101 @@
 use anyhow::{anyhow, Result};
 use log::setup_logger;
 use once_cell::sync::OnceCell;
+use rustix::{
+    fs::{flock, FlockOperation},
+    io::Errno,
+};
 use std::{
+    fs::{File, OpenOptions},
     io::{Read, Write},
-    path::{Path, PathBuf},
-    str::FromStr,
+    path::PathBuf,
 };
-use sysinfo::{Pid, System};
 
 use clap::Parser;
 use frontend::Args;
 
+mod cache;
+mod crypt;
 mod disks;
 mod frontend;
 mod install;
 mod log;
 mod network;
 mod parser;
+mod preflight;
+mod torrent;
+mod wifi;
+mod zfs;
+mod zsync;
 
 const LOCK: &str = "/run/lock/aoscdk.lock";
 
 pub static LOG_FILE: OnceCell<PathBuf> = OnceCell::new();
 
 fn main() {
-    if let Err(e) = create_lock() {
-        eprintln!("Installer failed to obtain the instance lock: {e}");
-        std::process::exit(1);
-    }
+    frontend::setup_panic_hook();
+
+    let args = Args::parse();
+    // held until the process exits, the kernel drops the lock even if we crash
+    let _lock = if args.needs_root() {
+        let problems = preflight::preflight_check();
+        if !problems.is_empty() {
+            eprintln!("Installer could not start because of the following problem(s):\n");
+            for i in problems {
+                eprintln!("- {i}");
+            }
+            std::process::exit(1);
+        }
 
-    if let Err(e) = execute() {
+        match create_lock() {
+            Ok(lock) => Some(lock),
+            Err(e) => {
+                eprintln!("Installer failed to obtain the instance lock: {e}");
+                std::process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+
+    if let Err(e) = execute(args) {
         eprintln!("{e}");
-        remove_lock().ok();
         std::process::exit(1);
     }
-    remove_lock().ok();
     std::process::exit(0);
 }
 
-fn execute() -> Result<()> {
-    let args = std::env::args();
-    if args.len() < 2 {
-        LOG_FILE.get_or_try_init(|| setup_logger(false))?;
-        frontend::tui_main();
-    } else {
-        let args = Args::parse();
-        LOG_FILE.get_or_try_init(|| setup_logger(true))?;
-        frontend::execute(args)?;
-    }
+fn execute(args: Args) -> Result<()> {
+    LOG_FILE.get_or_try_init(|| setup_logger(!args.is_tui(), args.log_level()))?;
+    frontend::execute(args)?;
 
     Ok(())
 }
 
-fn create_lock() -> Result<()> {
-    let lock = Path::new(LOCK);
-    if lock.is_file() {
-        let mut lock_file = std::fs::File::open(lock)?;
-        let mut old_pid = String::new();
-        lock_file.read_to_string(&mut old_pid)?;
-
-        let s = System::new_all();
-        let old_pid = Pid::from_str(&old_pid)?;
+fn create_lock() -> Result<File> {
+    let mut lock_file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(LOCK)?;
 
-        if s.process(old_pid).is_some() {
+    match flock(&lock_file, FlockOperation::NonBlockingLockExclusive) {
+        Ok(()) => {}
+        Err(Errno::WOULDBLOCK) => {
+            let mut old_pid = String::new();
+            lock_file.read_to_string(&mut old_pid).ok();
             return Err(anyhow!(
                 "Another instance of Installer (pid: {}) is still running!",
-                old_pid
+                old_pid.trim()
             ));
-        } else {
-            remove_lock()?;
         }
+        Err(e) => return Err(e.into()),
     }
-    let mut lock_file = std::fs::File::create(lock)?;
+
+    lock_file.set_len(0)?;
     let pid = std::process::id().to_string();
     lock_file.write_all(pid.as_bytes())?;
 
-    Ok(())
-}
-
-fn remove_lock() -> Result<()> {
-    std::fs::remove_file(LOCK)?;
-
-    Ok(())
+    Ok(lock_file)
 }
@@ -2,8 +2,8 @@ use std::str::Utf8Error;
 
 use nom::{
     branch::alt,
-    bytes::complete::{tag, take_until, take_while1},
-    character::complete::multispace1,
+    bytes::complete::{tag, take_until, take_while, take_while1},
+    character::complete::{digit1, multispace0, multispace1, space0, space1},
     combinator::{map, map_res},
     multi::many0,
     sequence::{preceded, terminated, tuple},
@@ -127,6 +127,206 @@ pub fn list_mounts(input: &[u8]) -> IResult<&[u8], Vec<(&str, &str)>> {
     Ok((input, result))
 }
 
+#[inline]
+fn os_release_single_line(input: &[u8]) -> IResult<&[u8], (&[u8], &[u8])> {
+    let (input, (key, _, value)) = tuple((
+        take_while1(|c: u8| c.is_ascii_alphanumeric() || c == b'_'),
+        tag("="),
+        take_while(|c: u8| c != b'\n'),
+    ))(input)?;
+
+    Ok((input, (key, value)))
+}
+
+fn os_release_to_tuple<'a>(input: (&'a [u8], &'a [u8])) -> Result<(&'a str, &'a str)> {
+    let key = std::str::from_utf8(input.0)?;
+    let value = std::str::from_utf8(input.1)?.trim();
+    let value = value
+        .strip_prefix('"')
+        .and_then(|x| x.strip_suffix('"'))
+        .or_else(|| value.strip_prefix('\'').and_then(|x| x.strip_suffix('\'')))
+        .unwrap_or(value);
+
+    Ok((key, value))
+}
+
+/// Parse os-release(5) into key-value pairs, quotes around values are removed
+#[inline]
+pub fn parse_os_release(input: &[u8]) -> IResult<&[u8], Vec<(&str, &str)>> {
+    let (input, result) = many0(preceded(
+        hr,
+        map_res(os_release_single_line, os_release_to_tuple),
+    ))(input)?;
+
+    Ok((input, result))
+}
+
+/// A single entry of `/proc/partitions`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProcPartition<'a> {
+    pub major: u64,
+    pub minor: u64,
+    /// Size in 1KiB blocks
+    pub blocks: u64,
+    pub name: &'a str,
+}
+
+#[inline]
+fn number(input: &[u8]) -> IResult<&[u8], u64> {
+    map_res(digit1, |x| -> Result<u64> {
+        Ok(std::str::from_utf8(x)?.parse()?)
+    })(input)
+}
+
+#[inline]
+fn proc_partitions_single_line(input: &[u8]) -> IResult<&[u8], ProcPartition> {
+    let (input, (_, major, _, minor, _, blocks, _, name)) = tuple((
+        space0,
+        number,
+        space1,
+        number,
+        space1,
+        number,
+        space1,
+        map_res(
+            take_while1(|c: u8| !c.is_ascii_whitespace()),
+            std::str::from_utf8,
+        ),
+    ))(input)?;
+
+    Ok((
+        input,
+        ProcPartition {
+            major,
+            minor,
+            blocks,
+            name,
+        },
+    ))
+}
+
+#[inline]
+pub fn parse_proc_partitions(input: &[u8]) -> IResult<&[u8], Vec<ProcPartition>> {
+    let (input, result) = preceded(
+        tuple((tag("major"), line_rest)),
+        many0(preceded(multispace0, proc_partitions_single_line)),
+    )(input)?;
+
+    Ok((input, result))
+}
+
+/// What the installer needs from a Metalink (RFC 5854) descriptor
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Metalink {
+    pub size: Option<u64>,
+    pub sha256sum: Option<String>,
+    /// From the most preferred to the least
+    pub urls: Vec<String>,
+}
+
+/// `<name attrs>content</name>`, without nested elements of the same name
+#[inline]
+fn xml_element<'a>(
+    name: &'a str,
+) -> impl FnMut(&'a [u8]) -> IResult<&'a [u8], (&'a [u8], &'a [u8])> {
+    let close = format!("</{name}>");
+    move |input| {
+        let (input, (_, _, attrs, _, content, _)) = tuple((
+            tag("<"),
+            tag(name),
+            take_until(">"),
+            tag(">"),
+            take_until(close.as_str()),
+            tag(close.as_str()),
+        ))(input)?;
+
+        Ok((input, (attrs, content)))
+    }
+}
+
+/// Attributes and contents of all `name` elements in `input`
+fn xml_elements<'a>(mut input: &'a [u8], name: &'a str) -> Vec<(&'a [u8], &'a str)> {
+    let open = format!("<{name}");
+    let mut res = vec![];
+    while let Ok((rest, _)) = take_until::<_, _, nom::error::Error<_>>(open.as_str())(input) {
+        match xml_element(name)(rest) {
+            // not some other element starting with the same name
+            Ok((rest, (attrs, content)))
+                if attrs.first().map_or(true, |x| x.is_ascii_whitespace()) =>
+            {
+                if let Ok(content) = std::str::from_utf8(content) {
+                    res.push((attrs, content.trim()));
+                }
+                input = rest;
+            }
+            _ => input = &rest[1..],
+        }
+    }
+
+    res
+}
+
+/// Value of the `name` attribute, in single or double quotes
+fn xml_attr<'a>(attrs: &'a [u8], name: &str) -> Option<&'a str> {
+    let attrs = std::str::from_utf8(attrs).ok()?;
+    let key = format!("{name}=");
+    let mut rest = attrs;
+    while let Some(pos) = rest.find(&key) {
+        let after_space = rest[..pos]
+            .chars()
+            .last()
+            .is_some_and(|x| x.is_ascii_whitespace());
+        rest = &rest[pos + key.len()..];
+        // not an attribute ending in `name`
+        if !after_space {
+            continue;
+        }
+        let quote = rest.chars().next().filter(|x| *x == '"' || *x == '\'')?;
+
+        return rest[1..].split_once(quote).map(|(value, _)| value);
+    }
+
+    None
+}
+
+/// Parse the `<file>` named `name` of a Metalink descriptor, or its only one
+/// if no name is given
+pub fn parse_metalink(input: &[u8], name: Option<&str>) -> Option<Metalink> {
+    let files = xml_elements(input, "file");
+    let (_, input) = match name {
+        Some(name) => files
+            .into_iter()
+            .find(|(attrs, _)| xml_attr(attrs, "name") == Some(name))?,
+        None if files.len() == 1 => files[0],
+        None => return None,
+    };
+    let input = input.as_bytes();
+    let size = xml_elements(input, "size")
+        .first()
+        .and_then(|(_, x)| x.parse().ok());
+    let sha256sum = xml_elements(input, "hash")
+        .into_iter()
+        .find(|(attrs, _)| xml_attr(attrs, "type") == Some("sha-256"))
+        .map(|(_, x)| x.to_lowercase());
+    let mut urls = xml_elements(input, "url")
+        .into_iter()
+        .map(|(attrs, url)| {
+            // 1 is the most preferred, no priority the least
+            let priority = xml_attr(attrs, "priority")
+                .and_then(|x| x.parse::<u32>().ok())
+                .unwrap_or(u32::MAX);
+            (priority, url.replace("&amp;", "&"))
+        })
+        .collect::<Vec<_>>();
+    urls.sort_by_key(|(priority, _)| *priority);
+
+    Some(Metalink {
+        size,
+        sha256sum,
+        urls: urls.into_iter().map(|(_, url)| url).collect(),
+    })
+}
+
 #[test]
 fn test_languagelist_single_line() {
     let s = "zh_CN;Chinese (Simplified);中文(简体);3;CN;zh_CN.UTF-8;zh_CN:zh;";
@@ -247,3 +447,88 @@ fn test_list_zoneinfo() {
     let buf = &b"#commit1\tcommit2\t\na\tb\tc/c\nd\te\tf/f\tg\n#commit3\nh\ti\tj/j\n"[..];
     assert_eq!(list_zoneinfo(buf).unwrap().1, vec!["c/c", "f/f", "j/j"]);
 }
+
+#[test]
+fn test_parse_proc_partitions() {
+    let buf = &b"major minor  #blocks  name
+
+ 259        0  500107608 nvme0n1
+ 259        1     524288 nvme0n1p1
+   8        0  976762584 sda
+ 179        0   31166976 mmcblk0
+"[..];
+    let parts = parse_proc_partitions(buf).unwrap().1;
+
+    assert_eq!(parts.len(), 4);
+    assert_eq!(
+        parts[1],
+        ProcPartition {
+            major: 259,
+            minor: 1,
+            blocks: 524288,
+            name: "nvme0n1p1",
+        }
+    );
+    assert_eq!(parts[3].name, "mmcblk0");
+}
+
+#[test]
+fn test_parse_os_release() {
+    let buf = &br#"# Generated by the system release
+NAME="AOSC OS"
+PRETTY_NAME="AOSC OS (11.4.0)"
+ID=aosc
+VERSION_ID='11.4.0'
+"#[..];
+    let os_release = parse_os_release(buf).unwrap().1;
+
+    assert_eq!(
+        os_release,
+        vec![
+            ("NAME", "AOSC OS"),
+            ("PRETTY_NAME", "AOSC OS (11.4.0)"),
+            ("ID", "aosc"),
+            ("VERSION_ID", "11.4.0"),
+        ]
+    );
+}
+
+#[test]
+fn test_parse_metalink() {
+    let buf = br#"<?xml version="1.0" encoding="UTF-8"?>
+<metalink xmlns="urn:ietf:params:xml:ns:metalink">
+  <file name="aosc-os_base_20240101_amd64.tar.xz">
+    <size>1073741824</size>
+    <hash type="sha-1">da39a3ee5e6b4b0d3255bfef95601890afd80709</hash>
+    <hash type="sha-256">E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855</hash>
+    <pieces length="262144" type="sha-256">
+      <hash>0000000000000000000000000000000000000000000000000000000000000000</hash>
+    </pieces>
+    <url location="us">https://mirror.example.com/aosc-os/base.tar.xz?a=1&amp;b=2</url>
+    <url location='cn'
+         priority='1'>https://repo.aosc.io/aosc-os/base.tar.xz</url>
+    <urlx>https://example.com/not-a-url</urlx>
+  </file>
+  <file name="aosc-os_base_20240101_amd64.tar.xz.sha256sum">
+    <size>98</size>
+    <url priority="1">https://repo.aosc.io/aosc-os/base.tar.xz.sha256sum</url>
+  </file>
+</metalink>
+"#;
+
+    assert_eq!(parse_metalink(buf, None), None);
+    assert_eq!(parse_metalink(buf, Some("base.tar.xz")), None);
+    assert_eq!(
+        parse_metalink(buf, Some("aosc-os_base_20240101_amd64.tar.xz")).unwrap(),
+        Metalink {
+            size: Some(1073741824),
+            sha256sum: Some(
+                "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855".to_string()
+            ),
+            urls: vec![
+                "https://repo.aosc.io/aosc-os/base.tar.xz".to_string(),
+                "https://mirror.example.com/aosc-os/base.tar.xz?a=1&b=2".to_string(),
+            ],
+        }
+    );
+}
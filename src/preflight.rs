@@ -0,0 +1,131 @@
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use rustix::process::geteuid;
+
+/// External tools every installation runs on the host
+///
+/// Those only needed by some filesystems and features are checked with
+/// [`check_tools`] once the installation options are known.
+///
+/// grub-install, chpasswd and friends are run inside the target system and
+/// ship with every variant, so they are not checked here.
+const REQUIRED_TOOLS: &[&str] = &["mkswap", "swapon", "swapoff"];
+
+/// Check that the installer can run in this environment
+///
+/// Returns a list of human-readable problems, empty if everything is in place.
+pub fn preflight_check() -> Vec<String> {
+    let mut problems = vec![];
+
+    if !geteuid().is_root() {
+        problems.push("Installer must be run as root.".to_string());
+    }
+
+    for tool in REQUIRED_TOOLS {
+        if !find_in_path(tool) {
+            problems.push(format!("Required command `{tool}` is not installed."));
+        }
+    }
+
+    let tmp = std::env::temp_dir();
+    if let Err(e) = tempfile::Builder::new()
+        .prefix(".dkpreflight")
+        .tempfile_in(&tmp)
+    {
+        problems.push(format!("{} is not writable: {e}", tmp.display()));
+    }
+
+    problems
+}
+
+/// Fail if any of `tools` is not installed on the host
+pub fn check_tools<S: AsRef<str>>(tools: &[S]) -> Result<()> {
+    let missing = tools
+        .iter()
+        .map(|x| x.as_ref())
+        .filter(|x| !find_in_path(x))
+        .collect::<Vec<_>>();
+    if !missing.is_empty() {
+        bail!(
+            "Installer could not find the following command(s) required for this installation: {}. Please install them or choose other options.",
+            missing.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+/// Battery charge in percent below which installing is refused, as running
+/// out of power while unpacking or installing GRUB leaves an unbootable system
+pub const LOW_BATTERY_PERCENT: u8 = 20;
+
+/// Charge of the battery in percent if running on it, None on AC power or
+/// without a battery
+pub fn battery_level() -> Option<u8> {
+    battery_level_in(Path::new(POWER_SUPPLY_PATH))
+}
+
+fn battery_level_in(dir: &Path) -> Option<u8> {
+    let read = |supply: &Path, name: &str| {
+        std::fs::read_to_string(supply.join(name))
+            .map(|x| x.trim().to_string())
+            .unwrap_or_default()
+    };
+    let mut level = None;
+    for supply in std::fs::read_dir(dir).ok()?.filter_map(|x| x.ok()) {
+        let supply = supply.path();
+        match read(&supply, "type").as_str() {
+            "Mains" | "USB" if read(&supply, "online") == "1" => return None,
+            // batteries of mice and other peripherals have the Device scope
+            "Battery" if read(&supply, "scope") != "Device" => {
+                if let Ok(capacity) = read(&supply, "capacity").parse::<u8>() {
+                    level = Some(level.map_or(capacity, |x: u8| x.min(capacity)));
+                }
+            }
+            _ => (),
+        }
+    }
+
+    level
+}
+
+fn find_in_path(tool: &str) -> bool {
+    let path = std::env::var_os("PATH").unwrap_or_default();
+    std::env::split_paths(&path)
+        .chain(
+            ["/usr/sbin", "/sbin"]
+                .iter()
+                .map(|x| Path::new(x).to_path_buf()),
+        )
+        .any(|dir| dir.join(tool).is_file())
+}
+
+#[test]
+fn test_battery_level() {
+    let dir = tempfile::tempdir().unwrap();
+    let supply = |name: &str, files: &[(&str, &str)]| {
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(&path).unwrap();
+        for (file, content) in files {
+            std::fs::write(path.join(file), content).unwrap();
+        }
+    };
+
+    assert_eq!(battery_level_in(dir.path()), None);
+    supply("BAT0", &[("type", "Battery\n"), ("capacity", "15\n")]);
+    supply(
+        "hidpp_battery_0",
+        &[("type", "Battery"), ("scope", "Device"), ("capacity", "5")],
+    );
+    assert_eq!(battery_level_in(dir.path()), Some(15));
+    supply("AC", &[("type", "Mains"), ("online", "1")]);
+    assert_eq!(battery_level_in(dir.path()), None);
+}
+
+#[test]
+fn test_find_in_path() {
+    assert!(find_in_path("sh"));
+    assert!(!find_in_path("deploykit-no-such-command"));
+}
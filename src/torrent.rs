@@ -0,0 +1,96 @@
+use std::{
+    os::unix::fs::MetadataExt,
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::info;
+
+use crate::network;
+
+/// Whether aria2c, which does the BitTorrent downloads, is installed
+pub fn is_available() -> bool {
+    Command::new("aria2c")
+        .arg("--version")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|x| x.success())
+}
+
+fn aria2c_args(torrent: &str, output: &Path) -> Vec<String> {
+    let dir = output.parent().unwrap_or(Path::new("/"));
+    let name = output.file_name().unwrap_or_default().to_string_lossy();
+    let mut args = vec![
+        format!("--dir={}", dir.display()),
+        // other files of the torrent are neither wanted nor accounted for
+        "--select-file=1".to_string(),
+        format!("--index-out=1={name}"),
+        "--follow-torrent=mem".to_string(),
+        // only what has been downloaded takes up space, to tell the progress
+        "--file-allocation=none".to_string(),
+        "--seed-time=0".to_string(),
+        format!(
+            "--bt-stop-timeout={}",
+            network::network_options().read_timeout.as_secs().max(1)
+        ),
+        "--quiet".to_string(),
+    ];
+    if let Some(proxy) = network::proxy() {
+        args.push(format!("--all-proxy={proxy}"));
+    }
+    if let Some(cert) = network::ca_certificate() {
+        args.push(format!("--ca-certificate={}", cert.display()));
+    }
+    args.push(torrent.to_string());
+
+    args
+}
+
+/// Download the first file of `torrent` to `output`, calling `progress` with
+/// the number of bytes received so far. Nothing is seeded afterwards.
+pub fn download(torrent: &str, output: &Path, mut progress: impl FnMut(u64)) -> Result<()> {
+    info!(
+        "Downloading {} with BitTorrent from {torrent}",
+        output.display()
+    );
+    let mut child = Command::new("aria2c")
+        .args(aria2c_args(torrent, output))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map_err(|e| anyhow!("Installer could not start aria2c: {e}"))?;
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        // the file is sparse, its blocks are what has arrived
+        if let Ok(meta) = std::fs::metadata(output) {
+            progress(meta.blocks() * 512);
+        }
+        thread::sleep(Duration::from_millis(200));
+    };
+    if !status.success() {
+        return Err(anyhow!(
+            "Installer could not download {torrent} with BitTorrent: aria2c {status}"
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_aria2c_args() {
+    let args = aria2c_args(
+        "https://example.com/base.torrent",
+        Path::new("/mnt/target/tarball"),
+    );
+    assert!(args.contains(&"--dir=/mnt/target".to_string()));
+    assert!(args.contains(&"--select-file=1".to_string()));
+    assert!(args.contains(&"--index-out=1=tarball".to_string()));
+    assert_eq!(args.last().unwrap(), "https://example.com/base.torrent");
+}
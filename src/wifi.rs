@@ -0,0 +1,130 @@
+use std::process::Command;
+
+use anyhow::{anyhow, Result};
+use log::info;
+
+use crate::install::{run_command, run_command_with_input};
+
+/// A wireless network seen by NetworkManager, which drives iwd or
+/// wpa_supplicant in the live environment
+#[derive(Debug, Clone, PartialEq)]
+pub struct WifiNetwork {
+    pub ssid: String,
+    /// Signal strength in percent
+    pub signal: u8,
+    pub secured: bool,
+}
+
+/// Whether this machine has a wireless interface NetworkManager can use
+pub fn has_wifi() -> bool {
+    Command::new("nmcli")
+        .args(["-t", "-f", "TYPE", "device"])
+        .output()
+        .is_ok_and(|x| {
+            String::from_utf8_lossy(&x.stdout)
+                .lines()
+                .any(|x| x == "wifi")
+        })
+}
+
+/// Scan for wireless networks, strongest first
+pub fn scan() -> Result<Vec<WifiNetwork>> {
+    let output = Command::new("nmcli")
+        .args([
+            "-t",
+            "-f",
+            "SSID,SIGNAL,SECURITY",
+            "device",
+            "wifi",
+            "list",
+            "--rescan",
+            "yes",
+        ])
+        .output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "Installer could not scan for wireless networks:\n\n{}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    Ok(parse_wifi_list(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Join `ssid`, the password is fed to nmcli through its standard input
+pub fn connect(ssid: &str, password: Option<&str>) -> Result<()> {
+    info!("Connecting to wireless network {ssid}");
+    let res = match password {
+        Some(password) => run_command_with_input(
+            "nmcli",
+            ["--ask", "device", "wifi", "connect", ssid],
+            format!("{password}\n").as_bytes(),
+        ),
+        None => run_command("nmcli", ["device", "wifi", "connect", ssid]),
+    };
+
+    res.map_err(|e| anyhow!("Installer could not connect to {ssid}: {e}"))
+}
+
+/// Split a line of `nmcli -t` output, where colons in values are escaped
+fn split_terse(line: &str) -> Vec<String> {
+    let mut res = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => res.last_mut().unwrap().extend(chars.next()),
+            ':' => res.push(String::new()),
+            c => res.last_mut().unwrap().push(c),
+        }
+    }
+
+    res
+}
+
+fn parse_wifi_list(output: &str) -> Vec<WifiNetwork> {
+    let mut res: Vec<WifiNetwork> = vec![];
+    for line in output.lines() {
+        let fields = split_terse(line);
+        let [ssid, signal, security] = fields.as_slice() else {
+            continue;
+        };
+        // hidden networks
+        if ssid.is_empty() {
+            continue;
+        }
+        let network = WifiNetwork {
+            ssid: ssid.clone(),
+            signal: signal.parse().unwrap_or(0),
+            secured: !security.is_empty() && security != "--",
+        };
+        // one entry per SSID, for the strongest access point
+        match res.iter_mut().find(|x| x.ssid == network.ssid) {
+            Some(x) if x.signal < network.signal => *x = network,
+            Some(_) => (),
+            None => res.push(network),
+        }
+    }
+    res.sort_by(|a, b| b.signal.cmp(&a.signal));
+
+    res
+}
+
+#[test]
+fn test_parse_wifi_list() {
+    let output = "AOSC:42:WPA2\nCafe\\: Free:80:\nAOSC:71:WPA2\n:90:WPA2\n";
+    assert_eq!(
+        parse_wifi_list(output),
+        vec![
+            WifiNetwork {
+                ssid: "Cafe: Free".to_string(),
+                signal: 80,
+                secured: false,
+            },
+            WifiNetwork {
+                ssid: "AOSC".to_string(),
+                signal: 71,
+                secured: true,
+            },
+        ]
+    );
+}
@@ -0,0 +1,201 @@
+use std::{ffi::OsStr, path::Path, process::Command, sync::Mutex};
+
+use anyhow::{anyhow, Result};
+use log::info;
+use once_cell::sync::Lazy;
+use rand::Rng;
+
+use crate::{disks::Partition, install::run_command};
+
+/// Pools are named this and a random suffix, so that they never clash with
+/// the pools of the live system or of other disks, e.g. the common "rpool"
+pub const POOL_PREFIX: &str = "aosc";
+/// Dataset the system is installed to, in the pool
+pub const ROOT_DATASET: &str = "ROOT/aosc";
+const HOME_DATASET: &str = "home";
+
+/// Name of the pool imported by [`import`], to export again
+static IMPORTED_POOL: Lazy<Mutex<Option<String>>> = Lazy::new(|| Mutex::new(None));
+
+fn new_pool_name() -> String {
+    format!("{POOL_PREFIX}-{:08x}", rand::thread_rng().gen::<u32>())
+}
+
+/// Name (LABEL) or GUID (UUID) of the pool on `partition`, as probed by blkid
+fn pool_tag(partition: &Partition, tag: &str) -> Result<String> {
+    let path = partition
+        .path
+        .as_deref()
+        .ok_or_else(|| anyhow!("Installer could not find the specified partition."))?;
+    let output = Command::new("blkid")
+        .args(["-o", "value", "-s", tag])
+        .arg(path)
+        .output()?;
+    let value = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    if !output.status.success() || value.is_empty() {
+        return Err(anyhow!(
+            "Installer could not find the ZFS pool on {}.",
+            path.display()
+        ));
+    }
+
+    Ok(value)
+}
+
+/// Packages the installed system needs to import the pool at boot
+pub const ZFS_PACKAGES: &[&str] = &["zfs"];
+
+pub fn is_zfs(partition: &Partition) -> bool {
+    partition.fs_type.as_deref() == Some("zfs")
+}
+
+/// Arguments of zpool to create the pool `pool` on `path`
+pub fn zpool_create_args<'a>(pool: &'a str, path: &'a Path) -> Vec<&'a OsStr> {
+    // GRUB can only read pools without newer features
+    vec![
+        OsStr::new("create"),
+        OsStr::new("-f"),
+        OsStr::new("-o"),
+        OsStr::new("ashift=12"),
+        OsStr::new("-o"),
+        OsStr::new("compatibility=grub2"),
+        OsStr::new("-O"),
+        OsStr::new("mountpoint=none"),
+        OsStr::new("-O"),
+        OsStr::new("compression=lz4"),
+        OsStr::new("-O"),
+        OsStr::new("acltype=posixacl"),
+        OsStr::new("-O"),
+        OsStr::new("xattr=sa"),
+        OsStr::new("-O"),
+        OsStr::new("relatime=on"),
+        OsStr::new(pool),
+        path.as_os_str(),
+    ]
+}
+
+/// Create a pool on `partition` with datasets for / and, unless `with_home`
+/// is false, for /home. The pool is exported afterwards.
+pub fn create_pool(partition: &Partition, with_home: bool) -> Result<()> {
+    let path = partition
+        .path
+        .as_deref()
+        .ok_or_else(|| anyhow!("Installer could not find the specified partition."))?;
+
+    let pool = new_pool_name();
+    info!("Creating ZFS pool {pool} on {}", path.display());
+    run_command("zpool", zpool_create_args(&pool, path))?;
+    if let Ok(mut imported) = IMPORTED_POOL.lock() {
+        *imported = Some(pool.clone());
+    }
+    run_command(
+        "zfs",
+        [
+            "create",
+            "-o",
+            "canmount=off",
+            "-o",
+            "mountpoint=none",
+            format!("{pool}/ROOT").as_str(),
+        ],
+    )?;
+    let root_dataset = format!("{pool}/{ROOT_DATASET}");
+    run_command(
+        "zfs",
+        [
+            "create",
+            "-o",
+            "canmount=noauto",
+            "-o",
+            "mountpoint=/",
+            root_dataset.as_str(),
+        ],
+    )?;
+    if with_home {
+        run_command(
+            "zfs",
+            [
+                "create",
+                "-o",
+                "mountpoint=/home",
+                format!("{pool}/{HOME_DATASET}").as_str(),
+            ],
+        )?;
+    }
+    run_command(
+        "zpool",
+        [
+            "set",
+            format!("bootfs={root_dataset}").as_str(),
+            pool.as_str(),
+        ],
+    )?;
+
+    export()
+}
+
+/// Import the pool on `partition` by its GUID, with `target` as its root, and
+/// mount its datasets
+pub fn import(partition: &Partition, target: &Path) -> Result<()> {
+    let pool = pool_tag(partition, "LABEL")?;
+    let guid = pool_tag(partition, "UUID")?;
+    info!("Importing ZFS pool {pool} ({guid}) at {}", target.display());
+    run_command(
+        "zpool",
+        [
+            OsStr::new("import"),
+            OsStr::new("-N"),
+            OsStr::new("-f"),
+            OsStr::new("-R"),
+            target.as_os_str(),
+            OsStr::new(&guid),
+        ],
+    )?;
+    if let Ok(mut imported) = IMPORTED_POOL.lock() {
+        *imported = Some(pool.clone());
+    }
+    run_command("zfs", ["mount", format!("{pool}/{ROOT_DATASET}").as_str()])?;
+    run_command("zfs", ["mount", "-a"])?;
+
+    Ok(())
+}
+
+/// Unmount all datasets and export the pool, if one has been imported
+pub fn export() -> Result<()> {
+    let Some(pool) = IMPORTED_POOL.lock().ok().and_then(|mut x| x.take()) else {
+        return Ok(());
+    };
+
+    info!("Exporting ZFS pool {pool}");
+    run_command("zpool", ["export", pool.as_str()])
+}
+
+/// Value of the root= kernel parameter for the pool on `partition`
+pub fn root_spec(partition: &Partition) -> Result<String> {
+    let pool = pool_tag(partition, "LABEL")?;
+
+    Ok(format!("ZFS={pool}/{ROOT_DATASET}"))
+}
+
+/// Give the system at `root_path` the host ID of the live environment, which
+/// the pool was created with, so that it imports the pool at boot
+pub fn copy_hostid(root_path: &Path) -> Result<()> {
+    let hostid = Path::new("/etc/hostid");
+    if hostid.exists() {
+        std::fs::copy(hostid, root_path.join("etc/hostid"))?;
+    }
+
+    Ok(())
+}
+
+/// Make sure the initramfs can import the pool
+/// Must be used in a chroot context
+pub fn write_dracut_conf() -> Result<()> {
+    std::fs::create_dir_all("/etc/dracut.conf.d")?;
+    std::fs::write(
+        "/etc/dracut.conf.d/80-deploykit-zfs.conf",
+        "add_dracutmodules+=\" zfs \"\n",
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,127 @@
+use std::{
+    ffi::OsString,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    thread,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use log::info;
+
+use crate::{cache, network};
+
+/// Whether zsync, which does the delta downloads, is installed
+pub fn is_available() -> bool {
+    Command::new("zsync")
+        .arg("-V")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .is_ok_and(|x| x.success())
+}
+
+/// Whether the release at `url` is worth a delta download. Classic zsync
+/// only fetches plain HTTP, so there is no TLS for a CA certificate to apply
+/// to, and only goes through HTTP proxies. xz compressed tarballs share next
+/// to no blocks between releases, only squashfs images are delta downloaded.
+pub fn is_usable(url: &str) -> bool {
+    url.starts_with("http://")
+        && url.ends_with(".squashfs")
+        && network::live_proxy().map_or(true, |x| x.starts_with("http://"))
+}
+
+/// Cached releases that can seed the delta download of `output`, those of
+/// the same kind
+pub fn seeds_for(output: &Path) -> Vec<PathBuf> {
+    cache::cached_tarballs()
+        .into_iter()
+        .filter(|x| x != output && x.extension() == output.extension())
+        .collect()
+}
+
+fn zsync_args(control: &str, seeds: &[PathBuf], output: &Path) -> Vec<OsString> {
+    let mut args: Vec<OsString> = vec!["-q".into()];
+    for seed in seeds.iter().filter(|x| *x != output) {
+        args.push("-i".into());
+        args.push(seed.into());
+    }
+    // an existing `output` is used as a seed by zsync itself
+    args.push("-o".into());
+    args.push(output.into());
+    args.push(control.into());
+
+    args
+}
+
+/// Build `output` from the blocks of `seeds` that are still in the release
+/// described by the `control` (.zsync) file, downloading the rest. `progress`
+/// is called with the size written so far.
+pub fn download(
+    control: &str,
+    seeds: &[PathBuf],
+    output: &Path,
+    mut progress: impl FnMut(u64),
+) -> Result<()> {
+    info!(
+        "Downloading {} with zsync from {control}, using {seeds:?}",
+        output.display()
+    );
+    let mut command = Command::new("zsync");
+    command
+        .args(zsync_args(control, seeds, output))
+        .stdout(Stdio::null())
+        .stderr(Stdio::null());
+    // set in the installer or only as all_proxy, which zsync does not read
+    if let Some(proxy) = network::live_proxy() {
+        command.env("http_proxy", proxy);
+    }
+    let mut child = command
+        .spawn()
+        .map_err(|e| anyhow!("Installer could not start zsync: {e}"))?;
+
+    let mut part = output.as_os_str().to_owned();
+    part.push(".part");
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if let Ok(meta) = std::fs::metadata(&part) {
+            progress(meta.len());
+        }
+        thread::sleep(Duration::from_millis(200));
+    };
+    if !status.success() {
+        return Err(anyhow!(
+            "Installer could not download {control} with zsync: zsync {status}"
+        ));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn test_zsync_args() {
+    let output = Path::new("/var/cache/deploykit/base_20240201.squashfs");
+    let seeds = [
+        PathBuf::from("/var/cache/deploykit/base_20240101.squashfs"),
+        output.to_path_buf(),
+    ];
+    let args = zsync_args(
+        "http://example.com/base_20240201.squashfs.zsync",
+        &seeds,
+        output,
+    );
+    assert_eq!(
+        args,
+        [
+            "-q",
+            "-i",
+            "/var/cache/deploykit/base_20240101.squashfs",
+            "-o",
+            "/var/cache/deploykit/base_20240201.squashfs",
+            "http://example.com/base_20240201.squashfs.zsync",
+        ]
+        .map(OsString::from)
+    );
+}